@@ -0,0 +1,14 @@
+// Text frames as the session hands them over: lossily decoded, since
+// Reassembly rejects invalid UTF-8 before the parser sees it. Anything the
+// parser refuses must come back as invalid_frame rather than a panic or
+// another error.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let text = String::from_utf8_lossy(data).into_owned();
+    if let Err(err) = rust_hw4::parse_frame(text) {
+        assert_eq!(err.code(), "invalid_frame");
+    }
+});
@@ -0,0 +1,532 @@
+use actix::prelude::*;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::history;
+use crate::rejections;
+use crate::store;
+use crate::visibility;
+use crate::{ChatRoom, RoomEvent, SharedState};
+
+pub const MAX_ROOM_NAME_LEN: usize = 100;
+const REDACTION_MARKER: &str = "[redacted]";
+// Caps compiled regex size so an admin typo can't exhaust memory
+const REDACT_REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+// Tells sessions in either room that history changed and they should resubscribe
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct RoomMerged {
+    pub from: Uuid,
+    pub into: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct RoomMerge {
+    source_room_id: Uuid,
+    target_room_id: Uuid,
+}
+
+pub async fn merge_rooms(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+    form: web::Json<RoomMerge>,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let source_id = state.resolve_room_id(form.source_room_id);
+    let target_id = state.resolve_room_id(form.target_room_id);
+    if source_id == target_id {
+        return Err(ApiError::SelfMerge);
+    }
+
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    if !rooms.contains_key(&target_id) {
+        return Err(ApiError::TargetRoomNotFound);
+    }
+    let Some(mut source) = rooms.remove(&source_id) else {
+        return Err(ApiError::SourceRoomNotFound);
+    };
+    let target = rooms.get_mut(&target_id).unwrap();
+    let source_members = source.members();
+    // Integrations addressing the source by its alias now reach the target
+    if target.external_id.is_none() {
+        target.external_id = source.external_id.take();
+    }
+
+    // The target's bans hold; the source's keep its banned users out unless
+    // they already belong to the target
+    source.participants.insert(source.created_by.clone());
+    for (username, ban) in source.bans {
+        if !target.participants.contains(&username) && username != target.created_by {
+            target.bans.entry(username).or_insert(ban);
+        }
+    }
+    for username in source.participants {
+        if target.bans.contains_key(&username) {
+            continue;
+        }
+        if let Some(joined_at) = source.joined_at.get(&username) {
+            let earliest = target
+                .joined_at
+                .entry(username.clone())
+                .or_insert(*joined_at);
+            *earliest = (*earliest).min(*joined_at);
+        }
+        // Staff of the source stay staff, under the target's owner
+        let staff = username == source.created_by || source.moderators.contains(&username);
+        if staff && username != target.created_by {
+            target.moderators.insert(username.clone());
+        }
+        target.participants.insert(username);
+    }
+    // One history in time order, numbered afresh. Read and delivery markers
+    // into either room move onto the new numbers; clients holding history
+    // cursors are told to start over by `RoomMerged`.
+    let mut old_seqs: HashMap<Uuid, (Uuid, u64)> = HashMap::new();
+    for msg in &target.message_log {
+        old_seqs.insert(msg.id, (target_id, msg.seq));
+    }
+    for mut msg in source.message_log {
+        old_seqs.insert(msg.id, (source_id, msg.seq));
+        msg.origin_room_id = Some(source_id);
+        msg.room_id = target_id;
+        target.message_log.push(msg);
+    }
+    target.resequence();
+    let mut renumbered: HashMap<Uuid, Vec<(u64, u64)>> = HashMap::new();
+    for msg in &target.message_log {
+        let (room_id, old_seq) = old_seqs[&msg.id];
+        renumbered
+            .entry(room_id)
+            .or_default()
+            .push((old_seq, msg.seq));
+    }
+    for seqs in renumbered.values_mut() {
+        seqs.sort_unstable();
+    }
+    // A marker stops just short of the first message from its room that it
+    // didn't cover, so interleaving never marks an unread message as read
+    let remap = |room_id: Uuid, seq: u64| {
+        let Some(seqs) = renumbered.get(&room_id) else {
+            return 0;
+        };
+        let covered = seqs.partition_point(|(old, _)| *old <= seq);
+        match seqs[covered..].iter().map(|(_, new)| *new).min() {
+            Some(first_unread) => first_unread - 1,
+            None => seqs.iter().map(|(_, new)| *new).max().unwrap_or(0),
+        }
+    };
+    state
+        .unread
+        .lock()
+        .unwrap()
+        .room_merged(source_id, target, remap);
+    state
+        .deliveries
+        .lock()
+        .unwrap()
+        .room_merged(source_id, target_id, remap);
+    let merged = target.clone();
+    drop(rooms);
+    store::room_removed(&state, source_id);
+    rejections::room_removed(&state, source_id);
+    store::room_changed(&state, &merged);
+    store::messages_changed(&state, &merged.message_log);
+
+    state
+        .room_redirects
+        .lock()
+        .unwrap()
+        .insert(source_id, target_id);
+
+    let notice = RoomMerged {
+        from: source_id,
+        into: target_id,
+    };
+    let mut sessions = state.active_sessions.lock().unwrap();
+    for room_id in [source_id, target_id] {
+        for addr in sessions.get(&room_id).into_iter().flatten() {
+            addr.do_send(notice.clone());
+        }
+    }
+    sessions.remove(&source_id);
+    drop(sessions);
+
+    state.notify_room_list_changed(&source_members, "deleted", source_id);
+    state.notify_room_list_changed(&merged.members(), "updated", target_id);
+
+    audit::record(
+        &state,
+        &user.username,
+        "merge_rooms",
+        &target_id.to_string(),
+        format!("merged {}", source_id),
+    );
+    Ok(HttpResponse::Ok().json(history::room_view(&merged)))
+}
+
+#[derive(Deserialize)]
+struct RoomManifestRow {
+    name: String,
+    owner: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    participants: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct RoomManifest {
+    rooms: Vec<RoomManifestRow>,
+}
+
+#[derive(Serialize)]
+struct ImportRowResult {
+    row: usize,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_id: Option<Uuid>,
+    errors: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ImportReport {
+    applied: bool,
+    rows: Vec<ImportRowResult>,
+}
+
+// Splits one CSV line, honouring double-quoted fields with "" escapes
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(';')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+// Expects a header row naming at least `name` and `owner`; `tags` and
+// `participants` columns are optional and `;`-separated
+fn parse_csv_manifest(body: &str) -> Result<Vec<RoomManifestRow>, ApiError> {
+    let mut lines = body.lines().filter(|line| !line.trim().is_empty());
+    let header = split_csv_line(lines.next().ok_or(ApiError::InvalidManifest)?);
+    let column = |name: &str| header.iter().position(|h| h.trim() == name);
+    let name_col = column("name").ok_or(ApiError::InvalidManifest)?;
+    let owner_col = column("owner").ok_or(ApiError::InvalidManifest)?;
+    let tags_col = column("tags");
+    let participants_col = column("participants");
+
+    Ok(lines
+        .map(|line| {
+            let fields = split_csv_line(line);
+            let get = |col: Option<usize>| {
+                col.and_then(|i| fields.get(i))
+                    .map(|v| v.trim().to_string())
+                    .unwrap_or_default()
+            };
+            RoomManifestRow {
+                name: get(Some(name_col)),
+                owner: get(Some(owner_col)),
+                tags: split_list(&get(tags_col)),
+                participants: split_list(&get(participants_col)),
+            }
+        })
+        .collect())
+}
+
+pub async fn import_rooms(
+    req: HttpRequest,
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let body = std::str::from_utf8(&body).map_err(|_| ApiError::InvalidManifest)?;
+    let is_csv = req
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/csv"));
+    let rows = if is_csv {
+        parse_csv_manifest(body)?
+    } else {
+        serde_json::from_str::<RoomManifest>(body)
+            .map_err(|_| ApiError::InvalidManifest)?
+            .rooms
+    };
+
+    // Validate every row first so a bad manifest leaves no partial state behind
+    let mut seen_names = HashSet::new();
+    let mut results: Vec<ImportRowResult> = rows
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let mut errors = Vec::new();
+            if row.name.trim().is_empty() {
+                errors.push("name is required".to_string());
+            } else if row.name.chars().count() > MAX_ROOM_NAME_LEN {
+                errors.push(format!("name exceeds {} characters", MAX_ROOM_NAME_LEN));
+            } else if !seen_names.insert(row.name.trim().to_string()) {
+                errors.push("duplicate name in manifest".to_string());
+            }
+            if row.owner.trim().is_empty() {
+                errors.push("owner is required".to_string());
+            }
+            if row.participants.iter().any(|p| p.trim().is_empty()) {
+                errors.push("participant names must not be empty".to_string());
+            }
+            ImportRowResult {
+                row: i + 1,
+                name: row.name.clone(),
+                room_id: None,
+                errors,
+            }
+        })
+        .collect();
+
+    if rows.is_empty() || results.iter().any(|r| !r.errors.is_empty()) {
+        return Ok(HttpResponse::UnprocessableEntity().json(ImportReport {
+            applied: false,
+            rows: results,
+        }));
+    }
+
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let mut created = Vec::new();
+    for (row, result) in rows.into_iter().zip(results.iter_mut()) {
+        let mut room = ChatRoom::new(
+            row.name.trim().to_string(),
+            state.canonical_username(&row.owner),
+        );
+        room.tags = row.tags;
+        room.participants = row
+            .participants
+            .iter()
+            .map(|name| state.canonical_username(name))
+            .collect();
+        result.room_id = Some(room.id);
+        created.push((room.id, room.members()));
+        store::room_changed(&state, &room);
+        rooms.insert(room.id, room);
+    }
+    drop(rooms);
+    for (room_id, members) in &created {
+        state.notify_room_list_changed(members, "created", *room_id);
+    }
+
+    audit::record(
+        &state,
+        &user.username,
+        "import_rooms",
+        "rooms",
+        format!("{} rooms provisioned", results.len()),
+    );
+    Ok(HttpResponse::Ok().json(ImportReport {
+        applied: true,
+        rows: results,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct RedactRequest {
+    #[serde(default)]
+    pattern: Option<String>,
+    #[serde(default)]
+    strings: Vec<String>,
+}
+
+pub async fn redact_room(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<RedactRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let pattern = form
+        .pattern
+        .as_deref()
+        .map(|p| {
+            regex::RegexBuilder::new(p)
+                .size_limit(REDACT_REGEX_SIZE_LIMIT)
+                .build()
+                .map_err(|_| ApiError::InvalidRedactPattern)
+        })
+        .transpose()?;
+    let strings: Vec<&str> = form
+        .strings
+        .iter()
+        .map(String::as_str)
+        .filter(|s| !s.is_empty())
+        .collect();
+    if pattern.is_none() && strings.is_empty() {
+        return Err(ApiError::InvalidRedactPattern);
+    }
+
+    let room_id = state.resolve_room_id(path.into_inner());
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    let mut redacted_ids = Vec::new();
+    let redact = |text: &str| {
+        let mut text = match &pattern {
+            Some(re) => re.replace_all(text, REDACTION_MARKER).into_owned(),
+            None => text.to_string(),
+        };
+        for needle in &strings {
+            text = text.replace(needle, REDACTION_MARKER);
+        }
+        text
+    };
+    for msg in room.message_log.iter_mut() {
+        let mut changed = false;
+        // Content an edit replaced is as visible as the current content
+        for text in std::iter::once(&mut msg.content).chain(
+            msg.edit_history
+                .iter_mut()
+                .map(|revision| &mut revision.content),
+        ) {
+            let redacted = redact(text);
+            if redacted != *text {
+                *text = redacted;
+                changed = true;
+            }
+        }
+        if changed {
+            msg.version.bump(&state.node_id);
+            redacted_ids.push(msg.id);
+        }
+    }
+    store::messages_changed(
+        &state,
+        room.message_log
+            .iter()
+            .filter(|msg| redacted_ids.contains(&msg.id)),
+    );
+    drop(rooms);
+
+    if !redacted_ids.is_empty() {
+        state.broadcast_event(
+            room_id,
+            RoomEvent(serde_json::json!({
+                "type": "messages_redacted",
+                "room_id": room_id,
+                "message_ids": redacted_ids,
+            })),
+        );
+    }
+    audit::record(
+        &state,
+        &user.username,
+        "redact_room",
+        &room_id.to_string(),
+        format!("{} messages redacted", redacted_ids.len()),
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "redacted": redacted_ids })))
+}
+
+// Adds a freshly registered account to every configured default room
+pub fn join_default_rooms(state: &SharedState, username: &str) {
+    let default_rooms: Vec<Uuid> = state.default_rooms.lock().unwrap().clone();
+    let default_rooms: Vec<Uuid> = default_rooms
+        .into_iter()
+        .map(|id| state.resolve_room_id(id))
+        .collect();
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let joined: Vec<Uuid> = default_rooms
+        .into_iter()
+        .filter(|room_id| {
+            let Some(room) = rooms.get_mut(room_id) else {
+                return false;
+            };
+            let joined = room.participants.insert(username.to_string());
+            if joined {
+                visibility::joined(room, username);
+                store::room_changed(state, room);
+            }
+            joined
+        })
+        .collect();
+    drop(rooms);
+    let username = username.to_string();
+    for room_id in joined {
+        state.notify_room_list_changed([&username], "joined", room_id);
+    }
+}
+
+pub async fn list_default_rooms(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let default_rooms = state.default_rooms.lock().unwrap().clone();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "room_ids": default_rooms })))
+}
+
+#[derive(Deserialize)]
+pub struct DefaultRoomsUpdate {
+    room_ids: Vec<Uuid>,
+}
+
+pub async fn set_default_rooms(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+    form: web::Json<DefaultRoomsUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let mut room_ids = Vec::new();
+    for room_id in &form.room_ids {
+        let room_id = state.resolve_room_id(*room_id);
+        if !room_ids.contains(&room_id) {
+            room_ids.push(room_id);
+        }
+    }
+    let rooms = state.chat_rooms.lock().unwrap();
+    if room_ids.iter().any(|room_id| !rooms.contains_key(room_id)) {
+        return Err(ApiError::RoomNotFound);
+    }
+    drop(rooms);
+    *state.default_rooms.lock().unwrap() = room_ids.clone();
+
+    audit::record(
+        &state,
+        &user.username,
+        "set_default_rooms",
+        "rooms",
+        format!("{} default rooms", room_ids.len()),
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "room_ids": room_ids })))
+}
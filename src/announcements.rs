@@ -0,0 +1,165 @@
+// Public announcement rooms: a room manager can flag a room so that its recent
+// messages are published as an Atom feed (RFC 4287) for readers without an
+// account. Rooms that aren't flagged don't have a feed, and look missing.
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::export::utc_datetime;
+use crate::roles::{self, Permission};
+use crate::store;
+use crate::{ChatMessage, MessageKind, SharedState};
+
+const FEED_ENTRIES: usize = 50;
+const TITLE_CHARS: usize = 80;
+
+#[derive(Deserialize)]
+pub struct AnnouncementUpdate {
+    enabled: bool,
+}
+
+pub async fn set_announcement(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<AnnouncementUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    roles::check(&state, room, &user.username, Permission::ChangeSettings)?;
+    let changed = room.announcement != form.enabled;
+    room.announcement = form.enabled;
+    store::room_changed(&state, room);
+    drop(rooms);
+    if changed {
+        audit::record(
+            &state,
+            &user.username,
+            "set_announcement",
+            &room_id.to_string(),
+            format!("enabled: {}", form.enabled),
+        );
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "announcement": form.enabled,
+    })))
+}
+
+fn rfc3339(millis: u64) -> String {
+    let (y, mo, d, h, mi, s) = utc_datetime(millis);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", y, mo, d, h, mi, s)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn title(msg: &ChatMessage) -> String {
+    let first_line = msg.content.lines().next().unwrap_or_default().trim();
+    let mut title: String = first_line.chars().take(TITLE_CHARS).collect();
+    if first_line.chars().count() > TITLE_CHARS {
+        title.push('\u{2026}');
+    }
+    if title.is_empty() {
+        title = format!("Message from {}", msg.sender);
+    }
+    title
+}
+
+// Deleted and expiring messages stay out of the feed since readers keep copies
+fn published(msg: &ChatMessage) -> bool {
+    msg.kind == MessageKind::User && msg.deleted_at.is_none() && msg.expires_at.is_none()
+}
+
+pub async fn room_feed(
+    req: HttpRequest,
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let info = req.connection_info();
+    let self_url = format!(
+        "{}://{}/rooms/{}/feed.atom",
+        info.scheme(),
+        info.host(),
+        room_id
+    );
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms
+        .get(&room_id)
+        .filter(|room| room.announcement)
+        .ok_or(ApiError::RoomNotFound)?;
+    let entries: Vec<&ChatMessage> = room
+        .message_log
+        .iter()
+        .rev()
+        .filter(|msg| published(msg))
+        .take(FEED_ENTRIES)
+        .collect();
+    let updated = entries
+        .iter()
+        .map(|msg| msg.edited_at.unwrap_or(msg.sent_at))
+        .max()
+        .unwrap_or(0);
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!("  <id>urn:uuid:{}</id>\n", room.id));
+    xml.push_str(&format!("  <title>{}</title>\n", escape(&room.name)));
+    xml.push_str(&format!("  <updated>{}</updated>\n", rfc3339(updated)));
+    xml.push_str(&format!(
+        "  <link rel=\"self\" type=\"application/atom+xml\" href=\"{}\"/>\n",
+        escape(&self_url)
+    ));
+    // The feed-level author covers entries, which always name their own
+    xml.push_str(&format!(
+        "  <author><name>{}</name></author>\n",
+        escape(&room.created_by)
+    ));
+    for msg in entries {
+        xml.push_str("  <entry>\n");
+        xml.push_str(&format!("    <id>urn:uuid:{}</id>\n", msg.id));
+        xml.push_str(&format!("    <title>{}</title>\n", escape(&title(msg))));
+        xml.push_str(&format!(
+            "    <published>{}</published>\n",
+            rfc3339(msg.sent_at)
+        ));
+        xml.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            rfc3339(msg.edited_at.unwrap_or(msg.sent_at))
+        ));
+        xml.push_str(&format!(
+            "    <author><name>{}</name></author>\n",
+            escape(&msg.sender)
+        ));
+        xml.push_str(&format!(
+            "    <content type=\"text\">{}</content>\n",
+            escape(&msg.content)
+        ));
+        for attachment in msg.attachments.iter().filter(|a| !a.is_expired()) {
+            if let Some(url) = &attachment.url {
+                xml.push_str(&format!(
+                    "    <link rel=\"enclosure\" href=\"{}\" type=\"{}\" length=\"{}\" title=\"{}\"/>\n",
+                    escape(url),
+                    escape(&attachment.content_type),
+                    attachment.size,
+                    escape(&attachment.name)
+                ));
+            }
+        }
+        xml.push_str("  </entry>\n");
+    }
+    xml.push_str("</feed>\n");
+    Ok(HttpResponse::Ok()
+        .content_type("application/atom+xml; charset=utf-8")
+        .body(xml))
+}
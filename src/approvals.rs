@@ -0,0 +1,215 @@
+// Link approval: in rooms that turn it on, a message from a regular member
+// that carries a URL or an attachment is held instead of published. Only the
+// sender and the room's staff see it until a moderator approves it, at which
+// point it goes through the normal publish path, or rejects it.
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::messages;
+use crate::probation::contains_link;
+use crate::roles::{self, is_staff, Permission};
+use crate::store;
+use crate::throttle::notify_moderators;
+use crate::uploads;
+use crate::{now_millis, ChatMessage, MessageKind, RoomEvent, SharedState};
+
+#[derive(Default)]
+pub struct ApprovalQueue {
+    pending: HashMap<Uuid, Vec<ChatMessage>>, // room_id -> held messages, oldest first
+}
+
+// The room's held messages, which now will never be published
+pub fn room_removed(state: &SharedState, room_id: Uuid) -> Vec<ChatMessage> {
+    let mut approvals = state.approvals.lock().unwrap();
+    approvals.pending.remove(&room_id).unwrap_or_default()
+}
+
+// Whether `draft` has to wait for a moderator before it is published
+pub fn required(state: &SharedState, draft: &ChatMessage) -> bool {
+    if draft.kind != MessageKind::User {
+        return false;
+    }
+    if !contains_link(&draft.content) && draft.attachments.is_empty() {
+        return false;
+    }
+    let rooms = state.chat_rooms.lock().unwrap();
+    rooms
+        .get(&draft.room_id)
+        .is_some_and(|room| room.link_approval && !is_staff(state, room, &draft.sender))
+}
+
+// Queues `draft` and shows it to the sender and the room's staff
+pub fn hold(state: &SharedState, mut draft: ChatMessage) -> Result<ChatMessage, ApiError> {
+    if !state
+        .chat_rooms
+        .lock()
+        .unwrap()
+        .contains_key(&draft.room_id)
+    {
+        return Err(ApiError::RoomNotFound);
+    }
+    draft.pending_since = Some(draft.sent_at);
+    state
+        .approvals
+        .lock()
+        .unwrap()
+        .pending
+        .entry(draft.room_id)
+        .or_default()
+        .push(draft.clone());
+    state.notify_user(
+        &draft.sender,
+        RoomEvent(serde_json::json!({ "type": "message_pending", "message": draft })),
+    );
+    notify_moderators(
+        state,
+        draft.room_id,
+        RoomEvent(serde_json::json!({ "type": "approval_requested", "message": draft })),
+    );
+    Ok(draft)
+}
+
+#[derive(Deserialize)]
+pub struct LinkApprovalUpdate {
+    enabled: bool,
+}
+
+pub async fn set_link_approval(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<LinkApprovalUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    roles::check(&state, room, &user.username, Permission::ChangeSettings)?;
+    let changed = room.link_approval != form.enabled;
+    room.link_approval = form.enabled;
+    store::room_changed(&state, room);
+    drop(rooms);
+    // Turning approval off leaves already held messages for moderators to decide
+    if changed {
+        audit::record(
+            &state,
+            &user.username,
+            "set_link_approval",
+            &room_id.to_string(),
+            format!("enabled: {}", form.enabled),
+        );
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "link_approval": form.enabled,
+    })))
+}
+
+// Staff see the whole queue, everyone else only their own held messages
+pub async fn list_pending(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let actor = user.username.clone();
+    let staff = {
+        let rooms = state.chat_rooms.lock().unwrap();
+        let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+        is_staff(&state, room, &actor)
+    };
+    let pending: Vec<ChatMessage> = state
+        .approvals
+        .lock()
+        .unwrap()
+        .pending
+        .get(&room_id)
+        .into_iter()
+        .flatten()
+        .filter(|msg| staff || msg.sender == actor)
+        .cloned()
+        .collect();
+    Ok(HttpResponse::Ok().json(pending))
+}
+
+#[derive(Deserialize)]
+pub struct ApprovalDecision {
+    approved: bool,
+}
+
+pub async fn decide(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, Uuid)>,
+    user: UserContext,
+    form: web::Json<ApprovalDecision>,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, message_id) = path.into_inner();
+    let room_id = state.resolve_room_id(room_id);
+    let actor = user.username.clone();
+    {
+        let rooms = state.chat_rooms.lock().unwrap();
+        let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+        if !is_staff(&state, room, &actor) {
+            return Err(ApiError::NotRoomModerator);
+        }
+    }
+    let mut held = {
+        let mut approvals = state.approvals.lock().unwrap();
+        let queue = approvals
+            .pending
+            .get_mut(&room_id)
+            .ok_or(ApiError::MessageNotFound)?;
+        let index = queue
+            .iter()
+            .position(|msg| msg.id == message_id)
+            .ok_or(ApiError::MessageNotFound)?;
+        let held = queue.remove(index);
+        if queue.is_empty() {
+            approvals.pending.remove(&room_id);
+        }
+        held
+    };
+
+    if !form.approved {
+        uploads::discard(&state, &held.attachments);
+        audit::record(
+            &state,
+            &actor,
+            "message_rejected",
+            &message_id.to_string(),
+            format!("from {} in room {}", held.sender, room_id),
+        );
+        state.notify_user(
+            &held.sender,
+            RoomEvent(serde_json::json!({
+                "type": "message_rejected",
+                "room_id": room_id,
+                "message_id": message_id,
+                "by": actor,
+            })),
+        );
+        return Ok(HttpResponse::NoContent().finish());
+    }
+
+    // Restamped on approval so the log stays in time order; a TTL counts from here
+    let now = now_millis();
+    let waited = now.saturating_sub(held.sent_at);
+    held.room_id = room_id;
+    held.sent_at = now;
+    held.expires_at = held.expires_at.map(|at| at + waited);
+    held.pending_since = None;
+    let message = messages::publish(&state, held)?;
+    audit::record(
+        &state,
+        &actor,
+        "message_approved",
+        &message_id.to_string(),
+        format!("from {} in room {}", message.sender, room_id),
+    );
+    Ok(HttpResponse::Ok().json(message))
+}
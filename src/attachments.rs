@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::store;
+use crate::uploads;
+use crate::{now_millis, RoomEvent, SharedState};
+
+pub const MAX_ATTACHMENTS: usize = 10;
+
+// A file referenced by a message, either by link or, for files uploaded to
+// the room, by upload id. Once the room's attachment retention passes, the
+// reference is dropped and only the placeholder metadata remains.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Attachment {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    // Clients attaching an upload send only its id
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub content_type: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expired_at: Option<u64>,
+}
+
+impl Attachment {
+    pub fn is_expired(&self) -> bool {
+        self.expired_at.is_some()
+    }
+}
+
+// Attachments as sent by clients: a live reference, never a placeholder
+pub fn validate(attachments: &[Attachment]) -> Result<(), ApiError> {
+    if attachments.len() > MAX_ATTACHMENTS {
+        return Err(ApiError::InvalidAttachment);
+    }
+    let valid = attachments.iter().all(|attachment| {
+        !attachment.name.trim().is_empty()
+            && attachment.expired_at.is_none()
+            && (attachment.id.is_some()
+                || attachment
+                    .url
+                    .as_deref()
+                    .is_some_and(|url| url.starts_with("https://") || url.starts_with("http://")))
+    });
+    if valid {
+        Ok(())
+    } else {
+        Err(ApiError::InvalidAttachment)
+    }
+}
+
+// Expires attachments older than each room's attachment retention while the
+// message text stays, then tells the room which messages changed
+pub fn sweep(state: &SharedState) {
+    let now = now_millis();
+    let holds = state.legal_holds.lock().unwrap().clone();
+    let mut expired: HashMap<Uuid, Vec<Uuid>> = HashMap::new(); // room_id -> message ids
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    for room in rooms.values_mut() {
+        let Some(max_age) = room.attachment_retention.max_age_millis() else {
+            continue;
+        };
+        let cutoff = now.saturating_sub(max_age);
+        for msg in room.message_log.iter_mut() {
+            if msg.sent_at >= cutoff || holds.covers(msg) {
+                continue;
+            }
+            let mut changed = false;
+            uploads::discard(state, msg.attachments.iter().filter(|a| !a.is_expired()));
+            for attachment in msg.attachments.iter_mut().filter(|a| !a.is_expired()) {
+                attachment.url = None;
+                attachment.expired_at = Some(now);
+                changed = true;
+            }
+            if changed {
+                msg.version.bump(&state.node_id);
+                store::messages_changed(state, [&*msg]);
+                expired.entry(room.id).or_default().push(msg.id);
+            }
+        }
+    }
+    drop(rooms);
+
+    for (room_id, message_ids) in expired {
+        for message_id in message_ids {
+            state.broadcast_event(
+                room_id,
+                RoomEvent(serde_json::json!({
+                    "type": "attachment_expired",
+                    "room_id": room_id,
+                    "message_id": message_id,
+                    "expired_at": now,
+                })),
+            );
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::{now_millis, SharedState};
+
+// Oldest entries are dropped once the log grows past this
+const AUDIT_LOG_CAPACITY: usize = 10_000;
+
+#[derive(Serialize, Clone)]
+pub struct AuditEvent {
+    pub at: u64,
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    pub detail: String,
+    pub node_id: String, // the instance that recorded it
+}
+
+pub fn record(state: &SharedState, actor: &str, action: &str, target: &str, detail: String) {
+    let mut log = state.audit_log.lock().unwrap();
+    if log.len() >= AUDIT_LOG_CAPACITY {
+        log.pop_front();
+    }
+    log.push_back(AuditEvent {
+        at: now_millis(),
+        actor: actor.to_string(),
+        action: action.to_string(),
+        target: target.to_string(),
+        detail,
+        node_id: state.node_id.clone(),
+    });
+}
+
+pub async fn list_audit_log(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let log = state.audit_log.lock().unwrap();
+    let events: Vec<_> = log.iter().cloned().collect();
+    Ok(HttpResponse::Ok().json(events))
+}
@@ -0,0 +1,116 @@
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::{now_millis, SharedState};
+
+pub const TOKEN_TTL_MILLIS: u64 = 60 * 60 * 1000;
+// How often authenticated WS sessions check whether their token has lapsed
+pub const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Grant {
+    username: String,
+    expires_at: u64,
+}
+
+// Opaque bearer tokens handed out by /login. A warm standby copies them, so
+// clients keep their logins when it takes over.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct AuthTokens {
+    grants: HashMap<String, Grant>,
+}
+
+impl AuthTokens {
+    pub fn issue(&mut self, username: &str) -> (String, u64) {
+        let now = now_millis();
+        self.grants.retain(|_, grant| grant.expires_at > now);
+        let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let expires_at = now + TOKEN_TTL_MILLIS;
+        self.grants.insert(
+            token.clone(),
+            Grant {
+                username: username.to_string(),
+                expires_at,
+            },
+        );
+        (token, expires_at)
+    }
+
+    // Returns the token's user and expiry
+    pub fn validate(&self, token: &str) -> Result<(String, u64), ApiError> {
+        match self.grants.get(token) {
+            Some(grant) if grant.expires_at > now_millis() => {
+                Ok((grant.username.clone(), grant.expires_at))
+            }
+            Some(_) => Err(ApiError::AuthTokenExpired),
+            None => Err(ApiError::InvalidAuthToken),
+        }
+    }
+
+    pub fn revoke_user(&mut self, username: &str) {
+        self.grants.retain(|_, grant| grant.username != username);
+    }
+}
+
+// The caller as proven by their login token. Handlers that take this no
+// longer trust a username from the body or query string.
+#[derive(Clone)]
+pub struct UserContext {
+    pub username: String,
+    pub expires_at: u64,
+}
+
+// "Authorization: Bearer <token>", or a `token` query parameter for
+// WebSocket upgrades, which browsers can't add headers to
+fn presented_token(req: &HttpRequest) -> Option<String> {
+    let header = req
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+    header.or_else(|| {
+        web::Query::<HashMap<String, String>>::from_query(req.query_string())
+            .ok()?
+            .get("token")
+            .cloned()
+    })
+}
+
+impl UserContext {
+    // Bodies that still carry a username must name the token's own user
+    pub fn claims(&self, state: &SharedState, username: Option<&str>) -> Result<(), ApiError> {
+        match username {
+            Some(name) if state.canonical_username(name) != self.username => {
+                Err(ApiError::AuthTokenMismatch)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromRequest for UserContext {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let state = req
+            .app_data::<web::Data<Arc<SharedState>>>()
+            .expect("SharedState is registered as app data");
+        let result = presented_token(req)
+            .ok_or(ApiError::AuthRequired)
+            .and_then(|token| state.auth_tokens.lock().unwrap().validate(&token))
+            .map(|(username, expires_at)| UserContext {
+                username,
+                expires_at,
+            });
+        ready(result)
+    }
+}
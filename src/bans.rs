@@ -0,0 +1,256 @@
+// Kicks and bans. POST /rooms/{id}/kick takes a user out of the room and
+// closes their connections to it; they may come back. POST /rooms/{id}/ban
+// does the same and keeps them out: a banned user can't join, accept an
+// invite, connect or post until staff lift it (DELETE /rooms/{id}/bans/
+// {username}). Moderators act on members only; moderators themselves are for
+// the owner, and the owner can't be removed at all.
+use actix::Message;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::roles::{self, Permission, RoomRole};
+use crate::sessions::ConnectionMeta;
+use crate::store;
+use crate::{audit, now_millis, ChatRoom, RoomEvent, SharedState};
+
+const MAX_REASON_CHARS: usize = 500;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Ban {
+    by: String,
+    at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+// Sent to each of the user's sessions in the room they were taken out of
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct RemovedFromRoom {
+    pub room_id: Uuid,
+    pub reason: &'static str, // "kicked" or "banned"
+}
+
+pub fn check(room: &ChatRoom, username: &str) -> Result<(), ApiError> {
+    if room.bans.contains_key(username) {
+        return Err(ApiError::BannedFromRoom);
+    }
+    Ok(())
+}
+
+// For handlers that don't otherwise lock the room
+pub fn check_room(state: &SharedState, room_id: Uuid, username: &str) -> Result<(), ApiError> {
+    match state.chat_rooms.lock().unwrap().get(&room_id) {
+        Some(room) => check(room, username),
+        None => Ok(()),
+    }
+}
+
+// Whether `actor` may take `username` out of the room, by removal, kick or ban
+pub fn check_removal(
+    state: &SharedState,
+    room: &ChatRoom,
+    actor: &str,
+    username: &str,
+) -> Result<(), ApiError> {
+    if room.direct {
+        return Err(ApiError::DirectRoomFixed);
+    }
+    let needed = match RoomRole::of(room, username) {
+        RoomRole::Owner => {
+            roles::check(state, room, actor, Permission::RemoveParticipants)?;
+            return Err(ApiError::OwnerRoleFixed);
+        }
+        RoomRole::Moderator => Permission::ManageModerators,
+        RoomRole::Member => Permission::RemoveParticipants,
+    };
+    roles::check(state, room, actor, needed)
+}
+
+// Drops the user from the room's participants and roles; false if they
+// weren't in it
+pub fn remove(room: &mut ChatRoom, username: &str) -> bool {
+    room.moderators.remove(username);
+    room.joined_at.remove(username);
+    room.participants.remove(username)
+}
+
+// Closes `username`'s sessions in the room, telling them why
+pub fn disconnect(state: &SharedState, room_id: Uuid, username: &str, reason: &'static str) {
+    let sessions = state.active_sessions.lock().unwrap();
+    let user_sessions = state.user_sessions.lock().unwrap();
+    let theirs = user_sessions.get(username).map_or(&[][..], Vec::as_slice);
+    let notice = RemovedFromRoom { room_id, reason };
+    for addr in sessions.get(&room_id).into_iter().flatten() {
+        if theirs.contains(addr) {
+            addr.do_send(notice.clone());
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RemovalRequest {
+    username: String,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+pub async fn kick_user(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<RemovalRequest>,
+) -> Result<HttpResponse, ApiError> {
+    take_out(&state, path.into_inner(), &user, form.into_inner(), false)
+}
+
+pub async fn ban_user(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<RemovalRequest>,
+) -> Result<HttpResponse, ApiError> {
+    take_out(&state, path.into_inner(), &user, form.into_inner(), true)
+}
+
+fn take_out(
+    state: &SharedState,
+    room_id: Uuid,
+    user: &UserContext,
+    form: RemovalRequest,
+    ban: bool,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(room_id);
+    let username = state.canonical_username(&form.username);
+    let reason = form
+        .reason
+        .map(|reason| reason.trim().to_string())
+        .filter(|reason| !reason.is_empty());
+    if reason
+        .as_ref()
+        .is_some_and(|reason| reason.chars().count() > MAX_REASON_CHARS)
+    {
+        return Err(ApiError::InvalidBanReason);
+    }
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    check_removal(state, room, &user.username, &username)?;
+    let removed = remove(room, &username);
+    // A ban also keeps out people who only ever read the room
+    if !removed && !ban {
+        return Err(ApiError::ParticipantNotFound);
+    }
+    if ban {
+        room.bans.insert(
+            username.clone(),
+            Ban {
+                by: user.username.clone(),
+                at: now_millis(),
+                reason: reason.clone(),
+            },
+        );
+    }
+    store::room_changed(state, room);
+    drop(rooms);
+
+    // What the audit log records of the connections this closes
+    let connections: Vec<ConnectionMeta> = state
+        .session_registry
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|session| session.room_id == room_id && session.username == username)
+        .map(|session| session.meta.clone())
+        .collect();
+    let action = if ban { "banned" } else { "kicked" };
+    disconnect(state, room_id, &username, action);
+    if removed {
+        state.notify_room_list_changed([&username], "left", room_id);
+    }
+    state.broadcast_event(
+        room_id,
+        RoomEvent(serde_json::json!({
+            "type": if ban { "user_banned" } else { "user_kicked" },
+            "room_id": room_id,
+            "username": username,
+            "by": user.username,
+            "reason": reason,
+        })),
+    );
+    audit::record(
+        state,
+        &user.username,
+        if ban { "ban_user" } else { "kick_user" },
+        &room_id.to_string(),
+        format!(
+            "{}{}; sessions: {}",
+            username,
+            reason
+                .as_ref()
+                .map_or(String::new(), |reason| format!(": {}", reason)),
+            serde_json::to_string(&connections).unwrap_or_default(),
+        ),
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "username": username,
+        "action": action,
+    })))
+}
+
+pub async fn list_bans(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    if !roles::is_staff(&state, room, &user.username) {
+        return Err(ApiError::NotRoomModerator);
+    }
+    let bans: Vec<_> = room
+        .bans
+        .iter()
+        .map(|(username, ban)| {
+            serde_json::json!({
+                "username": username,
+                "by": ban.by,
+                "at": ban.at,
+                "reason": ban.reason,
+            })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(bans))
+}
+
+pub async fn lift_ban(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, String)>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, username) = path.into_inner();
+    let room_id = state.resolve_room_id(room_id);
+    let username = state.canonical_username(&username);
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    roles::check(&state, room, &user.username, Permission::RemoveParticipants)?;
+    if room.bans.remove(&username).is_none() {
+        return Err(ApiError::BanNotFound);
+    }
+    store::room_changed(&state, room);
+    drop(rooms);
+
+    audit::record(
+        &state,
+        &user.username,
+        "lift_ban",
+        &room_id.to_string(),
+        username,
+    );
+    Ok(HttpResponse::NoContent().finish())
+}
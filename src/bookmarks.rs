@@ -0,0 +1,138 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::bans;
+use crate::error::ApiError;
+use crate::history::HistoryEntry;
+use crate::invites;
+use crate::users::owned_username;
+use crate::visibility;
+use crate::{now_millis, SharedState};
+
+// Private to the user who created it; unrelated to room-wide pins
+#[derive(Serialize, Clone)]
+pub struct Bookmark {
+    room_id: Uuid,
+    message_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    created_at: u64,
+}
+
+#[derive(Deserialize)]
+pub struct BookmarkCreate {
+    room_id: Uuid,
+    message_id: Uuid,
+    #[serde(default)]
+    note: Option<String>,
+}
+
+pub async fn add_bookmark(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<String>,
+    user: UserContext,
+    form: web::Json<BookmarkCreate>,
+) -> Result<HttpResponse, ApiError> {
+    let form = form.into_inner();
+    let username = owned_username(&state, &path, &user)?;
+    let room_id = state.resolve_room_id(form.room_id);
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    invites::check_access(&state, room, Some(&username))?;
+    bans::check(room, &username)?;
+    if !visibility::visible_log(&state, room, Some(&username))
+        .iter()
+        .any(|msg| msg.id == form.message_id)
+    {
+        return Err(ApiError::MessageNotFound);
+    }
+    drop(rooms);
+
+    let mut bookmarks = state.bookmarks.lock().unwrap();
+    let list = bookmarks.entry(username).or_default();
+    // Bookmarking the same message again just updates the note
+    if let Some(existing) = list.iter_mut().find(|b| b.message_id == form.message_id) {
+        existing.note = form.note;
+        return Ok(HttpResponse::Ok().json(existing.clone()));
+    }
+    let bookmark = Bookmark {
+        room_id,
+        message_id: form.message_id,
+        note: form.note,
+        created_at: now_millis(),
+    };
+    list.push(bookmark.clone());
+    Ok(HttpResponse::Created().json(bookmark))
+}
+
+pub async fn list_bookmarks(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<String>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let username = owned_username(&state, &path, &user)?;
+    let bookmarks = state
+        .bookmarks
+        .lock()
+        .unwrap()
+        .get(&username)
+        .cloned()
+        .unwrap_or_default();
+
+    let resolved: Vec<_> = bookmarks
+        .iter()
+        .rev()
+        .map(|bookmark| (bookmark, state.resolve_room_id(bookmark.room_id)))
+        .collect();
+
+    let rooms = state.chat_rooms.lock().unwrap();
+    let listed: Vec<_> = resolved
+        .into_iter()
+        .map(|(bookmark, room_id)| {
+            let room = rooms.get(&room_id);
+            // Messages removed by retention or expiry leave the bookmark without
+            // a message, and so does losing access to the room since
+            let readable = room.filter(|room| {
+                invites::check_access(&state, room, Some(&username)).is_ok()
+                    && bans::check(room, &username).is_ok()
+            });
+            let message = readable.and_then(|room| {
+                visibility::visible_log(&state, room, Some(&username))
+                    .iter()
+                    .find(|msg| msg.id == bookmark.message_id)
+                    .map(HistoryEntry::project)
+            });
+            serde_json::json!({
+                "bookmark": bookmark,
+                "room": room.map(|room| serde_json::json!({
+                    "id": room.id,
+                    "name": room.name,
+                })),
+                "message": message,
+            })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(listed))
+}
+
+pub async fn remove_bookmark(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(String, Uuid)>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let (username, message_id) = path.into_inner();
+    let username = owned_username(&state, &username, &user)?;
+    let mut bookmarks = state.bookmarks.lock().unwrap();
+    let list = bookmarks
+        .get_mut(&username)
+        .ok_or(ApiError::BookmarkNotFound)?;
+    let before = list.len();
+    list.retain(|b| b.message_id != message_id);
+    if list.len() == before {
+        return Err(ApiError::BookmarkNotFound);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
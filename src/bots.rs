@@ -0,0 +1,461 @@
+// Built-in bot framework. A bot reacts to server hooks (registrations and
+// room messages) and acts through `BotContext`, which goes through the same
+// send path as human users. `WelcomeBot` is the reference implementation.
+//
+// Bots are sandboxed per room: outside its own direct rooms a bot only sees
+// and posts to rooms whose owner put it on `ChatRoom::bot_allowlist` and then
+// added it, and only the commands granted there reach it.
+use actix_web::{rt, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::invites;
+use crate::messages::{self, Outgoing};
+use crate::rejections;
+use crate::roles::{self, Permission};
+use crate::store;
+use crate::{audit, ChatMessage, ChatRoom, MessageKind, SharedState};
+
+pub trait Bot: Send + Sync {
+    fn name(&self) -> &str;
+
+    // Slash-commands the bot registers, without the leading `/`
+    fn commands(&self) -> &[&str] {
+        &[]
+    }
+
+    fn on_user_registered(&self, _ctx: &BotContext, _username: &str) {}
+
+    fn on_message(&self, _ctx: &BotContext, _msg: &ChatMessage) {}
+
+    fn on_command(&self, _ctx: &BotContext, _msg: &ChatMessage, _command: &str, _args: &str) {}
+}
+
+// What a room's owner lets one bot do in that room
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BotGrant {
+    // None allows every command the bot registers
+    #[serde(default)]
+    pub commands: Option<BTreeSet<String>>,
+}
+
+// A bot's own direct rooms are always open to it; anywhere else it must be
+// both allowlisted and a participant
+fn present(room: &ChatRoom, bot: &str) -> bool {
+    room.created_by == bot
+        || (room.participants.contains(bot) && room.bot_allowlist.contains_key(bot))
+}
+
+fn command_allowed(room: &ChatRoom, bot: &str, command: &str) -> bool {
+    if room.created_by == bot {
+        return true;
+    }
+    room.bot_allowlist
+        .get(bot)
+        .is_some_and(|grant| match &grant.commands {
+            Some(commands) => commands.contains(command),
+            None => true,
+        })
+}
+
+// `/name args` -> ("name", "args")
+fn parse_command(content: &str) -> Option<(&str, &str)> {
+    let rest = content.trim().strip_prefix('/')?;
+    let (command, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    (!command.is_empty()).then_some((command, args.trim()))
+}
+
+#[derive(Default)]
+pub struct BotRegistry {
+    bots: Vec<Arc<dyn Bot>>,
+    direct_rooms: HashMap<(String, String), Uuid>, // (bot, username) -> room_id
+}
+
+impl BotRegistry {
+    pub fn register(&mut self, bot: Arc<dyn Bot>) {
+        self.bots.push(bot);
+    }
+
+    // Bot names can't be registered as accounts, so nobody can impersonate one
+    pub fn names(&self) -> Vec<String> {
+        self.bots.iter().map(|bot| bot.name().to_string()).collect()
+    }
+
+    fn get(&self, name: &str) -> Option<&Arc<dyn Bot>> {
+        self.bots.iter().find(|bot| bot.name() == name)
+    }
+
+    // The room's command table: each command granted to a bot present in the
+    // room. When two bots register the same command the first one keeps it.
+    fn commands_for(&self, room: &ChatRoom) -> BTreeMap<String, Arc<dyn Bot>> {
+        let mut table = BTreeMap::new();
+        for bot in self.bots.iter().filter(|bot| present(room, bot.name())) {
+            for command in bot.commands() {
+                if command_allowed(room, bot.name(), command) {
+                    table
+                        .entry(command.to_string())
+                        .or_insert_with(|| bot.clone());
+                }
+            }
+        }
+        table
+    }
+}
+
+pub struct BotContext {
+    state: Arc<SharedState>,
+    bot_name: String,
+}
+
+impl BotContext {
+    fn new(state: &Arc<SharedState>, bot: &dyn Bot) -> Self {
+        BotContext {
+            state: state.clone(),
+            bot_name: bot.name().to_string(),
+        }
+    }
+
+    pub fn send(&self, room_id: Uuid, content: String) -> Result<ChatMessage, ApiError> {
+        let room_id = self.state.resolve_room_id(room_id);
+        let allowed = {
+            let rooms = self.state.chat_rooms.lock().unwrap();
+            let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+            present(room, &self.bot_name)
+        };
+        if !allowed {
+            let err = ApiError::BotNotAllowed;
+            rejections::record(
+                &self.state,
+                room_id,
+                &self.bot_name,
+                MessageKind::System,
+                rejections::excerpt(&content),
+                &err,
+            );
+            return Err(err);
+        }
+        let outgoing = Outgoing {
+            content,
+            ..Default::default()
+        };
+        messages::send_with_kind(
+            &self.state,
+            room_id,
+            &self.bot_name,
+            MessageKind::System,
+            outgoing,
+        )
+    }
+
+    // The bot's private room with `username`, created on first use
+    pub fn direct_room(&self, username: &str) -> Uuid {
+        let key = (self.bot_name.clone(), username.to_string());
+        let mut registry = self.state.bots.lock().unwrap();
+        if let Some(room_id) = registry.direct_rooms.get(&key) {
+            return *room_id;
+        }
+        let mut room = ChatRoom::new(
+            format!("{} \u{2194} {}", self.bot_name, username),
+            self.bot_name.clone(),
+        );
+        room.participants.insert(username.to_string());
+        room.tags.push("bot".to_string());
+        let room_id = room.id;
+        registry.direct_rooms.insert(key, room_id);
+        drop(registry);
+
+        store::room_changed(&self.state, &room);
+        self.state.chat_rooms.lock().unwrap().insert(room_id, room);
+        self.state
+            .notify_room_list_changed([&username.to_string()], "created", room_id);
+        room_id
+    }
+
+    pub fn is_direct_room(&self, room_id: Uuid) -> bool {
+        let registry = self.state.bots.lock().unwrap();
+        registry
+            .direct_rooms
+            .iter()
+            .any(|((bot, _), id)| *bot == self.bot_name && *id == room_id)
+    }
+
+    pub fn rooms_of(&self, username: &str) -> Vec<String> {
+        let rooms = self.state.chat_rooms.lock().unwrap();
+        let mut names: Vec<_> = rooms
+            .values()
+            .filter(|room| room.created_by == username || room.participants.contains(username))
+            .map(|room| room.name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+}
+
+pub fn user_registered(state: &Arc<SharedState>, username: &str) {
+    let bots = state.bots.lock().unwrap().bots.clone();
+    for bot in bots {
+        bot.on_user_registered(&BotContext::new(state, bot.as_ref()), username);
+    }
+}
+
+pub fn message_posted(state: &Arc<SharedState>, msg: &ChatMessage) {
+    let (handler, listeners) = {
+        let rooms = state.chat_rooms.lock().unwrap();
+        let Some(room) = rooms.get(&msg.room_id) else {
+            return;
+        };
+        let registry = state.bots.lock().unwrap();
+        let handler = parse_command(&msg.content).and_then(|(command, args)| {
+            let mut table = registry.commands_for(room);
+            table
+                .remove(command)
+                .map(|bot| (bot, command.to_string(), args.to_string()))
+        });
+        let listeners: Vec<_> = registry
+            .bots
+            .iter()
+            .filter(|bot| present(room, bot.name()))
+            .cloned()
+            .collect();
+        (handler, listeners)
+    };
+
+    // Bots never see their own messages, which rules out reply loops
+    if let Some((bot, command, args)) = handler {
+        if msg.sender != bot.name() {
+            bot.on_command(&BotContext::new(state, bot.as_ref()), msg, &command, &args);
+        }
+        return;
+    }
+    for bot in listeners {
+        if msg.sender != bot.name() {
+            bot.on_message(&BotContext::new(state, bot.as_ref()), msg);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BotAllowlistUpdate {
+    bots: BTreeMap<String, BotGrant>,
+}
+
+#[derive(Deserialize)]
+pub struct BotJoin {
+    bot: String,
+}
+
+pub async fn list_room_bots(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: Option<UserContext>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    invites::check_reader(&state, room, user.as_ref())?;
+    let registry = state.bots.lock().unwrap();
+    let joined: Vec<_> = registry
+        .bots
+        .iter()
+        .map(|bot| bot.name())
+        .filter(|name| present(room, name))
+        .collect();
+    let commands: BTreeMap<_, _> = registry
+        .commands_for(room)
+        .into_iter()
+        .map(|(command, bot)| (command, bot.name().to_string()))
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "allowlist": room.bot_allowlist,
+        "joined": joined,
+        "commands": commands,
+    })))
+}
+
+pub async fn set_bot_allowlist(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<BotAllowlistUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let form = form.into_inner();
+    let bots = state.bots.lock().unwrap().names();
+    if form.bots.keys().any(|name| !bots.contains(name)) {
+        return Err(ApiError::BotNotFound);
+    }
+
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    roles::check(&state, room, &user.username, Permission::ChangeSettings)?;
+    // Taking a bot off the list also removes it from the room
+    room.participants
+        .retain(|name| !bots.contains(name) || form.bots.contains_key(name));
+    room.bot_allowlist = form.bots;
+    store::room_changed(&state, room);
+    let detail = format!("{:?}", room.bot_allowlist);
+    let allowlist = room.bot_allowlist.clone();
+    drop(rooms);
+
+    audit::record(
+        &state,
+        &user.username,
+        "set_bot_allowlist",
+        &room_id.to_string(),
+        detail,
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "allowlist": allowlist,
+    })))
+}
+
+pub async fn add_room_bot(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<BotJoin>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let bot = state.canonical_username(&form.bot);
+    if state.bots.lock().unwrap().get(&bot).is_none() {
+        return Err(ApiError::BotNotFound);
+    }
+
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    roles::check(&state, room, &user.username, Permission::ChangeSettings)?;
+    if !room.bot_allowlist.contains_key(&bot) {
+        return Err(ApiError::BotNotAllowed);
+    }
+    let newly_added = room.participants.insert(bot.clone());
+    if newly_added {
+        store::room_changed(&state, room);
+    }
+    drop(rooms);
+
+    if newly_added {
+        audit::record(
+            &state,
+            &user.username,
+            "add_room_bot",
+            &room_id.to_string(),
+            bot.clone(),
+        );
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "bot": bot,
+    })))
+}
+
+#[derive(Deserialize, Clone)]
+pub struct OnboardingStep {
+    #[serde(default)]
+    pub delay_secs: u64,
+    pub message: String,
+}
+
+pub struct WelcomeBot {
+    steps: Vec<OnboardingStep>,
+}
+
+impl WelcomeBot {
+    pub const NAME: &'static str = "welcomebot";
+
+    // Steps come from the JSON file at WELCOME_BOT_STEPS_FILE when set
+    pub fn from_env() -> Self {
+        let steps = std::env::var("WELCOME_BOT_STEPS_FILE")
+            .ok()
+            .and_then(|path| match std::fs::read_to_string(&path) {
+                Ok(raw) => serde_json::from_str(&raw)
+                    .map_err(|err| log::error!("invalid onboarding steps in {}: {}", path, err))
+                    .ok(),
+                Err(err) => {
+                    log::error!("cannot read onboarding steps {}: {}", path, err);
+                    None
+                }
+            })
+            .unwrap_or_else(Self::default_steps);
+        WelcomeBot { steps }
+    }
+
+    fn default_steps() -> Vec<OnboardingStep> {
+        vec![
+            OnboardingStep {
+                delay_secs: 0,
+                message: "Welcome! I'm the welcome bot. Type `help` here any time.".to_string(),
+            },
+            OnboardingStep {
+                delay_secs: 5,
+                message: "Rooms you've been added to show up in your room list; type `rooms` to see them here.".to_string(),
+            },
+        ]
+    }
+}
+
+impl Bot for WelcomeBot {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn commands(&self) -> &[&str] {
+        &["help", "rooms"]
+    }
+
+    fn on_user_registered(&self, ctx: &BotContext, username: &str) {
+        let room_id = ctx.direct_room(username);
+        let ctx = BotContext {
+            state: ctx.state.clone(),
+            bot_name: ctx.bot_name.clone(),
+        };
+        let steps = self.steps.clone();
+        rt::spawn(async move {
+            for step in steps {
+                if step.delay_secs > 0 {
+                    rt::time::sleep(Duration::from_secs(step.delay_secs)).await;
+                }
+                if let Err(err) = ctx.send(room_id, step.message) {
+                    log::warn!("onboarding step not delivered: {}", err);
+                }
+            }
+        });
+    }
+
+    // Direct rooms also accept the commands without the leading slash
+    fn on_message(&self, ctx: &BotContext, msg: &ChatMessage) {
+        if ctx.is_direct_room(msg.room_id) {
+            self.reply(ctx, msg, msg.content.trim());
+        }
+    }
+
+    fn on_command(&self, ctx: &BotContext, msg: &ChatMessage, command: &str, _args: &str) {
+        self.reply(ctx, msg, command);
+    }
+}
+
+impl WelcomeBot {
+    fn reply(&self, ctx: &BotContext, msg: &ChatMessage, command: &str) {
+        let reply = match command {
+            "help" => "Commands: `help` shows this message, `rooms` lists your rooms.".to_string(),
+            "rooms" => {
+                let rooms = ctx.rooms_of(&msg.sender);
+                if rooms.is_empty() {
+                    "You aren't in any rooms yet.".to_string()
+                } else {
+                    format!("Your rooms: {}", rooms.join(", "))
+                }
+            }
+            _ => return,
+        };
+        if let Err(err) = ctx.send(msg.room_id, reply) {
+            log::warn!("welcome bot reply not delivered: {}", err);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+// Bridges to external chat networks. Each bridge maps an external
+// conversation onto a room: messages from the network are stored under their
+// original author's name with `bridged_from` set, and room messages are
+// relayed out to every other linked conversation, never back to their source.
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::attachments::Attachment;
+use crate::audit;
+use crate::auth::UserContext;
+use crate::config::{EmailConfig, SlackConfig};
+use crate::deadletter::Undelivered;
+use crate::email::{self, EmailBridge};
+use crate::error::ApiError;
+use crate::messages::{self, MAX_MESSAGE_LEN};
+use crate::ratelimit::Limit;
+use crate::sanitize;
+use crate::slack::{self, SlackBridge};
+use crate::telegram::{self, TelegramBridge};
+use crate::{ChatMessage, MessageKind, SharedState};
+
+// Per external conversation, so one busy channel can't starve the others
+const INBOUND_LIMIT: Limit = Limit {
+    capacity: 30.0,
+    refill_per_sec: 5.0,
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BridgeOrigin {
+    pub network: String,
+    // Channel, group or thread id on that network
+    pub conversation: String,
+    pub author_id: String,
+}
+
+#[derive(Default)]
+pub struct Bridges {
+    pub slack: SlackBridge,
+    pub telegram: TelegramBridge,
+    pub email: EmailBridge,
+}
+
+impl Bridges {
+    pub fn new(slack: Option<SlackConfig>, email: Option<EmailConfig>) -> Self {
+        Bridges {
+            slack: SlackBridge::new(slack),
+            email: EmailBridge::new(email),
+            ..Default::default()
+        }
+    }
+}
+
+// Posts a message that arrived from an external network. Per-user limits,
+// probation and shadow bans don't apply: the author has no account here.
+pub fn post_inbound(
+    state: &Arc<SharedState>,
+    room_id: Uuid,
+    author: &str,
+    origin: BridgeOrigin,
+    content: &str,
+    attachments: Vec<Attachment>,
+) -> Result<ChatMessage, ApiError> {
+    let room_id = state.resolve_room_id(room_id);
+    // Other networks allow longer messages than we do
+    let content = sanitize::clean(content);
+    let content = sanitize::truncate(&content, MAX_MESSAGE_LEN).to_string();
+    messages::validate(&content, &attachments, None)?;
+    state.rate_limiter.check(
+        "bridge",
+        &format!("{}:{}", origin.network, origin.conversation),
+        INBOUND_LIMIT,
+    )?;
+    let mut draft = messages::compose(
+        room_id,
+        author,
+        content,
+        None,
+        MessageKind::User,
+        attachments,
+    );
+    draft.bridged_from = Some(origin);
+    messages::publish(state, draft)
+}
+
+// Whether `msg` should go out to `conversation` on `network`
+pub fn should_relay(msg: &ChatMessage, network: &str, conversation: &str) -> bool {
+    // Ephemeral messages would outlive their TTL on the other side
+    if msg.expires_at.is_some() {
+        return false;
+    }
+    !msg.bridged_from
+        .as_ref()
+        .is_some_and(|origin| origin.network == network && origin.conversation == conversation)
+}
+
+// Keeps a message a bridge couldn't hand over, addressed to "network:conversation"
+pub fn relay_failed(state: &SharedState, network: &str, conversation: &str, msg: ChatMessage) {
+    state.dead_letters.record(
+        Some(msg.room_id),
+        Some(format!("{}:{}", network, conversation)),
+        "bridge_failed",
+        Undelivered::Chat(Box::new(msg)),
+    );
+}
+
+pub fn message_posted(state: &Arc<SharedState>, msg: &ChatMessage) {
+    slack::relay(state, msg);
+    telegram::relay(state, msg);
+    email::relay(state, msg);
+}
+
+#[derive(Deserialize)]
+pub struct SlackLinkUpdate {
+    channel: String,
+    // None unlinks the channel
+    #[serde(default)]
+    room_id: Option<Uuid>,
+    #[serde(default)]
+    webhook_url: Option<String>,
+}
+
+pub async fn list_bridges(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let bridges = state.bridges.lock().unwrap();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "slack": bridges.slack.describe(),
+        "telegram": bridges.telegram.describe(),
+        "email": bridges.email.describe(),
+    })))
+}
+
+pub async fn set_slack_link(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+    form: web::Json<SlackLinkUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let form = form.into_inner();
+    let channel = form.channel.trim().to_string();
+    if channel.is_empty() {
+        return Err(ApiError::InvalidQuery);
+    }
+    let Some(room_id) = form.room_id else {
+        let removed = state.bridges.lock().unwrap().slack.unlink(&channel);
+        if removed {
+            audit::record(
+                &state,
+                &user.username,
+                "bridge_unlinked",
+                &channel,
+                "slack".into(),
+            );
+        }
+        return Ok(HttpResponse::NoContent().finish());
+    };
+    let webhook_url = form
+        .webhook_url
+        .filter(|url| url.starts_with("https://") || url.starts_with("http://"))
+        .ok_or(ApiError::InvalidQuery)?;
+    let room_id = state.resolve_room_id(room_id);
+    if !state.chat_rooms.lock().unwrap().contains_key(&room_id) {
+        return Err(ApiError::RoomNotFound);
+    }
+    state
+        .bridges
+        .lock()
+        .unwrap()
+        .slack
+        .link(channel.clone(), room_id, webhook_url);
+    audit::record(
+        &state,
+        &user.username,
+        "bridge_linked",
+        &channel,
+        format!("slack -> room {}", room_id),
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "network": "slack",
+        "channel": channel,
+        "room_id": room_id,
+    })))
+}
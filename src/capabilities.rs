@@ -0,0 +1,49 @@
+use actix_web::{web, HttpResponse};
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::attachments::MAX_ATTACHMENTS;
+use crate::expiry::MAX_TTL_SECS;
+use crate::import::MAX_IMPORT_BYTES;
+use crate::messages::{MAX_MESSAGE_BYTES, MAX_MESSAGE_LEN, SEND_LIMIT};
+use crate::protocol::EventCategory;
+use crate::retention::RetentionClass;
+use crate::sessions::SUPPORTED_PROTOCOLS;
+use crate::SharedState;
+
+// Describes what this server build and configuration support, so clients
+// can adapt instead of hardcoding limits
+pub async fn get_capabilities(state: web::Data<Arc<SharedState>>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "server": {
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "node_id": state.node_id,
+        },
+        "messages": {
+            "max_length": MAX_MESSAGE_LEN,
+            "max_bytes": MAX_MESSAGE_BYTES,
+            "max_ttl_seconds": MAX_TTL_SECS,
+            "send_rate_limit": SEND_LIMIT,
+        },
+        "websocket": {
+            "protocols": SUPPORTED_PROTOCOLS,
+            "event_categories": EventCategory::ALL,
+        },
+        "uploads": {
+            "max_import_bytes": MAX_IMPORT_BYTES,
+            "import_formats": ["slack", "ndjson"],
+            "attachments": {
+                "max_per_message": MAX_ATTACHMENTS,
+                "uploads": false,
+            },
+        },
+        "retention_classes": RetentionClass::ALL,
+        "usernames": {
+            "case_insensitive": state.username_policy.case_insensitive,
+            "rejects_confusables": state.username_policy.reject_confusables,
+        },
+        "e2ee": "none",
+        "federation": false,
+    }))
+}
@@ -0,0 +1,455 @@
+// Cross-instance fan-out over Redis pub/sub (REDIS_URL), so several instances
+// behind a load balancer can serve the same rooms. Every change that the store
+// hooks see is also published: messages on their room's channel
+// (chat:room:<id>), rooms and accounts on chat:meta. Each instance subscribes
+// to all of them and applies what its peers publish to its own maps, relaying
+// new messages to its local sessions. Only the instance a change came from
+// writes it to the database, so peers should share one DATABASE_URL.
+//
+// Login tokens stay with the instance that issued them, so the balancer must
+// keep each client on one instance. Sequence numbers come from a per-room
+// counter in Redis (chat:seq:<id>), so two instances never hand out the same
+// one; while Redis can't be reached, sends fail instead. Only built with the
+// `redis` feature.
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::deadletter::{self, Undelivered};
+use crate::error::ApiError;
+use crate::external;
+use crate::search;
+use crate::store;
+use crate::{ChatMessage, ChatRoom, SharedState};
+
+const ROOM_CHANNEL_PREFIX: &str = "chat:room:";
+const META_CHANNEL: &str = "chat:meta";
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Relay {
+    User {
+        username: String,
+        password_hash: String,
+        email: Option<String>,
+    },
+    Room {
+        room: Box<ChatRoom>,
+    },
+    RoomRemoved {
+        room_id: Uuid,
+    },
+    Messages {
+        messages: Vec<ChatMessage>,
+    },
+    MessagesRemoved {
+        ids: Vec<Uuid>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    origin: String, // node_id of the publishing instance
+    #[serde(flatten)]
+    relay: Relay,
+}
+
+#[derive(Default)]
+pub struct Cluster {
+    #[cfg(feature = "redis")]
+    outbound: Option<tokio::sync::mpsc::UnboundedSender<(String, String)>>, // (channel, payload)
+    #[cfg(feature = "redis")]
+    seqs: Option<Arc<link::SeqCounter>>,
+}
+
+impl Cluster {
+    #[cfg_attr(not(feature = "redis"), allow(unused_variables))]
+    fn publish(&self, channel: String, payload: String) {
+        #[cfg(feature = "redis")]
+        if let Some(outbound) = &self.outbound {
+            let _ = outbound.send((channel, payload));
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    fn enabled(&self) -> bool {
+        self.outbound.is_some()
+    }
+
+    #[cfg(not(feature = "redis"))]
+    fn enabled(&self) -> bool {
+        false
+    }
+}
+
+fn publish(state: &SharedState, channel: String, relay: Relay) {
+    let envelope = Envelope {
+        origin: state.node_id.clone(),
+        relay,
+    };
+    let payload = serde_json::to_string(&envelope).expect("relays serialize");
+    state.cluster.lock().unwrap().publish(channel, payload);
+}
+
+fn enabled(state: &SharedState) -> bool {
+    state.cluster.lock().unwrap().enabled()
+}
+
+#[cfg_attr(not(feature = "redis"), allow(unused_variables))]
+pub fn spawn(state: Arc<SharedState>, url: String) {
+    #[cfg(feature = "redis")]
+    link::spawn(state, url);
+    #[cfg(not(feature = "redis"))]
+    log::warn!("REDIS_URL is set but this build lacks the redis feature");
+}
+
+// First of `count` consecutive seqs for new messages in `room_id`, or None
+// when this instance numbers its rooms itself. Call without holding
+// `chat_rooms`; it waits on Redis.
+#[cfg_attr(not(feature = "redis"), allow(unused_variables))]
+pub fn allocate_seqs(
+    state: &SharedState,
+    room_id: Uuid,
+    count: u64,
+) -> Result<Option<u64>, ApiError> {
+    #[cfg(feature = "redis")]
+    {
+        let counter = state.cluster.lock().unwrap().seqs.clone();
+        if let Some(counter) = counter {
+            // Where a room Redis hasn't counted yet starts
+            let floor = state
+                .chat_rooms
+                .lock()
+                .unwrap()
+                .get(&room_id)
+                .ok_or(ApiError::RoomNotFound)?
+                .next_seq;
+            return counter.allocate(room_id, floor, count).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+// Hooks, called from the store's hooks so every persisted change reaches peers
+
+pub fn user_changed(state: &SharedState, username: &str, password_hash: &str, email: Option<&str>) {
+    if !enabled(state) {
+        return;
+    }
+    let relay = Relay::User {
+        username: username.to_string(),
+        password_hash: password_hash.to_string(),
+        email: email.map(str::to_string),
+    };
+    publish(state, META_CHANNEL.to_string(), relay);
+}
+
+// `room` is the copy without a message log
+pub fn room_changed(state: &SharedState, room: &ChatRoom) {
+    if !enabled(state) {
+        return;
+    }
+    let relay = Relay::Room {
+        room: Box::new(room.clone()),
+    };
+    publish(state, META_CHANNEL.to_string(), relay);
+}
+
+pub fn room_removed(state: &SharedState, room_id: Uuid) {
+    if enabled(state) {
+        publish(
+            state,
+            META_CHANNEL.to_string(),
+            Relay::RoomRemoved { room_id },
+        );
+    }
+}
+
+pub fn messages_changed(state: &SharedState, messages: &[ChatMessage]) {
+    if !enabled(state) {
+        return;
+    }
+    let mut by_room: Vec<(Uuid, Vec<ChatMessage>)> = Vec::new();
+    for msg in messages {
+        match by_room
+            .iter_mut()
+            .find(|(room_id, _)| *room_id == msg.room_id)
+        {
+            Some((_, batch)) => batch.push(msg.clone()),
+            None => by_room.push((msg.room_id, vec![msg.clone()])),
+        }
+    }
+    for (room_id, messages) in by_room {
+        let channel = format!("{}{}", ROOM_CHANNEL_PREFIX, room_id);
+        publish(state, channel, Relay::Messages { messages });
+    }
+}
+
+pub fn messages_removed(state: &SharedState, ids: &[Uuid]) {
+    if enabled(state) {
+        let ids = ids.to_vec();
+        publish(
+            state,
+            META_CHANNEL.to_string(),
+            Relay::MessagesRemoved { ids },
+        );
+    }
+}
+
+// Applies a peer's change without going through the hooks above, so it is
+// neither stored again nor echoed back
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+fn apply(state: &SharedState, payload: &str) {
+    let envelope: Envelope = match serde_json::from_str(payload) {
+        Ok(envelope) => envelope,
+        Err(err) => {
+            log::warn!("ignoring unreadable cluster relay: {}", err);
+            return;
+        }
+    };
+    if envelope.origin == state.node_id {
+        return;
+    }
+    match envelope.relay {
+        Relay::User {
+            username,
+            password_hash,
+            email,
+        } => {
+            if let Some(email) = email {
+                state
+                    .user_emails
+                    .lock()
+                    .unwrap()
+                    .insert(username.clone(), email);
+            }
+            state
+                .user_accounts
+                .lock()
+                .unwrap()
+                .insert(username, password_hash);
+        }
+        Relay::Room { room } => {
+            let known = state.chat_rooms.lock().unwrap().contains_key(&room.id);
+            // A room first seen here may already have history in the shared database
+            if !known {
+                store::mark_unloaded(state, room.id);
+            }
+            let mut room = *room;
+            let mut rooms = state.chat_rooms.lock().unwrap();
+            if let Some(existing) = rooms.get_mut(&room.id) {
+                room.message_log = std::mem::take(&mut existing.message_log);
+                room.next_seq = room.next_seq.max(existing.next_seq);
+            }
+            external::room_changed(state, &room);
+            rooms.insert(room.id, room);
+        }
+        Relay::RoomRemoved { room_id } => {
+            state.chat_rooms.lock().unwrap().remove(&room_id);
+            search::room_reset(state, room_id);
+            external::room_removed(state, room_id);
+        }
+        Relay::Messages { messages } => {
+            let added = merge_messages(state, messages);
+            let sessions = state.active_sessions.lock().unwrap();
+            let undelivered: Vec<_> = added
+                .into_iter()
+                .map(|msg| {
+                    let recipients = sessions.get(&msg.room_id).map_or(&[][..], Vec::as_slice);
+                    (deadletter::fan_out(recipients, &msg), msg)
+                })
+                .collect();
+            drop(sessions);
+            for (dead, msg) in undelivered {
+                let room_id = msg.room_id;
+                deadletter::record_undelivered(
+                    state,
+                    room_id,
+                    dead,
+                    Undelivered::Chat(Box::new(msg)),
+                );
+            }
+        }
+        Relay::MessagesRemoved { ids } => {
+            let ids: HashSet<Uuid> = ids.into_iter().collect();
+            let mut rooms = state.chat_rooms.lock().unwrap();
+            for room in rooms.values_mut() {
+                room.message_log.retain(|msg| !ids.contains(&msg.id));
+            }
+            drop(rooms);
+            search::messages_removed(state, &ids.into_iter().collect::<Vec<_>>());
+        }
+    }
+}
+
+// Replaces messages already in the log and inserts the rest in seq order;
+// returns the new ones
+fn merge_messages(state: &SharedState, messages: Vec<ChatMessage>) -> Vec<ChatMessage> {
+    let mut added = Vec::new();
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    for msg in messages {
+        let Some(room) = rooms.get_mut(&msg.room_id) else {
+            continue;
+        };
+        search::messages_changed(state, std::slice::from_ref(&msg));
+        if let Some(existing) = room.message_log.iter_mut().find(|m| m.id == msg.id) {
+            *existing = msg;
+            continue;
+        }
+        let at = room.message_log.partition_point(|m| m.seq <= msg.seq);
+        room.message_log.insert(at, msg.clone());
+        room.next_seq = room.next_seq.max(msg.seq);
+        state.unread.lock().unwrap().message_appended(room, &msg);
+        added.push(msg);
+    }
+    added
+}
+
+#[cfg(feature = "redis")]
+mod link {
+    use futures_util::StreamExt;
+    use redis::AsyncCommands;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    use super::{apply, META_CHANNEL, ROOM_CHANNEL_PREFIX};
+    use crate::error::ApiError;
+    use crate::SharedState;
+
+    const MAX_BACKOFF: Duration = Duration::from_secs(30);
+    const SEQ_KEY_PREFIX: &str = "chat:seq:";
+    const SEQ_TIMEOUT: Duration = Duration::from_millis(500);
+    // Lifts the counter to the caller's floor, then takes `count` seqs from it
+    const ALLOCATE_SEQS: &str = "local last = math.max(tonumber(redis.call('GET', KEYS[1]) or '0'), tonumber(ARGV[1])) + tonumber(ARGV[2]) \
+        redis.call('SET', KEYS[1], last) \
+        return last";
+
+    // Seqs are handed out on the send path, which isn't async, so this uses
+    // a blocking connection of its own
+    pub struct SeqCounter {
+        client: redis::Client,
+        connection: Mutex<Option<redis::Connection>>,
+    }
+
+    impl SeqCounter {
+        pub fn allocate(&self, room_id: Uuid, floor: u64, count: u64) -> Result<u64, ApiError> {
+            let mut connection = self.connection.lock().unwrap();
+            if connection.is_none() {
+                let connected = self
+                    .client
+                    .get_connection_with_timeout(SEQ_TIMEOUT)
+                    .and_then(|conn| {
+                        conn.set_read_timeout(Some(SEQ_TIMEOUT))?;
+                        conn.set_write_timeout(Some(SEQ_TIMEOUT))?;
+                        Ok(conn)
+                    });
+                match connected {
+                    Ok(conn) => *connection = Some(conn),
+                    Err(err) => {
+                        log::error!("cannot reach Redis for seqs: {}", err);
+                        return Err(ApiError::DeadlineExceeded);
+                    }
+                }
+            }
+            let conn = connection.as_mut().expect("connected above");
+            let last: redis::RedisResult<u64> = redis::cmd("EVAL")
+                .arg(ALLOCATE_SEQS)
+                .arg(1)
+                .arg(format!("{}{}", SEQ_KEY_PREFIX, room_id))
+                .arg(floor)
+                .arg(count)
+                .query(conn);
+            match last {
+                Ok(last) => Ok(last + 1 - count),
+                Err(err) => {
+                    log::error!("cannot allocate seqs in room {}: {}", room_id, err);
+                    *connection = None;
+                    Err(ApiError::DeadlineExceeded)
+                }
+            }
+        }
+    }
+
+    pub fn spawn(state: Arc<SharedState>, url: String) {
+        let client = match redis::Client::open(url) {
+            Ok(client) => client,
+            Err(err) => {
+                log::error!("invalid REDIS_URL: {}", err);
+                return;
+            }
+        };
+        let (outbound, queue) = mpsc::unbounded_channel();
+        let mut cluster = state.cluster.lock().unwrap();
+        cluster.outbound = Some(outbound);
+        cluster.seqs = Some(Arc::new(SeqCounter {
+            client: client.clone(),
+            connection: Mutex::new(None),
+        }));
+        drop(cluster);
+        actix_web::rt::spawn(publisher(client.clone(), queue));
+        actix_web::rt::spawn(subscriber(state, client));
+    }
+
+    // Changes published while Redis is unreachable are lost to peers; they
+    // still reach the database, which peers read on their next start
+    async fn publisher(
+        client: redis::Client,
+        mut queue: mpsc::UnboundedReceiver<(String, String)>,
+    ) {
+        let mut connection = None;
+        while let Some((channel, payload)) = queue.recv().await {
+            if connection.is_none() {
+                match client.get_multiplexed_async_connection().await {
+                    Ok(conn) => connection = Some(conn),
+                    Err(err) => {
+                        log::error!("cannot reach Redis, dropping relay: {}", err);
+                        continue;
+                    }
+                }
+            }
+            let conn = connection.as_mut().expect("connected above");
+            let published: redis::RedisResult<()> = conn.publish(&channel, payload).await;
+            if let Err(err) = published {
+                log::error!("cannot publish to {}: {}", channel, err);
+                connection = None;
+            }
+        }
+    }
+
+    async fn subscriber(state: Arc<SharedState>, client: redis::Client) {
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            match listen(&state, &client, &mut backoff).await {
+                Ok(()) => log::warn!("Redis subscription closed; resubscribing"),
+                Err(err) => log::error!("Redis subscription failed: {}", err),
+            }
+            actix_web::rt::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    async fn listen(
+        state: &SharedState,
+        client: &redis::Client,
+        backoff: &mut Duration,
+    ) -> redis::RedisResult<()> {
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub
+            .psubscribe(format!("{}*", ROOM_CHANNEL_PREFIX))
+            .await?;
+        pubsub.subscribe(META_CHANNEL).await?;
+        *backoff = Duration::from_secs(1);
+        log::info!("relaying rooms through Redis as node {}", state.node_id);
+        let mut messages = pubsub.on_message();
+        while let Some(msg) = messages.next().await {
+            match msg.get_payload::<String>() {
+                Ok(payload) => apply(state, &payload),
+                Err(err) => log::warn!("ignoring non-text relay: {}", err),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+// Coalescing of ephemeral events. In a busy room typing indicators can
+// outnumber the messages themselves, so instead of relaying every start and
+// stop the server marks the room and, at most once per interval, sends its
+// sessions one `typing_summary` with everyone typing there at that moment.
+// Presence changes wait for the same flush, and only users whose status
+// actually differs from the last flush are announced, so a reconnect within
+// the interval goes unnoticed. The interval comes from EPHEMERAL_COALESCE_MS
+// (default 1000); zero turns coalescing off and indicators are relayed one by
+// one as they happen.
+use actix_web::rt;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::deadletter;
+use crate::presence;
+use crate::{RoomEvent, SharedState};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(1000);
+
+pub struct Coalescer {
+    interval: Duration,
+    typing: Mutex<HashSet<Uuid>>, // rooms whose typing summary is due
+    presence: Mutex<HashMap<String, (bool, bool)>>, // username -> (online before, online now)
+}
+
+impl Default for Coalescer {
+    fn default() -> Self {
+        Coalescer::new(DEFAULT_INTERVAL)
+    }
+}
+
+impl Coalescer {
+    pub fn new(interval: Duration) -> Self {
+        Coalescer {
+            interval,
+            typing: Mutex::new(HashSet::new()),
+            presence: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.interval.is_zero()
+    }
+
+    pub fn typing_changed(&self, room_id: Uuid) {
+        self.typing.lock().unwrap().insert(room_id);
+    }
+
+    pub fn presence_changed(&self, username: &str, online: bool) {
+        let mut presence = self.presence.lock().unwrap();
+        let status = presence
+            .entry(username.to_string())
+            .or_insert((!online, online));
+        status.1 = online;
+    }
+}
+
+fn flush(state: &SharedState) {
+    let due = std::mem::take(&mut *state.coalescer.typing.lock().unwrap());
+    for room_id in due {
+        let typing = state.typing.lock().unwrap().typists(room_id);
+        let event = RoomEvent(serde_json::json!({
+            "type": "typing_summary",
+            "room_id": room_id,
+            "typing": typing,
+        }));
+        let sessions = state.active_sessions.lock().unwrap();
+        let recipients = sessions.get(&room_id).cloned().unwrap_or_default();
+        drop(sessions);
+        // Each summary replaces the last, so there is nothing to redeliver
+        deadletter::fan_out(&recipients, &event);
+    }
+    let presence = std::mem::take(&mut *state.coalescer.presence.lock().unwrap());
+    for (username, (before, now)) in presence {
+        if before != now {
+            presence::announce(state, &username, now);
+        }
+    }
+}
+
+pub fn spawn_flusher(state: Arc<SharedState>) {
+    if !state.coalescer.enabled() {
+        return;
+    }
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(state.coalescer.interval);
+        loop {
+            interval.tick().await;
+            flush(&state);
+        }
+    });
+}
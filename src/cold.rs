@@ -0,0 +1,277 @@
+// Cold storage for idle rooms. Rooms nobody has touched for the configured
+// idle period are written to `<dir>/<room_id>.json.gz` and evicted from
+// `chat_rooms`; the next lookup through `SharedState::resolve_room_id`
+// loads them back transparently. A history-less copy of each archived room
+// stays in memory, so room listings still show it and the retention sweep
+// knows which archives it has to open.
+//
+// Lock order: the cold storage lock is taken before `active_sessions` and
+// `chat_rooms`, so callers must not hold either when resolving a room id.
+use actix_web::{rt, web, HttpResponse};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::config::ColdStorageConfig;
+use crate::error::ApiError;
+use crate::{now_millis, telemetry, ChatRoom, SharedState};
+
+const OFFLOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Default, Clone, Copy)]
+pub struct HydrationStats {
+    hydrations: u64,
+    failures: u64,
+    total_us: u64,
+    max_us: u64,
+    last_us: u64,
+}
+
+// What stays in memory of an archived room
+struct Archived {
+    // The room with only its newest message left in the log
+    header: ChatRoom,
+    oldest_sent_at: Option<u64>,
+}
+
+impl Archived {
+    fn of(room: &ChatRoom) -> Self {
+        let mut header = room.clone();
+        header.message_log = room.message_log.last().cloned().into_iter().collect();
+        Archived {
+            header,
+            oldest_sent_at: room.message_log.iter().map(|msg| msg.sent_at).min(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ColdStorage {
+    config: Option<ColdStorageConfig>,
+    touched: HashMap<Uuid, u64>, // room_id -> last lookup or offload scan
+    archived: HashMap<Uuid, Archived>,
+    offloaded: u64,
+    stats: HydrationStats,
+}
+
+fn room_path(dir: &Path, room_id: Uuid) -> PathBuf {
+    dir.join(format!("{}.json.gz", room_id))
+}
+
+// Also used to archive deleted rooms
+pub fn write_room(dir: &Path, room: &ChatRoom) -> std::io::Result<()> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    serde_json::to_writer(&mut encoder, room)?;
+    let compressed = encoder.finish()?;
+    // Write then rename, so a crash never leaves a truncated archive behind
+    let path = room_path(dir, room.id);
+    let partial = path.with_extension("gz.partial");
+    std::fs::File::create(&partial)?.write_all(&compressed)?;
+    std::fs::rename(partial, path)
+}
+
+fn read_room(dir: &Path, room_id: Uuid) -> std::io::Result<ChatRoom> {
+    let mut raw = Vec::new();
+    GzDecoder::new(std::fs::File::open(room_path(dir, room_id))?).read_to_end(&mut raw)?;
+    Ok(serde_json::from_slice(&raw)?)
+}
+
+impl ColdStorage {
+    // Rooms archived by an earlier run are picked up from the directory
+    pub fn new(config: ColdStorageConfig) -> Self {
+        let mut archived = HashMap::new();
+        match std::fs::read_dir(&config.dir) {
+            Ok(entries) => {
+                for entry in entries.flatten() {
+                    let name = entry.file_name();
+                    let id = name.to_str().and_then(|name| name.strip_suffix(".json.gz"));
+                    let Some(id) = id.and_then(|id| Uuid::parse_str(id).ok()) else {
+                        continue;
+                    };
+                    match read_room(&config.dir, id) {
+                        Ok(room) => {
+                            archived.insert(id, Archived::of(&room));
+                        }
+                        Err(err) => log::error!("cannot read archived room {}: {}", id, err),
+                    }
+                }
+            }
+            Err(err) => log::error!("cannot read cold storage {}: {}", config.dir.display(), err),
+        }
+        ColdStorage {
+            config: Some(config),
+            archived,
+            ..Default::default()
+        }
+    }
+
+    // Archived rooms with only their newest message, for listings. Lock the
+    // cold storage before `chat_rooms`, so no room shows up twice or not at
+    // all while it is moved between them.
+    pub fn archived(&self) -> impl Iterator<Item = &ChatRoom> {
+        self.archived.values().map(|archived| &archived.header)
+    }
+}
+
+// Records the lookup and brings an archived room back into memory
+pub fn touch(state: &SharedState, room_id: Uuid) {
+    let mut cold = state.cold_storage.lock().unwrap();
+    let Some(dir) = cold.config.as_ref().map(|config| config.dir.clone()) else {
+        return;
+    };
+    cold.touched.insert(room_id, now_millis());
+    if !cold.archived.contains_key(&room_id) {
+        return;
+    }
+
+    let mut span = telemetry::span("storage.rehydrate");
+    let started = Instant::now();
+    match read_room(&dir, room_id) {
+        Ok(room) => {
+            state.chat_rooms.lock().unwrap().insert(room_id, room);
+            cold.archived.remove(&room_id);
+            if let Err(err) = std::fs::remove_file(room_path(&dir, room_id)) {
+                log::warn!("rehydrated room {} but kept its archive: {}", room_id, err);
+            }
+            let elapsed = started.elapsed().as_micros() as u64;
+            span.attr("duration_us", elapsed);
+            let stats = &mut cold.stats;
+            stats.hydrations += 1;
+            stats.total_us += elapsed;
+            stats.max_us = stats.max_us.max(elapsed);
+            stats.last_us = elapsed;
+        }
+        Err(err) => {
+            // The room stays archived and the lookup reports it as missing
+            cold.stats.failures += 1;
+            log::error!("failed to rehydrate room {}: {}", room_id, err);
+        }
+    }
+}
+
+fn offload_idle(state: &SharedState) {
+    let mut cold = state.cold_storage.lock().unwrap();
+    let Some(config) = cold.config.clone() else {
+        return;
+    };
+    let now = now_millis();
+    let idle_millis = config.idle.as_millis() as u64;
+    let live: HashSet<Uuid> = state
+        .active_sessions
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, sessions)| !sessions.is_empty())
+        .map(|(room_id, _)| *room_id)
+        .collect();
+
+    let mut evicted = Vec::new();
+    {
+        let mut rooms = state.chat_rooms.lock().unwrap();
+        let idle: Vec<Uuid> = rooms
+            .values()
+            .filter(|room| !live.contains(&room.id))
+            .filter(|room| {
+                // Rooms not looked up since startup start their idle clock at the first scan
+                let last_message = room.message_log.last().map_or(0, |msg| msg.sent_at);
+                let touched = *cold.touched.entry(room.id).or_insert(now);
+                now.saturating_sub(touched.max(last_message)) >= idle_millis
+            })
+            .map(|room| room.id)
+            .collect();
+        for room_id in idle {
+            evicted.extend(rooms.remove(&room_id));
+        }
+    }
+
+    for room in evicted {
+        match write_room(&config.dir, &room) {
+            Ok(()) => {
+                cold.archived.insert(room.id, Archived::of(&room));
+                cold.touched.remove(&room.id);
+                cold.offloaded += 1;
+            }
+            Err(err) => {
+                log::error!("failed to offload room {}: {}", room.id, err);
+                state.chat_rooms.lock().unwrap().insert(room.id, room);
+            }
+        }
+    }
+}
+
+// Applies `prune` to each archive that may hold messages sent before its
+// room's cutoff, rewriting the ones it changed. `prune` returns whether it
+// dropped anything.
+pub fn sweep_archived(
+    state: &SharedState,
+    cutoff: impl Fn(&ChatRoom) -> Option<u64>,
+    mut prune: impl FnMut(&mut ChatRoom) -> bool,
+) {
+    let mut cold = state.cold_storage.lock().unwrap();
+    let Some(dir) = cold.config.as_ref().map(|config| config.dir.clone()) else {
+        return;
+    };
+    let due: Vec<Uuid> = cold
+        .archived
+        .iter()
+        .filter(|(_, archived)| {
+            let cutoff = cutoff(&archived.header);
+            cutoff.is_some_and(|cutoff| archived.oldest_sent_at.is_some_and(|at| at < cutoff))
+        })
+        .map(|(room_id, _)| *room_id)
+        .collect();
+    for room_id in due {
+        let mut room = match read_room(&dir, room_id) {
+            Ok(room) => room,
+            Err(err) => {
+                log::error!("cannot sweep archived room {}: {}", room_id, err);
+                continue;
+            }
+        };
+        if !prune(&mut room) {
+            continue;
+        }
+        match write_room(&dir, &room) {
+            Ok(()) => {
+                cold.archived.insert(room_id, Archived::of(&room));
+            }
+            Err(err) => log::error!("cannot rewrite archived room {}: {}", room_id, err),
+        }
+    }
+}
+
+pub fn spawn_offloader(state: Arc<SharedState>) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(OFFLOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+            offload_idle(&state);
+        }
+    });
+}
+
+pub async fn cold_storage_status(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let cold = state.cold_storage.lock().unwrap();
+    let stats = cold.stats;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "enabled": cold.config.is_some(),
+        "archived_rooms": cold.archived.len(),
+        "offloaded": cold.offloaded,
+        "hydration": stats,
+        "hydration_avg_us": stats.total_us.checked_div(stats.hydrations).unwrap_or(0),
+    })))
+}
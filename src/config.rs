@@ -0,0 +1,471 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::coalesce;
+use crate::deadline::Deadlines;
+use crate::heartbeat::Heartbeat;
+use crate::probation::ProbationPolicy;
+use crate::ratelimit::Limit;
+use crate::store::PersistencePolicy;
+use crate::usernames::UsernamePolicy;
+
+// Server configuration read once at startup from environment variables
+pub struct Config {
+    pub bind: String,
+    // Identifies this instance in version vectors, messages, audit events and
+    // peer traffic. CHAT_NODE_ID wins; otherwise it is generated on first boot
+    // and kept in NODE_ID_FILE (default ./node-id)
+    pub node_id: String,
+    pub admins: Vec<String>,
+    pub export: Option<ExportConfig>,
+    pub usernames: UsernamePolicy,
+    pub probation: ProbationPolicy,
+    // Rooms every new account joins; adjustable at runtime via /admin/default_rooms
+    pub default_rooms: Vec<Uuid>,
+    // Posting in one of these shadow-bans the sender
+    pub honeypot_rooms: Vec<Uuid>,
+    // Shared secret guarding /replication/snapshot; replicas present it too
+    pub replication_token: Option<String>,
+    pub replica: Option<ReplicaConfig>,
+    pub cold_storage: Option<ColdStorageConfig>,
+    // sqlite://chat.db or postgres://user@host/db (postgres feature); unset
+    // keeps everything in memory
+    pub database_url: Option<String>,
+    // redis://host:6379 links instances serving the same rooms (redis feature)
+    pub redis_url: Option<String>,
+    // UPLOAD_DIR (default ./uploads), or a bucket once UPLOAD_S3_ENDPOINT and
+    // UPLOAD_S3_BUCKET are set
+    pub uploads: UploadConfig,
+    // PERSIST_EVENTS=messages,reactions by default; leaving reactions out
+    // keeps them live-only
+    pub persistence: PersistencePolicy,
+    // REQUEST_TIMEOUT_MS, STORAGE_TIMEOUT_MS and BROADCAST_WAIT_MS; 0 turns
+    // the first two off
+    pub deadlines: Deadlines,
+    pub slack: Option<SlackConfig>,
+    pub telegram: Option<TelegramConfig>,
+    pub email: Option<EmailConfig>,
+    // How often mentions-only rooms are summarised into feed digests
+    pub digest_interval: Duration,
+    // ROOM_ARCHIVE_DIR (default ./archived-rooms), where deleted rooms are
+    // kept when deleted with ?archive=true
+    pub room_archive_dir: PathBuf,
+    // How often coalesced typing and presence changes go out; zero relays each event
+    pub ephemeral_interval: Duration,
+    // JOIN_MESSAGES_IN_HISTORY: also store join and leave notices as messages
+    pub join_history: bool,
+    // HEARTBEAT_INTERVAL_SECS and HEARTBEAT_MAX_MISSED
+    pub heartbeat: Heartbeat,
+    pub outbound: OutboundConfig,
+}
+
+// Proxy and trust settings for every outbound HTTP request; see `outbound`
+#[derive(Clone, Default)]
+pub struct OutboundConfig {
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    pub no_proxy: Vec<String>,
+    // PEM certificates trusted on top of the system roots
+    pub ca_file: Option<PathBuf>,
+}
+
+// Set when this instance runs as a read-only replica of REPLICA_OF, or as a
+// warm standby of STANDBY_OF
+#[derive(Clone)]
+pub struct ReplicaConfig {
+    pub primary: String,
+    pub token: Option<String>,
+    pub interval: Duration,
+    // A standby's own public URL, where clients move when it is promoted
+    pub standby: Option<String>,
+}
+
+// Rooms idle this long are offloaded to compressed files under `dir`
+#[derive(Clone)]
+pub struct ColdStorageConfig {
+    pub dir: PathBuf,
+    pub idle: Duration,
+}
+
+// The Slack bridge is enabled once SLACK_SIGNING_SECRET is set
+#[derive(Clone)]
+pub struct SlackConfig {
+    pub signing_secret: String,
+    // Lets the bridge look up author names Slack leaves out of events
+    pub bot_token: Option<String>,
+}
+
+// The email gateway accepts inbound mail once EMAIL_WEBHOOK_TOKEN is set;
+// replies additionally need EMAIL_OUTBOUND_URL
+#[derive(Clone)]
+pub struct EmailConfig {
+    pub webhook_token: String,
+    pub from: Option<String>,
+    pub outbound_url: Option<String>,
+    pub outbound_token: Option<String>,
+}
+
+// Only acted on in builds with the `telegram` feature
+#[derive(Clone)]
+#[cfg_attr(not(feature = "telegram"), allow(dead_code))]
+pub struct TelegramConfig {
+    pub token: String,
+}
+
+// An S3-compatible bucket, addressed path-style
+#[derive(Clone)]
+pub struct S3Bucket {
+    pub endpoint: String,
+    pub name: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+// Where uploaded files are kept and which ones are accepted
+#[derive(Clone)]
+pub struct UploadConfig {
+    pub backend: UploadBackend,
+    pub max_bytes: usize,
+    // `image/*` covers every image type
+    pub allowed_types: Vec<String>,
+    // Where clients reach this server; attachment links are built on it
+    pub public_url: String,
+}
+
+#[derive(Clone)]
+pub enum UploadBackend {
+    Disk(PathBuf),
+    S3 { bucket: S3Bucket, prefix: String },
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        UploadConfig {
+            backend: UploadBackend::Disk(PathBuf::from("uploads")),
+            max_bytes: 10 * 1024 * 1024,
+            allowed_types: ["image/*", "application/pdf", "text/plain"]
+                .map(String::from)
+                .to_vec(),
+            public_url: "http://127.0.0.1:8080".to_string(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ExportConfig {
+    pub s3: S3Bucket,
+    pub prefix: String,
+    pub interval: Duration,
+    // Empty means every room on the server
+    pub rooms: Vec<Uuid>,
+}
+
+fn var(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn flag(name: &str, default: bool) -> bool {
+    match var(name).as_deref() {
+        Some("1" | "true" | "yes" | "on") => true,
+        Some("0" | "false" | "no" | "off") => false,
+        Some(other) => {
+            log::error!("ignoring invalid boolean {:?} in {}", other, name);
+            default
+        }
+        None => default,
+    }
+}
+
+fn list(name: &str) -> Vec<String> {
+    var(name)
+        .map(|value| {
+            value
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn room_ids(name: &str) -> Vec<Uuid> {
+    list(name)
+        .iter()
+        .filter_map(|id| match Uuid::parse_str(id) {
+            Ok(id) => Some(id),
+            Err(_) => {
+                log::error!("ignoring invalid room id {:?} in {}", id, name);
+                None
+            }
+        })
+        .collect()
+}
+
+// Reads the id an earlier boot generated, or generates and saves one. An
+// unwritable path only costs stability: the id then lasts one process.
+fn persistent_node_id(path: &Path) -> String {
+    if let Ok(saved) = std::fs::read_to_string(path) {
+        let saved = saved.trim();
+        if !saved.is_empty() {
+            return saved.to_string();
+        }
+    }
+    let node_id = Uuid::new_v4().simple().to_string();
+    match std::fs::write(path, format!("{}\n", node_id)) {
+        Ok(()) => log::info!("generated node id {} in {}", node_id, path.display()),
+        Err(err) => log::warn!(
+            "cannot save node id to {}, it will change on restart: {}",
+            path.display(),
+            err
+        ),
+    }
+    node_id
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let bind = var("CHAT_BIND").unwrap_or_else(|| "127.0.0.1:8080".to_string());
+        Config {
+            node_id: var("CHAT_NODE_ID").unwrap_or_else(|| {
+                persistent_node_id(&PathBuf::from(
+                    var("NODE_ID_FILE").unwrap_or_else(|| "node-id".to_string()),
+                ))
+            }),
+            // Comma-separated list of server admin usernames
+            admins: list("CHAT_ADMINS"),
+            default_rooms: room_ids("DEFAULT_ROOMS"),
+            honeypot_rooms: room_ids("HONEYPOT_ROOMS"),
+            export: ExportConfig::from_env(),
+            cold_storage: var("COLD_STORAGE_DIR").map(|dir| ColdStorageConfig {
+                dir: PathBuf::from(dir),
+                idle: Duration::from_secs(
+                    var("COLD_STORAGE_IDLE_SECS")
+                        .and_then(|secs| secs.parse::<u64>().ok())
+                        .filter(|secs| *secs > 0)
+                        .unwrap_or(90 * 24 * 60 * 60),
+                ),
+            }),
+            database_url: var("DATABASE_URL"),
+            redis_url: var("REDIS_URL"),
+            persistence: persistence_policy(),
+            deadlines: deadlines(),
+            uploads: UploadConfig::from_env(&bind),
+            room_archive_dir: PathBuf::from(
+                var("ROOM_ARCHIVE_DIR").unwrap_or_else(|| "archived-rooms".to_string()),
+            ),
+            ephemeral_interval: var("EPHEMERAL_COALESCE_MS")
+                .and_then(|millis| millis.parse::<u64>().ok())
+                .map_or(coalesce::DEFAULT_INTERVAL, Duration::from_millis),
+            join_history: flag("JOIN_MESSAGES_IN_HISTORY", false),
+            heartbeat: heartbeat(),
+            outbound: OutboundConfig {
+                http_proxy: var("HTTP_PROXY").or_else(|| var("http_proxy")),
+                https_proxy: var("HTTPS_PROXY").or_else(|| var("https_proxy")),
+                no_proxy: if var("NO_PROXY").is_some() {
+                    list("NO_PROXY")
+                } else {
+                    list("no_proxy")
+                },
+                ca_file: var("OUTBOUND_CA_FILE").map(PathBuf::from),
+            },
+            slack: var("SLACK_SIGNING_SECRET").map(|signing_secret| SlackConfig {
+                signing_secret,
+                bot_token: var("SLACK_BOT_TOKEN"),
+            }),
+            telegram: var("TELEGRAM_BOT_TOKEN").map(|token| TelegramConfig { token }),
+            email: var("EMAIL_WEBHOOK_TOKEN").map(|webhook_token| EmailConfig {
+                webhook_token,
+                from: var("EMAIL_FROM"),
+                outbound_url: var("EMAIL_OUTBOUND_URL"),
+                outbound_token: var("EMAIL_OUTBOUND_TOKEN"),
+            }),
+            replication_token: var("REPLICATION_TOKEN"),
+            replica: replica(&bind),
+            digest_interval: Duration::from_secs(
+                var("DIGEST_INTERVAL_SECS")
+                    .and_then(|secs| secs.parse::<u64>().ok())
+                    .filter(|secs| *secs > 0)
+                    .unwrap_or(3600),
+            ),
+            probation: probation_policy(),
+            usernames: UsernamePolicy {
+                case_insensitive: flag("USERNAME_CASE_INSENSITIVE", true),
+                reject_confusables: flag("USERNAME_REJECT_CONFUSABLES", true),
+            },
+            bind,
+        }
+    }
+}
+
+// PUBLIC_URL, or the bind address when the server is reached directly
+fn public_url(bind: &str) -> String {
+    var("PUBLIC_URL")
+        .map(|url| url.trim_end_matches('/').to_string())
+        .unwrap_or_else(|| format!("http://{}", bind))
+}
+
+// REPLICA_OF wins if both are set: a read replica never takes over
+fn replica(bind: &str) -> Option<ReplicaConfig> {
+    let (primary, standby) = match (var("REPLICA_OF"), var("STANDBY_OF")) {
+        (Some(primary), _) => (primary, None),
+        (None, Some(primary)) => (primary, Some(public_url(bind))),
+        (None, None) => return None,
+    };
+    Some(ReplicaConfig {
+        primary: primary.trim_end_matches('/').to_string(),
+        token: var("REPLICATION_TOKEN"),
+        interval: Duration::from_secs(
+            var("REPLICA_SYNC_INTERVAL_SECS")
+                .and_then(|secs| secs.parse::<u64>().ok())
+                .filter(|secs| *secs > 0)
+                .unwrap_or(2),
+        ),
+        standby,
+    })
+}
+
+fn probation_policy() -> ProbationPolicy {
+    let defaults = ProbationPolicy::default();
+    let number = |name: &str| var(name).and_then(|value| value.parse::<u64>().ok());
+    ProbationPolicy {
+        // PROBATION_SECS=0 disables probation
+        min_age: number("PROBATION_SECS")
+            .map(Duration::from_secs)
+            .unwrap_or(defaults.min_age),
+        min_messages: number("PROBATION_MIN_MESSAGES").unwrap_or(defaults.min_messages),
+        send_limit: number("PROBATION_SENDS_PER_MINUTE")
+            .filter(|per_minute| *per_minute > 0)
+            .map(|per_minute| Limit {
+                capacity: defaults.send_limit.capacity,
+                refill_per_sec: per_minute as f64 / 60.0,
+            })
+            .unwrap_or(defaults.send_limit),
+    }
+}
+
+fn deadlines() -> Deadlines {
+    let defaults = Deadlines::default();
+    let millis = |name: &str| {
+        var(name)
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(Duration::from_millis)
+    };
+    let optional = |name: &str, default: Option<Duration>| match millis(name) {
+        Some(limit) => (!limit.is_zero()).then_some(limit),
+        None => default,
+    };
+    Deadlines {
+        request: optional("REQUEST_TIMEOUT_MS", defaults.request),
+        storage: optional("STORAGE_TIMEOUT_MS", defaults.storage),
+        broadcast_wait: millis("BROADCAST_WAIT_MS").unwrap_or(defaults.broadcast_wait),
+    }
+}
+
+fn heartbeat() -> Heartbeat {
+    let defaults = Heartbeat::default();
+    let number = |name: &str| var(name).and_then(|value| value.parse::<u64>().ok());
+    Heartbeat {
+        interval: match number("HEARTBEAT_INTERVAL_SECS") {
+            Some(secs) => (secs > 0).then(|| Duration::from_secs(secs)),
+            None => defaults.interval,
+        },
+        max_missed: number("HEARTBEAT_MAX_MISSED")
+            .filter(|missed| *missed > 0)
+            .map_or(defaults.max_missed, |missed| {
+                missed.min(u32::MAX as u64) as u32
+            }),
+    }
+}
+
+fn persistence_policy() -> PersistencePolicy {
+    if var("PERSIST_EVENTS").is_none() {
+        return PersistencePolicy::default();
+    }
+    let mut policy = PersistencePolicy { reactions: false };
+    for kind in list("PERSIST_EVENTS") {
+        match kind.as_str() {
+            "messages" => {}
+            "reactions" => policy.reactions = true,
+            "typing" | "presence" | "receipts" => {
+                log::warn!(
+                    "{} events are never persisted, ignoring in PERSIST_EVENTS",
+                    kind
+                )
+            }
+            other => log::error!("ignoring unknown event kind {:?} in PERSIST_EVENTS", other),
+        }
+    }
+    policy
+}
+
+impl S3Bucket {
+    // The endpoint and bucket are required; the rest is read from
+    // {prefix}_S3_REGION, _ACCESS_KEY and _SECRET_KEY
+    fn from_env(prefix: &str, endpoint: String, name: String) -> Self {
+        S3Bucket {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            name,
+            region: var(&format!("{}_S3_REGION", prefix))
+                .unwrap_or_else(|| "us-east-1".to_string()),
+            access_key: var(&format!("{}_S3_ACCESS_KEY", prefix)).unwrap_or_default(),
+            secret_key: var(&format!("{}_S3_SECRET_KEY", prefix)).unwrap_or_default(),
+        }
+    }
+}
+
+impl UploadConfig {
+    fn from_env(bind: &str) -> Self {
+        let defaults = UploadConfig::default();
+        let backend = match (var("UPLOAD_S3_ENDPOINT"), var("UPLOAD_S3_BUCKET")) {
+            (Some(endpoint), Some(bucket)) => UploadBackend::S3 {
+                bucket: S3Bucket::from_env("UPLOAD", endpoint, bucket),
+                prefix: var("UPLOAD_S3_PREFIX")
+                    .map(|prefix| prefix.trim_matches('/').to_string())
+                    .unwrap_or_else(|| "uploads".to_string()),
+            },
+            _ => var("UPLOAD_DIR")
+                .map(|dir| UploadBackend::Disk(PathBuf::from(dir)))
+                .unwrap_or(defaults.backend),
+        };
+        let allowed_types = list("UPLOAD_ALLOWED_TYPES");
+        UploadConfig {
+            backend,
+            max_bytes: var("UPLOAD_MAX_BYTES")
+                .and_then(|bytes| bytes.parse::<usize>().ok())
+                .filter(|bytes| *bytes > 0)
+                .unwrap_or(defaults.max_bytes),
+            allowed_types: if allowed_types.is_empty() {
+                defaults.allowed_types
+            } else {
+                allowed_types
+            },
+            public_url: public_url(bind),
+        }
+    }
+}
+
+impl ExportConfig {
+    // Exports are enabled once EXPORT_S3_ENDPOINT and EXPORT_S3_BUCKET are both set
+    fn from_env() -> Option<Self> {
+        let endpoint = var("EXPORT_S3_ENDPOINT")?;
+        let bucket = var("EXPORT_S3_BUCKET")?;
+        let rooms = room_ids("EXPORT_ROOMS");
+        let interval_secs = var("EXPORT_INTERVAL_SECS")
+            .and_then(|secs| secs.parse::<u64>().ok())
+            .filter(|secs| *secs > 0)
+            .unwrap_or(3600);
+        Some(ExportConfig {
+            s3: S3Bucket::from_env("EXPORT", endpoint, bucket),
+            prefix: var("EXPORT_S3_PREFIX")
+                .map(|prefix| prefix.trim_matches('/').to_string())
+                .unwrap_or_else(|| "exports".to_string()),
+            interval: Duration::from_secs(interval_secs),
+            rooms,
+        })
+    }
+}
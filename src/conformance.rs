@@ -0,0 +1,351 @@
+// WebSocket conformance: drives `ClientSession` over a real socket the way
+// broken and hostile clients do, and checks that every session answers with
+// error frames or a proper close, stays usable where it should, and is gone
+// from the shared maps once its client is.
+use actix_codec::Encoder;
+use actix_http::ws::Item;
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{rt, web, App, HttpServer};
+use awc::ws::{CloseCode, Codec, Frame, Message};
+use futures_util::{SinkExt, StreamExt};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::protocol::MAX_FRAME_BYTES;
+use crate::{ws_handler, ChatRoom, SharedState};
+
+type Connection = actix_codec::Framed<awc::BoxedSocket, Codec>;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+struct Harness {
+    state: Arc<SharedState>,
+    addr: SocketAddr,
+    room_id: Uuid,
+}
+
+impl Harness {
+    fn start() -> Harness {
+        let state = Arc::new(SharedState::default());
+        let mut room = ChatRoom::new("conformance".to_string(), "alice".to_string());
+        room.participants.insert("bob".to_string());
+        let room_id = room.id;
+        state.chat_rooms.lock().unwrap().insert(room_id, room);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_state = state.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(server_state.clone()))
+                .route("/ws/", web::get().to(ws_handler))
+        })
+        .workers(1)
+        .disable_signals()
+        .listen(listener)
+        .unwrap()
+        .run();
+        rt::spawn(server);
+        Harness {
+            state,
+            addr,
+            room_id,
+        }
+    }
+
+    // Connects over chat.v2 and swallows the join frame
+    async fn connect(&self, username: &str) -> Connection {
+        let (token, _) = self.state.auth_tokens.lock().unwrap().issue(username);
+        let url = format!(
+            "http://{}/ws/?roomId={}&token={}",
+            self.addr, self.room_id, token
+        );
+        let (_, mut conn) = awc::Client::new()
+            .ws(url)
+            .protocols(["chat.v2"])
+            .connect()
+            .await
+            .expect("handshake succeeds");
+        assert_eq!(next_json(&mut conn).await["type"], "join");
+        conn
+    }
+
+    fn room_sessions(&self) -> usize {
+        self.state
+            .active_sessions
+            .lock()
+            .unwrap()
+            .get(&self.room_id)
+            .map_or(0, Vec::len)
+    }
+
+    fn leftover_sessions(&self) -> usize {
+        self.room_sessions()
+            + self.state.user_sessions.lock().unwrap().len()
+            + self.state.session_registry.lock().unwrap().len()
+    }
+
+    // Actors stop asynchronously after their socket goes away
+    async fn wait_for_no_sessions(&self) {
+        let deadline = rt::time::Instant::now() + WAIT;
+        while self.leftover_sessions() > 0 {
+            assert!(
+                rt::time::Instant::now() < deadline,
+                "{} session entries left behind",
+                self.leftover_sessions()
+            );
+            rt::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+}
+
+async fn next_frame(conn: &mut Connection) -> Option<Frame> {
+    rt::time::timeout(WAIT, conn.next())
+        .await
+        .expect("server answers in time")
+        .map(|frame| frame.expect("server frames decode"))
+}
+
+async fn next_json(conn: &mut Connection) -> serde_json::Value {
+    match next_frame(conn).await {
+        Some(Frame::Text(text)) => serde_json::from_slice(&text).expect("server sends JSON"),
+        other => panic!("expected a text frame, got {:?}", other),
+    }
+}
+
+async fn send(conn: &mut Connection, msg: Message) {
+    conn.send(msg).await.expect("client frame is sent");
+}
+
+async fn send_text(conn: &mut Connection, text: &str) {
+    send(conn, Message::Text(text.into())).await;
+}
+
+// Proves the session still reads frames after whatever came before
+async fn assert_alive(conn: &mut Connection) {
+    send(conn, Message::Ping(Bytes::from_static(b"alive"))).await;
+    match next_frame(conn).await {
+        Some(Frame::Pong(payload)) => assert_eq!(&payload[..], b"alive"),
+        other => panic!("expected a pong, got {:?}", other),
+    }
+}
+
+async fn assert_closed_with(conn: &mut Connection, code: CloseCode) {
+    match next_frame(conn).await {
+        Some(Frame::Close(Some(reason))) => assert_eq!(reason.code, code),
+        other => panic!("expected a close, got {:?}", other),
+    }
+}
+
+#[actix_web::test]
+async fn malformed_typed_frames_get_error_frames() {
+    let harness = Harness::start();
+    let mut conn = harness.connect("alice").await;
+    let malformed = [
+        r#"{"type":"no_such_frame"}"#,
+        r#"{"type":"message"}"#,
+        r#"{"type":"message","content":42}"#,
+        r#"{"type":"subscribe","events":["everything"]}"#,
+        r#"{"type":"mark_read","seq":"latest"}"#,
+        r#"{"type":"refresh_token"}"#,
+        r#"{"type":"edit_message","message_id":"not-a-uuid","content":"x"}"#,
+        r#"{"type":null}"#,
+        r#"{"type":"message","content":"hi","attachments":"none"}"#,
+    ];
+    for frame in malformed {
+        send_text(&mut conn, frame).await;
+        let reply = next_json(&mut conn).await;
+        assert_eq!(reply["type"], "error", "for {}", frame);
+        assert_eq!(reply["code"], "invalid_frame", "for {}", frame);
+    }
+    assert_alive(&mut conn).await;
+    assert!(harness.state.chat_rooms.lock().unwrap()[&harness.room_id]
+        .message_log
+        .is_empty());
+}
+
+#[actix_web::test]
+async fn untyped_text_is_still_chat() {
+    let harness = Harness::start();
+    let mut conn = harness.connect("alice").await;
+    for text in ["{", "\"", "[1,2", "null", "\u{0}\u{feff}"] {
+        send_text(&mut conn, text).await;
+        let reply = next_json(&mut conn).await;
+        assert_eq!(reply["type"], "message", "for {:?}", text);
+        // Stored as sanitized, which drops the NUL
+        assert_eq!(reply["message"]["content"], crate::sanitize::clean(text));
+    }
+}
+
+#[actix_web::test]
+async fn binary_frames_are_refused() {
+    let harness = Harness::start();
+    let mut conn = harness.connect("alice").await;
+    send(
+        &mut conn,
+        Message::Binary(Bytes::from_static(&[0xff, 0x00])),
+    )
+    .await;
+    assert_eq!(next_json(&mut conn).await["code"], "invalid_frame");
+    assert_alive(&mut conn).await;
+}
+
+#[actix_web::test]
+async fn long_messages_are_refused_but_keep_the_session() {
+    let harness = Harness::start();
+    let mut conn = harness.connect("alice").await;
+    let content = "x".repeat(crate::messages::MAX_MESSAGE_LEN + 1);
+    send_text(&mut conn, &content).await;
+    assert_eq!(next_json(&mut conn).await["code"], "message_too_long");
+    assert_alive(&mut conn).await;
+}
+
+#[actix_web::test]
+async fn oversized_frames_close_the_session() {
+    let harness = Harness::start();
+    let mut conn = harness.connect("alice").await;
+    send_text(&mut conn, &"x".repeat(MAX_FRAME_BYTES + 1)).await;
+    assert_closed_with(&mut conn, CloseCode::Size).await;
+    drop(conn);
+    harness.wait_for_no_sessions().await;
+}
+
+#[actix_web::test]
+async fn fragmented_messages_are_reassembled_around_control_frames() {
+    let harness = Harness::start();
+    let mut conn = harness.connect("alice").await;
+    let pieces = [
+        Item::FirstText(Bytes::from_static(br#"{"type":"mess"#)),
+        Item::Continue(Bytes::from_static(br#"age","content":"h"#)),
+        Item::Continue(Bytes::new()),
+        Item::Last(Bytes::from_static(br#"ello"}"#)),
+    ];
+    for (i, piece) in pieces.into_iter().enumerate() {
+        send(&mut conn, Message::Continuation(piece)).await;
+        // Control frames may arrive between the pieces of a message
+        if i == 1 {
+            assert_alive(&mut conn).await;
+        }
+    }
+    let reply = next_json(&mut conn).await;
+    assert_eq!(reply["type"], "message");
+    assert_eq!(reply["message"]["content"], "hello");
+}
+
+#[actix_web::test]
+async fn fragmented_binary_is_refused() {
+    let harness = Harness::start();
+    let mut conn = harness.connect("alice").await;
+    let first = Item::FirstBinary(Bytes::from_static(b"\x01"));
+    send(&mut conn, Message::Continuation(first)).await;
+    let last = Item::Last(Bytes::from_static(b"\x02"));
+    send(&mut conn, Message::Continuation(last)).await;
+    assert_eq!(next_json(&mut conn).await["code"], "invalid_frame");
+    assert_alive(&mut conn).await;
+}
+
+#[actix_web::test]
+async fn fragments_split_invalid_utf8_close_the_session() {
+    let harness = Harness::start();
+    let mut conn = harness.connect("alice").await;
+    // "é" is 0xc3 0xa9; the second piece swaps in a byte that can't follow
+    let first = Item::FirstText(Bytes::from_static(b"caf\xc3"));
+    send(&mut conn, Message::Continuation(first)).await;
+    let last = Item::Last(Bytes::from_static(b"\x41"));
+    send(&mut conn, Message::Continuation(last)).await;
+    assert_closed_with(&mut conn, CloseCode::Invalid).await;
+}
+
+#[actix_web::test]
+async fn oversized_fragmented_messages_close_the_session() {
+    let harness = Harness::start();
+    let mut conn = harness.connect("alice").await;
+    let chunk = Bytes::from(vec![b'x'; MAX_FRAME_BYTES / 4]);
+    send(
+        &mut conn,
+        Message::Continuation(Item::FirstText(chunk.clone())),
+    )
+    .await;
+    for _ in 0..4 {
+        send(
+            &mut conn,
+            Message::Continuation(Item::Continue(chunk.clone())),
+        )
+        .await;
+    }
+    assert_closed_with(&mut conn, CloseCode::Size).await;
+    drop(conn);
+    harness.wait_for_no_sessions().await;
+}
+
+#[actix_web::test]
+async fn interleaved_messages_mid_fragment_close_the_session() {
+    let harness = Harness::start();
+    let mut conn = harness.connect("alice").await;
+    let first = Item::FirstText(Bytes::from_static(b"one"));
+    send(&mut conn, Message::Continuation(first)).await;
+    // A new data frame before the fragmented one finished
+    send_text(&mut conn, "two").await;
+    assert_closed_with(&mut conn, CloseCode::Protocol).await;
+    drop(conn);
+    harness.wait_for_no_sessions().await;
+}
+
+#[actix_web::test]
+async fn continuation_without_a_first_fragment_closes_the_session() {
+    let harness = Harness::start();
+    let mut conn = harness.connect("alice").await;
+    // Our own codec won't send a stray continuation unless it thinks one began
+    let mut codec = Codec::new().client_mode();
+    let first = Message::Continuation(Item::FirstText(Bytes::from_static(b"unsent")));
+    codec.encode(first, &mut BytesMut::new()).unwrap();
+    *conn.codec_mut() = codec;
+    let last = Item::Last(Bytes::from_static(b"orphan"));
+    send(&mut conn, Message::Continuation(last)).await;
+    assert_closed_with(&mut conn, CloseCode::Protocol).await;
+}
+
+#[actix_web::test]
+async fn close_frames_are_answered() {
+    let harness = Harness::start();
+    let mut conn = harness.connect("alice").await;
+    send(&mut conn, Message::Close(Some(CloseCode::Normal.into()))).await;
+    assert_closed_with(&mut conn, CloseCode::Normal).await;
+    drop(conn);
+    harness.wait_for_no_sessions().await;
+}
+
+#[actix_web::test]
+async fn rapid_connect_disconnect_leaves_no_sessions() {
+    let harness = Harness::start();
+    for round in 0..40 {
+        let username = if round % 2 == 0 { "alice" } else { "bob" };
+        let mut conn = harness.connect(username).await;
+        match round % 4 {
+            // Polite close
+            0 => send(&mut conn, Message::Close(None)).await,
+            // Mid-fragment, then gone without a close
+            1 => {
+                let first = Item::FirstText(Bytes::from_static(b"half"));
+                send(&mut conn, Message::Continuation(first)).await;
+            }
+            // Typing when the socket drops
+            2 => send_text(&mut conn, r#"{"type":"typing_start"}"#).await,
+            // Nothing at all
+            _ => {}
+        }
+        drop(conn);
+    }
+    harness.wait_for_no_sessions().await;
+
+    // Overlapping connections from one user, dropped in a burst
+    let mut conns = Vec::new();
+    for _ in 0..10 {
+        conns.push(harness.connect("alice").await);
+    }
+    assert_eq!(harness.room_sessions(), 10);
+    drop(conns);
+    harness.wait_for_no_sessions().await;
+}
@@ -0,0 +1,185 @@
+use actix::prelude::*;
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::{now_millis, ChatMessage, ClientSession, RoomEvent, SharedState};
+
+const DEAD_LETTER_CAPACITY: usize = 1_000;
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "kind", content = "payload", rename_all = "snake_case")]
+pub enum Undelivered {
+    Chat(Box<ChatMessage>),
+    Event(serde_json::Value),
+}
+
+#[derive(Serialize, Clone)]
+pub struct DeadLetter {
+    id: Uuid,
+    at: u64,
+    room_id: Option<Uuid>,
+    // None when the session was already gone from every registry
+    recipient: Option<String>,
+    reason: &'static str,
+    attempts: u32,
+    #[serde(flatten)]
+    undelivered: Undelivered,
+}
+
+#[derive(Default)]
+pub struct DeadLetterStore {
+    entries: Mutex<VecDeque<DeadLetter>>,
+}
+
+impl DeadLetterStore {
+    pub fn record(
+        &self,
+        room_id: Option<Uuid>,
+        recipient: Option<String>,
+        reason: &'static str,
+        undelivered: Undelivered,
+    ) {
+        log::warn!(
+            "dead letter: {} to {:?} in room {:?}",
+            reason,
+            recipient,
+            room_id
+        );
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= DEAD_LETTER_CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(DeadLetter {
+            id: Uuid::new_v4(),
+            at: now_millis(),
+            room_id,
+            recipient,
+            reason,
+            attempts: 1,
+            undelivered,
+        });
+    }
+}
+
+// Sends to every recipient whose actor is still alive and hands back the
+// rest, so the caller can record them once it has released its locks.
+pub fn fan_out<M>(recipients: &[Addr<ClientSession>], msg: &M) -> Vec<Addr<ClientSession>>
+where
+    M: Message + Send + Clone + 'static,
+    M::Result: Send,
+    ClientSession: Handler<M>,
+{
+    let mut dead = Vec::new();
+    for addr in recipients {
+        if addr.connected() {
+            addr.do_send(msg.clone());
+        } else {
+            dead.push(addr.clone());
+        }
+    }
+    dead
+}
+
+// Records undelivered broadcasts to a room. Must not be called while holding
+// `user_sessions`, which is used to name the recipients.
+pub fn record_undelivered(
+    state: &SharedState,
+    room_id: Uuid,
+    dead: Vec<Addr<ClientSession>>,
+    undelivered: Undelivered,
+) {
+    if dead.is_empty() {
+        return;
+    }
+    let owners: Vec<_> = {
+        let user_sessions = state.user_sessions.lock().unwrap();
+        dead.iter()
+            .map(|addr| {
+                user_sessions
+                    .iter()
+                    .find(|(_, addrs)| addrs.contains(addr))
+                    .map(|(username, _)| username.clone())
+            })
+            .collect()
+    };
+    for owner in owners {
+        state
+            .dead_letters
+            .record(Some(room_id), owner, "actor_stopped", undelivered.clone());
+    }
+}
+
+pub async fn list_dead_letters(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let entries = state.dead_letters.entries.lock().unwrap();
+    let letters: Vec<_> = entries.iter().cloned().collect();
+    Ok(HttpResponse::Ok().json(letters))
+}
+
+// Redelivers to the recipient's current sessions (in the original room, if any)
+pub async fn retry_dead_letter(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let letter_id = path.into_inner();
+    let letter = {
+        let entries = state.dead_letters.entries.lock().unwrap();
+        entries
+            .iter()
+            .find(|letter| letter.id == letter_id)
+            .cloned()
+            .ok_or(ApiError::DeadLetterNotFound)?
+    };
+
+    let targets: Vec<Addr<ClientSession>> = {
+        let sessions = state.active_sessions.lock().unwrap();
+        let user_sessions = state.user_sessions.lock().unwrap();
+        let user_addrs = letter
+            .recipient
+            .as_ref()
+            .and_then(|username| user_sessions.get(username));
+        match (user_addrs, letter.room_id) {
+            (Some(addrs), Some(room_id)) => {
+                let room_addrs = sessions.get(&room_id).map_or(&[][..], Vec::as_slice);
+                addrs
+                    .iter()
+                    .filter(|addr| room_addrs.contains(addr))
+                    .cloned()
+                    .collect()
+            }
+            (Some(addrs), None) => addrs.clone(),
+            (None, _) => Vec::new(),
+        }
+    };
+
+    let delivered = match &letter.undelivered {
+        Undelivered::Chat(msg) => targets.len() - fan_out(&targets, msg.as_ref()).len(),
+        Undelivered::Event(event) => {
+            targets.len() - fan_out(&targets, &RoomEvent(event.clone())).len()
+        }
+    };
+
+    let mut entries = state.dead_letters.entries.lock().unwrap();
+    if delivered > 0 {
+        entries.retain(|entry| entry.id != letter_id);
+        return Ok(HttpResponse::Ok().json(serde_json::json!({ "delivered": delivered })));
+    }
+    if let Some(entry) = entries.iter_mut().find(|entry| entry.id == letter_id) {
+        entry.attempts += 1;
+    }
+    Err(ApiError::RecipientOffline)
+}
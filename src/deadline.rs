@@ -0,0 +1,85 @@
+// Bounds on how long work may wait, so one stuck backend can't pile up every
+// request behind it. A REST request that runs past its deadline is answered
+// with 503 deadline_exceeded, though only a handler that is awaiting can be
+// cut short: one blocked on a lock or the store holds its worker until that
+// returns. Those waits are bounded where they happen instead. History loads
+// give up after the storage timeout (see `store::touch`), and broadcasts wait
+// at most `broadcast_wait` for the session lists.
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{rt, web};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+
+use crate::error::ApiError;
+use crate::i18n;
+use crate::SharedState;
+
+const LOCK_POLL: Duration = Duration::from_micros(200);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Deadlines {
+    // None lets requests run as long as they take
+    pub request: Option<Duration>,
+    // Applies to reads that block a request; None waits indefinitely
+    pub storage: Option<Duration>,
+    pub broadcast_wait: Duration,
+}
+
+impl Default for Deadlines {
+    fn default() -> Self {
+        Deadlines {
+            request: Some(Duration::from_secs(15)),
+            storage: Some(Duration::from_secs(5)),
+            broadcast_wait: Duration::from_millis(500),
+        }
+    }
+}
+
+// A handler's request can't be kept aside while it runs, so the timeout
+// answer is built from the headers up front and returned as an error, which
+// the middleware inside this one never see. That includes CORS, whose
+// headers it therefore sets itself.
+pub async fn enforce(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let deadline = req
+        .app_data::<web::Data<Arc<SharedState>>>()
+        .and_then(|state| state.deadlines.request);
+    let Some(deadline) = deadline else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let lang = i18n::accept_language(req.headers());
+    let origin = req.headers().get(header::ORIGIN).cloned();
+    let target = format!("{} {}", req.method(), req.path());
+    match rt::time::timeout(deadline, next.call(req)).await {
+        Ok(res) => Ok(res?.map_into_boxed_body()),
+        Err(_) => {
+            log::warn!("{} ran past its {:?} deadline", target, deadline);
+            let mut res = ApiError::DeadlineExceeded.render(lang);
+            if let Some(origin) = origin {
+                let headers = res.headers_mut();
+                headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+                headers.insert(header::VARY, header::HeaderValue::from_static("Origin"));
+            }
+            Err(InternalError::from_response(ApiError::DeadlineExceeded, res).into())
+        }
+    }
+}
+
+// Like `Mutex::lock`, but gives up after `wait`
+pub fn lock_within<T>(mutex: &Mutex<T>, wait: Duration) -> Option<MutexGuard<'_, T>> {
+    let started = Instant::now();
+    loop {
+        match mutex.try_lock() {
+            Ok(guard) => return Some(guard),
+            Err(TryLockError::Poisoned(err)) => panic!("poisoned lock: {}", err),
+            Err(TryLockError::WouldBlock) if started.elapsed() >= wait => return None,
+            Err(TryLockError::WouldBlock) => std::thread::sleep(LOCK_POLL),
+        }
+    }
+}
@@ -0,0 +1,115 @@
+// Deleting a room. DELETE /rooms/{id} (owner or admin) removes the room
+// with its history, held messages and uploaded files, and closes every
+// connection to it: clients get a `room_deleted` event, then a close frame
+// with the same reason. With `?archive=true` the room is written, history
+// and all, to ROOM_ARCHIVE_DIR first and its files are kept. Rooms under
+// legal hold can only be deleted that way.
+use actix::Message;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::roles::{self, Permission};
+use crate::{approvals, audit, cold, rejections, store, uploads, SharedState};
+
+// Sent to every session connected to the deleted room
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct RoomDeleted {
+    pub room_id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteQuery {
+    #[serde(default)]
+    archive: bool,
+}
+
+pub async fn delete_room(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    query: web::Query<DeleteQuery>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let archive = query.archive;
+
+    let room = {
+        let mut rooms = state.chat_rooms.lock().unwrap();
+        let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+        roles::check(&state, room, &user.username, Permission::ChangeSettings)?;
+        let held = {
+            let holds = state.legal_holds.lock().unwrap();
+            holds.covers_room(room_id) || room.message_log.iter().any(|msg| holds.covers(msg))
+        };
+        if held && !archive {
+            return Err(ApiError::LegalHoldActive);
+        }
+        rooms.remove(&room_id).unwrap()
+    };
+
+    if archive {
+        let dir = state.room_archive_dir.clone();
+        let archived = room.clone();
+        let written = web::block(move || {
+            std::fs::create_dir_all(&dir)?;
+            cold::write_room(&dir, &archived)
+        });
+        let written = match written.await {
+            Ok(written) => written,
+            Err(err) => Err(std::io::Error::other(err)),
+        };
+        if let Err(err) = written {
+            log::error!("cannot archive room {}: {}", room_id, err);
+            // Put back as it was, like a failed offload
+            state.chat_rooms.lock().unwrap().insert(room_id, room);
+            return Err(ApiError::RoomArchiveFailed);
+        }
+    }
+
+    store::room_removed(&state, room_id);
+    rejections::room_removed(&state, room_id);
+    state.invites.lock().unwrap().room_removed(room_id);
+    state.unread.lock().unwrap().room_removed(room_id);
+    state.deliveries.lock().unwrap().room_removed(room_id);
+    state
+        .default_rooms
+        .lock()
+        .unwrap()
+        .retain(|id| *id != room_id);
+    let held_back = approvals::room_removed(&state, room_id);
+    if !archive {
+        uploads::discard(
+            &state,
+            room.message_log.iter().flat_map(|msg| &msg.attachments),
+        );
+    }
+    uploads::discard(&state, held_back.iter().flat_map(|msg| &msg.attachments));
+
+    let sessions = state.active_sessions.lock().unwrap().remove(&room_id);
+    let notice = RoomDeleted { room_id };
+    for addr in sessions.into_iter().flatten() {
+        addr.do_send(notice.clone());
+    }
+    state.notify_room_list_changed(&room.members(), "deleted", room_id);
+
+    audit::record(
+        &state,
+        &user.username,
+        "delete_room",
+        &room_id.to_string(),
+        format!(
+            "{} with {} messages{}",
+            room.name,
+            room.message_log.len(),
+            if archive { ", archived" } else { "" }
+        ),
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "archived": archive,
+    })))
+}
@@ -0,0 +1,178 @@
+// Periodic digests for rooms a user has set to mentions-only: instead of
+// following every message there, the user gets one feed item per period
+// summarising what was missed ("214 new messages, 3 mentions in lobby").
+use actix_web::{rt, web, HttpResponse};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::users::{owned_username, NotificationLevel};
+use crate::{now_millis, RoomEvent, SharedState};
+
+// Oldest items are dropped once a user's feed grows past this
+const FEED_CAPACITY: usize = 100;
+
+#[derive(Serialize, Clone)]
+pub struct DigestRoom {
+    room_id: Uuid,
+    name: String,
+    messages: u64,
+    mentions: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct FeedItem {
+    id: Uuid,
+    at: u64,
+    kind: &'static str,
+    text: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    rooms: Vec<DigestRoom>,
+    // Set on items about a single message, such as keyword alerts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_id: Option<Uuid>,
+}
+
+impl FeedItem {
+    pub fn about_message(
+        kind: &'static str,
+        text: String,
+        room_id: Uuid,
+        message_id: Uuid,
+    ) -> Self {
+        FeedItem {
+            id: Uuid::new_v4(),
+            at: now_millis(),
+            kind,
+            text,
+            rooms: Vec::new(),
+            room_id: Some(room_id),
+            message_id: Some(message_id),
+        }
+    }
+}
+
+// Per-user mailbox of server-generated items
+#[derive(Default)]
+pub struct Feeds {
+    items: HashMap<String, VecDeque<FeedItem>>,
+}
+
+impl Feeds {
+    pub fn push(&mut self, username: &str, item: FeedItem) {
+        let feed = self.items.entry(username.to_string()).or_default();
+        if feed.len() >= FEED_CAPACITY {
+            feed.pop_front();
+        }
+        feed.push_back(item);
+    }
+}
+
+fn plural(count: u64, word: &str) -> String {
+    format!("{} {}{}", count, word, if count == 1 { "" } else { "s" })
+}
+
+fn summary(room: &DigestRoom) -> String {
+    let mut text = plural(room.messages, "new message");
+    if room.mentions > 0 {
+        text = format!("{}, {}", text, plural(room.mentions, "mention"));
+    }
+    format!("{} in {}", text, room.name)
+}
+
+pub fn run(state: &SharedState) {
+    // Settings are copied out first so no settings lock is held under the tracker's
+    let mentions_only: HashSet<(String, Uuid)> = state
+        .user_settings
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|(username, settings)| {
+            settings
+                .room_notifications
+                .iter()
+                .filter(|(_, level)| **level == NotificationLevel::Mentions)
+                .map(move |(room_id, _)| (username.clone(), *room_id))
+        })
+        .collect();
+    let entries = state
+        .unread
+        .lock()
+        .unwrap()
+        .take_digests(|username, room_id| mentions_only.contains(&(username.to_string(), room_id)));
+    if entries.is_empty() {
+        return;
+    }
+
+    let mut per_user: HashMap<String, Vec<DigestRoom>> = HashMap::new();
+    {
+        let rooms = state.chat_rooms.lock().unwrap();
+        for entry in entries {
+            let Some(room) = rooms.get(&entry.room_id) else {
+                continue;
+            };
+            per_user
+                .entry(entry.username)
+                .or_default()
+                .push(DigestRoom {
+                    room_id: entry.room_id,
+                    name: room.name.clone(),
+                    messages: entry.messages,
+                    mentions: entry.mentions,
+                });
+        }
+    }
+
+    let now = now_millis();
+    for (username, mut rooms) in per_user {
+        rooms.sort_by_key(|room| std::cmp::Reverse(room.messages));
+        let item = FeedItem {
+            id: Uuid::new_v4(),
+            at: now,
+            kind: "digest",
+            text: rooms.iter().map(summary).collect::<Vec<_>>().join("; "),
+            rooms,
+            room_id: None,
+            message_id: None,
+        };
+        state.feeds.lock().unwrap().push(&username, item.clone());
+        state.notify_user(
+            &username,
+            RoomEvent(serde_json::json!({ "type": "feed_item", "item": item })),
+        );
+    }
+}
+
+pub fn spawn_digester(state: Arc<SharedState>, period: Duration) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(period);
+        // The first tick fires immediately and would only report startup noise
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            run(&state);
+        }
+    });
+}
+
+pub async fn list_feed(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<String>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let username = owned_username(&state, &path, &user)?;
+    let feeds = state.feeds.lock().unwrap();
+    let items: Vec<_> = feeds
+        .items
+        .get(&username)
+        .into_iter()
+        .flat_map(|feed| feed.iter().rev())
+        .collect();
+    Ok(HttpResponse::Ok().json(items))
+}
@@ -0,0 +1,111 @@
+// Direct messages: private conversations between exactly two users. Each is
+// an ordinary room flagged `direct`, so sending, history, edits and receipts
+// work as in any room, but only its two participants may join, read or post,
+// and it is listed under GET /dm instead of the room list. A pair's room id
+// is derived from the two usernames, so repeated POST /dm/{username} calls
+// from either side land in the same conversation, even after it was
+// offloaded to cold storage.
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use uuid::{Builder, Uuid};
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::history::{self, HistoryEntry};
+use crate::store;
+use crate::{ChatRoom, SharedState};
+
+// The same id whichever of the two starts the conversation
+fn room_id_for(a: &str, b: &str) -> Uuid {
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+    let digest = Sha256::new()
+        .chain_update(b"direct\0")
+        .chain_update(first.as_bytes())
+        .chain_update(b"\0")
+        .chain_update(second.as_bytes())
+        .finalize();
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    Builder::from_custom_bytes(bytes).into_uuid()
+}
+
+// Whether `username` must be kept out of the room, which
+// `invites::check_access` enforces; regular rooms are open
+pub fn excludes(room: &ChatRoom, username: &str) -> bool {
+    room.direct && !room.participants.contains(username)
+}
+
+fn other_participant<'a>(room: &'a ChatRoom, username: &'a str) -> &'a str {
+    room.participants
+        .iter()
+        .find(|name| *name != username)
+        .map_or(username, String::as_str)
+}
+
+pub async fn open_direct_room(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<String>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let peer = state.canonical_username(&path.into_inner());
+    if peer == user.username {
+        return Err(ApiError::SelfDirectMessage);
+    }
+    if !state.user_accounts.lock().unwrap().contains_key(&peer) {
+        return Err(ApiError::UserNotFound);
+    }
+    let room_id = state.resolve_room_id(room_id_for(&user.username, &peer));
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    if let Some(room) = rooms.get(&room_id) {
+        return Ok(HttpResponse::Ok().json(history::room_view(room)));
+    }
+    let mut room = ChatRoom::new(
+        format!("{} ↔ {}", user.username, peer),
+        user.username.clone(),
+    );
+    room.id = room_id;
+    room.direct = true;
+    room.participants.insert(user.username.clone());
+    room.participants.insert(peer);
+    rooms.insert(room_id, room.clone());
+    drop(rooms);
+    store::room_changed(&state, &room);
+    state.notify_room_list_changed(&room.members(), "created", room_id);
+    Ok(HttpResponse::Created().json(history::room_view(&room)))
+}
+
+#[derive(Serialize)]
+struct DirectRoomSummary<'a> {
+    room_id: Uuid,
+    with: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_message: Option<HistoryEntry<'a>>,
+}
+
+// The caller's conversations, most recently active first
+pub async fn list_direct_rooms(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+) -> HttpResponse {
+    let cold = state.cold_storage.lock().unwrap();
+    let rooms = state.chat_rooms.lock().unwrap();
+    let mut mine: Vec<&ChatRoom> = rooms
+        .values()
+        .chain(cold.archived())
+        .filter(|room| room.direct && room.participants.contains(&user.username))
+        .collect();
+    mine.sort_by_key(|room| {
+        std::cmp::Reverse(room.message_log.last().map_or(0, |msg| msg.sent_at))
+    });
+    let summaries: Vec<_> = mine
+        .into_iter()
+        .map(|room| DirectRoomSummary {
+            room_id: room.id,
+            with: other_participant(room, &user.username),
+            last_message: room.message_log.last().map(HistoryEntry::project),
+        })
+        .collect();
+    HttpResponse::Ok().json(summaries)
+}
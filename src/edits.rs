@@ -0,0 +1,240 @@
+// Changes to sent messages. Only the sender can edit, and only what the
+// room's policy allows; every edit keeps the content it replaced, so members
+// can see what a message said before. The sender or room staff can delete,
+// which leaves a tombstone in the message's place in the log.
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::approvals;
+use crate::audit;
+use crate::auth::UserContext;
+use crate::bans;
+use crate::error::ApiError;
+use crate::history::{self, HistoryEntry};
+use crate::invites;
+use crate::mentions;
+use crate::messages::{self, SEND_LIMIT};
+use crate::mutes;
+use crate::policy::Mutation;
+use crate::probation::{self, contains_link};
+use crate::roles::{self, Permission};
+use crate::roomrefs;
+use crate::sanitize;
+use crate::store;
+use crate::uploads;
+use crate::visibility;
+use crate::{now_millis, ChatMessage, ChatRoom, RoomEvent, SharedState};
+
+// Content a message had before an edit replaced it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Revision {
+    pub content: String,
+    pub replaced_at: u64,
+}
+
+// The message `editor` may edit right now
+fn editable<'a>(
+    room: &'a mut ChatRoom,
+    editor: &str,
+    message_id: Uuid,
+) -> Result<&'a mut ChatMessage, ApiError> {
+    let msg = room
+        .message_log
+        .iter_mut()
+        .find(|msg| msg.id == message_id)
+        .ok_or(ApiError::MessageNotFound)?;
+    if msg.sender != editor {
+        return Err(ApiError::NotMessageSender);
+    }
+    room.policy.check(msg, Mutation::Edit)?;
+    Ok(msg)
+}
+
+// Shared by the WS frame and the REST endpoint. Editing to the same content
+// changes nothing and isn't broadcast.
+pub fn edit_message(
+    state: &SharedState,
+    room_id: Uuid,
+    editor: &str,
+    message_id: Uuid,
+    content: String,
+) -> Result<ChatMessage, ApiError> {
+    state.rate_limiter.check("edit", editor, SEND_LIMIT)?;
+    let content = sanitize::clean(&content);
+    let mut edited = {
+        let mut rooms = state.chat_rooms.lock().unwrap();
+        let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+        editable(room, editor, message_id)?.clone()
+    };
+    edited.content = content;
+    // The new content passes the checks a send of it would. Link approval
+    // can't hold an edit of a published message, so it refuses it instead.
+    invites::check_member(state, room_id, editor)?;
+    bans::check_room(state, room_id, editor)?;
+    messages::validate(&edited.content, &edited.attachments, None)?;
+    mutes::check_send(state, room_id, editor, edited.kind)?;
+    probation::check_send(
+        state,
+        editor,
+        edited.kind,
+        &edited.content,
+        &edited.attachments,
+    )?;
+    if contains_link(&edited.content) && approvals::required(state, &edited) {
+        return Err(ApiError::EditNeedsApproval);
+    }
+
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room_refs = roomrefs::resolve(state, &rooms, &edited);
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    let mentioned = mentions::resolve(state, room, &edited);
+    let msg = editable(room, editor, message_id)?;
+    let changed = edited.content != msg.content;
+    if changed {
+        let now = now_millis();
+        let previous = std::mem::replace(&mut msg.content, edited.content);
+        msg.mentions = mentioned;
+        msg.room_refs = room_refs;
+        msg.edit_history.push(Revision {
+            content: previous,
+            replaced_at: now,
+        });
+        msg.edited_at = Some(now);
+        msg.version.bump(&state.node_id);
+        store::messages_changed(state, [&*msg]);
+    }
+    let msg = msg.clone();
+    drop(rooms);
+
+    if changed {
+        state.broadcast_event(
+            room_id,
+            RoomEvent(serde_json::json!({
+                "type": "message_edited",
+                "room_id": room_id,
+                "message": HistoryEntry::project(&msg),
+            })),
+        );
+    }
+    Ok(msg)
+}
+
+// Deleting a tombstone again is a no-op. Unless a legal hold covers the
+// message, its content, attachments and edits are wiped, not just hidden.
+pub fn delete_message(
+    state: &SharedState,
+    room_id: Uuid,
+    actor: &str,
+    message_id: Uuid,
+) -> Result<ChatMessage, ApiError> {
+    let holds = state.legal_holds.lock().unwrap().clone();
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    let moderating = roles::has_permission(state, room, actor, Permission::DeleteMessages);
+    let msg = room
+        .message_log
+        .iter_mut()
+        .find(|msg| msg.id == message_id)
+        .ok_or(ApiError::MessageNotFound)?;
+    if msg.sender != actor && !moderating {
+        return Err(ApiError::NotMessageSender);
+    }
+    let deleted = msg.deleted_at.is_none();
+    if deleted {
+        msg.deleted_at = Some(now_millis());
+        if !holds.covers(msg) {
+            msg.content.clear();
+            uploads::discard(state, &msg.attachments);
+            msg.attachments.clear();
+            msg.edit_history.clear();
+            msg.reactions.clear();
+        }
+        msg.version.bump(&state.node_id);
+        store::messages_changed(state, [&*msg]);
+    }
+    let msg = msg.clone();
+    drop(rooms);
+
+    if deleted {
+        state.broadcast_event(
+            room_id,
+            RoomEvent(serde_json::json!({
+                "type": "message_deleted",
+                "room_id": room_id,
+                "message_id": message_id,
+                "seq": msg.seq,
+            })),
+        );
+        if msg.sender != actor {
+            audit::record(
+                state,
+                actor,
+                "delete_message",
+                &message_id.to_string(),
+                format!("sent by {} in room {}", msg.sender, room_id),
+            );
+        }
+    }
+    Ok(msg)
+}
+
+#[derive(Deserialize)]
+pub struct MessageEdit {
+    // Optional since the editor comes from the login token
+    #[serde(default)]
+    sender: Option<String>,
+    content: String,
+}
+
+pub async fn patch_message(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, Uuid)>,
+    user: UserContext,
+    form: web::Json<MessageEdit>,
+) -> Result<HttpResponse, ApiError> {
+    user.claims(&state, form.sender.as_deref())?;
+    let (room_id, message_id) = path.into_inner();
+    let room_id = state.resolve_room_id(room_id);
+    let form = form.into_inner();
+    let message = edit_message(&state, room_id, &user.username, message_id, form.content)?;
+    Ok(HttpResponse::Ok().json(message))
+}
+
+pub async fn remove_message(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, Uuid)>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, message_id) = path.into_inner();
+    let room_id = state.resolve_room_id(room_id);
+    let message = delete_message(&state, room_id, &user.username, message_id)?;
+    Ok(HttpResponse::Ok().json(HistoryEntry::project(&message)))
+}
+
+// Oldest revision first. A deleted message's history goes with its content.
+pub async fn edit_history(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, Uuid)>,
+    user: Option<UserContext>,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, message_id) = path.into_inner();
+    let room_id = state.resolve_room_id(room_id);
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    invites::check_reader(&state, room, user.as_ref())?;
+    let msg = visibility::visible_log(&state, room, history::username(user.as_ref()))
+        .iter()
+        .find(|msg| msg.id == message_id)
+        .ok_or(ApiError::MessageNotFound)?;
+    let deleted = msg.deleted_at.is_some();
+    let revisions: &[Revision] = if deleted { &[] } else { &msg.edit_history };
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "message_id": message_id,
+        "content": (!deleted).then_some(&msg.content),
+        "edited_at": msg.edited_at,
+        "revisions": revisions,
+    })))
+}
@@ -0,0 +1,336 @@
+// Email to room gateway for support-style rooms. An inbound-parse webhook (the
+// kind SendGrid, Mailgun or Postfix pipes post) delivers mail sent to a linked
+// address into its room with the subject as a header line. When replies are
+// enabled on the link, room messages go back to the people on the room's
+// current email thread through EMAIL_OUTBOUND_URL, threaded with In-Reply-To.
+use actix_web::{rt, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::auth::UserContext;
+use crate::bridges::{self, BridgeOrigin};
+use crate::config::EmailConfig;
+use crate::error::ApiError;
+use crate::notifications;
+use crate::outbound;
+use crate::{ChatMessage, SharedState};
+
+const NETWORK: &str = "email";
+const TOKEN_HEADER: &str = "x-email-webhook-token";
+
+// The conversation a room is currently having over email
+#[derive(Clone)]
+struct Thread {
+    subject: String,
+    correspondents: BTreeSet<String>,
+    last_message_id: Option<String>,
+}
+
+struct EmailLink {
+    room_id: Uuid,
+    relay_replies: bool,
+    thread: Option<Thread>,
+}
+
+#[derive(Default)]
+pub struct EmailBridge {
+    config: Option<EmailConfig>,
+    links: HashMap<String, EmailLink>, // lowercased address -> room
+}
+
+impl EmailBridge {
+    pub fn new(config: Option<EmailConfig>) -> Self {
+        EmailBridge {
+            config,
+            links: HashMap::new(),
+        }
+    }
+
+    pub fn describe(&self) -> serde_json::Value {
+        let mut links: Vec<_> = self
+            .links
+            .iter()
+            .map(|(address, link)| {
+                serde_json::json!({
+                    "address": address,
+                    "room_id": link.room_id,
+                    "relay_replies": link.relay_replies,
+                    "thread": link.thread.as_ref().map(|thread| serde_json::json!({
+                        "subject": thread.subject,
+                        "correspondents": thread.correspondents,
+                    })),
+                })
+            })
+            .collect();
+        links.sort_by(|a, b| a["address"].as_str().cmp(&b["address"].as_str()));
+        serde_json::json!({
+            "enabled": self.config.is_some(),
+            "replies": self
+                .config
+                .as_ref()
+                .is_some_and(|config| config.outbound_url.is_some()),
+            "links": links,
+        })
+    }
+}
+
+// "Jane Doe <jane@example.com>" -> ("Jane Doe", "jane@example.com")
+fn parse_mailbox(raw: &str) -> (String, String) {
+    let raw = raw.trim();
+    match (raw.rfind('<'), raw.rfind('>')) {
+        (Some(open), Some(close)) if open < close => {
+            let address = raw[open + 1..close].trim().to_lowercase();
+            let name = raw[..open].trim().trim_matches('"').trim();
+            let name = if name.is_empty() { &address } else { name };
+            (name.to_string(), address)
+        }
+        _ => (raw.to_lowercase(), raw.to_lowercase()),
+    }
+}
+
+// Thread identity ignores reply and forward prefixes
+fn base_subject(subject: &str) -> String {
+    let mut subject = subject.trim();
+    loop {
+        let lower = subject.to_lowercase();
+        let Some(prefix) = ["re:", "fwd:", "fw:"]
+            .into_iter()
+            .find(|prefix| lower.starts_with(prefix))
+        else {
+            return subject.to_string();
+        };
+        subject = subject[prefix.len()..].trim_start();
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Recipients {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+pub struct InboundEmail {
+    from: String,
+    to: Recipients,
+    #[serde(default)]
+    subject: String,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    message_id: Option<String>,
+    // RFC 3834; anything but "no" marks an autoresponder we must not answer
+    #[serde(default)]
+    auto_submitted: Option<String>,
+}
+
+pub async fn inbound(
+    req: HttpRequest,
+    state: web::Data<Arc<SharedState>>,
+    form: web::Json<InboundEmail>,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.bridges.lock().unwrap().email.config.clone();
+    let presented = req
+        .headers()
+        .get(TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match (&config, presented) {
+        (Some(config), Some(presented)) if config.webhook_token == presented => {}
+        _ => return Err(ApiError::InvalidBridgeSignature),
+    }
+    let config = config.unwrap();
+    let email = form.into_inner();
+    let (name, address) = parse_mailbox(&email.from);
+    let automated = email
+        .auto_submitted
+        .as_deref()
+        .is_some_and(|value| !value.trim().eq_ignore_ascii_case("no"));
+    // Our own replies can come back through a list or a forward
+    if automated
+        || config.from.as_deref().map(|from| parse_mailbox(from).1) == Some(address.clone())
+    {
+        return Ok(HttpResponse::Accepted().finish());
+    }
+    let recipients = match email.to {
+        Recipients::One(to) => to.split(',').map(str::to_string).collect(),
+        Recipients::Many(to) => to,
+    };
+
+    let mut delivered = Vec::new();
+    for recipient in recipients {
+        let (_, recipient) = parse_mailbox(&recipient);
+        let room_id = {
+            let mut bridges = state.bridges.lock().unwrap();
+            let Some(link) = bridges.email.links.get_mut(&recipient) else {
+                continue;
+            };
+            let subject = base_subject(&email.subject);
+            let thread = match &mut link.thread {
+                Some(thread) if thread.subject == subject => thread,
+                thread => thread.insert(Thread {
+                    subject,
+                    correspondents: BTreeSet::new(),
+                    last_message_id: None,
+                }),
+            };
+            thread.correspondents.insert(address.clone());
+            if email.message_id.is_some() {
+                thread.last_message_id = email.message_id.clone();
+            }
+            link.room_id
+        };
+        let content = if email.subject.trim().is_empty() {
+            email.text.trim().to_string()
+        } else {
+            format!("Subject: {}\n\n{}", email.subject.trim(), email.text.trim())
+        };
+        let origin = BridgeOrigin {
+            network: NETWORK.to_string(),
+            conversation: recipient.clone(),
+            author_id: address.clone(),
+        };
+        match bridges::post_inbound(&state, room_id, &name, origin, &content, Vec::new()) {
+            Ok(msg) => delivered.push(msg.id),
+            Err(err) => log::warn!("dropped email to {}: {}", recipient, err.code()),
+        }
+    }
+    Ok(HttpResponse::Accepted().json(serde_json::json!({ "delivered": delivered })))
+}
+
+// Sends `msg` to the correspondents of the room's current thread on every
+// reply-enabled link, except messages that arrived by email on that link
+pub fn relay(state: &SharedState, msg: &ChatMessage) {
+    let (config, candidates) = {
+        let linked = state.bridges.lock().unwrap();
+        let Some(config) = linked.email.config.clone() else {
+            return;
+        };
+        let candidates: Vec<(String, Uuid, Thread)> = linked
+            .email
+            .links
+            .iter()
+            .filter(|(address, link)| {
+                link.relay_replies && bridges::should_relay(msg, NETWORK, address)
+            })
+            .filter_map(|(address, link)| {
+                Some((address.clone(), link.room_id, link.thread.clone()?))
+            })
+            .collect();
+        (config, candidates)
+    };
+    let Some(outbound_url) = config.outbound_url.clone() else {
+        return;
+    };
+    let notification = notifications::for_room(state, msg.room_id);
+    for (address, room_id, thread) in candidates {
+        if state.follow_redirects(room_id) != msg.room_id {
+            continue;
+        }
+        let mut headers = serde_json::Map::new();
+        if let Some(parent) = &thread.last_message_id {
+            headers.insert("In-Reply-To".into(), parent.clone().into());
+            headers.insert("References".into(), parent.clone().into());
+        }
+        let mut text = format!("{} wrote:\n\n{}", msg.sender, msg.content);
+        for attachment in &msg.attachments {
+            if let Some(url) = &attachment.url {
+                text.push_str(&format!("\n\n{}: {}", attachment.name, url));
+            }
+        }
+        let payload = serde_json::json!({
+            "from": config.from.clone().unwrap_or_else(|| address.clone()),
+            "reply_to": address,
+            "to": thread.correspondents,
+            "subject": format!("Re: {}", thread.subject),
+            "text": text,
+            "headers": headers,
+            "notification": notification,
+        });
+        let url = outbound_url.clone();
+        let token = config.outbound_token.clone();
+        rt::spawn(async move {
+            let mut request = outbound::client().post(&url);
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            match request.send_json(&payload).await {
+                Ok(resp) if resp.status().is_success() => {}
+                Ok(resp) => log::warn!("email relay returned {}", resp.status()),
+                Err(err) => log::warn!("email relay failed: {}", err),
+            }
+        });
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EmailLinkUpdate {
+    address: String,
+    // None unlinks the address
+    #[serde(default)]
+    room_id: Option<Uuid>,
+    #[serde(default)]
+    relay_replies: bool,
+}
+
+pub async fn set_link(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+    form: web::Json<EmailLinkUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let (_, address) = parse_mailbox(&form.address);
+    if !address.contains('@') {
+        return Err(ApiError::InvalidQuery);
+    }
+    let Some(room_id) = form.room_id else {
+        let removed = state
+            .bridges
+            .lock()
+            .unwrap()
+            .email
+            .links
+            .remove(&address)
+            .is_some();
+        if removed {
+            audit::record(
+                &state,
+                &user.username,
+                "bridge_unlinked",
+                &address,
+                NETWORK.into(),
+            );
+        }
+        return Ok(HttpResponse::NoContent().finish());
+    };
+    let room_id = state.resolve_room_id(room_id);
+    if !state.chat_rooms.lock().unwrap().contains_key(&room_id) {
+        return Err(ApiError::RoomNotFound);
+    }
+    state.bridges.lock().unwrap().email.links.insert(
+        address.clone(),
+        EmailLink {
+            room_id,
+            relay_replies: form.relay_replies,
+            thread: None,
+        },
+    );
+    audit::record(
+        &state,
+        &user.username,
+        "bridge_linked",
+        &address,
+        format!("email -> room {}", room_id),
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "network": NETWORK,
+        "address": address,
+        "room_id": room_id,
+        "relay_replies": form.relay_replies,
+    })))
+}
@@ -0,0 +1,218 @@
+// In-process API for applications that embed the chat engine instead of
+// talking to it over HTTP. A `ChatServerHandle` owns its own in-memory
+// state; rooms, messages and events behave as they do for network clients,
+// and subscribers get a room's traffic over a channel rather than a socket.
+//
+//     let chat = rust_hw4::ChatServerHandle::new();
+//     let room = chat.create_room("lobby", "alice");
+//     let mut events = chat.subscribe(room.id);
+//     chat.send_message(room.id, "alice", "hello")?;
+//     // events.recv().await yields Event::Message(..)
+//
+// The handle needs no runtime of its own. Background jobs such as message
+// expiry aren't started, so TTLs and typing timeouts never lapse.
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use uuid::Uuid;
+
+use crate::bans;
+use crate::error::ApiError;
+use crate::messages::{self, Outgoing};
+use crate::store;
+use crate::visibility;
+use crate::{ChatMessage, ChatRoom, SharedState};
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Room {
+    pub id: Uuid,
+    pub name: String,
+    pub created_by: String,
+    pub participants: Vec<String>,
+}
+
+impl Room {
+    fn of(room: &ChatRoom) -> Self {
+        let mut participants: Vec<_> = room.participants.iter().cloned().collect();
+        participants.sort();
+        Room {
+            id: room.id,
+            name: room.name.clone(),
+            created_by: room.created_by.clone(),
+            participants,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Message {
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub seq: u64,
+    pub sender: String,
+    // Empty once the message is deleted
+    pub content: String,
+    pub sent_at: u64,
+    pub deleted: bool,
+    // Held for moderator approval and not in the room yet
+    pub pending: bool,
+}
+
+impl Message {
+    fn of(msg: &ChatMessage) -> Self {
+        Message {
+            id: msg.id,
+            room_id: msg.room_id,
+            seq: msg.seq,
+            sender: msg.sender.clone(),
+            content: msg.content.clone(),
+            sent_at: msg.sent_at,
+            deleted: msg.deleted_at.is_some(),
+            pending: msg.pending_since.is_some(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    Message(Message),
+    // Every other room event (edits, reactions, receipts, moderation...) as the
+    // JSON a WebSocket client would get
+    Room(serde_json::Value),
+}
+
+#[derive(Default)]
+pub struct Subscribers {
+    rooms: Mutex<HashMap<Uuid, Vec<UnboundedSender<Event>>>>, // room_id -> channels
+}
+
+impl Subscribers {
+    // Subscribers whose receiver was dropped are forgotten on the next delivery
+    fn deliver(&self, room_id: Uuid, event: impl FnOnce() -> Event) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(senders) = rooms.get_mut(&room_id) else {
+            return;
+        };
+        let event = event();
+        senders.retain(|sender| sender.send(event.clone()).is_ok());
+        if senders.is_empty() {
+            rooms.remove(&room_id);
+        }
+    }
+}
+
+// Called with the room's session list locked, so subscribers see seq order
+pub fn message_appended(state: &SharedState, msg: &ChatMessage) {
+    state
+        .embedded
+        .deliver(msg.room_id, || Event::Message(Message::of(msg)));
+}
+
+pub fn room_event(state: &SharedState, room_id: Uuid, event: &serde_json::Value) {
+    state
+        .embedded
+        .deliver(room_id, || Event::Room(event.clone()));
+}
+
+#[derive(Clone)]
+pub struct ChatServerHandle {
+    state: Arc<SharedState>,
+}
+
+impl Default for ChatServerHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChatServerHandle {
+    pub fn new() -> Self {
+        ChatServerHandle {
+            state: Arc::new(SharedState {
+                node_id: Uuid::new_v4().simple().to_string(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn create_room(&self, name: &str, created_by: &str) -> Room {
+        let created_by = self.state.canonical_username(created_by);
+        let room = ChatRoom::new(name.to_string(), created_by);
+        self.state
+            .chat_rooms
+            .lock()
+            .unwrap()
+            .insert(room.id, room.clone());
+        store::room_changed(&self.state, &room);
+        Room::of(&room)
+    }
+
+    pub fn add_participant(&self, room_id: Uuid, username: &str) -> Result<Room, ApiError> {
+        let username = self.state.canonical_username(username);
+        let mut rooms = self.state.chat_rooms.lock().unwrap();
+        let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+        bans::check(room, &username)?;
+        if room.participants.insert(username.clone()) {
+            visibility::joined(room, &username);
+            store::room_changed(&self.state, room);
+        }
+        Ok(Room::of(room))
+    }
+
+    pub fn rooms(&self) -> Vec<Room> {
+        let rooms = self.state.chat_rooms.lock().unwrap();
+        let mut rooms: Vec<_> = rooms.values().map(Room::of).collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+        rooms
+    }
+
+    // Goes through the same checks as a network send: validation, rate
+    // limits, mutes, moderation holds
+    pub fn send_message(
+        &self,
+        room_id: Uuid,
+        sender: &str,
+        content: &str,
+    ) -> Result<Message, ApiError> {
+        let sender = self.state.canonical_username(sender);
+        let is_member = self
+            .state
+            .chat_rooms
+            .lock()
+            .unwrap()
+            .get(&room_id)
+            .ok_or(ApiError::RoomNotFound)?
+            .members()
+            .contains(&sender);
+        if !is_member {
+            return Err(ApiError::ParticipantNotFound);
+        }
+        let outgoing = Outgoing {
+            content: content.to_string(),
+            ..Default::default()
+        };
+        messages::send_message(&self.state, room_id, &sender, outgoing).map(|msg| Message::of(&msg))
+    }
+
+    // Oldest first
+    pub fn history(&self, room_id: Uuid) -> Result<Vec<Message>, ApiError> {
+        let rooms = self.state.chat_rooms.lock().unwrap();
+        let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+        Ok(room.message_log.iter().map(Message::of).collect())
+    }
+
+    // Everything the room broadcasts from now on, until the receiver is dropped
+    pub fn subscribe(&self, room_id: Uuid) -> UnboundedReceiver<Event> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.state
+            .embedded
+            .rooms
+            .lock()
+            .unwrap()
+            .entry(room_id)
+            .or_default()
+            .push(tx);
+        rx
+    }
+}
@@ -0,0 +1,370 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+
+use crate::i18n::{self, Lang};
+use crate::protocol::ServerFrame;
+
+// Stable error codes; clients should match on these rather than on the message text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiError {
+    InvalidQuery,
+    InvalidRoomId,
+    UserExists,
+    InvalidCredentials,
+    RoomNotFound,
+    SourceRoomNotFound,
+    TargetRoomNotFound,
+    SelfMerge,
+    AdminRequired,
+    NotRoomManager,
+    MessageNotFound,
+    MutationNotAllowed,
+    EditWindowExpired,
+    UserNotFound,
+    NotAccountOwner,
+    PresenceHidden,
+    // How long the client should wait before its next attempt
+    RateLimited { retry_after_ms: u64 },
+    // Until the mute lapses
+    Muted { retry_after_ms: u64 },
+    InvalidManifest,
+    InvalidRedactPattern,
+    EmptyMessage,
+    MessageTooLong,
+    ImportJobNotFound,
+    InvalidTtl,
+    DeadLetterNotFound,
+    RecipientOffline,
+    InvalidRecoveryToken,
+    EmptyPassword,
+    InvalidFrame,
+    UsernameConfusable,
+    ParticipantNotFound,
+    OwnerRoleFixed,
+    BookmarkNotFound,
+    InvalidHoldTarget,
+    InvalidReplicationToken,
+    ReadOnlyReplica,
+    InvalidAuthToken,
+    AuthTokenExpired,
+    AuthTokenMismatch,
+    BotNotFound,
+    BotNotAllowed,
+    InvalidAttachment,
+    ProbationRestricted,
+    InvalidBridgeSignature,
+    AttachmentNotFound,
+    AuthRequired,
+    InvalidKeyword,
+    TooManyKeywords,
+    KeywordNotFound,
+    NotRoomModerator,
+    InvalidIdempotencyKey,
+    IdempotencyKeyReused,
+    NotMessageSender,
+    InvalidReaction,
+    InvalidMuteDuration,
+    MuteNotAllowed,
+    InvalidUpload,
+    UploadTooLarge,
+    UploadTypeNotAllowed,
+    UploadFailed,
+    NotStandby,
+    InvalidPrimaryUrl,
+    DeadlineExceeded,
+    InvalidSearchQuery,
+    InvalidNotificationSound,
+    SelfDirectMessage,
+    DirectRoomFixed,
+    InvalidNotice,
+    InvalidExternalId,
+    ExternalIdTaken,
+    NotRoomMember,
+    InviteRequired,
+    InviteNotFound,
+    AlreadyRoomMember,
+    LegalHoldActive,
+    RoomArchiveFailed,
+    InvalidRoomSettings,
+    RoomReadOnly,
+    UnsupportedManifestVersion,
+    BannedFromRoom,
+    BanNotFound,
+    InvalidBanReason,
+    InvalidInviteOptions,
+    InviteLinkExpired,
+    EditNeedsApproval,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_ms: Option<u64>,
+}
+
+impl ApiError {
+    pub fn code(self) -> &'static str {
+        match self {
+            ApiError::InvalidQuery => "invalid_query",
+            ApiError::InvalidRoomId => "invalid_room_id",
+            ApiError::UserExists => "user_exists",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::RoomNotFound => "room_not_found",
+            ApiError::SourceRoomNotFound => "source_room_not_found",
+            ApiError::TargetRoomNotFound => "target_room_not_found",
+            ApiError::SelfMerge => "self_merge",
+            ApiError::AdminRequired => "admin_required",
+            ApiError::NotRoomManager => "not_room_manager",
+            ApiError::MessageNotFound => "message_not_found",
+            ApiError::MutationNotAllowed => "mutation_not_allowed",
+            ApiError::EditWindowExpired => "edit_window_expired",
+            ApiError::UserNotFound => "user_not_found",
+            ApiError::NotAccountOwner => "not_account_owner",
+            ApiError::PresenceHidden => "presence_hidden",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::Muted { .. } => "muted",
+            ApiError::InvalidManifest => "invalid_manifest",
+            ApiError::InvalidRedactPattern => "invalid_redact_pattern",
+            ApiError::EmptyMessage => "empty_message",
+            ApiError::MessageTooLong => "message_too_long",
+            ApiError::ImportJobNotFound => "import_job_not_found",
+            ApiError::InvalidTtl => "invalid_ttl",
+            ApiError::DeadLetterNotFound => "dead_letter_not_found",
+            ApiError::RecipientOffline => "recipient_offline",
+            ApiError::InvalidRecoveryToken => "invalid_recovery_token",
+            ApiError::EmptyPassword => "empty_password",
+            ApiError::InvalidFrame => "invalid_frame",
+            ApiError::UsernameConfusable => "username_confusable",
+            ApiError::ParticipantNotFound => "participant_not_found",
+            ApiError::OwnerRoleFixed => "owner_role_fixed",
+            ApiError::BookmarkNotFound => "bookmark_not_found",
+            ApiError::InvalidHoldTarget => "invalid_hold_target",
+            ApiError::InvalidReplicationToken => "invalid_replication_token",
+            ApiError::ReadOnlyReplica => "read_only_replica",
+            ApiError::InvalidAuthToken => "invalid_auth_token",
+            ApiError::AuthTokenExpired => "auth_token_expired",
+            ApiError::AuthTokenMismatch => "auth_token_mismatch",
+            ApiError::BotNotFound => "bot_not_found",
+            ApiError::BotNotAllowed => "bot_not_allowed",
+            ApiError::InvalidAttachment => "invalid_attachment",
+            ApiError::ProbationRestricted => "probation_restricted",
+            ApiError::InvalidBridgeSignature => "invalid_bridge_signature",
+            ApiError::AttachmentNotFound => "attachment_not_found",
+            ApiError::AuthRequired => "auth_required",
+            ApiError::InvalidKeyword => "invalid_keyword",
+            ApiError::TooManyKeywords => "too_many_keywords",
+            ApiError::KeywordNotFound => "keyword_not_found",
+            ApiError::NotRoomModerator => "not_room_moderator",
+            ApiError::InvalidIdempotencyKey => "invalid_idempotency_key",
+            ApiError::IdempotencyKeyReused => "idempotency_key_reused",
+            ApiError::NotMessageSender => "not_message_sender",
+            ApiError::InvalidReaction => "invalid_reaction",
+            ApiError::InvalidMuteDuration => "invalid_mute_duration",
+            ApiError::MuteNotAllowed => "mute_not_allowed",
+            ApiError::InvalidUpload => "invalid_upload",
+            ApiError::UploadTooLarge => "upload_too_large",
+            ApiError::UploadTypeNotAllowed => "upload_type_not_allowed",
+            ApiError::UploadFailed => "upload_failed",
+            ApiError::NotStandby => "not_standby",
+            ApiError::InvalidPrimaryUrl => "invalid_primary_url",
+            ApiError::DeadlineExceeded => "deadline_exceeded",
+            ApiError::InvalidSearchQuery => "invalid_search_query",
+            ApiError::InvalidNotificationSound => "invalid_notification_sound",
+            ApiError::SelfDirectMessage => "self_direct_message",
+            ApiError::DirectRoomFixed => "direct_room_fixed",
+            ApiError::InvalidNotice => "invalid_notice",
+            ApiError::InvalidExternalId => "invalid_external_id",
+            ApiError::ExternalIdTaken => "external_id_taken",
+            ApiError::NotRoomMember => "not_room_member",
+            ApiError::InviteRequired => "invite_required",
+            ApiError::InviteNotFound => "invite_not_found",
+            ApiError::AlreadyRoomMember => "already_room_member",
+            ApiError::LegalHoldActive => "legal_hold_active",
+            ApiError::RoomArchiveFailed => "room_archive_failed",
+            ApiError::InvalidRoomSettings => "invalid_room_settings",
+            ApiError::RoomReadOnly => "room_read_only",
+            ApiError::UnsupportedManifestVersion => "unsupported_manifest_version",
+            ApiError::BannedFromRoom => "banned_from_room",
+            ApiError::BanNotFound => "ban_not_found",
+            ApiError::InvalidBanReason => "invalid_ban_reason",
+            ApiError::InvalidInviteOptions => "invalid_invite_options",
+            ApiError::InviteLinkExpired => "invite_link_expired",
+            ApiError::EditNeedsApproval => "edit_needs_approval",
+        }
+    }
+
+    pub fn retry_after_ms(self) -> Option<u64> {
+        match self {
+            ApiError::RateLimited { retry_after_ms } | ApiError::Muted { retry_after_ms } => {
+                Some(retry_after_ms)
+            }
+            _ => None,
+        }
+    }
+
+    // Error frame sent back over a WebSocket instead of an HTTP response
+    pub fn to_frame(self, lang: Lang) -> String {
+        ServerFrame::Error {
+            code: self.code(),
+            message: i18n::error_message(lang, self),
+            retry_after_ms: self.retry_after_ms(),
+        }
+        .to_text()
+    }
+
+    pub fn render(self, lang: Lang) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let Some(retry_after_ms) = self.retry_after_ms() {
+            // Retry-After only has whole-second resolution
+            response.insert_header((
+                actix_web::http::header::RETRY_AFTER,
+                retry_after_ms.div_ceil(1000).to_string(),
+            ));
+        }
+        response.json(ErrorBody {
+            code: self.code(),
+            message: i18n::error_message(lang, self),
+            retry_after_ms: self.retry_after_ms(),
+        })
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(i18n::error_message(Lang::default(), *self))
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidQuery
+            | ApiError::InvalidRoomId
+            | ApiError::SelfMerge
+            | ApiError::InvalidManifest
+            | ApiError::InvalidRedactPattern
+            | ApiError::EmptyMessage
+            | ApiError::InvalidTtl
+            | ApiError::InvalidRecoveryToken
+            | ApiError::EmptyPassword
+            | ApiError::InvalidFrame
+            | ApiError::InvalidHoldTarget
+            | ApiError::InvalidAttachment
+            | ApiError::InvalidKeyword
+            | ApiError::InvalidReaction
+            | ApiError::InvalidMuteDuration
+            | ApiError::InvalidUpload
+            | ApiError::InvalidPrimaryUrl
+            | ApiError::InvalidSearchQuery
+            | ApiError::InvalidNotificationSound
+            | ApiError::SelfDirectMessage
+            | ApiError::InvalidNotice
+            | ApiError::InvalidExternalId
+            | ApiError::InvalidRoomSettings
+            | ApiError::UnsupportedManifestVersion
+            | ApiError::InvalidBanReason
+            | ApiError::InvalidIdempotencyKey
+            | ApiError::InvalidInviteOptions => StatusCode::BAD_REQUEST,
+            ApiError::MessageTooLong | ApiError::UploadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UploadTypeNotAllowed => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::UserExists
+            | ApiError::UsernameConfusable
+            | ApiError::OwnerRoleFixed
+            | ApiError::TooManyKeywords
+            | ApiError::NotStandby
+            | ApiError::DirectRoomFixed
+            | ApiError::ExternalIdTaken
+            | ApiError::AlreadyRoomMember
+            | ApiError::LegalHoldActive
+            | ApiError::IdempotencyKeyReused => StatusCode::CONFLICT,
+            ApiError::InvalidCredentials
+            | ApiError::InvalidAuthToken
+            | ApiError::AuthTokenExpired
+            | ApiError::AuthRequired
+            | ApiError::InvalidBridgeSignature => StatusCode::UNAUTHORIZED,
+            ApiError::RoomNotFound
+            | ApiError::SourceRoomNotFound
+            | ApiError::TargetRoomNotFound
+            | ApiError::MessageNotFound
+            | ApiError::UserNotFound
+            | ApiError::ImportJobNotFound
+            | ApiError::DeadLetterNotFound
+            | ApiError::ParticipantNotFound
+            | ApiError::BookmarkNotFound
+            | ApiError::BotNotFound
+            | ApiError::AttachmentNotFound
+            | ApiError::KeywordNotFound
+            | ApiError::InviteNotFound
+            | ApiError::BanNotFound => StatusCode::NOT_FOUND,
+            ApiError::RecipientOffline => StatusCode::CONFLICT,
+            ApiError::AdminRequired
+            | ApiError::NotRoomManager
+            | ApiError::MutationNotAllowed
+            | ApiError::EditWindowExpired
+            | ApiError::NotAccountOwner
+            | ApiError::PresenceHidden
+            | ApiError::InvalidReplicationToken
+            | ApiError::AuthTokenMismatch
+            | ApiError::BotNotAllowed
+            | ApiError::ProbationRestricted
+            | ApiError::NotRoomModerator
+            | ApiError::NotMessageSender
+            | ApiError::Muted { .. }
+            | ApiError::MuteNotAllowed
+            | ApiError::NotRoomMember
+            | ApiError::InviteRequired
+            | ApiError::RoomReadOnly
+            | ApiError::BannedFromRoom
+            | ApiError::EditNeedsApproval => StatusCode::FORBIDDEN,
+            ApiError::ReadOnlyReplica => StatusCode::MISDIRECTED_REQUEST,
+            ApiError::InviteLinkExpired => StatusCode::GONE,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::UploadFailed | ApiError::DeadlineExceeded | ApiError::RoomArchiveFailed => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.render(Lang::default())
+    }
+}
+
+// Re-renders ApiError responses in the language picked from Accept-Language
+pub async fn localize_errors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let lang = i18n::accept_language(req.headers());
+
+    let res = next.call(req).await?;
+    let api_error = res
+        .response()
+        .error()
+        .and_then(|err| err.as_error::<ApiError>())
+        .copied();
+
+    Ok(match api_error {
+        Some(err) if lang != Lang::default() => {
+            let (req, original) = res.into_parts();
+            let mut localized = err.render(lang);
+            // Keep headers other middleware attached to the error response
+            for (name, value) in original.headers() {
+                if name != actix_web::http::header::CONTENT_TYPE
+                    && name != actix_web::http::header::CONTENT_LENGTH
+                {
+                    localized.headers_mut().insert(name.clone(), value.clone());
+                }
+            }
+            ServiceResponse::new(req, localized)
+        }
+        _ => res.map_into_boxed_body(),
+    })
+}
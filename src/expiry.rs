@@ -0,0 +1,104 @@
+use actix_web::rt;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::mutes;
+use crate::store;
+use crate::uploads;
+use crate::{now_millis, RoomEvent, SharedState};
+
+pub const MAX_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+const EXPIRY_TICK: Duration = Duration::from_secs(1);
+
+// Min-heap of (expires_at, room_id, message_id) for per-message TTLs
+#[derive(Default)]
+pub struct ExpiryQueue {
+    heap: Mutex<BinaryHeap<Reverse<(u64, Uuid, Uuid)>>>,
+}
+
+impl ExpiryQueue {
+    pub fn schedule(&self, expires_at: u64, room_id: Uuid, message_id: Uuid) {
+        self.heap
+            .lock()
+            .unwrap()
+            .push(Reverse((expires_at, room_id, message_id)));
+    }
+
+    fn pop_due(&self, now: u64) -> Vec<(Uuid, Uuid)> {
+        let mut heap = self.heap.lock().unwrap();
+        let mut due = Vec::new();
+        while let Some(Reverse((expires_at, room_id, message_id))) = heap.peek().copied() {
+            if expires_at > now {
+                break;
+            }
+            heap.pop();
+            due.push((room_id, message_id));
+        }
+        due
+    }
+}
+
+fn expire_due(state: &SharedState) {
+    let now = now_millis();
+    let due = state.expiry_queue.pop_due(now);
+    if due.is_empty() {
+        return;
+    }
+
+    let holds = state.legal_holds.lock().unwrap().clone();
+    let mut expired = Vec::new();
+    let mut removed = Vec::new();
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    for (room_id, message_id) in due {
+        // Merges move messages between rooms, so follow the redirect
+        let room_id = state.follow_redirects(room_id);
+        let Some(room) = rooms.get_mut(&room_id) else {
+            continue;
+        };
+        let Some(index) = room.message_log.iter().position(|msg| msg.id == message_id) else {
+            continue;
+        };
+        // Held messages disappear for clients but stay in storage as tombstones
+        if holds.covers(&room.message_log[index]) {
+            let msg = &mut room.message_log[index];
+            if msg.deleted_at.is_none() {
+                msg.deleted_at = Some(now);
+                msg.version.bump(&state.node_id);
+                store::messages_changed(state, [&*msg]);
+            }
+        } else {
+            let msg = room.message_log.remove(index);
+            uploads::discard(state, &msg.attachments);
+            removed.push(message_id);
+        }
+        expired.push((room_id, message_id));
+    }
+    drop(rooms);
+    store::messages_removed(state, removed);
+
+    for (room_id, message_id) in expired {
+        state.broadcast_event(
+            room_id,
+            RoomEvent(serde_json::json!({
+                "type": "message_expired",
+                "room_id": room_id,
+                "message_id": message_id,
+            })),
+        );
+    }
+}
+
+pub fn spawn_scheduler(state: Arc<SharedState>) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(EXPIRY_TICK);
+        loop {
+            interval.tick().await;
+            expire_due(&state);
+            mutes::lift_expired(&state);
+        }
+    });
+}
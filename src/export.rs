@@ -0,0 +1,313 @@
+use actix_web::http::StatusCode;
+use actix_web::rt;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::config::{ExportConfig, S3Bucket};
+use crate::outbound;
+use crate::{now_millis, ChatMessage, SharedState};
+
+const MANIFEST_NAME: &str = "manifest.json";
+const MAX_MANIFEST_BYTES: usize = 64 * 1024 * 1024;
+
+// One archive object. Archives are NDJSON in the same shape the
+// `?format=ndjson` room import accepts, so restoring a room is replaying
+// its entries in order against /rooms/{id}/import.
+#[derive(Serialize, Deserialize, Clone)]
+struct ManifestEntry {
+    key: String,
+    room_id: Uuid,
+    room_name: String,
+    first_seq: u64,
+    last_seq: u64,
+    messages: usize,
+    first_sent_at: u64,
+    last_sent_at: u64,
+    exported_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    #[serde(default)]
+    objects: Vec<ManifestEntry>,
+}
+
+struct Batch {
+    room_id: Uuid,
+    room_name: String,
+    messages: Vec<ChatMessage>,
+}
+
+// Civil date from unix millis (Howard Hinnant's days_from_civil, inverted)
+pub fn utc_datetime(millis: u64) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = (millis / 1000) as i64;
+    let days = secs.div_euclid(86_400);
+    let rem = secs.rem_euclid(86_400) as u32;
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day, rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Percent-encodes a key for the canonical URI, leaving `/` separators intact
+fn uri_encode(path: &str) -> String {
+    let mut out = String::new();
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+pub struct S3Client {
+    bucket: S3Bucket,
+    http: awc::Client,
+}
+
+impl S3Client {
+    pub fn new(bucket: S3Bucket) -> Self {
+        S3Client {
+            bucket,
+            http: outbound::client(),
+        }
+    }
+
+    fn host(&self) -> &str {
+        let rest = self
+            .bucket
+            .endpoint
+            .split_once("://")
+            .map_or(self.bucket.endpoint.as_str(), |(_, rest)| rest);
+        rest.split('/').next().unwrap_or(rest)
+    }
+
+    // Path-style addressing (endpoint/bucket/key) works with AWS and with
+    // self-hosted S3-compatible stores alike
+    fn request(&self, method: &str, key: &str, body: &[u8]) -> awc::ClientRequest {
+        let (y, mo, d, h, mi, s) = utc_datetime(now_millis());
+        let date = format!("{:04}{:02}{:02}", y, mo, d);
+        let amz_date = format!("{}T{:02}{:02}{:02}Z", date, h, mi, s);
+        let path = uri_encode(&format!("/{}/{}", self.bucket.name, key));
+        let payload_hash = hex(&Sha256::digest(body));
+        let host = self.host().to_string();
+
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method, path, host, payload_hash, amz_date, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.bucket.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut key_bytes = hmac(format!("AWS4{}", self.bucket.secret_key).as_bytes(), &date);
+        for part in [self.bucket.region.as_str(), "s3", "aws4_request"] {
+            key_bytes = hmac(&key_bytes, part);
+        }
+        let signature = hex(&hmac(&key_bytes, &string_to_sign));
+
+        let url = format!("{}{}", self.bucket.endpoint, path);
+        let method = actix_web::http::Method::from_bytes(method.as_bytes()).unwrap();
+        self.http
+            .request(method, url)
+            .insert_header(("host", host))
+            .insert_header(("x-amz-date", amz_date))
+            .insert_header(("x-amz-content-sha256", payload_hash))
+            .insert_header((
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+                    self.bucket.access_key, scope, signature
+                ),
+            ))
+    }
+
+    pub async fn put(&self, key: &str, content_type: &str, body: Vec<u8>) -> Result<(), String> {
+        let resp = self
+            .request("PUT", key, &body)
+            .content_type(content_type)
+            .send_body(body)
+            .await
+            .map_err(|err| err.to_string())?;
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("PUT {} returned {}", key, resp.status()))
+        }
+    }
+
+    pub async fn get(&self, key: &str, limit: usize) -> Result<Option<Vec<u8>>, String> {
+        let mut resp = self
+            .request("GET", key, b"")
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        match resp.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => resp
+                .body()
+                .limit(limit)
+                .await
+                .map(|body| Some(body.to_vec()))
+                .map_err(|err| err.to_string()),
+            status => Err(format!("GET {} returned {}", key, status)),
+        }
+    }
+
+    // Deleting a missing object succeeds, as S3 itself treats it
+    pub async fn delete(&self, key: &str) -> Result<(), String> {
+        let resp = self
+            .request("DELETE", key, b"")
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        if resp.status().is_success() || resp.status() == StatusCode::NOT_FOUND {
+            Ok(())
+        } else {
+            Err(format!("DELETE {} returned {}", key, resp.status()))
+        }
+    }
+}
+
+// Collects messages not yet archived, forgetting ids that retention or
+// expiry have since removed from the log
+fn pending_batches(
+    state: &SharedState,
+    config: &ExportConfig,
+    exported: &mut HashMap<Uuid, HashSet<Uuid>>,
+) -> Vec<Batch> {
+    let rooms = state.chat_rooms.lock().unwrap();
+    exported.retain(|room_id, _| rooms.contains_key(room_id));
+    rooms
+        .values()
+        .filter(|room| config.rooms.is_empty() || config.rooms.contains(&room.id))
+        .filter_map(|room| {
+            let done = exported.entry(room.id).or_default();
+            let live: HashSet<Uuid> = room.message_log.iter().map(|msg| msg.id).collect();
+            done.retain(|id| live.contains(id));
+            let messages: Vec<ChatMessage> = room
+                .message_log
+                .iter()
+                .filter(|msg| !done.contains(&msg.id))
+                .cloned()
+                .collect();
+            (!messages.is_empty()).then(|| Batch {
+                room_id: room.id,
+                room_name: room.name.clone(),
+                messages,
+            })
+        })
+        .collect()
+}
+
+fn to_ndjson(messages: &[ChatMessage]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for msg in messages {
+        serde_json::to_writer(&mut out, msg).unwrap();
+        out.push(b'\n');
+    }
+    out
+}
+
+pub fn spawn_exporter(state: Arc<SharedState>, config: ExportConfig) {
+    rt::spawn(async move {
+        let client = S3Client::new(config.s3.clone());
+        let manifest_key = format!("{}/{}", config.prefix, MANIFEST_NAME);
+        let mut manifest: Option<Manifest> = None;
+        let mut exported: HashMap<Uuid, HashSet<Uuid>> = HashMap::new();
+        let mut interval = rt::time::interval(config.interval);
+        loop {
+            interval.tick().await;
+            // Appending to the existing manifest keeps earlier archives
+            // restorable, so nothing is exported until it has been read
+            let manifest = match &mut manifest {
+                Some(manifest) => manifest,
+                None => match client.get(&manifest_key, MAX_MANIFEST_BYTES).await {
+                    Ok(raw) => manifest.insert(match raw {
+                        Some(raw) => match serde_json::from_slice(&raw) {
+                            Ok(existing) => existing,
+                            Err(err) => {
+                                log::error!("unreadable export manifest {}: {}", manifest_key, err);
+                                continue;
+                            }
+                        },
+                        None => Manifest::default(),
+                    }),
+                    Err(err) => {
+                        log::warn!("could not load export manifest {}: {}", manifest_key, err);
+                        continue;
+                    }
+                },
+            };
+            let batches = pending_batches(&state, &config, &mut exported);
+            let mut changed = false;
+            for batch in batches {
+                let now = now_millis();
+                let (y, mo, d, h, mi, s) = utc_datetime(now);
+                let first = batch.messages.first().unwrap();
+                let last = batch.messages.last().unwrap();
+                let key = format!(
+                    "{}/{:04}/{:02}/{:02}/{}/{:02}{:02}{:02}-{}-{}.ndjson",
+                    config.prefix, y, mo, d, batch.room_id, h, mi, s, first.seq, last.seq
+                );
+                let entry = ManifestEntry {
+                    key: key.clone(),
+                    room_id: batch.room_id,
+                    room_name: batch.room_name.clone(),
+                    first_seq: first.seq,
+                    last_seq: last.seq,
+                    messages: batch.messages.len(),
+                    first_sent_at: first.sent_at,
+                    last_sent_at: last.sent_at,
+                    exported_at: now,
+                };
+                // Unmarked messages are picked up again on the next run
+                if let Err(err) = client
+                    .put(&key, "application/x-ndjson", to_ndjson(&batch.messages))
+                    .await
+                {
+                    log::warn!("export of room {} failed: {}", batch.room_id, err);
+                    continue;
+                }
+                exported
+                    .entry(batch.room_id)
+                    .or_default()
+                    .extend(batch.messages.iter().map(|msg| msg.id));
+                manifest.objects.push(entry);
+                changed = true;
+            }
+            if changed {
+                let body = serde_json::to_vec_pretty(manifest).unwrap();
+                if let Err(err) = client.put(&manifest_key, "application/json", body).await {
+                    log::warn!("export manifest update failed: {}", err);
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,159 @@
+// External ids: an alias an integration assigns to a room so it can keep
+// using its own identifiers. PUT /rooms/{id}/external_id sets or replaces
+// the alias (null clears it) without touching the room itself, so members,
+// history and the room id stay as they are. GET /rooms/lookup?external_id=
+// and WS connects with `externalId` instead of `roomId` resolve the alias.
+//
+// The index lives beside `chat_rooms` rather than in it, so that rooms
+// offloaded to cold storage stay reachable by alias. It is rebuilt whenever
+// the room map is replaced wholesale (startup, replica snapshots) and
+// follows single rooms through `store::room_changed` and `room_removed`.
+// Lock order: after `chat_rooms`.
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::history;
+use crate::invites;
+use crate::roles::{self, Permission};
+use crate::store;
+use crate::{ChatRoom, SharedState};
+
+const MAX_EXTERNAL_ID_CHARS: usize = 128;
+
+#[derive(Default)]
+pub struct ExternalIds {
+    rooms: HashMap<String, Uuid>, // external id -> room_id
+}
+
+impl ExternalIds {
+    // Drops any alias the room no longer carries and records its current one,
+    // unless another room already claimed it
+    fn update(&mut self, room: &ChatRoom) {
+        self.remove(room.id);
+        if let Some(external_id) = &room.external_id {
+            self.rooms.entry(external_id.clone()).or_insert(room.id);
+        }
+    }
+
+    fn remove(&mut self, room_id: Uuid) {
+        self.rooms.retain(|_, id| *id != room_id);
+    }
+
+    fn get(&self, external_id: &str) -> Option<Uuid> {
+        self.rooms.get(external_id).copied()
+    }
+}
+
+pub fn room_changed(state: &SharedState, room: &ChatRoom) {
+    state.external_ids.lock().unwrap().update(room);
+}
+
+pub fn room_removed(state: &SharedState, room_id: Uuid) {
+    state.external_ids.lock().unwrap().remove(room_id);
+}
+
+// After the whole room map was replaced
+pub fn rebuild(state: &SharedState, rooms: &HashMap<Uuid, ChatRoom>) {
+    let mut index = state.external_ids.lock().unwrap();
+    index.rooms.clear();
+    for room in rooms.values() {
+        index.update(room);
+    }
+}
+
+// Kept to what survives a URL path or query string unescaped
+fn valid_external_id(external_id: &str) -> bool {
+    !external_id.is_empty()
+        && external_id.len() <= MAX_EXTERNAL_ID_CHARS
+        && external_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | ':'))
+}
+
+// The room an alias names, following merges and loading it if offloaded
+pub fn resolve(state: &SharedState, external_id: &str) -> Result<Uuid, ApiError> {
+    let room_id = state
+        .external_ids
+        .lock()
+        .unwrap()
+        .get(external_id)
+        .ok_or(ApiError::RoomNotFound)?;
+    Ok(state.resolve_room_id(room_id))
+}
+
+#[derive(Deserialize)]
+pub struct ExternalIdUpdate {
+    external_id: Option<String>,
+}
+
+pub async fn set_external_id(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<ExternalIdUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let actor = user.username;
+    let ExternalIdUpdate { external_id } = form.into_inner();
+    if external_id
+        .as_deref()
+        .is_some_and(|external_id| !valid_external_id(external_id))
+    {
+        return Err(ApiError::InvalidExternalId);
+    }
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    roles::check(&state, room, &actor, Permission::ChangeSettings)?;
+    // Claims are settled under the rooms lock, so the check can't go stale
+    let holder = external_id
+        .as_deref()
+        .and_then(|id| state.external_ids.lock().unwrap().get(id));
+    if holder.is_some_and(|holder| holder != room_id) {
+        return Err(ApiError::ExternalIdTaken);
+    }
+    let previous = std::mem::replace(&mut room.external_id, external_id.clone());
+    store::room_changed(&state, room);
+    drop(rooms);
+
+    if previous != external_id {
+        audit::record(
+            &state,
+            &actor,
+            "set_external_id",
+            &room_id.to_string(),
+            format!(
+                "{} -> {}",
+                previous.as_deref().unwrap_or("none"),
+                external_id.as_deref().unwrap_or("none")
+            ),
+        );
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "external_id": external_id,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct LookupQuery {
+    external_id: String,
+}
+
+pub async fn lookup_room(
+    state: web::Data<Arc<SharedState>>,
+    query: web::Query<LookupQuery>,
+    user: Option<UserContext>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = resolve(&state, &query.external_id)?;
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    invites::check_reader(&state, room, user.as_ref())?;
+    let view = history::reader_view(&state, room, user.as_ref());
+    Ok(HttpResponse::Ok().json(view))
+}
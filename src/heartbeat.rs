@@ -0,0 +1,49 @@
+// WS heartbeats. A client that vanishes without closing, a laptop lid shut
+// or a dropped mobile link, leaves its TCP connection open for a long time,
+// and until then the session counts as online and is sent every room event.
+// So each session pings its client every HEARTBEAT_INTERVAL_SECS (default
+// 20) and is stopped once HEARTBEAT_MAX_MISSED (default 3) pings in a row go
+// without a pong. Zero for the interval turns pinging off.
+use actix::prelude::*;
+use actix_web_actors::ws;
+use std::time::Duration;
+
+use crate::ClientSession;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Heartbeat {
+    pub interval: Option<Duration>,
+    pub max_missed: u32,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: Some(Duration::from_secs(20)),
+            max_missed: 3,
+        }
+    }
+}
+
+// Call from the session's `started`
+pub fn start(session: &ClientSession, ctx: &mut ws::WebsocketContext<ClientSession>) {
+    let Some(interval) = session.state.heartbeat.interval else {
+        return;
+    };
+    let max_missed = session.state.heartbeat.max_missed;
+    ctx.run_interval(interval, move |act, ctx| {
+        if act.unanswered_pings >= max_missed {
+            log::info!(
+                "closing session {} of {} after {} pings without a pong",
+                act.id,
+                act.username,
+                act.unanswered_pings
+            );
+            ctx.close(Some(ws::CloseCode::Away.into()));
+            ctx.stop();
+            return;
+        }
+        act.unanswered_pings += 1;
+        ctx.ping(b"");
+    });
+}
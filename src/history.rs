@@ -0,0 +1,265 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::attachments::Attachment;
+use crate::auth::UserContext;
+use crate::bridges::BridgeOrigin;
+use crate::error::ApiError;
+use crate::invites;
+use crate::messages::Priority;
+use crate::presence;
+use crate::roomrefs::{self, RoomRef};
+use crate::threads;
+use crate::visibility;
+use crate::{ChatMessage, ChatRoom, MessageKind, SharedState};
+
+const DEFAULT_CONTEXT: usize = 10;
+const MAX_CONTEXT: usize = 100;
+const DEFAULT_PAGE: usize = 50;
+const MAX_PAGE: usize = 200;
+
+#[derive(Serialize)]
+pub struct ReactionSummary<'a> {
+    emoji: &'a str,
+    count: usize,
+    users: Vec<&'a str>,
+}
+
+// A message as clients should see it now: current content after edits,
+// a tombstone in place of deleted content, and reactions aggregated per emoji
+#[derive(Serialize)]
+pub struct HistoryEntry<'a> {
+    id: Uuid,
+    room_id: Uuid,
+    seq: u64,
+    sender: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<&'a str>,
+    sent_at: u64,
+    kind: MessageKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    edited_at: Option<u64>,
+    deleted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<u64>,
+    reactions: Vec<ReactionSummary<'a>>,
+    // Expired ones keep their name, type and size as a placeholder
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    attachments: &'a [Attachment],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    origin_room_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    imported_from: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bridged_from: Option<&'a BridgeOrigin>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    #[serde(skip_serializing_if = "Priority::is_normal")]
+    priority: Priority,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_message_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    mentions: &'a [String],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    room_refs: &'a [RoomRef],
+    // Only on thread roots, and only where the whole log was at hand to count
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_count: Option<usize>,
+}
+
+impl<'a> HistoryEntry<'a> {
+    pub fn project(msg: &'a ChatMessage) -> Self {
+        let deleted = msg.deleted_at.is_some();
+        HistoryEntry {
+            id: msg.id,
+            room_id: msg.room_id,
+            seq: msg.seq,
+            sender: &msg.sender,
+            content: (!deleted).then_some(msg.content.as_str()),
+            sent_at: msg.sent_at,
+            kind: msg.kind,
+            edited_at: msg.edited_at,
+            deleted,
+            deleted_at: msg.deleted_at,
+            reactions: if deleted {
+                Vec::new()
+            } else {
+                msg.reactions
+                    .iter()
+                    .filter(|(_, users)| !users.is_empty())
+                    .map(|(emoji, users)| ReactionSummary {
+                        emoji,
+                        count: users.len(),
+                        users: users.iter().map(String::as_str).collect(),
+                    })
+                    .collect()
+            },
+            attachments: if deleted { &[] } else { &msg.attachments },
+            origin_room_id: msg.origin_room_id,
+            imported_from: msg.imported_from.as_deref(),
+            bridged_from: msg.bridged_from.as_ref(),
+            expires_at: msg.expires_at,
+            priority: msg.priority,
+            parent_message_id: msg.parent_message_id,
+            mentions: if deleted { &[] } else { &msg.mentions },
+            room_refs: if deleted { &[] } else { &msg.room_refs },
+            reply_count: None,
+        }
+    }
+
+    pub fn with_reply_count(mut self, replies: usize) -> Self {
+        self.reply_count = (replies > 0).then_some(replies);
+        self
+    }
+}
+
+// `replies` comes from `threads::reply_counts` over the room's whole log
+pub fn project<'a>(
+    messages: &'a [ChatMessage],
+    replies: &HashMap<Uuid, usize>,
+) -> Vec<HistoryEntry<'a>> {
+    messages
+        .iter()
+        .map(|msg| {
+            let count = replies.get(&msg.id).copied().unwrap_or(0);
+            HistoryEntry::project(msg).with_reply_count(count)
+        })
+        .collect()
+}
+
+// Room JSON for REST responses, with the stored log replaced by its projection
+pub fn room_view(room: &ChatRoom) -> serde_json::Value {
+    view_with_log(room, &room.message_log)
+}
+
+// `room_view` cut down to the history the reader may see
+pub fn reader_view(
+    state: &SharedState,
+    room: &ChatRoom,
+    user: Option<&UserContext>,
+) -> serde_json::Value {
+    let mut view = view_with_log(room, visibility::visible_log(state, room, username(user)));
+    view["last_seen"] = serde_json::json!(presence::members_last_seen(state, room));
+    view
+}
+
+fn view_with_log(room: &ChatRoom, log: &[ChatMessage]) -> serde_json::Value {
+    let mut view = serde_json::to_value(room).unwrap();
+    // Moderation state is for staff, see `bans::list_bans`
+    if let Some(fields) = view.as_object_mut() {
+        fields.remove("bans");
+        fields.remove("mutes");
+    }
+    let replies = threads::reply_counts(log);
+    view["message_log"] = serde_json::to_value(project(log, &replies)).unwrap();
+    view["slug"] = roomrefs::slug(&room.name).into();
+    view
+}
+
+pub fn username(user: Option<&UserContext>) -> Option<&str> {
+    user.map(|user| user.username.as_str())
+}
+
+#[derive(Deserialize)]
+pub struct ContextQuery {
+    #[serde(default)]
+    before: Option<usize>,
+    #[serde(default)]
+    after: Option<usize>,
+}
+
+// The conversation around one message, for jumping to search hits or bookmarks
+pub async fn message_context(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, Uuid)>,
+    query: web::Query<ContextQuery>,
+    user: Option<UserContext>,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, message_id) = path.into_inner();
+    let room_id = state.resolve_room_id(room_id);
+    let before = query.before.unwrap_or(DEFAULT_CONTEXT).min(MAX_CONTEXT);
+    let after = query.after.unwrap_or(DEFAULT_CONTEXT).min(MAX_CONTEXT);
+
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    invites::check_reader(&state, room, user.as_ref())?;
+    let log = visibility::visible_log(&state, room, username(user.as_ref()));
+    let index = log
+        .iter()
+        .position(|msg| msg.id == message_id)
+        .ok_or(ApiError::MessageNotFound)?;
+    let start = index.saturating_sub(before);
+    let end = (index + 1 + after).min(log.len());
+    let replies = threads::reply_counts(log);
+    let count = replies.get(&message_id).copied().unwrap_or(0);
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "message": HistoryEntry::project(&log[index]).with_reply_count(count),
+        "before": project(&log[start..index], &replies),
+        "after": project(&log[index + 1..end], &replies),
+        "has_more_before": start > 0,
+        "has_more_after": end < log.len(),
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct PageQuery {
+    #[serde(default)]
+    limit: Option<usize>,
+    // Only messages with a lower seq; the `next_before` of the previous page
+    #[serde(default)]
+    before: Option<u64>,
+}
+
+// One page of the room's log, newest first, for back-filling from the end
+pub async fn message_page(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    query: web::Query<PageQuery>,
+    user: Option<UserContext>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    invites::check_reader(&state, room, user.as_ref())?;
+    let page = page(
+        &state,
+        room,
+        username(user.as_ref()),
+        query.before,
+        query.limit,
+    );
+    Ok(HttpResponse::Ok().json(page))
+}
+
+// Also answers `history_request` frames, for clients that only hold a socket
+pub fn page(
+    state: &SharedState,
+    room: &ChatRoom,
+    username: Option<&str>,
+    before: Option<u64>,
+    limit: Option<usize>,
+) -> serde_json::Value {
+    let limit = limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+    let log = visibility::visible_log(state, room, username);
+    // The log is kept in seq order
+    let end = before.map_or(log.len(), |before| {
+        log.partition_point(|msg| msg.seq < before)
+    });
+    let start = end.saturating_sub(limit);
+    let replies = threads::reply_counts(log);
+    let mut messages = project(&log[start..end], &replies);
+    messages.reverse();
+    let has_more = start > 0;
+
+    serde_json::json!({
+        "room_id": room.id,
+        "messages": messages,
+        "has_more": has_more,
+        "next_before": has_more.then(|| log[start].seq),
+    })
+}
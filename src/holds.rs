@@ -0,0 +1,99 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::{ChatMessage, SharedState};
+
+// Scopes under legal hold. Held messages are never removed from storage:
+// retention skips them and expiry only tombstones them.
+#[derive(Serialize, Clone, Default)]
+pub struct LegalHolds {
+    rooms: HashSet<Uuid>,
+    users: HashSet<String>,
+}
+
+impl LegalHolds {
+    pub fn covers(&self, msg: &ChatMessage) -> bool {
+        self.rooms.contains(&msg.room_id) || self.users.contains(&msg.sender)
+    }
+
+    pub fn covers_room(&self, room_id: Uuid) -> bool {
+        self.rooms.contains(&room_id)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct HoldUpdate {
+    #[serde(default)]
+    room_id: Option<Uuid>,
+    #[serde(default)]
+    username: Option<String>,
+    held: bool,
+}
+
+pub async fn list_legal_holds(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let holds = state.legal_holds.lock().unwrap().clone();
+    Ok(HttpResponse::Ok().json(holds))
+}
+
+pub async fn set_legal_hold(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+    form: web::Json<HoldUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    // Exactly one scope per request keeps the audit trail unambiguous
+    let target = match (form.room_id, form.username.as_deref()) {
+        (Some(room_id), None) => {
+            let room_id = state.resolve_room_id(room_id);
+            if !state.chat_rooms.lock().unwrap().contains_key(&room_id) {
+                return Err(ApiError::RoomNotFound);
+            }
+            let mut holds = state.legal_holds.lock().unwrap();
+            if form.held {
+                holds.rooms.insert(room_id);
+            } else {
+                holds.rooms.remove(&room_id);
+            }
+            format!("room:{}", room_id)
+        }
+        (None, Some(username)) => {
+            let username = state.canonical_username(username);
+            let mut holds = state.legal_holds.lock().unwrap();
+            if form.held {
+                holds.users.insert(username.clone());
+            } else {
+                holds.users.remove(&username);
+            }
+            format!("user:{}", username)
+        }
+        _ => return Err(ApiError::InvalidHoldTarget),
+    };
+
+    audit::record(
+        &state,
+        &user.username,
+        if form.held {
+            "legal_hold_placed"
+        } else {
+            "legal_hold_released"
+        },
+        &target,
+        String::new(),
+    );
+    let holds = state.legal_holds.lock().unwrap().clone();
+    Ok(HttpResponse::Ok().json(holds))
+}
@@ -0,0 +1,265 @@
+use actix_web::http::header;
+use actix_web::HttpRequest;
+
+use crate::error::ApiError;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Lang {
+    #[default]
+    En,
+    Uk,
+}
+
+impl Lang {
+    fn from_tag(tag: &str) -> Option<Lang> {
+        let primary = tag.split('-').next()?.trim().to_ascii_lowercase();
+        match primary.as_str() {
+            "en" => Some(Lang::En),
+            "uk" => Some(Lang::Uk),
+            _ => None,
+        }
+    }
+}
+
+// Picks the supported language with the highest q-value, e.g. "uk-UA,uk;q=0.9,en;q=0.8"
+pub fn negotiate(accept_language: &str) -> Lang {
+    let mut best: Option<(Lang, f32)> = None;
+    for entry in accept_language.split(',') {
+        let mut parts = entry.split(';');
+        let tag = parts.next().unwrap_or("").trim();
+        let quality = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if quality <= 0.0 {
+            continue;
+        }
+        if let Some(lang) = Lang::from_tag(tag) {
+            if best.is_none_or(|(_, q)| quality > q) {
+                best = Some((lang, quality));
+            }
+        }
+    }
+    best.map(|(lang, _)| lang).unwrap_or_default()
+}
+
+pub fn request_lang(req: &HttpRequest) -> Lang {
+    accept_language(req.headers())
+}
+
+pub fn accept_language(headers: &header::HeaderMap) -> Lang {
+    headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .map(negotiate)
+        .unwrap_or_default()
+}
+
+pub fn error_message(lang: Lang, err: ApiError) -> &'static str {
+    match lang {
+        Lang::En => en(err),
+        Lang::Uk => uk(err),
+    }
+}
+
+fn en(err: ApiError) -> &'static str {
+    match err {
+        ApiError::InvalidQuery => "Invalid query",
+        ApiError::InvalidRoomId => "Invalid roomId",
+        ApiError::UserExists => "User already exists",
+        ApiError::InvalidCredentials => "Invalid credentials",
+        ApiError::RoomNotFound => "Room not found",
+        ApiError::SourceRoomNotFound => "Source room not found",
+        ApiError::TargetRoomNotFound => "Target room not found",
+        ApiError::SelfMerge => "Cannot merge a room into itself",
+        ApiError::AdminRequired => "Admin access required",
+        ApiError::NotRoomManager => "Only the room owner or an admin can do that",
+        ApiError::MessageNotFound => "Message not found",
+        ApiError::MutationNotAllowed => "This room does not allow that action on this message",
+        ApiError::EditWindowExpired => "The edit window for this message has passed",
+        ApiError::UserNotFound => "User not found",
+        ApiError::NotAccountOwner => "You can only change your own account",
+        ApiError::PresenceHidden => "This user does not share their presence",
+        ApiError::RateLimited { .. } => "Too many requests, slow down",
+        ApiError::Muted { .. } => "You are muted in this room",
+        ApiError::InvalidManifest => "The manifest could not be parsed",
+        ApiError::InvalidRedactPattern => "Provide a valid regex pattern or a list of strings",
+        ApiError::EmptyMessage => "Message must not be empty",
+        ApiError::MessageTooLong => "Message is too long",
+        ApiError::ImportJobNotFound => "Import job not found",
+        ApiError::InvalidTtl => "ttl_seconds must be between 1 second and 7 days",
+        ApiError::DeadLetterNotFound => "Dead letter not found",
+        ApiError::RecipientOffline => "The recipient has no live session to deliver to",
+        ApiError::InvalidRecoveryToken => "The recovery token is invalid or has expired",
+        ApiError::EmptyPassword => "Password must not be empty",
+        ApiError::InvalidFrame => "Unrecognised or malformed frame",
+        ApiError::UsernameConfusable => "Username is too similar to an existing account",
+        ApiError::ParticipantNotFound => "User is not a participant of this room",
+        ApiError::OwnerRoleFixed => "The room owner's role cannot be changed",
+        ApiError::BookmarkNotFound => "Bookmark not found",
+        ApiError::InvalidHoldTarget => "Specify exactly one of room_id or username",
+        ApiError::InvalidReplicationToken => "Missing or invalid replication token",
+        ApiError::ReadOnlyReplica => {
+            "This replica only serves reads; send the request to the primary"
+        }
+        ApiError::InvalidAuthToken => "Invalid login token",
+        ApiError::AuthTokenExpired => "Login token has expired",
+        ApiError::AuthTokenMismatch => "Token belongs to a different user",
+        ApiError::BotNotFound => "Bot not found",
+        ApiError::BotNotAllowed => "This room's owner has not allowed that bot",
+        ApiError::InvalidAttachment => {
+            "Attachments need a name and an http(s) url, or an upload of yours to this room, at most 10 per message"
+        }
+        ApiError::ProbationRestricted => {
+            "New accounts cannot do this until their probation period ends"
+        }
+        ApiError::InvalidBridgeSignature => "Bridge request signature is missing or invalid",
+        ApiError::AttachmentNotFound => "Attachment not found or no longer available",
+        ApiError::AuthRequired => "Log in first: this endpoint needs a bearer token",
+        ApiError::InvalidKeyword => "Keywords must be 2 to 64 characters long",
+        ApiError::TooManyKeywords => "This account already watches the maximum number of keywords",
+        ApiError::KeywordNotFound => "Keyword not found",
+        ApiError::NotRoomModerator => "Only room moderators can do that",
+        ApiError::InvalidIdempotencyKey => "Idempotency key must be 1 to 255 characters",
+        ApiError::IdempotencyKeyReused => {
+            "This idempotency key was already used for a different request"
+        }
+        ApiError::NotMessageSender => "Only the sender can change this message",
+        ApiError::InvalidReaction => "Reactions must be a single short emoji",
+        ApiError::InvalidMuteDuration => "Mutes must last between 1 second and 30 days",
+        ApiError::MuteNotAllowed => "Room staff can't be muted",
+        ApiError::InvalidUpload => "Uploads must be multipart/form-data with one file",
+        ApiError::UploadTooLarge => "The file is too large",
+        ApiError::UploadTypeNotAllowed => "This file type is not accepted",
+        ApiError::UploadFailed => "The file could not be stored, try again later",
+        ApiError::NotStandby => "This instance is not a standby that can be promoted",
+        ApiError::InvalidPrimaryUrl => "The new primary must be given as an http(s) url",
+        ApiError::DeadlineExceeded => "The server took too long to handle this request; try again",
+        ApiError::InvalidSearchQuery => "Search for between 1 and 16 words",
+        ApiError::InvalidNotificationSound => "Sound names are up to 64 letters, digits, dots, dashes or underscores",
+        ApiError::SelfDirectMessage => "You can't start a direct conversation with yourself",
+        ApiError::DirectRoomFixed => "Direct conversations always have exactly two participants",
+        ApiError::InvalidNotice => "Notices need between 1 and 2000 characters of text",
+        ApiError::InvalidExternalId => "External ids are up to 128 letters, digits, dots, dashes, colons or underscores",
+        ApiError::ExternalIdTaken => "Another room already uses that external id",
+        ApiError::NotRoomMember => "Only members of this private room can do that",
+        ApiError::InviteRequired => "This room is private; ask a moderator for an invite",
+        ApiError::InviteNotFound => "No open invite to this room",
+        ApiError::AlreadyRoomMember => "That user is already in the room",
+        ApiError::LegalHoldActive => "This room is under legal hold; it can only be deleted with archive=true",
+        ApiError::RoomArchiveFailed => "The room could not be archived, so it was not deleted",
+        ApiError::InvalidRoomSettings => "Room name must be 1-100 characters, the description at most 500 and last_n at most 1000",
+        ApiError::RoomReadOnly => "This room is read-only; only moderators can post",
+        ApiError::UnsupportedManifestVersion => "This manifest version is not supported",
+        ApiError::BannedFromRoom => "You are banned from this room",
+        ApiError::BanNotFound => "That user is not banned from this room",
+        ApiError::InvalidBanReason => "The reason must be at most 500 characters",
+        ApiError::InvalidInviteOptions => "Expiry and usage limits apply to invite links only and must be positive",
+        ApiError::InviteLinkExpired => "This invite link has expired or been used up",
+        ApiError::EditNeedsApproval => {
+            "Links in this room need a moderator's approval, so send them in a new message"
+        }
+    }
+}
+
+fn uk(err: ApiError) -> &'static str {
+    match err {
+        ApiError::InvalidQuery => "Некоректний запит",
+        ApiError::InvalidRoomId => "Некоректний roomId",
+        ApiError::UserExists => "Користувач уже існує",
+        ApiError::InvalidCredentials => "Невірні облікові дані",
+        ApiError::RoomNotFound => "Кімнату не знайдено",
+        ApiError::SourceRoomNotFound => "Вихідну кімнату не знайдено",
+        ApiError::TargetRoomNotFound => "Цільову кімнату не знайдено",
+        ApiError::SelfMerge => "Неможливо об'єднати кімнату саму з собою",
+        ApiError::AdminRequired => "Потрібні права адміністратора",
+        ApiError::NotRoomManager => "Це може зробити лише власник кімнати або адміністратор",
+        ApiError::MessageNotFound => "Повідомлення не знайдено",
+        ApiError::MutationNotAllowed => "Ця кімната не дозволяє такої дії з цим повідомленням",
+        ApiError::EditWindowExpired => "Час на редагування цього повідомлення минув",
+        ApiError::UserNotFound => "Користувача не знайдено",
+        ApiError::NotAccountOwner => "Можна змінювати лише власний обліковий запис",
+        ApiError::PresenceHidden => "Цей користувач не ділиться своєю присутністю",
+        ApiError::RateLimited { .. } => "Забагато запитів, зачекайте",
+        ApiError::Muted { .. } => "Вас заглушено в цій кімнаті",
+        ApiError::InvalidManifest => "Не вдалося розібрати маніфест",
+        ApiError::InvalidRedactPattern => "Вкажіть коректний regex-шаблон або список рядків",
+        ApiError::EmptyMessage => "Повідомлення не може бути порожнім",
+        ApiError::MessageTooLong => "Повідомлення задовге",
+        ApiError::ImportJobNotFound => "Завдання імпорту не знайдено",
+        ApiError::InvalidTtl => "ttl_seconds має бути від 1 секунди до 7 днів",
+        ApiError::DeadLetterNotFound => "Недоставлене повідомлення не знайдено",
+        ApiError::RecipientOffline => "Одержувач не має активної сесії для доставки",
+        ApiError::InvalidRecoveryToken => "Токен відновлення недійсний або прострочений",
+        ApiError::EmptyPassword => "Пароль не може бути порожнім",
+        ApiError::InvalidFrame => "Нерозпізнаний або пошкоджений кадр",
+        ApiError::UsernameConfusable => "Ім'я користувача надто схоже на наявний обліковий запис",
+        ApiError::ParticipantNotFound => "Користувач не є учасником цієї кімнати",
+        ApiError::OwnerRoleFixed => "Роль власника кімнати змінити не можна",
+        ApiError::BookmarkNotFound => "Закладку не знайдено",
+        ApiError::InvalidHoldTarget => "Вкажіть рівно одне: room_id або username",
+        ApiError::InvalidReplicationToken => "Токен реплікації відсутній або недійсний",
+        ApiError::ReadOnlyReplica => {
+            "Ця репліка обслуговує лише читання; надішліть запит на основний сервер"
+        }
+        ApiError::InvalidAuthToken => "Недійсний токен входу",
+        ApiError::AuthTokenExpired => "Термін дії токена входу минув",
+        ApiError::AuthTokenMismatch => "Токен належить іншому користувачеві",
+        ApiError::BotNotFound => "Бота не знайдено",
+        ApiError::BotNotAllowed => "Власник кімнати не дозволив цього бота",
+        ApiError::InvalidAttachment => {
+            "Вкладення потребують назви та http(s)-адреси або вашого файлу, завантаженого в цю кімнату, не більше 10 на повідомлення"
+        }
+        ApiError::ProbationRestricted => {
+            "Нові облікові записи не можуть цього робити до завершення випробувального терміну"
+        }
+        ApiError::InvalidBridgeSignature => "Підпис запиту мосту відсутній або недійсний",
+        ApiError::AttachmentNotFound => "Вкладення не знайдено або воно більше недоступне",
+        ApiError::AuthRequired => "Спершу увійдіть: цей запит потребує токена доступу",
+        ApiError::InvalidKeyword => "Ключові слова мають містити від 2 до 64 символів",
+        ApiError::TooManyKeywords => {
+            "Цей обліковий запис уже відстежує максимальну кількість ключових слів"
+        }
+        ApiError::KeywordNotFound => "Ключове слово не знайдено",
+        ApiError::NotRoomModerator => "Це можуть зробити лише модератори кімнати",
+        ApiError::InvalidIdempotencyKey => "Ключ ідемпотентності має містити від 1 до 255 символів",
+        ApiError::IdempotencyKeyReused => {
+            "Цей ключ ідемпотентності вже використано для іншого запиту"
+        }
+        ApiError::NotMessageSender => "Змінити це повідомлення може лише його автор",
+        ApiError::InvalidReaction => "Реакція має бути одним коротким емодзі",
+        ApiError::InvalidMuteDuration => "Заглушення має тривати від 1 секунди до 30 днів",
+        ApiError::MuteNotAllowed => "Персонал кімнати не можна заглушити",
+        ApiError::InvalidUpload => "Завантаження має бути multipart/form-data з одним файлом",
+        ApiError::UploadTooLarge => "Файл завеликий",
+        ApiError::UploadTypeNotAllowed => "Цей тип файлу не приймається",
+        ApiError::UploadFailed => "Не вдалося зберегти файл, спробуйте пізніше",
+        ApiError::NotStandby => "Цей екземпляр не є резервним і не може стати основним",
+        ApiError::InvalidPrimaryUrl => "Новий основний сервер слід вказати як http(s)-адресу",
+        ApiError::DeadlineExceeded => "Сервер надто довго обробляв запит; спробуйте ще раз",
+        ApiError::InvalidSearchQuery => "Шукайте від 1 до 16 слів",
+        ApiError::InvalidNotificationSound => "Назва звуку: до 64 латинських літер, цифр, крапок, дефісів чи підкреслень",
+        ApiError::SelfDirectMessage => "Не можна почати особисту розмову із самим собою",
+        ApiError::DirectRoomFixed => "Особисті розмови завжди мають рівно двох учасників",
+        ApiError::InvalidNotice => "Сповіщення має містити від 1 до 2000 символів тексту",
+        ApiError::InvalidExternalId => "Зовнішній ідентифікатор може містити до 128 літер, цифр, крапок, дефісів, двокрапок або підкреслень",
+        ApiError::ExternalIdTaken => "Цей зовнішній ідентифікатор уже використовує інша кімната",
+        ApiError::NotRoomMember => "Це можуть лише учасники цієї приватної кімнати",
+        ApiError::InviteRequired => "Ця кімната приватна; попросіть модератора про запрошення",
+        ApiError::InviteNotFound => "Немає дійсного запрошення до цієї кімнати",
+        ApiError::AlreadyRoomMember => "Цей користувач уже є в кімнаті",
+        ApiError::LegalHoldActive => "Ця кімната під юридичним утриманням; її можна видалити лише з archive=true",
+        ApiError::RoomArchiveFailed => "Не вдалося заархівувати кімнату, тому її не видалено",
+        ApiError::InvalidRoomSettings => "Назва кімнати має містити 1-100 символів, опис — не більше 500, а last_n — не більше 1000",
+        ApiError::RoomReadOnly => "Ця кімната лише для читання; писати можуть тільки модератори",
+        ApiError::UnsupportedManifestVersion => "Ця версія маніфесту не підтримується",
+        ApiError::BannedFromRoom => "Вас заблоковано в цій кімнаті",
+        ApiError::BanNotFound => "Цього користувача не заблоковано в цій кімнаті",
+        ApiError::InvalidBanReason => "Причина має містити не більше 500 символів",
+        ApiError::InvalidInviteOptions => "Термін дії та ліміт використань задаються лише для посилань-запрошень і мають бути додатними",
+        ApiError::InviteLinkExpired => "Термін дії цього посилання-запрошення минув або його вичерпано",
+        ApiError::EditNeedsApproval => {
+            "Посилання в цій кімнаті потребують схвалення модератора, тож надішліть їх новим повідомленням"
+        }
+    }
+}
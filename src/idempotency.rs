@@ -0,0 +1,86 @@
+// Dedup for room creation. A client retrying a create sends the same
+// Idempotency-Key header and gets the room from the first attempt back instead
+// of a second one. Creates without a key are still deduplicated for a few
+// seconds on creator and name, which catches double-clicks and burst retries.
+use actix_web::HttpRequest;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::now_millis;
+
+pub const HEADER: &str = "idempotency-key";
+const MAX_KEY_CHARS: usize = 255;
+const KEY_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+const BURST_WINDOW_MS: u64 = 10_000;
+
+struct Created {
+    room_id: Uuid,
+    name: String,
+    at: u64,
+}
+
+#[derive(Default)]
+pub struct RecentCreations {
+    by_key: HashMap<(String, String), Created>, // (creator, key)
+    by_name: HashMap<(String, String), Created>, // (creator, name), within the burst window
+}
+
+pub fn key(req: &HttpRequest) -> Result<Option<String>, ApiError> {
+    let Some(value) = req.headers().get(HEADER) else {
+        return Ok(None);
+    };
+    let key = value
+        .to_str()
+        .map_err(|_| ApiError::InvalidIdempotencyKey)?
+        .trim();
+    if key.is_empty() || key.chars().count() > MAX_KEY_CHARS {
+        return Err(ApiError::InvalidIdempotencyKey);
+    }
+    Ok(Some(key.to_string()))
+}
+
+impl RecentCreations {
+    fn prune(&mut self, now: u64) {
+        self.by_key
+            .retain(|_, created| now.saturating_sub(created.at) < KEY_TTL_MS);
+        self.by_name
+            .retain(|_, created| now.saturating_sub(created.at) < BURST_WINDOW_MS);
+    }
+
+    // The room an earlier attempt of this create made, if any. Reusing a key
+    // for a room with another name is refused rather than answered with the
+    // wrong room.
+    pub fn earlier(
+        &mut self,
+        creator: &str,
+        key: Option<&str>,
+        name: &str,
+    ) -> Result<Option<Uuid>, ApiError> {
+        self.prune(now_millis());
+        if let Some(key) = key {
+            return match self.by_key.get(&(creator.to_string(), key.to_string())) {
+                Some(created) if created.name != name => Err(ApiError::IdempotencyKeyReused),
+                Some(created) => Ok(Some(created.room_id)),
+                None => Ok(None),
+            };
+        }
+        Ok(self
+            .by_name
+            .get(&(creator.to_string(), name.to_string()))
+            .map(|created| created.room_id))
+    }
+
+    pub fn record(&mut self, creator: &str, key: Option<String>, name: &str, room_id: Uuid) {
+        let created = || Created {
+            room_id,
+            name: name.to_string(),
+            at: now_millis(),
+        };
+        if let Some(key) = key {
+            self.by_key.insert((creator.to_string(), key), created());
+        }
+        self.by_name
+            .insert((creator.to_string(), name.to_string()), created());
+    }
+}
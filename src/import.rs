@@ -0,0 +1,291 @@
+use actix_web::{rt, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::cluster;
+use crate::error::ApiError;
+use crate::messages::Priority;
+use crate::roles::{self, Permission};
+use crate::store;
+use crate::versions::VersionVector;
+use crate::{audit, ChatMessage, MessageKind, SharedState};
+
+pub const MAX_IMPORT_BYTES: usize = 64 * 1024 * 1024;
+
+// Messages applied per lock acquisition, so live traffic isn't starved during big imports
+const IMPORT_CHUNK_SIZE: usize = 500;
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    Slack,
+    Ndjson,
+}
+
+impl ImportFormat {
+    fn marker(self) -> &'static str {
+        match self {
+            ImportFormat::Slack => "slack",
+            ImportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    format: ImportFormat,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportState {
+    Running,
+    Completed,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ImportJob {
+    id: Uuid,
+    room_id: Uuid,
+    state: ImportState,
+    total: usize,
+    imported: usize,
+}
+
+#[derive(Serialize)]
+struct ImportLineError {
+    line: usize,
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct SlackMessage {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    subtype: Option<String>,
+    user: Option<String>,
+    user_name: Option<String>,
+    text: Option<String>,
+    ts: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NdjsonMessage {
+    sender: String,
+    content: String,
+    sent_at: u64,
+}
+
+struct ImportedMessage {
+    sender: String,
+    content: String,
+    sent_at: u64,
+}
+
+// Slack timestamps look like "1512085950.000216" (seconds.micros)
+fn parse_slack_ts(ts: &str) -> Option<u64> {
+    let seconds: f64 = ts.parse().ok()?;
+    (seconds >= 0.0).then_some((seconds * 1000.0) as u64)
+}
+
+fn parse_slack(body: &str) -> Result<Vec<ImportedMessage>, Vec<ImportLineError>> {
+    let raw: Vec<SlackMessage> = serde_json::from_str(body).map_err(|err| {
+        vec![ImportLineError {
+            line: err.line(),
+            error: err.to_string(),
+        }]
+    })?;
+
+    let mut messages = Vec::new();
+    let mut errors = Vec::new();
+    for (i, msg) in raw.into_iter().enumerate() {
+        // Joins, channel topic changes and similar carry a subtype; only plain messages are kept
+        if msg.kind.as_deref() != Some("message") || msg.subtype.is_some() {
+            continue;
+        }
+        let sender = msg.user_name.or(msg.user).filter(|s| !s.is_empty());
+        let sent_at = msg.ts.as_deref().and_then(parse_slack_ts);
+        match (sender, sent_at, msg.text) {
+            (Some(sender), Some(sent_at), Some(content)) => messages.push(ImportedMessage {
+                sender,
+                content,
+                sent_at,
+            }),
+            _ => errors.push(ImportLineError {
+                line: i + 1,
+                error: "message needs user, ts and text".to_string(),
+            }),
+        }
+    }
+    if errors.is_empty() {
+        Ok(messages)
+    } else {
+        Err(errors)
+    }
+}
+
+fn parse_ndjson(body: &str) -> Result<Vec<ImportedMessage>, Vec<ImportLineError>> {
+    let mut messages = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line) in body.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<NdjsonMessage>(line) {
+            Ok(msg) => messages.push(ImportedMessage {
+                sender: msg.sender,
+                content: msg.content,
+                sent_at: msg.sent_at,
+            }),
+            Err(err) => errors.push(ImportLineError {
+                line: i + 1,
+                error: err.to_string(),
+            }),
+        }
+    }
+    if errors.is_empty() {
+        Ok(messages)
+    } else {
+        Err(errors)
+    }
+}
+
+fn check_manager(state: &SharedState, room_id: Uuid, username: &str) -> Result<(), ApiError> {
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    roles::check(state, room, username, Permission::ChangeSettings)?;
+    Ok(())
+}
+
+pub async fn import_history(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    query: web::Query<ImportQuery>,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    check_manager(&state, room_id, &user.username)?;
+
+    let body = std::str::from_utf8(&body).map_err(|_| ApiError::InvalidManifest)?;
+    let parsed = match query.format {
+        ImportFormat::Slack => parse_slack(body),
+        ImportFormat::Ndjson => parse_ndjson(body),
+    };
+    let mut messages = match parsed {
+        Ok(messages) => messages,
+        Err(errors) => {
+            return Ok(HttpResponse::UnprocessableEntity().json(serde_json::json!({
+                "errors": errors,
+            })))
+        }
+    };
+
+    let job = ImportJob {
+        id: Uuid::new_v4(),
+        room_id,
+        state: ImportState::Running,
+        total: messages.len(),
+        imported: 0,
+    };
+    state
+        .import_jobs
+        .lock()
+        .unwrap()
+        .insert(job.id, job.clone());
+    audit::record(
+        &state,
+        &user.username,
+        "import_history",
+        &room_id.to_string(),
+        format!("{} {} messages", messages.len(), query.format.marker()),
+    );
+
+    let marker = query.format.marker();
+    let state = state.get_ref().clone();
+    let job_id = job.id;
+    messages.sort_by_key(|msg| msg.sent_at);
+    rt::spawn(async move {
+        let mut pending = messages.into_iter().peekable();
+        while pending.peek().is_some() {
+            let chunk: Vec<_> = pending.by_ref().take(IMPORT_CHUNK_SIZE).collect();
+            let applied = chunk.len();
+            // Numbered after everything already in the room, so read markers,
+            // receipts and cursors into the live log stay put
+            let Ok(first_seq) = cluster::allocate_seqs(&state, room_id, applied as u64) else {
+                break;
+            };
+            {
+                let mut rooms = state.chat_rooms.lock().unwrap();
+                let Some(room) = rooms.get_mut(&room_id) else {
+                    break;
+                };
+                let first_seq = first_seq.unwrap_or(room.next_seq + 1);
+                let imported: Vec<ChatMessage> = chunk
+                    .into_iter()
+                    .zip(first_seq..)
+                    .map(|(msg, seq)| ChatMessage {
+                        id: Uuid::new_v4(),
+                        room_id,
+                        sender: msg.sender,
+                        content: msg.content,
+                        sent_at: msg.sent_at,
+                        origin_room_id: None,
+                        kind: MessageKind::User,
+                        seq,
+                        imported_from: Some(marker.to_string()),
+                        expires_at: None,
+                        edited_at: None,
+                        edit_history: Vec::new(),
+                        deleted_at: None,
+                        reactions: BTreeMap::new(),
+                        attachments: Vec::new(),
+                        version: VersionVector::default(),
+                        bridged_from: None,
+                        pending_since: None,
+                        node_id: None,
+                        priority: Priority::Normal,
+                        parent_message_id: None,
+                        mentions: Vec::new(),
+                        room_refs: Vec::new(),
+                    })
+                    .collect();
+                for msg in &imported {
+                    room.next_seq = room.next_seq.max(msg.seq);
+                    let at = room.message_log.partition_point(|m| m.seq < msg.seq);
+                    room.message_log.insert(at, msg.clone());
+                }
+                store::room_changed(&state, room);
+                store::messages_changed(&state, &imported);
+            }
+            if let Some(job) = state.import_jobs.lock().unwrap().get_mut(&job_id) {
+                job.imported += applied;
+            }
+            rt::task::yield_now().await;
+        }
+        if let Some(job) = state.import_jobs.lock().unwrap().get_mut(&job_id) {
+            job.state = ImportState::Completed;
+        }
+    });
+
+    Ok(HttpResponse::Accepted().json(job))
+}
+
+// Only for those who may start an import in the room
+pub async fn import_status(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, Uuid)>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, job_id) = path.into_inner();
+    let room_id = state.resolve_room_id(room_id);
+    check_manager(&state, room_id, &user.username)?;
+    let jobs = state.import_jobs.lock().unwrap();
+    jobs.get(&job_id)
+        .filter(|job| job.room_id == room_id)
+        .map(|job| HttpResponse::Ok().json(job))
+        .ok_or(ApiError::ImportJobNotFound)
+}
@@ -0,0 +1,405 @@
+// Private rooms and invites. A room created with `"private": true` is only
+// open to its members: others can't connect to it, read its history or post
+// in it, and it is left out of the public room list. Nobody can add
+// themselves; staff invite users instead (POST /rooms/{id}/invites) and the
+// invitee accepts or declines. Managers may still add people directly.
+//
+// The owner can also mint a shareable link: the same POST without a username
+// returns a token, optionally with an expiry and a cap on how many people
+// may use it, and POST /invites/{token}/accept joins whoever presents it.
+// DELETE /invites/{token} revokes a link before it runs out.
+//
+// This is also where direct rooms are kept private, see `check_access`.
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::bans;
+use crate::direct;
+use crate::error::ApiError;
+use crate::history;
+use crate::roles::{self, Permission};
+use crate::store;
+use crate::visibility;
+use crate::{audit, now_millis, ChatRoom, RoomEvent, SharedState};
+
+// Invites lapse after a week
+const INVITE_TTL_MS: u64 = 7 * 24 * 60 * 60 * 1000;
+// Links don't lapse unless asked to, up to a year out
+const MAX_LINK_TTL_SECS: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Serialize, Clone)]
+pub struct Invite {
+    room_id: Uuid,
+    username: String,
+    invited_by: String,
+    created_at: u64,
+    expires_at: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct InviteLink {
+    token: String,
+    room_id: Uuid,
+    created_by: String,
+    created_at: u64,
+    expires_at: Option<u64>,
+    max_uses: Option<u32>,
+    uses: u32,
+}
+
+impl InviteLink {
+    fn usable(&self) -> bool {
+        self.expires_at.is_none_or(|at| at >= now_millis())
+            && self.max_uses.is_none_or(|max| self.uses < max)
+    }
+}
+
+#[derive(Default)]
+pub struct Invites {
+    pending: HashMap<Uuid, HashMap<String, Invite>>, // room_id -> invitee -> invite
+    links: HashMap<String, InviteLink>,              // token -> link
+}
+
+impl Invites {
+    fn take(&mut self, room_id: Uuid, username: &str) -> Option<Invite> {
+        let room = self.pending.get_mut(&room_id)?;
+        let invite = room.remove(username);
+        if room.is_empty() {
+            self.pending.remove(&room_id);
+        }
+        invite.filter(|invite| invite.expires_at >= now_millis())
+    }
+
+    fn insert(&mut self, invite: Invite) {
+        let now = now_millis();
+        let room = self.pending.entry(invite.room_id).or_default();
+        room.retain(|_, invite| invite.expires_at >= now);
+        room.insert(invite.username.clone(), invite);
+    }
+
+    pub fn room_removed(&mut self, room_id: Uuid) {
+        self.pending.remove(&room_id);
+        self.links.retain(|_, link| link.room_id != room_id);
+    }
+
+    fn insert_link(&mut self, link: InviteLink) {
+        self.links.retain(|_, link| link.usable());
+        self.links.insert(link.token.clone(), link);
+    }
+
+    // Links that have run out stay until the next one is minted, so whoever
+    // follows them late is told why
+    fn use_link(&mut self, token: &str) -> Result<(), ApiError> {
+        let link = self.links.get_mut(token).ok_or(ApiError::InviteNotFound)?;
+        if !link.usable() {
+            return Err(ApiError::InviteLinkExpired);
+        }
+        link.uses += 1;
+        Ok(())
+    }
+
+    // Once the user is in, whether by invite or added by a manager
+    pub fn joined(&mut self, room_id: Uuid, username: &str) {
+        self.take(room_id, username);
+    }
+}
+
+// Whether the caller, if any, may connect to the room or read its history.
+// Outsiders are told a direct room doesn't exist, and only that a private
+// one is closed to them.
+pub fn check_access(
+    state: &SharedState,
+    room: &ChatRoom,
+    username: Option<&str>,
+) -> Result<(), ApiError> {
+    if room.direct {
+        return match username {
+            Some(username) if !direct::excludes(room, username) => Ok(()),
+            _ => Err(ApiError::RoomNotFound),
+        };
+    }
+    if !room.private {
+        return Ok(());
+    }
+    match username {
+        None => Err(ApiError::AuthRequired),
+        Some(username) if room.members().contains(username) || state.is_admin(username) => Ok(()),
+        Some(_) => Err(ApiError::NotRoomMember),
+    }
+}
+
+// For REST reads, where signing in is optional
+pub fn check_reader(
+    state: &SharedState,
+    room: &ChatRoom,
+    user: Option<&UserContext>,
+) -> Result<(), ApiError> {
+    check_access(state, room, user.map(|user| user.username.as_str()))
+}
+
+// For handlers that don't otherwise lock the room
+pub fn check_member(state: &SharedState, room_id: Uuid, username: &str) -> Result<(), ApiError> {
+    let rooms = state.chat_rooms.lock().unwrap();
+    match rooms.get(&room_id) {
+        Some(room) => check_access(state, room, Some(username)),
+        None => Ok(()),
+    }
+}
+
+// Without a username this mints a link instead, see `create_link`
+#[derive(Deserialize)]
+pub struct InviteRequest {
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    expires_in_secs: Option<u64>,
+    #[serde(default)]
+    max_uses: Option<u32>,
+}
+
+pub async fn invite_user(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<InviteRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let form = form.into_inner();
+    let Some(username) = form.username else {
+        return create_link(&state, room_id, &user, form.expires_in_secs, form.max_uses);
+    };
+    if form.expires_in_secs.is_some() || form.max_uses.is_some() {
+        return Err(ApiError::InvalidInviteOptions);
+    }
+    let username = state.canonical_username(&username);
+    if !state.user_accounts.lock().unwrap().contains_key(&username) {
+        return Err(ApiError::UserNotFound);
+    }
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    if room.direct {
+        return Err(ApiError::DirectRoomFixed);
+    }
+    if !roles::is_staff(&state, room, &user.username) {
+        return Err(ApiError::NotRoomModerator);
+    }
+    if room.members().contains(&username) {
+        return Err(ApiError::AlreadyRoomMember);
+    }
+    bans::check(room, &username)?;
+    let room_name = room.name.clone();
+    drop(rooms);
+
+    let created_at = now_millis();
+    let invite = Invite {
+        room_id,
+        username: username.clone(),
+        invited_by: user.username.clone(),
+        created_at,
+        expires_at: created_at + INVITE_TTL_MS,
+    };
+    state.invites.lock().unwrap().insert(invite.clone());
+    state.notify_user(
+        &username,
+        RoomEvent(serde_json::json!({
+            "type": "room_invite",
+            "room_name": room_name,
+            "invite": invite,
+        })),
+    );
+    audit::record(
+        &state,
+        &user.username,
+        "invite_user",
+        &username,
+        format!("to room {}", room_id),
+    );
+    Ok(HttpResponse::Created().json(invite))
+}
+
+fn create_link(
+    state: &SharedState,
+    room_id: Uuid,
+    user: &UserContext,
+    expires_in_secs: Option<u64>,
+    max_uses: Option<u32>,
+) -> Result<HttpResponse, ApiError> {
+    if expires_in_secs.is_some_and(|secs| secs == 0 || secs > MAX_LINK_TTL_SECS)
+        || max_uses == Some(0)
+    {
+        return Err(ApiError::InvalidInviteOptions);
+    }
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    if room.direct {
+        return Err(ApiError::DirectRoomFixed);
+    }
+    // Anyone holding the link gets in, so it's for the owner to hand out
+    roles::check(state, room, &user.username, Permission::ChangeSettings)?;
+    drop(rooms);
+
+    let created_at = now_millis();
+    let link = InviteLink {
+        token: Uuid::new_v4().simple().to_string(),
+        room_id,
+        created_by: user.username.clone(),
+        created_at,
+        expires_at: expires_in_secs.map(|secs| created_at + secs * 1000),
+        max_uses,
+        uses: 0,
+    };
+    state.invites.lock().unwrap().insert_link(link.clone());
+    audit::record(
+        state,
+        &user.username,
+        "create_invite_link",
+        &room_id.to_string(),
+        match max_uses {
+            Some(max) => format!("up to {} uses", max),
+            None => "unlimited uses".to_string(),
+        },
+    );
+    Ok(HttpResponse::Created().json(link))
+}
+
+// The caller's open invites, newest first
+pub async fn list_invites(state: web::Data<Arc<SharedState>>, user: UserContext) -> HttpResponse {
+    let now = now_millis();
+    let invites = state.invites.lock().unwrap();
+    let mut mine: Vec<&Invite> = invites
+        .pending
+        .values()
+        .filter_map(|room| room.get(&user.username))
+        .filter(|invite| invite.expires_at >= now)
+        .collect();
+    mine.sort_by_key(|invite| std::cmp::Reverse(invite.created_at));
+    HttpResponse::Ok().json(mine)
+}
+
+pub async fn accept_invite(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    bans::check(room, &user.username)?;
+    state
+        .invites
+        .lock()
+        .unwrap()
+        .take(room_id, &user.username)
+        .ok_or(ApiError::InviteNotFound)?;
+    let newly_added = room.participants.insert(user.username.clone());
+    if newly_added {
+        visibility::joined(room, &user.username);
+    }
+    let view = history::reader_view(&state, room, Some(&user));
+    if newly_added {
+        store::room_changed(&state, room);
+    }
+    drop(rooms);
+
+    if newly_added {
+        state.notify_room_list_changed([&user.username], "joined", room_id);
+    }
+    Ok(HttpResponse::Ok().json(view))
+}
+
+// Declined by the invitee or withdrawn by staff
+pub async fn cancel_invite(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, String)>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, username) = path.into_inner();
+    let room_id = state.resolve_room_id(room_id);
+    let username = state.canonical_username(&username);
+    if username != user.username {
+        let rooms = state.chat_rooms.lock().unwrap();
+        let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+        if !roles::is_staff(&state, room, &user.username) {
+            return Err(ApiError::NotRoomModerator);
+        }
+    }
+    state
+        .invites
+        .lock()
+        .unwrap()
+        .take(room_id, &username)
+        .ok_or(ApiError::InviteNotFound)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn accept_link(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<String>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let token = path.into_inner();
+    let room_id = {
+        let invites = state.invites.lock().unwrap();
+        let link = invites.links.get(&token).ok_or(ApiError::InviteNotFound)?;
+        link.room_id
+    };
+    let room_id = state.resolve_room_id(room_id);
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    bans::check(room, &user.username)?;
+    // Members following the link again don't use it up
+    if room.members().contains(&user.username) {
+        let view = history::reader_view(&state, room, Some(&user));
+        return Ok(HttpResponse::Ok().json(view));
+    }
+    let mut invites = state.invites.lock().unwrap();
+    invites.use_link(&token)?;
+    invites.joined(room_id, &user.username);
+    drop(invites);
+    room.participants.insert(user.username.clone());
+    visibility::joined(room, &user.username);
+    let view = history::reader_view(&state, room, Some(&user));
+    store::room_changed(&state, room);
+    drop(rooms);
+
+    state.notify_room_list_changed([&user.username], "joined", room_id);
+    Ok(HttpResponse::Ok().json(view))
+}
+
+pub async fn revoke_link(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<String>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let token = path.into_inner();
+    let room_id = {
+        let invites = state.invites.lock().unwrap();
+        let link = invites.links.get(&token).ok_or(ApiError::InviteNotFound)?;
+        link.room_id
+    };
+    let room_id = state.resolve_room_id(room_id);
+    {
+        let rooms = state.chat_rooms.lock().unwrap();
+        let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+        roles::check(&state, room, &user.username, Permission::ChangeSettings)?;
+    }
+    state
+        .invites
+        .lock()
+        .unwrap()
+        .links
+        .remove(&token)
+        .ok_or(ApiError::InviteNotFound)?;
+    audit::record(
+        &state,
+        &user.username,
+        "revoke_invite_link",
+        &room_id.to_string(),
+        token,
+    );
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,89 @@
+// Join and leave notices. When a user's first session in a room opens, the
+// room gets `{"type":"system","event":"joined","user":...}`, and `"left"`
+// once their last one there closes, so clients can say who came and went
+// without polling. The session that joined has its join frame instead.
+// With JOIN_MESSAGES_IN_HISTORY the notice is also stored as a system
+// message, without being delivered a second time; the event then carries its
+// seq so clients can match it up with history.
+use actix::Addr;
+use uuid::Uuid;
+
+use crate::deadletter;
+use crate::embed;
+use crate::messages;
+use crate::{ClientSession, MessageKind, RoomEvent, SharedState};
+
+// Call after the session is registered with `presence`
+pub fn session_started(
+    state: &SharedState,
+    room_id: Uuid,
+    username: &str,
+    session: &Addr<ClientSession>,
+) {
+    if state
+        .presence
+        .lock()
+        .unwrap()
+        .sessions_in(username, room_id)
+        == 1
+    {
+        announce(state, room_id, username, "joined", Some(session));
+    }
+}
+
+// Call after `presence` has dropped the session
+pub fn session_stopped(state: &SharedState, room_id: Uuid, username: &str) {
+    if state
+        .presence
+        .lock()
+        .unwrap()
+        .sessions_in(username, room_id)
+        == 0
+    {
+        announce(state, room_id, username, "left", None);
+    }
+}
+
+fn announce(
+    state: &SharedState,
+    room_id: Uuid,
+    username: &str,
+    event: &'static str,
+    skip: Option<&Addr<ClientSession>>,
+) {
+    let seq = state
+        .join_history
+        .then(|| {
+            let content = format!("{} {} the room", username, event);
+            let notice = messages::compose(
+                room_id,
+                username,
+                content,
+                None,
+                MessageKind::System,
+                Vec::new(),
+            );
+            messages::record(state, notice).ok()
+        })
+        .flatten()
+        .map(|msg| msg.seq);
+    let event = RoomEvent(serde_json::json!({
+        "type": "system",
+        "event": event,
+        "room_id": room_id,
+        "user": username,
+        "seq": seq,
+    }));
+    let sessions = state.active_sessions.lock().unwrap();
+    let recipients: Vec<_> = sessions
+        .get(&room_id)
+        .into_iter()
+        .flatten()
+        .filter(|addr| Some(*addr) != skip)
+        .cloned()
+        .collect();
+    drop(sessions);
+    // Stored notices are in history for anyone who missed them
+    deadletter::fan_out(&recipients, &event);
+    embed::room_event(state, room_id, &event.0);
+}
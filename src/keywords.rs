@@ -0,0 +1,259 @@
+// Keyword subscriptions: a user watches words or phrases, and any message in a
+// room they belong to that contains one raises a `keyword_alert` event on all
+// their connections plus an item in their feed. Each keyword alerts at most
+// once per cooldown; matches in between are counted and reported with the
+// next alert instead of being delivered one by one.
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::auth::UserContext;
+use crate::digest::FeedItem;
+use crate::error::ApiError;
+use crate::sanitize;
+use crate::users::owned_username;
+use crate::{now_millis, ChatMessage, MessageKind, RoomEvent, SharedState};
+
+const MAX_KEYWORDS: usize = 50;
+const MIN_KEYWORD_CHARS: usize = 2;
+const MAX_KEYWORD_CHARS: usize = 64;
+const ALERT_COOLDOWN_MS: u64 = 60_000;
+const EXCERPT_CHARS: usize = 140;
+
+#[derive(Serialize, Clone)]
+pub struct Keyword {
+    keyword: String,
+    muted: bool,
+    created_at: u64,
+    #[serde(skip)]
+    last_alert_at: Option<u64>,
+    #[serde(skip)]
+    suppressed: u64, // matches swallowed by the cooldown since the last alert
+}
+
+#[derive(Default)]
+pub struct KeywordSubscriptions {
+    by_user: HashMap<String, Vec<Keyword>>,
+}
+
+struct Alert {
+    username: String,
+    keyword: String,
+    suppressed: u64,
+}
+
+// Lowercased and whitespace-collapsed, so "Release  Notes" and "release notes" are one keyword
+fn normalize(keyword: &str) -> Result<String, ApiError> {
+    let keyword = keyword.split_whitespace().collect::<Vec<_>>().join(" ");
+    let keyword = keyword.to_lowercase();
+    let chars = keyword.chars().count();
+    if !(MIN_KEYWORD_CHARS..=MAX_KEYWORD_CHARS).contains(&chars) {
+        return Err(ApiError::InvalidKeyword);
+    }
+    Ok(keyword)
+}
+
+// Whole-word match, so "art" doesn't fire on "start"
+fn matches(content: &str, keyword: &str) -> bool {
+    content.match_indices(keyword).any(|(start, found)| {
+        let before = content[..start].chars().next_back();
+        let after = content[start + found.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+pub fn excerpt(content: &str) -> String {
+    let mut excerpt = sanitize::truncate(content, EXCERPT_CHARS).to_string();
+    if excerpt.len() < content.len() {
+        excerpt.push('\u{2026}');
+    }
+    excerpt
+}
+
+impl KeywordSubscriptions {
+    // At most one alert per user and message, for the first unmuted keyword
+    // that matches and isn't cooling down
+    fn collect_alerts(&mut self, members: &[String], msg: &ChatMessage, now: u64) -> Vec<Alert> {
+        let content = msg.content.to_lowercase();
+        let mut alerts = Vec::new();
+        for member in members {
+            let Some(keywords) = self.by_user.get_mut(member) else {
+                continue;
+            };
+            let mut alerted = false;
+            for keyword in keywords.iter_mut() {
+                if alerted || keyword.muted || !matches(&content, &keyword.keyword) {
+                    continue;
+                }
+                let cooling = keyword
+                    .last_alert_at
+                    .is_some_and(|at| now.saturating_sub(at) < ALERT_COOLDOWN_MS);
+                if cooling {
+                    keyword.suppressed += 1;
+                    continue;
+                }
+                alerts.push(Alert {
+                    username: member.clone(),
+                    keyword: keyword.keyword.clone(),
+                    suppressed: keyword.suppressed,
+                });
+                keyword.last_alert_at = Some(now);
+                keyword.suppressed = 0;
+                alerted = true;
+            }
+        }
+        alerts
+    }
+}
+
+pub fn watched_by(state: &SharedState, username: &str) -> Vec<Keyword> {
+    state
+        .keywords
+        .lock()
+        .unwrap()
+        .by_user
+        .get(username)
+        .cloned()
+        .unwrap_or_default()
+}
+
+// Called from the publish path once the message has been broadcast
+pub fn message_posted(state: &SharedState, msg: &ChatMessage) {
+    if msg.kind != MessageKind::User || msg.content.is_empty() {
+        return;
+    }
+    let (room_name, notification, members) = {
+        let rooms = state.chat_rooms.lock().unwrap();
+        let Some(room) = rooms.get(&msg.room_id) else {
+            return;
+        };
+        let members: Vec<String> = room
+            .members()
+            .into_iter()
+            .filter(|member| *member != msg.sender)
+            .collect();
+        (room.name.clone(), room.notifications.clone(), members)
+    };
+    let alerts = state
+        .keywords
+        .lock()
+        .unwrap()
+        .collect_alerts(&members, msg, now_millis());
+
+    for alert in alerts {
+        let item = FeedItem::about_message(
+            "keyword_alert",
+            format!(
+                "\"{}\" mentioned by {} in {}",
+                alert.keyword, msg.sender, room_name
+            ),
+            msg.room_id,
+            msg.id,
+        );
+        state.feeds.lock().unwrap().push(&alert.username, item);
+        state.notify_user(
+            &alert.username,
+            RoomEvent(serde_json::json!({
+                "type": "keyword_alert",
+                "keyword": alert.keyword,
+                "room_id": msg.room_id,
+                "room_name": room_name,
+                "message_id": msg.id,
+                "sender": msg.sender,
+                "excerpt": excerpt(&msg.content),
+                "suppressed": alert.suppressed,
+                "notification": notification,
+            })),
+        );
+    }
+}
+
+#[derive(Deserialize)]
+pub struct KeywordCreate {
+    keyword: String,
+}
+
+#[derive(Deserialize)]
+pub struct KeywordUpdate {
+    muted: bool,
+}
+
+pub async fn add_keyword(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<String>,
+    user: UserContext,
+    form: web::Json<KeywordCreate>,
+) -> Result<HttpResponse, ApiError> {
+    let username = owned_username(&state, &path, &user)?;
+    let keyword = normalize(&form.keyword)?;
+    let mut subscriptions = state.keywords.lock().unwrap();
+    let list = subscriptions.by_user.entry(username).or_default();
+    // Adding a keyword that is already watched is a no-op
+    if let Some(existing) = list.iter().find(|k| k.keyword == keyword) {
+        return Ok(HttpResponse::Ok().json(existing.clone()));
+    }
+    if list.len() >= MAX_KEYWORDS {
+        return Err(ApiError::TooManyKeywords);
+    }
+    let entry = Keyword {
+        keyword,
+        muted: false,
+        created_at: now_millis(),
+        last_alert_at: None,
+        suppressed: 0,
+    };
+    list.push(entry.clone());
+    Ok(HttpResponse::Created().json(entry))
+}
+
+pub async fn list_keywords(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<String>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let username = owned_username(&state, &path, &user)?;
+    Ok(HttpResponse::Ok().json(watched_by(&state, &username)))
+}
+
+pub async fn update_keyword(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(String, String)>,
+    user: UserContext,
+    form: web::Json<KeywordUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    let (path_username, keyword) = path.into_inner();
+    let username = owned_username(&state, &path_username, &user)?;
+    let keyword = normalize(&keyword)?;
+    let mut subscriptions = state.keywords.lock().unwrap();
+    let entry = subscriptions
+        .by_user
+        .get_mut(&username)
+        .and_then(|list| list.iter_mut().find(|k| k.keyword == keyword))
+        .ok_or(ApiError::KeywordNotFound)?;
+    entry.muted = form.muted;
+    // Matches seen while muted aren't worth reporting after unmuting
+    entry.suppressed = 0;
+    Ok(HttpResponse::Ok().json(entry.clone()))
+}
+
+pub async fn remove_keyword(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(String, String)>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let (path_username, keyword) = path.into_inner();
+    let username = owned_username(&state, &path_username, &user)?;
+    let keyword = normalize(&keyword)?;
+    let mut subscriptions = state.keywords.lock().unwrap();
+    let list = subscriptions
+        .by_user
+        .get_mut(&username)
+        .ok_or(ApiError::KeywordNotFound)?;
+    let before = list.len();
+    list.retain(|k| k.keyword != keyword);
+    if list.len() == before {
+        return Err(ApiError::KeywordNotFound);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,1727 @@
+mod admin;
+mod announcements;
+mod approvals;
+mod attachments;
+mod audit;
+mod auth;
+mod bans;
+mod bookmarks;
+mod bots;
+mod bridges;
+mod capabilities;
+mod cluster;
+mod coalesce;
+mod cold;
+mod config;
+#[cfg(test)]
+mod conformance;
+mod deadletter;
+mod deadline;
+mod deletion;
+mod digest;
+mod direct;
+mod edits;
+mod email;
+mod embed;
+mod error;
+mod expiry;
+mod export;
+mod external;
+mod heartbeat;
+mod history;
+mod holds;
+mod i18n;
+mod idempotency;
+mod import;
+mod invites;
+mod joins;
+mod keywords;
+mod manifest;
+mod mentions;
+mod messages;
+mod mutes;
+mod netsim;
+mod notices;
+mod notifications;
+mod outbound;
+mod passwords;
+mod policy;
+mod portability;
+mod postgres;
+mod presence;
+mod probation;
+mod protocol;
+mod ratelimit;
+mod reactions;
+mod receipts;
+mod recovery;
+mod rejections;
+mod replica;
+mod retention;
+mod roles;
+mod roomrefs;
+mod sanitize;
+mod search;
+mod seed;
+mod sessions;
+mod settings;
+mod shadowban;
+mod slack;
+mod sqlite;
+mod standby;
+mod stats;
+mod store;
+mod telegram;
+mod telemetry;
+mod threads;
+mod throttle;
+mod typing;
+mod unread;
+mod uploads;
+mod usernames;
+mod users;
+mod versions;
+mod visibility;
+
+use actix::prelude::*;
+use actix_cors::Cors;
+use actix_http::ws::Item;
+use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer};
+use actix_web_actors::ws;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+pub use approvals::ApprovalQueue;
+use attachments::Attachment;
+use audit::AuditEvent;
+use auth::{AuthTokens, UserContext};
+use bans::Ban;
+use bookmarks::Bookmark;
+use bots::{BotGrant, BotRegistry};
+use bridges::{BridgeOrigin, Bridges};
+use cluster::Cluster;
+use coalesce::Coalescer;
+use cold::ColdStorage;
+use deadletter::{DeadLetterStore, Undelivered};
+use deadline::Deadlines;
+use digest::Feeds;
+use edits::Revision;
+use embed::Subscribers;
+pub use embed::{ChatServerHandle, Event};
+use error::ApiError;
+use expiry::ExpiryQueue;
+use external::ExternalIds;
+use heartbeat::Heartbeat;
+use holds::LegalHolds;
+use i18n::Lang;
+use idempotency::RecentCreations;
+use import::ImportJob;
+use invites::Invites;
+use keywords::KeywordSubscriptions;
+use messages::{Outgoing, Priority};
+use notices::Notices;
+use notifications::NotificationSettings;
+use passwords::Verified;
+use policy::{MessageKind, RoomPolicy};
+use presence::PresenceTracker;
+use probation::Probation;
+use protocol::{Assembled, ClientFrame, EventCategory, Reassembly, ServerFrame};
+use ratelimit::RateLimiter;
+use receipts::Deliveries;
+use recovery::RecoveryTokens;
+use rejections::Rejections;
+use retention::RetentionClass;
+use roles::{Permission, RoomRole};
+use roomrefs::RoomRef;
+use search::SearchIndex;
+use sessions::{ConnectionMeta, SessionInfo, Traffic};
+use shadowban::ShadowBans;
+use standby::Standby;
+use stats::StatsAggregator;
+use store::{PersistencePolicy, Store};
+use telemetry::SpanContext;
+use throttle::BroadcastThrottle;
+use typing::TypingTracker;
+use unread::UnreadTracker;
+use uploads::Uploads;
+use usernames::UsernamePolicy;
+use users::UserSettings;
+use versions::VersionVector;
+use visibility::HistoryVisibility;
+
+// For the cargo-fuzz target in fuzz/; not part of the embedding API
+#[doc(hidden)]
+pub use protocol::parse_frame;
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ChatRoom {
+    id: Uuid,
+    name: String,
+    created_by: String,
+    participants: HashSet<String>,
+    message_log: Vec<ChatMessage>,
+    #[serde(default)]
+    retention: RetentionClass,
+    #[serde(default)]
+    policy: RoomPolicy,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    next_seq: u64,
+    #[serde(default)]
+    moderators: HashSet<String>,
+    // Separate from `retention`: files can lapse while the text history stays
+    #[serde(default)]
+    attachment_retention: RetentionClass,
+    // Bots the owner allows in this room; a bot not listed here can't join
+    #[serde(default)]
+    bot_allowlist: BTreeMap<String, BotGrant>,
+    // Public announcement channel: recent messages are published as an Atom feed
+    #[serde(default)]
+    announcement: bool,
+    // Links and attachments from regular members wait for a moderator
+    #[serde(default)]
+    link_approval: bool,
+    #[serde(default)]
+    mutes: BTreeMap<String, u64>, // username -> muted until
+    #[serde(default)]
+    notifications: NotificationSettings,
+    // A two-person conversation from `direct`, hidden from everyone else
+    #[serde(default)]
+    direct: bool,
+    // An integration's own id for the room, see `external`
+    #[serde(default)]
+    external_id: Option<String>,
+    // Members only, joined by invite, see `invites`
+    #[serde(default)]
+    private: bool,
+    // Owner-set topic shown with the room, see `settings`
+    #[serde(default)]
+    description: Option<String>,
+    // Only staff may post
+    #[serde(default)]
+    read_only: bool,
+    // How far back members who joined later can read, see `visibility`
+    #[serde(default)]
+    history_visibility: HistoryVisibility,
+    #[serde(default)]
+    joined_at: BTreeMap<String, u64>, // username -> when they joined
+    // Kept out until lifted, see `bans`
+    #[serde(default)]
+    bans: BTreeMap<String, Ban>,
+}
+
+impl ChatRoom {
+    fn new(name: String, created_by: String) -> Self {
+        ChatRoom {
+            id: Uuid::new_v4(),
+            name,
+            created_by,
+            participants: HashSet::new(),
+            message_log: Vec::new(),
+            retention: RetentionClass::default(),
+            policy: RoomPolicy::default(),
+            tags: Vec::new(),
+            next_seq: 0,
+            moderators: HashSet::new(),
+            attachment_retention: RetentionClass::default(),
+            bot_allowlist: BTreeMap::new(),
+            announcement: false,
+            link_approval: false,
+            mutes: BTreeMap::new(),
+            notifications: NotificationSettings::default(),
+            direct: false,
+            external_id: None,
+            private: false,
+            description: None,
+            read_only: false,
+            history_visibility: HistoryVisibility::default(),
+            joined_at: BTreeMap::new(),
+            bans: BTreeMap::new(),
+        }
+    }
+
+    // Orders history by timestamp and renumbers seq after out-of-band inserts.
+    // The sort is stable, so same-millisecond messages keep their relative order.
+    fn resequence(&mut self) {
+        self.message_log.sort_by_key(|msg| msg.sent_at);
+        for (i, msg) in self.message_log.iter_mut().enumerate() {
+            msg.seq = i as u64 + 1;
+        }
+        self.next_seq = self.message_log.len() as u64;
+    }
+
+    // A copy without the message log, which the store keeps row by row and
+    // peers receive message by message
+    fn metadata(&self) -> ChatRoom {
+        ChatRoom {
+            id: self.id,
+            name: self.name.clone(),
+            created_by: self.created_by.clone(),
+            participants: self.participants.clone(),
+            message_log: Vec::new(),
+            retention: self.retention,
+            policy: self.policy.clone(),
+            tags: self.tags.clone(),
+            next_seq: self.next_seq,
+            moderators: self.moderators.clone(),
+            attachment_retention: self.attachment_retention,
+            bot_allowlist: self.bot_allowlist.clone(),
+            announcement: self.announcement,
+            link_approval: self.link_approval,
+            mutes: self.mutes.clone(),
+            notifications: self.notifications.clone(),
+            direct: self.direct,
+            external_id: self.external_id.clone(),
+            private: self.private,
+            description: self.description.clone(),
+            read_only: self.read_only,
+            history_visibility: self.history_visibility,
+            joined_at: self.joined_at.clone(),
+            bans: self.bans.clone(),
+        }
+    }
+
+    // Everyone who sees this room in their room list
+    fn members(&self) -> HashSet<String> {
+        let mut members = self.participants.clone();
+        members.insert(self.created_by.clone());
+        members
+    }
+}
+
+#[derive(Default)]
+struct SharedState {
+    user_accounts: Mutex<HashMap<String, String>>, // username -> password hash
+    user_emails: Mutex<HashMap<String, String>>,   // username -> registered recovery email
+    chat_rooms: Mutex<HashMap<Uuid, ChatRoom>>,    // room_id -> ChatRoom
+    active_sessions: Mutex<HashMap<Uuid, Vec<Addr<ClientSession>>>>, // room_id -> WebSocket connections
+    user_sessions: Mutex<HashMap<String, Vec<Addr<ClientSession>>>>, // username -> WebSocket connections in any room
+    admins: Mutex<HashSet<String>>,
+    audit_log: Mutex<VecDeque<AuditEvent>>,
+    room_redirects: Mutex<HashMap<Uuid, Uuid>>, // merged room_id -> surviving room_id
+    external_ids: Mutex<ExternalIds>,           // integration aliases -> room_id
+    user_settings: Mutex<HashMap<String, UserSettings>>,
+    presence: Mutex<PresenceTracker>,
+    rate_limiter: RateLimiter,
+    session_registry: Mutex<HashMap<Uuid, SessionInfo>>, // session_id -> connection details
+    import_jobs: Mutex<HashMap<Uuid, ImportJob>>,
+    broadcast_throttle: BroadcastThrottle,
+    expiry_queue: ExpiryQueue,
+    dead_letters: DeadLetterStore,
+    recovery_tokens: Mutex<RecoveryTokens>,
+    bots: Mutex<BotRegistry>,
+    username_policy: UsernamePolicy,
+    bookmarks: Mutex<HashMap<String, Vec<Bookmark>>>, // username -> private bookmarks
+    default_rooms: Mutex<Vec<Uuid>>,
+    room_creations: Mutex<RecentCreations>, // recent creates, so retries return the same room
+    invites: Mutex<Invites>,                // pending invites to private rooms
+    legal_holds: Mutex<LegalHolds>,
+    replication_token: Option<String>,
+    replica_of: Mutex<Option<String>>, // primary base URL while running as a read-only replica
+    standby: Mutex<Option<Standby>>,   // set on a standby until it is promoted
+    auth_tokens: Mutex<AuthTokens>,
+    unread: Mutex<UnreadTracker>,
+    deliveries: Mutex<Deliveries>, // how far each member's devices have acknowledged
+    node_id: String, // this instance's stable id: version vectors, messages, audit, peers
+    cold_storage: Mutex<ColdStorage>,
+    shadow_bans: Mutex<ShadowBans>,
+    probation: Mutex<Probation>, // new accounts still under stricter limits
+    approvals: Mutex<ApprovalQueue>, // messages held for moderator approval
+    keywords: Mutex<KeywordSubscriptions>, // username -> watched words and phrases
+    rejections: Mutex<Rejections>, // refused and dropped sends, for /metrics and moderators
+    feeds: Mutex<Feeds>,         // username -> server-generated items such as digests
+    notices: Mutex<Notices>,     // username -> security, policy and maintenance notices
+    bridges: Mutex<Bridges>,
+    cluster: Mutex<Cluster>,        // peer instances sharing rooms over Redis
+    typing: Mutex<TypingTracker>,   // who is typing where; never stored
+    store: Option<Store>,           // set when DATABASE_URL is configured
+    persistence: PersistencePolicy, // which event kinds the store keeps
+    deadlines: Deadlines,           // how long requests and broadcasts may wait
+    stats: StatsAggregator,         // cached per-room activity summaries
+    search: Mutex<SearchIndex>,     // word index over the rooms searched so far
+    uploads: Uploads,               // stored files and the uploads not yet attached
+    room_archive_dir: PathBuf,      // where rooms deleted with ?archive=true are written
+    coalescer: Coalescer,           // typing and presence changes waiting for the next flush
+    join_history: bool,             // whether join and leave notices are stored
+    heartbeat: Heartbeat,           // how often sessions ping and how many pongs they may miss
+    embedded: Subscribers,          // in-process subscribers, see `ChatServerHandle`
+    #[cfg(feature = "dev")]
+    network_shaper: Mutex<netsim::NetworkShaper>,
+}
+
+impl SharedState {
+    fn is_admin(&self, username: &str) -> bool {
+        self.admins
+            .lock()
+            .unwrap()
+            .contains(&self.canonical_username(username))
+    }
+
+    fn canonical_username(&self, username: &str) -> String {
+        self.username_policy.canonical(username)
+    }
+
+    // Follows merge redirects so old room ids keep working, and loads the
+    // room back from cold storage if it was offloaded
+    fn resolve_room_id(&self, room_id: Uuid) -> Uuid {
+        let current = self.follow_redirects(room_id);
+        cold::touch(self, current);
+        store::touch(self, current);
+        current
+    }
+
+    // Redirects only; safe to call while holding `chat_rooms`
+    fn follow_redirects(&self, room_id: Uuid) -> Uuid {
+        let redirects = self.room_redirects.lock().unwrap();
+        let mut current = room_id;
+        while let Some(next) = redirects.get(&current) {
+            current = *next;
+        }
+        current
+    }
+
+    fn user_settings(&self, username: &str) -> UserSettings {
+        self.user_settings
+            .lock()
+            .unwrap()
+            .get(username)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn broadcast_event(&self, room_id: Uuid, event: RoomEvent) {
+        let mut span = telemetry::span("broadcast.fanout");
+        let Some(sessions) =
+            deadline::lock_within(&self.active_sessions, self.deadlines.broadcast_wait)
+        else {
+            embed::room_event(self, room_id, &event.0);
+            self.dead_letters.record(
+                Some(room_id),
+                None,
+                "fanout_timeout",
+                Undelivered::Event(event.0),
+            );
+            return;
+        };
+        let recipients = sessions.get(&room_id).map_or(&[][..], Vec::as_slice);
+        span.attr("recipients", recipients.len());
+        let dead = deadletter::fan_out(recipients, &event);
+        drop(sessions);
+        embed::room_event(self, room_id, &event.0);
+        deadletter::record_undelivered(self, room_id, dead, Undelivered::Event(event.0));
+    }
+
+    fn notify_user(&self, username: &str, event: RoomEvent) {
+        let Some(sessions) =
+            deadline::lock_within(&self.user_sessions, self.deadlines.broadcast_wait)
+        else {
+            self.dead_letters.record(
+                None,
+                Some(username.to_string()),
+                "fanout_timeout",
+                Undelivered::Event(event.0),
+            );
+            return;
+        };
+        let recipients = sessions.get(username).map_or(&[][..], Vec::as_slice);
+        let dead = deadletter::fan_out(recipients, &event);
+        drop(sessions);
+        for _ in dead {
+            self.dead_letters.record(
+                None,
+                Some(username.to_string()),
+                "actor_stopped",
+                Undelivered::Event(event.0.clone()),
+            );
+        }
+    }
+
+    fn notify_room_list_changed<'a>(
+        &self,
+        members: impl IntoIterator<Item = &'a String>,
+        change: &str,
+        room_id: Uuid,
+    ) {
+        let event = RoomEvent(serde_json::json!({
+            "type": "room_list_changed",
+            "change": change,
+            "room_id": room_id,
+        }));
+        for member in members {
+            self.notify_user(member, event.clone());
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct UserRegistration {
+    username: String,
+    password: String,
+    #[serde(default)]
+    email: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UserLogin {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct RoomCreation {
+    name: String,
+    // Optional now that the creator comes from the login token
+    #[serde(default)]
+    creator: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    private: bool,
+}
+
+#[derive(Deserialize)]
+struct AddParticipant {
+    room_id: Uuid,
+    username: String,
+}
+
+#[derive(Deserialize, Message, Clone, Serialize)]
+#[rtype(result = "()")]
+struct ChatMessage {
+    #[serde(default)]
+    id: Uuid,
+    room_id: Uuid,
+    sender: String,
+    content: String,
+    #[serde(default)]
+    sent_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    origin_room_id: Option<Uuid>,
+    #[serde(default)]
+    kind: MessageKind,
+    #[serde(default)]
+    seq: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    imported_from: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    edited_at: Option<u64>,
+    // What edits replaced, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    edit_history: Vec<Revision>,
+    // Set when the message is deleted; the entry stays as a tombstone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    reactions: BTreeMap<String, BTreeSet<String>>, // emoji -> usernames
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<Attachment>,
+    #[serde(default, skip_serializing_if = "VersionVector::is_empty")]
+    version: VersionVector,
+    // Set on messages relayed in from an external network
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bridged_from: Option<BridgeOrigin>,
+    // Set while the message waits in the approval queue, never in the room log
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending_since: Option<u64>,
+    // The instance that accepted the message and gave it its seq
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    node_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Priority::is_normal")]
+    priority: Priority,
+    // Root of the thread this message replies in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent_message_id: Option<Uuid>,
+    // Members the content @mentions, resolved when it was appended
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mentions: Vec<String>,
+    // Rooms the content #references, resolved with the mentions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    room_refs: Vec<RoomRef>,
+}
+
+// Server-generated JSON frame pushed to every session in a room
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+struct RoomEvent(serde_json::Value);
+
+// WebSocket Client Session
+struct ClientSession {
+    id: Uuid,
+    room_id: Uuid,
+    username: String,
+    state: Arc<SharedState>,
+    meta: ConnectionMeta,
+    lang: Lang,
+    // Trace context of the upgrade request; frame spans join that trace
+    trace_parent: Option<SpanContext>,
+    // None until the client sends a subscribe frame, meaning everything
+    subscriptions: Option<HashSet<EventCategory>>,
+    // Cached at connect and replaced in place on role_changed
+    role: RoomRole,
+    // Set for sessions opened with a login token; extended by refresh_token frames
+    auth_expires_at: u64,
+    link: netsim::Link,
+    traffic: Arc<Traffic>,
+    fragments: Reassembly,
+    // When the client last sent a frame, for recording last seen once it idles
+    last_active: u64,
+    idle: bool,
+    // Pings sent since the client last answered one
+    unanswered_pings: u32,
+}
+
+impl ClientSession {
+    // Outbound frames go through here so dev builds can simulate slow links
+    fn send_text(&mut self, ctx: &mut ws::WebsocketContext<Self>, text: String) {
+        self.traffic.sent(text.len());
+        match self
+            .link
+            .delay(&self.state, self.id, self.room_id, text.len())
+        {
+            Some(delay) => {
+                ctx.run_later(delay, move |_, ctx| ctx.text(text));
+            }
+            None => ctx.text(text),
+        }
+    }
+
+    fn plain_text(&self) -> bool {
+        self.meta.protocol.as_deref() == Some(sessions::PLAIN_TEXT_PROTOCOL)
+    }
+
+    fn wants(&self, category: EventCategory) -> bool {
+        self.subscriptions
+            .as_ref()
+            .is_none_or(|subscribed| subscribed.contains(&category))
+    }
+}
+
+impl Actor for ClientSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let mut sessions = self.state.active_sessions.lock().unwrap();
+        sessions
+            .entry(self.room_id)
+            .or_default()
+            .push(ctx.address());
+        drop(sessions);
+        self.state
+            .user_sessions
+            .lock()
+            .unwrap()
+            .entry(self.username.clone())
+            .or_default()
+            .push(ctx.address());
+        self.state.session_registry.lock().unwrap().insert(
+            self.id,
+            SessionInfo {
+                session_id: self.id,
+                room_id: self.room_id,
+                username: self.username.clone(),
+                role: self.role,
+                meta: self.meta.clone(),
+                traffic: self.traffic.clone(),
+            },
+        );
+        let came_online = self
+            .state
+            .presence
+            .lock()
+            .unwrap()
+            .connected(&self.username, self.room_id);
+        if came_online {
+            presence::changed(&self.state, &self.username, true);
+        }
+        joins::session_started(&self.state, self.room_id, &self.username, &ctx.address());
+        if !self.plain_text() {
+            let join = ServerFrame::Join {
+                room_id: self.room_id,
+                username: &self.username,
+                role: self.role,
+                node_id: &self.state.node_id,
+            }
+            .to_text();
+            self.send_text(ctx, join);
+        }
+
+        heartbeat::start(self, ctx);
+        ctx.run_interval(auth::SESSION_CHECK_INTERVAL, |act, ctx| {
+            if !act.idle && now_millis().saturating_sub(act.last_active) >= presence::IDLE_AFTER_MS
+            {
+                act.idle = true;
+                presence::record_seen(&act.state, &act.username, act.last_active);
+            }
+            if act.auth_expires_at <= now_millis() {
+                let frame = ApiError::AuthTokenExpired.to_frame(act.lang);
+                act.traffic.sent(frame.len());
+                ctx.text(frame);
+                ctx.close(Some(ws::CloseCode::Policy.into()));
+                ctx.stop();
+            }
+        });
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        let mut sessions = self.state.active_sessions.lock().unwrap();
+        if let Some(user_list) = sessions.get_mut(&self.room_id) {
+            user_list.retain(|addr| addr != &ctx.address());
+        }
+        drop(sessions);
+        let mut user_sessions = self.state.user_sessions.lock().unwrap();
+        if let Some(addrs) = user_sessions.get_mut(&self.username) {
+            addrs.retain(|addr| addr != &ctx.address());
+            if addrs.is_empty() {
+                user_sessions.remove(&self.username);
+            }
+        }
+        drop(user_sessions);
+        self.state.session_registry.lock().unwrap().remove(&self.id);
+        let went_offline = self
+            .state
+            .presence
+            .lock()
+            .unwrap()
+            .disconnected(&self.username, self.room_id);
+        presence::record_seen(&self.state, &self.username, now_millis());
+        if went_offline {
+            presence::changed(&self.state, &self.username, false);
+        }
+        joins::session_stopped(&self.state, self.room_id, &self.username);
+        typing::typing_stopped(&self.state, self.room_id, &self.username);
+    }
+}
+
+impl Handler<ChatMessage> for ClientSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: ChatMessage, ctx: &mut Self::Context) {
+        if msg.room_id != self.room_id || !self.wants(EventCategory::Messages) {
+            return;
+        }
+        if self.plain_text() {
+            self.send_text(ctx, msg.content);
+        } else {
+            let frame = ServerFrame::Message { message: &msg }.to_text();
+            self.send_text(ctx, frame);
+        }
+    }
+}
+
+impl Handler<RoomEvent> for ClientSession {
+    type Result = ();
+
+    fn handle(&mut self, event: RoomEvent, ctx: &mut Self::Context) {
+        let category = event
+            .0
+            .get("type")
+            .and_then(|t| t.as_str())
+            .and_then(EventCategory::of_event);
+        if category.is_none_or(|category| self.wants(category)) {
+            self.send_text(ctx, event.0.to_string());
+        }
+    }
+}
+
+impl Handler<admin::RoomMerged> for ClientSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: admin::RoomMerged, ctx: &mut Self::Context) {
+        let frame = serde_json::json!({
+            "type": "room_merged",
+            "from": msg.from,
+            "into": msg.into,
+        })
+        .to_string();
+        self.traffic.sent(frame.len());
+        ctx.text(frame);
+        // The old room no longer exists, so its clients must reconnect to the new id
+        if self.room_id == msg.from {
+            ctx.stop();
+        }
+    }
+}
+
+impl Handler<standby::Failover> for ClientSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: standby::Failover, ctx: &mut Self::Context) {
+        let frame = serde_json::json!({
+            "type": "failover",
+            "primary": msg.primary,
+        })
+        .to_string();
+        self.traffic.sent(frame.len());
+        ctx.text(frame);
+        // This instance no longer takes writes, so clients move to the new primary
+        ctx.stop();
+    }
+}
+
+impl Handler<deletion::RoomDeleted> for ClientSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: deletion::RoomDeleted, ctx: &mut Self::Context) {
+        let frame = serde_json::json!({
+            "type": "room_deleted",
+            "room_id": msg.room_id,
+        })
+        .to_string();
+        self.traffic.sent(frame.len());
+        ctx.text(frame);
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Normal,
+            description: Some("room_deleted".to_string()),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Handler<bans::RemovedFromRoom> for ClientSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: bans::RemovedFromRoom, ctx: &mut Self::Context) {
+        let frame = serde_json::json!({
+            "type": "removed_from_room",
+            "room_id": msg.room_id,
+            "reason": msg.reason,
+        })
+        .to_string();
+        self.traffic.sent(frame.len());
+        ctx.text(frame);
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(msg.reason.to_string()),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Handler<roles::RoleChanged> for ClientSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: roles::RoleChanged, ctx: &mut Self::Context) {
+        if msg.room_id == self.room_id && msg.username == self.username {
+            self.role = msg.role;
+            if let Some(info) = self
+                .state
+                .session_registry
+                .lock()
+                .unwrap()
+                .get_mut(&self.id)
+            {
+                info.role = msg.role;
+            }
+        }
+        self.send_text(ctx, msg.frame().to_string());
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ClientSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let _span = telemetry::span_with_parent("ws.frame", self.trace_parent);
+        let text = match msg {
+            // A new message can't start before the fragmented one is finished
+            Ok(ws::Message::Text(_) | ws::Message::Binary(_)) if self.fragments.in_progress() => {
+                ctx.close(Some(ws::CloseCode::Protocol.into()));
+                ctx.stop();
+                return;
+            }
+            Ok(ws::Message::Text(text)) => {
+                self.traffic.received(text.len());
+                text.to_string()
+            }
+            Ok(ws::Message::Ping(bytes)) => {
+                ctx.pong(&bytes);
+                return;
+            }
+            Ok(ws::Message::Pong(_)) => {
+                self.unanswered_pings = 0;
+                return;
+            }
+            Ok(ws::Message::Binary(bytes)) => {
+                self.traffic.received(bytes.len());
+                self.send_text(ctx, ApiError::InvalidFrame.to_frame(self.lang));
+                return;
+            }
+            Ok(ws::Message::Continuation(item)) => {
+                let (Item::FirstText(bytes)
+                | Item::FirstBinary(bytes)
+                | Item::Continue(bytes)
+                | Item::Last(bytes)) = &item;
+                self.traffic.received(bytes.len());
+                match self.fragments.push(item) {
+                    Ok(Some(Assembled::Text(text))) => text,
+                    Ok(Some(Assembled::Binary)) => {
+                        self.send_text(ctx, ApiError::InvalidFrame.to_frame(self.lang));
+                        return;
+                    }
+                    Ok(None) => return,
+                    Err(code) => {
+                        ctx.close(Some(code.into()));
+                        ctx.stop();
+                        return;
+                    }
+                }
+            }
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+                return;
+            }
+            Ok(_) => return,
+            // The codec skips past the bad frame, but a client that sent one
+            // can't be trusted to frame what follows
+            Err(err) => {
+                log::debug!("closing session {}: {}", self.id, err);
+                let code = match err {
+                    ws::ProtocolError::Overflow => ws::CloseCode::Size,
+                    _ => ws::CloseCode::Protocol,
+                };
+                ctx.close(Some(code.into()));
+                ctx.stop();
+                return;
+            }
+        };
+        self.last_active = now_millis();
+        self.idle = false;
+        let result = protocol::parse_frame(text).and_then(|frame| match frame {
+            ClientFrame::Message {
+                content,
+                ttl_seconds,
+                attachments,
+                priority,
+                parent_message_id,
+            } => {
+                let outgoing = Outgoing {
+                    content,
+                    ttl_seconds,
+                    attachments,
+                    priority,
+                    parent_message_id,
+                };
+                messages::send_message(&self.state, self.room_id, &self.username, outgoing)
+                    .map(|_| ())
+            }
+            ClientFrame::Subscribe { events } => {
+                self.send_text(
+                    ctx,
+                    serde_json::json!({ "type": "subscribed", "events": events }).to_string(),
+                );
+                self.subscriptions = Some(events);
+                Ok(())
+            }
+            ClientFrame::RefreshToken { token } => {
+                let (username, expires_at) =
+                    self.state.auth_tokens.lock().unwrap().validate(&token)?;
+                if username != self.username {
+                    return Err(ApiError::AuthTokenMismatch);
+                }
+                self.auth_expires_at = expires_at;
+                self.send_text(
+                    ctx,
+                    serde_json::json!({ "type": "token_refreshed", "expires_at": expires_at })
+                        .to_string(),
+                );
+                Ok(())
+            }
+            ClientFrame::EditMessage {
+                message_id,
+                content,
+            } => edits::edit_message(
+                &self.state,
+                self.room_id,
+                &self.username,
+                message_id,
+                content,
+            )
+            .map(|_| ()),
+            ClientFrame::DeleteMessage { message_id } => {
+                edits::delete_message(&self.state, self.room_id, &self.username, message_id)
+                    .map(|_| ())
+            }
+            ClientFrame::AddReaction { message_id, emoji } => reactions::set_reaction(
+                &self.state,
+                self.room_id,
+                &self.username,
+                message_id,
+                &emoji,
+                true,
+            ),
+            ClientFrame::RemoveReaction { message_id, emoji } => reactions::set_reaction(
+                &self.state,
+                self.room_id,
+                &self.username,
+                message_id,
+                &emoji,
+                false,
+            ),
+            ClientFrame::MarkRead { seq } => {
+                unread::mark_read(&self.state, &self.username, self.room_id, seq).map(|_| ())
+            }
+            ClientFrame::Ack { seq } => {
+                receipts::delivered(&self.state, self.room_id, &self.username, seq)
+            }
+            ClientFrame::TypingStart => {
+                typing::typing_started(&self.state, self.room_id, &self.username);
+                Ok(())
+            }
+            ClientFrame::TypingStop => {
+                typing::typing_stopped(&self.state, self.room_id, &self.username);
+                Ok(())
+            }
+            ClientFrame::HistoryRequest { before_seq, limit } => {
+                let room_id = self.state.resolve_room_id(self.room_id);
+                let rooms = self.state.chat_rooms.lock().unwrap();
+                let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+                // Access may have changed since the socket was opened
+                invites::check_access(&self.state, room, Some(&self.username))?;
+                bans::check(room, &self.username)?;
+                let mut page =
+                    history::page(&self.state, room, Some(&self.username), before_seq, limit);
+                drop(rooms);
+                page["type"] = "history_page".into();
+                self.send_text(ctx, page.to_string());
+                Ok(())
+            }
+        });
+        if let Err(err) = result {
+            self.send_text(ctx, err.to_frame(self.lang));
+        }
+    }
+}
+
+// WebSocket entry point
+async fn ws_handler(
+    req: HttpRequest,
+    stream: web::Payload,
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+) -> Result<HttpResponse, actix_web::Error> {
+    let query: HashMap<String, String> =
+        serde_urlencoded::from_str(req.query_string()).map_err(|_| ApiError::InvalidQuery)?;
+
+    let room_id = match query.get("externalId") {
+        Some(external_id) => external::resolve(&state, external_id)?,
+        None => query
+            .get("roomId")
+            .and_then(|id| Uuid::parse_str(id).ok())
+            .map(|id| state.resolve_room_id(id))
+            .ok_or(ApiError::InvalidRoomId)?,
+    };
+
+    // Sockets without a token used to share one "guest" name; now every
+    // session is a registered account, so there is no guest identity for
+    // registration to link or upgrade
+    let UserContext {
+        username,
+        expires_at: auth_expires_at,
+    } = user;
+
+    let role = match state.chat_rooms.lock().unwrap().get(&room_id) {
+        Some(room) => {
+            invites::check_access(&state, room, Some(&username))?;
+            bans::check(room, &username)?;
+            RoomRole::of(room, &username)
+        }
+        None => RoomRole::Member,
+    };
+    let session = ClientSession {
+        id: Uuid::new_v4(),
+        room_id,
+        username,
+        state: state.get_ref().clone(),
+        meta: ConnectionMeta::from_request(&req),
+        lang: i18n::request_lang(&req),
+        trace_parent: req
+            .headers()
+            .get("traceparent")
+            .and_then(|value| value.to_str().ok())
+            .and_then(telemetry::parse_traceparent),
+        subscriptions: None,
+        role,
+        auth_expires_at,
+        link: netsim::Link::default(),
+        traffic: Arc::new(Traffic::default()),
+        fragments: Reassembly::default(),
+        last_active: now_millis(),
+        idle: false,
+        unanswered_pings: 0,
+    };
+    ws::WsResponseBuilder::new(session, &req, stream)
+        .protocols(&sessions::SUPPORTED_PROTOCOLS)
+        .frame_size(protocol::MAX_FRAME_BYTES)
+        .start()
+}
+
+// REST Handlers
+async fn register_user(
+    state: web::Data<Arc<SharedState>>,
+    form: web::Json<UserRegistration>,
+) -> Result<HttpResponse, ApiError> {
+    let username = state.canonical_username(&form.username);
+    let bot_names = state.bots.lock().unwrap().names();
+    if bot_names.contains(&username) {
+        return Err(ApiError::UserExists);
+    }
+    // Hashed before taking the lock, which would otherwise stall every login
+    let password_hash = passwords::hash_blocking(form.password.clone()).await?;
+    let mut accounts = state.user_accounts.lock().unwrap();
+    if accounts.contains_key(&username) {
+        return Err(ApiError::UserExists);
+    }
+    if state
+        .username_policy
+        .confusable_with(&username, accounts.keys().chain(&bot_names))
+    {
+        return Err(ApiError::UsernameConfusable);
+    }
+    accounts.insert(username.clone(), password_hash);
+    drop(accounts);
+    if let Some(email) = form.email.as_ref().filter(|email| !email.trim().is_empty()) {
+        state
+            .user_emails
+            .lock()
+            .unwrap()
+            .insert(username.clone(), email.trim().to_string());
+    }
+    store::user_changed(&state, &username);
+    probation::enroll(&state, &username);
+    admin::join_default_rooms(&state, &username);
+    bots::user_registered(&state, &username);
+    Ok(HttpResponse::Ok().body("User registered successfully"))
+}
+
+async fn login_user(
+    state: web::Data<Arc<SharedState>>,
+    form: web::Json<UserLogin>,
+) -> Result<HttpResponse, ApiError> {
+    let username = state.canonical_username(&form.username);
+    let stored = state.user_accounts.lock().unwrap().get(&username).cloned();
+    let stored = stored.ok_or(ApiError::InvalidCredentials)?;
+    match passwords::verify_blocking(stored.clone(), form.password.clone()).await? {
+        Verified::Match => {}
+        Verified::LegacyMatch { hash } => {
+            // Only replace what we verified against, in case of a concurrent reset
+            let mut accounts = state.user_accounts.lock().unwrap();
+            if let Some(current) = accounts.get_mut(&username).filter(|c| **c == stored) {
+                *current = hash;
+                drop(accounts);
+                store::user_changed(&state, &username);
+            }
+        }
+        Verified::Mismatch => return Err(ApiError::InvalidCredentials),
+    }
+    let (token, expires_at) = state.auth_tokens.lock().unwrap().issue(&username);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Login successful",
+        "token": token,
+        "expires_at": expires_at,
+    })))
+}
+
+async fn create_chat_room(
+    req: HttpRequest,
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+    form: web::Json<RoomCreation>,
+) -> Result<HttpResponse, ApiError> {
+    user.claims(&state, form.creator.as_deref())?;
+    let creator = user.username;
+    if probation::restricted(&state, &creator) {
+        return Err(ApiError::ProbationRestricted);
+    }
+    let key = idempotency::key(&req)?;
+    // Checked and recorded under the rooms lock, so concurrent retries can't both create
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let mut creations = state.room_creations.lock().unwrap();
+    let earlier = creations.earlier(&creator, key.as_deref(), &form.name)?;
+    if let Some(room) = earlier.and_then(|room_id| rooms.get(&room_id)) {
+        return Ok(HttpResponse::Ok()
+            .insert_header(("idempotent-replayed", "true"))
+            .json(history::room_view(room)));
+    }
+    let mut room = ChatRoom::new(form.name.clone(), creator.clone());
+    room.tags = form.tags.clone();
+    room.private = form.private;
+    creations.record(&creator, key, &room.name, room.id);
+    drop(creations);
+    rooms.insert(room.id, room.clone());
+    drop(rooms);
+    store::room_changed(&state, &room);
+    state.notify_room_list_changed(&room.members(), "created", room.id);
+    Ok(HttpResponse::Ok().json(history::room_view(&room)))
+}
+
+async fn add_participant(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+    form: web::Json<AddParticipant>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(form.room_id);
+    let username = state.canonical_username(&form.username);
+    let is_bot = state.bots.lock().unwrap().names().contains(&username);
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    if room.direct {
+        return Err(ApiError::DirectRoomFixed);
+    }
+    // Anyone may join a room themselves; adding others is for its staff
+    let staff = roles::has_permission(&state, room, &user.username, Permission::AddParticipants);
+    if username != user.username && !staff {
+        return Err(ApiError::NotRoomModerator);
+    }
+    if room.private && !staff {
+        return Err(ApiError::InviteRequired);
+    }
+    bans::check(room, &username)?;
+    // Bots only enter rooms whose owner allowlisted them
+    if is_bot && !room.bot_allowlist.contains_key(&username) {
+        return Err(ApiError::BotNotAllowed);
+    }
+    let newly_added = room.participants.insert(username.clone());
+    if newly_added {
+        visibility::joined(room, &username);
+    }
+    state.invites.lock().unwrap().joined(room_id, &username);
+    let room = room.clone();
+    drop(rooms);
+    if newly_added {
+        store::room_changed(&state, &room);
+        state.notify_room_list_changed([&username], "joined", room_id);
+    }
+    let view = history::reader_view(&state, &room, Some(&user));
+    Ok(HttpResponse::Ok().json(view))
+}
+
+// Leaving a room, or being removed from it by staff. Moderators can only
+// remove members; taking out a moderator is for the owner.
+async fn remove_participant(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, String)>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, username) = path.into_inner();
+    let room_id = state.resolve_room_id(room_id);
+    let username = state.canonical_username(&username);
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    if username != user.username {
+        bans::check_removal(&state, room, &user.username, &username)?;
+    } else if room.direct {
+        return Err(ApiError::DirectRoomFixed);
+    } else if room.created_by == username {
+        return Err(ApiError::OwnerRoleFixed);
+    }
+    if !bans::remove(room, &username) {
+        return Err(ApiError::ParticipantNotFound);
+    }
+    store::room_changed(&state, room);
+    drop(rooms);
+
+    let reason = if username == user.username {
+        "left"
+    } else {
+        "removed"
+    };
+    bans::disconnect(&state, room_id, &username, reason);
+    state.notify_room_list_changed([&username], "left", room_id);
+    if username != user.username {
+        audit::record(
+            &state,
+            &user.username,
+            "remove_participant",
+            &room_id.to_string(),
+            username,
+        );
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn get_chat_room(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: Option<UserContext>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    invites::check_reader(&state, room, user.as_ref())?;
+    let view = history::reader_view(&state, room, user.as_ref());
+    Ok(HttpResponse::Ok().json(view))
+}
+
+async fn list_chat_rooms(
+    state: web::Data<Arc<SharedState>>,
+    user: Option<UserContext>,
+) -> HttpResponse {
+    let cold = state.cold_storage.lock().unwrap();
+    let rooms = state.chat_rooms.lock().unwrap();
+    // Direct conversations are listed per user under /dm, and private rooms
+    // only to their members. Rooms in cold storage come with just their newest
+    // message until GET /rooms/{id} loads them back.
+    let room_list: Vec<_> = rooms
+        .values()
+        .chain(cold.archived())
+        .filter(|room| !room.direct && invites::check_reader(&state, room, user.as_ref()).is_ok())
+        .map(|room| history::reader_view(&state, room, user.as_ref()))
+        .collect();
+    HttpResponse::Ok().json(room_list)
+}
+
+// Configuration for the background jobs that only a primary runs
+struct PrimaryJobs {
+    digest_interval: Duration,
+    cold_storage: Option<config::ColdStorageConfig>,
+    export: Option<config::ExportConfig>,
+    telegram: Option<config::TelegramConfig>,
+    redis_url: Option<String>,
+}
+
+fn spawn_primary_jobs(state: Arc<SharedState>, jobs: PrimaryJobs) {
+    state
+        .bots
+        .lock()
+        .unwrap()
+        .register(Arc::new(bots::WelcomeBot::from_env()));
+    retention::spawn_janitor(state.clone());
+    throttle::spawn_drainer(state.clone());
+    expiry::spawn_scheduler(state.clone());
+    digest::spawn_digester(state.clone(), jobs.digest_interval);
+    store::spawn_warmup(state.clone());
+    if let Some(cold_storage) = jobs.cold_storage {
+        *state.cold_storage.lock().unwrap() = ColdStorage::new(cold_storage);
+        cold::spawn_offloader(state.clone());
+    }
+    if let Some(export) = jobs.export {
+        export::spawn_exporter(state.clone(), export);
+    }
+    if let Some(telegram) = jobs.telegram {
+        telegram::spawn_poller(state.clone(), telegram);
+    }
+    if let Some(url) = jobs.redis_url {
+        cluster::spawn(state, url);
+    }
+}
+
+// Runs the HTTP and WebSocket server configured from the environment until
+// it is shut down
+pub async fn serve() -> std::io::Result<()> {
+    let config = config::Config::from_env();
+    outbound::configure(&config.outbound)?;
+    let standby = config
+        .replica
+        .as_ref()
+        .is_some_and(|replica| replica.standby.is_some());
+    // A standby keeps a store for after its promotion, but starts empty
+    let store = match config.database_url.as_deref() {
+        Some(_) if config.replica.is_some() && !standby => {
+            log::warn!("ignoring DATABASE_URL: replicas take their state from the primary");
+            None
+        }
+        Some(url) => {
+            Some(Store::open(url, config.deadlines.storage).map_err(std::io::Error::other)?)
+        }
+        None => None,
+    };
+    let state = Arc::new(SharedState {
+        username_policy: config.usernames,
+        node_id: config.node_id,
+        shadow_bans: Mutex::new(ShadowBans::with_honeypots(config.honeypot_rooms)),
+        probation: Mutex::new(Probation::new(config.probation)),
+        bridges: Mutex::new(Bridges::new(config.slack, config.email)),
+        default_rooms: Mutex::new(config.default_rooms),
+        replication_token: config.replication_token,
+        persistence: config.persistence,
+        deadlines: config.deadlines,
+        uploads: Uploads::new(config.uploads),
+        room_archive_dir: config.room_archive_dir,
+        coalescer: Coalescer::new(config.ephemeral_interval),
+        join_history: config.join_history,
+        heartbeat: config.heartbeat,
+        replica_of: Mutex::new(
+            config
+                .replica
+                .as_ref()
+                .map(|replica| replica.primary.clone()),
+        ),
+        store,
+        ..Default::default()
+    });
+    if !standby {
+        store::load(&state).map_err(std::io::Error::other)?;
+    }
+    state.admins.lock().unwrap().extend(
+        config
+            .admins
+            .iter()
+            .map(|name| state.canonical_username(name)),
+    );
+    telemetry::spawn_exporter();
+    typing::spawn_sweeper(state.clone());
+    coalesce::spawn_flusher(state.clone());
+    let jobs = PrimaryJobs {
+        digest_interval: config.digest_interval,
+        cold_storage: config.cold_storage,
+        export: config.export,
+        telegram: config.telegram,
+        redis_url: config.redis_url,
+    };
+    // A replica's rooms are overwritten from the primary, which alone runs
+    // the jobs that mutate them; a standby holds them back until promoted
+    match config.replica {
+        Some(replica) => {
+            replica::spawn_follower(state.clone(), replica.clone());
+            if replica.standby.is_some() {
+                *state.standby.lock().unwrap() = Some(Standby { replica, jobs });
+            }
+        }
+        None => spawn_primary_jobs(state.clone(), jobs),
+    }
+
+    let server_state = state.clone();
+    let max_upload_form = state.uploads.max_form_bytes();
+    HttpServer::new(move || {
+        App::new()
+            .wrap(
+                Cors::default()
+                    .allow_any_origin()
+                    .allow_any_header()
+                    .allow_any_method(),
+            )
+            .wrap(middleware::from_fn(deadline::enforce))
+            .wrap(middleware::from_fn(replica::read_only_guard))
+            .wrap(middleware::from_fn(error::localize_errors))
+            .wrap(middleware::from_fn(telemetry::trace_requests))
+            .app_data(web::Data::new(server_state.clone()))
+            .route(
+                "/capabilities",
+                web::get().to(capabilities::get_capabilities),
+            )
+            .route("/metrics", web::get().to(rejections::metrics))
+            .route("/register", web::post().to(register_user))
+            .route("/login", web::post().to(login_user))
+            .route(
+                "/account/recovery",
+                web::post().to(recovery::request_recovery),
+            )
+            .route(
+                "/account/recovery/reset",
+                web::post().to(recovery::reset_password),
+            )
+            .route("/create_room", web::post().to(create_chat_room))
+            .route("/add_user", web::post().to(add_participant))
+            .route("/list_rooms", web::get().to(list_chat_rooms))
+            .route("/invites", web::get().to(invites::list_invites))
+            .route(
+                "/invites/{token}/accept",
+                web::post().to(invites::accept_link),
+            )
+            .route("/invites/{token}", web::delete().to(invites::revoke_link))
+            .route("/dm", web::get().to(direct::list_direct_rooms))
+            .route("/dm/{username}", web::post().to(direct::open_direct_room))
+            .route("/rooms/lookup", web::get().to(external::lookup_room))
+            .route("/rooms/{id}", web::get().to(get_chat_room))
+            .route("/rooms/{id}", web::delete().to(deletion::delete_room))
+            .route("/rooms/{id}", web::patch().to(settings::update_room))
+            .route(
+                "/rooms/{id}/external_id",
+                web::put().to(external::set_external_id),
+            )
+            .route(
+                "/rooms/{id}/retention",
+                web::put().to(retention::set_room_retention),
+            )
+            .route(
+                "/rooms/{id}/announcement",
+                web::put().to(announcements::set_announcement),
+            )
+            .route(
+                "/rooms/{id}/link_approval",
+                web::put().to(approvals::set_link_approval),
+            )
+            .route(
+                "/rooms/{id}/pending",
+                web::get().to(approvals::list_pending),
+            )
+            .route(
+                "/rooms/{id}/pending/{message_id}",
+                web::post().to(approvals::decide),
+            )
+            .route(
+                "/rooms/{id}/rejections",
+                web::get().to(rejections::list_rejections),
+            )
+            .route(
+                "/rooms/{id}/feed.atom",
+                web::get().to(announcements::room_feed),
+            )
+            .route(
+                "/rooms/{id}/messages",
+                web::post().to(messages::post_message),
+            )
+            .route("/rooms/{id}/messages", web::get().to(history::message_page))
+            .service(
+                web::resource("/rooms/{id}/import")
+                    .app_data(web::PayloadConfig::new(import::MAX_IMPORT_BYTES))
+                    .route(web::post().to(import::import_history)),
+            )
+            .service(
+                web::resource("/rooms/{id}/attachments")
+                    .app_data(web::PayloadConfig::new(max_upload_form))
+                    .route(web::post().to(uploads::upload_attachment)),
+            )
+            .route(
+                "/rooms/{id}/attachments/{aid}",
+                web::get().to(uploads::download_attachment),
+            )
+            .route(
+                "/rooms/{id}/import/{job_id}",
+                web::get().to(import::import_status),
+            )
+            .route("/rooms/{id}/policy", web::put().to(policy::set_room_policy))
+            .route(
+                "/rooms/{id}/roles",
+                web::put().to(roles::set_participant_role),
+            )
+            .route(
+                "/rooms/{id}/moderators/{username}",
+                web::put().to(roles::grant_moderator),
+            )
+            .route(
+                "/rooms/{id}/moderators/{username}",
+                web::delete().to(roles::revoke_moderator),
+            )
+            .route(
+                "/rooms/{id}/participants/{username}",
+                web::delete().to(remove_participant),
+            )
+            .route("/rooms/{id}/kick", web::post().to(bans::kick_user))
+            .route("/rooms/{id}/ban", web::post().to(bans::ban_user))
+            .route("/rooms/{id}/bans", web::get().to(bans::list_bans))
+            .route(
+                "/rooms/{id}/bans/{username}",
+                web::delete().to(bans::lift_ban),
+            )
+            .route("/rooms/{id}/bots", web::get().to(bots::list_room_bots))
+            .route("/rooms/{id}/bots", web::post().to(bots::add_room_bot))
+            .route(
+                "/rooms/{id}/bots/allowlist",
+                web::put().to(bots::set_bot_allowlist),
+            )
+            .route(
+                "/rooms/{id}/messages/{mid}/context",
+                web::get().to(history::message_context),
+            )
+            .route(
+                "/rooms/{id}/messages/{mid}",
+                web::patch().to(edits::patch_message),
+            )
+            .route(
+                "/rooms/{id}/messages/{mid}",
+                web::delete().to(edits::remove_message),
+            )
+            .route(
+                "/rooms/{id}/messages/{mid}/thread",
+                web::get().to(threads::thread_replies),
+            )
+            .route(
+                "/rooms/{id}/messages/{mid}/edits",
+                web::get().to(edits::edit_history),
+            )
+            .route(
+                "/rooms/{id}/messages/{mid}/reactions/{emoji}",
+                web::put().to(reactions::add_reaction),
+            )
+            .route(
+                "/rooms/{id}/messages/{mid}/reactions/{emoji}",
+                web::delete().to(reactions::remove_reaction),
+            )
+            .route(
+                "/rooms/{id}/search",
+                web::get().to(search::search_room_messages),
+            )
+            .route("/search", web::get().to(search::search_all_rooms))
+            .route(
+                "/rooms/{id}/stats/export",
+                web::get().to(stats::export_room_stats),
+            )
+            .route(
+                "/rooms/{id}/notifications",
+                web::put().to(notifications::set_room_notifications),
+            )
+            .route("/rooms/{id}/invites", web::post().to(invites::invite_user))
+            .route(
+                "/rooms/{id}/accept_invite",
+                web::post().to(invites::accept_invite),
+            )
+            .route(
+                "/rooms/{id}/invites/{username}",
+                web::delete().to(invites::cancel_invite),
+            )
+            .route("/rooms/{id}/mute", web::post().to(mutes::mute_user))
+            .route(
+                "/rooms/{id}/mute/{username}",
+                web::delete().to(mutes::unmute_user),
+            )
+            .route(
+                "/rooms/{id}/receipts",
+                web::get().to(receipts::room_receipts),
+            )
+            .route(
+                "/rooms/{id}/messages/{mid}/receipts",
+                web::get().to(receipts::message_receipts),
+            )
+            .route(
+                "/rooms/{id}/messages/{mid}/allowed_actions",
+                web::get().to(policy::message_allowed_actions),
+            )
+            .route(
+                "/rooms/{id}/presence",
+                web::get().to(presence::get_room_presence),
+            )
+            .route(
+                "/rooms/{id}/participants",
+                web::get().to(presence::get_room_participants),
+            )
+            .route(
+                "/users/{username}/presence",
+                web::get().to(presence::get_user_presence),
+            )
+            .route(
+                "/users/{username}/bookmarks",
+                web::post().to(bookmarks::add_bookmark),
+            )
+            .route(
+                "/users/{username}/bookmarks",
+                web::get().to(bookmarks::list_bookmarks),
+            )
+            .route(
+                "/users/{username}/bookmarks/{message_id}",
+                web::delete().to(bookmarks::remove_bookmark),
+            )
+            .route(
+                "/users/{username}/keywords",
+                web::post().to(keywords::add_keyword),
+            )
+            .route(
+                "/users/{username}/keywords",
+                web::get().to(keywords::list_keywords),
+            )
+            .route(
+                "/users/{username}/keywords/{keyword}",
+                web::put().to(keywords::update_keyword),
+            )
+            .route(
+                "/users/{username}/keywords/{keyword}",
+                web::delete().to(keywords::remove_keyword),
+            )
+            .route(
+                "/users/{username}/mentions",
+                web::get().to(mentions::unread_mentions),
+            )
+            .route(
+                "/users/{username}/unread_summary",
+                web::get().to(unread::unread_summary),
+            )
+            .route(
+                "/users/{username}/read_markers/{room_id}",
+                web::put().to(unread::set_read_marker),
+            )
+            .route("/users/{username}/feed", web::get().to(digest::list_feed))
+            .route(
+                "/users/{username}/notices",
+                web::get().to(notices::list_notices),
+            )
+            .route(
+                "/users/{username}/export",
+                web::get().to(portability::export_user_data),
+            )
+            .route(
+                "/users/{username}/settings",
+                web::get().to(users::get_user_settings),
+            )
+            .route(
+                "/users/{username}/settings",
+                web::put().to(users::update_user_settings),
+            )
+            .route("/admin/audit_log", web::get().to(audit::list_audit_log))
+            .route("/admin/manifest", web::get().to(manifest::export_manifest))
+            .route("/admin/manifest", web::post().to(manifest::apply_manifest))
+            .route("/admin/notices", web::post().to(notices::broadcast_notice))
+            .route("/admin/sessions", web::get().to(sessions::list_sessions))
+            .route(
+                "/admin/dead_letters",
+                web::get().to(deadletter::list_dead_letters),
+            )
+            .route(
+                "/admin/dead_letters/{id}/retry",
+                web::post().to(deadletter::retry_dead_letter),
+            )
+            .route(
+                "/admin/default_rooms",
+                web::get().to(admin::list_default_rooms),
+            )
+            .route(
+                "/admin/default_rooms",
+                web::put().to(admin::set_default_rooms),
+            )
+            .route(
+                "/admin/cold_storage",
+                web::get().to(cold::cold_storage_status),
+            )
+            .route(
+                "/admin/shadow_bans",
+                web::get().to(shadowban::list_shadow_bans),
+            )
+            .route(
+                "/admin/shadow_bans",
+                web::put().to(shadowban::set_shadow_ban),
+            )
+            .route("/admin/bridges", web::get().to(bridges::list_bridges))
+            .route(
+                "/admin/bridges/slack",
+                web::put().to(bridges::set_slack_link),
+            )
+            .route("/admin/bridges/email", web::put().to(email::set_link))
+            .route("/admin/probation", web::get().to(probation::list_probation))
+            .route("/admin/probation", web::put().to(probation::set_probation))
+            .route("/admin/legal_holds", web::get().to(holds::list_legal_holds))
+            .route("/admin/legal_holds", web::put().to(holds::set_legal_hold))
+            .route("/admin/rooms/merge", web::post().to(admin::merge_rooms))
+            .route("/admin/rooms/import", web::post().to(admin::import_rooms))
+            .route(
+                "/admin/rooms/{id}/redact",
+                web::post().to(admin::redact_room),
+            )
+            .route(standby::PROMOTE_PATH, web::post().to(standby::promote))
+            .route("/replication/snapshot", web::get().to(replica::snapshot))
+            .route("/replication/demote", web::post().to(standby::demote))
+            .route("/bridges/slack/events", web::post().to(slack::events))
+            .route("/bridges/email/inbound", web::post().to(email::inbound))
+            .configure(telegram::configure)
+            .configure(netsim::configure)
+            .configure(seed::configure)
+            .route("/ws/", web::get().to(ws_handler))
+    })
+    .bind(config.bind)?
+    .run()
+    .await?;
+    // Changes still queued at shutdown would otherwise be lost
+    store::flush(&state);
+    Ok(())
+}
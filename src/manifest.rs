@@ -0,0 +1,286 @@
+// Server manifest: the non-secret configuration admins can change at
+// runtime, as one versioned document. GET /admin/manifest exports it;
+// POST /admin/manifest applies one, so an environment can be set up again
+// from a file. It covers the probation limits, honeypot filters, default
+// rooms, server admins and each room's retention classes, policy, flags,
+// history visibility and moderators. Tokens, passwords and anything read
+// from the environment at startup are left out.
+//
+// Sections missing from an applied manifest are left as they are. The whole
+// document is checked before anything changes, so a bad one leaves no
+// partial state behind. Rooms are matched by id and must already exist.
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::policy::RoomPolicy;
+use crate::probation::ProbationPolicy;
+use crate::retention::RetentionClass;
+use crate::roles::{self, RoleChanged, RoomRole};
+use crate::store;
+use crate::visibility::HistoryVisibility;
+use crate::{now_millis, ChatRoom, SharedState};
+
+// Bumped whenever a section changes meaning; older documents are refused
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct ServerManifest {
+    version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exported_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    limits: Option<Limits>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    filters: Option<Filters>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_rooms: Option<Vec<Uuid>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    admins: Option<BTreeSet<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rooms: Option<Vec<RoomConfig>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Limits {
+    probation: ProbationPolicy,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Filters {
+    honeypot_rooms: BTreeSet<Uuid>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RoomConfig {
+    id: Uuid,
+    #[serde(default)]
+    retention: RetentionClass,
+    #[serde(default)]
+    attachment_retention: RetentionClass,
+    #[serde(default)]
+    policy: RoomPolicy,
+    #[serde(default)]
+    link_approval: bool,
+    #[serde(default)]
+    private: bool,
+    #[serde(default)]
+    read_only: bool,
+    #[serde(default)]
+    history_visibility: HistoryVisibility,
+    #[serde(default)]
+    moderators: BTreeSet<String>,
+}
+
+impl RoomConfig {
+    fn of(room: &ChatRoom) -> Self {
+        RoomConfig {
+            id: room.id,
+            retention: room.retention,
+            attachment_retention: room.attachment_retention,
+            policy: room.policy.clone(),
+            link_approval: room.link_approval,
+            private: room.private,
+            read_only: room.read_only,
+            history_visibility: room.history_visibility,
+            moderators: room.moderators.iter().cloned().collect(),
+        }
+    }
+
+    // Role changes for live sessions, as (username, new role, previous role)
+    fn apply(self, room: &mut ChatRoom) -> Vec<(String, RoomRole, RoomRole)> {
+        room.retention = self.retention;
+        room.attachment_retention = self.attachment_retention;
+        room.policy = self.policy;
+        room.link_approval = self.link_approval;
+        room.private = self.private;
+        room.read_only = self.read_only;
+        room.history_visibility = self.history_visibility;
+        let moderators: HashSet<String> = self.moderators.into_iter().collect();
+        let mut changes = Vec::new();
+        for username in room.moderators.symmetric_difference(&moderators) {
+            let role = if moderators.contains(username) {
+                RoomRole::Moderator
+            } else {
+                RoomRole::Member
+            };
+            let previous = RoomRole::of(room, username);
+            changes.push((username.clone(), role, previous));
+        }
+        room.moderators = moderators;
+        changes
+    }
+}
+
+pub async fn export_manifest(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let probation = state.probation.lock().unwrap().policy();
+    let honeypot_rooms = state.shadow_bans.lock().unwrap().honeypot_rooms();
+    let default_rooms = state.default_rooms.lock().unwrap().clone();
+    let admins = state.admins.lock().unwrap().iter().cloned().collect();
+    // Rooms offloaded to cold storage keep their settings there and are left
+    // out; direct conversations have none to speak of
+    let rooms = state.chat_rooms.lock().unwrap();
+    let mut room_configs: Vec<RoomConfig> = rooms
+        .values()
+        .filter(|room| !room.direct)
+        .map(RoomConfig::of)
+        .collect();
+    drop(rooms);
+    room_configs.sort_by_key(|room| room.id);
+
+    Ok(HttpResponse::Ok().json(ServerManifest {
+        version: MANIFEST_VERSION,
+        exported_at: Some(now_millis()),
+        limits: Some(Limits { probation }),
+        filters: Some(Filters { honeypot_rooms }),
+        default_rooms: Some(default_rooms),
+        admins: Some(admins),
+        rooms: Some(room_configs),
+    }))
+}
+
+// Everything `apply_manifest` would refuse, before it changes anything
+fn check(state: &SharedState, manifest: &ServerManifest) -> Result<(), ApiError> {
+    if manifest.version != MANIFEST_VERSION {
+        return Err(ApiError::UnsupportedManifestVersion);
+    }
+    let rooms = state.chat_rooms.lock().unwrap();
+    let referenced = manifest
+        .default_rooms
+        .iter()
+        .flatten()
+        .chain(manifest.filters.iter().flat_map(|f| &f.honeypot_rooms));
+    for room_id in referenced {
+        if !rooms.contains_key(room_id) {
+            return Err(ApiError::RoomNotFound);
+        }
+    }
+    for config in manifest.rooms.iter().flatten() {
+        let room = rooms.get(&config.id).ok_or(ApiError::RoomNotFound)?;
+        if room.direct {
+            return Err(ApiError::DirectRoomFixed);
+        }
+        if !config.history_visibility.is_valid() {
+            return Err(ApiError::InvalidRoomSettings);
+        }
+        // Moderators are promoted from the room's participants, never the owner
+        let unknown = config
+            .moderators
+            .iter()
+            .any(|username| *username == room.created_by || !room.participants.contains(username));
+        if unknown {
+            return Err(ApiError::ParticipantNotFound);
+        }
+    }
+    Ok(())
+}
+
+pub async fn apply_manifest(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+    body: web::Json<ServerManifest>,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let actor = user.username.clone();
+    let mut manifest = body.into_inner();
+    manifest.default_rooms = manifest.default_rooms.map(|room_ids| {
+        let mut resolved = Vec::new();
+        for room_id in room_ids {
+            let room_id = state.resolve_room_id(room_id);
+            if !resolved.contains(&room_id) {
+                resolved.push(room_id);
+            }
+        }
+        resolved
+    });
+    // Loads any room still offloaded, so `check` sees every one it names
+    for config in manifest.rooms.iter_mut().flatten() {
+        config.id = state.resolve_room_id(config.id);
+    }
+    if let Some(filters) = &mut manifest.filters {
+        filters.honeypot_rooms = filters
+            .honeypot_rooms
+            .iter()
+            .map(|room_id| state.resolve_room_id(*room_id))
+            .collect();
+    }
+    check(&state, &manifest)?;
+
+    let mut applied = Vec::new();
+    if let Some(limits) = manifest.limits {
+        state.probation.lock().unwrap().set_policy(limits.probation);
+        applied.push("limits");
+    }
+    if let Some(filters) = manifest.filters {
+        state
+            .shadow_bans
+            .lock()
+            .unwrap()
+            .set_honeypot_rooms(filters.honeypot_rooms);
+        applied.push("filters");
+    }
+    if let Some(default_rooms) = manifest.default_rooms {
+        *state.default_rooms.lock().unwrap() = default_rooms;
+        applied.push("default_rooms");
+    }
+    if let Some(admins) = manifest.admins {
+        let mut current = state.admins.lock().unwrap();
+        *current = admins
+            .iter()
+            .map(|username| state.canonical_username(username))
+            .collect();
+        // Whoever applies the manifest can't lock themselves out with it
+        current.insert(actor.clone());
+        applied.push("admins");
+    }
+    if let Some(configs) = manifest.rooms {
+        let mut role_changes = Vec::new();
+        let mut rooms = state.chat_rooms.lock().unwrap();
+        for config in configs {
+            let Some(room) = rooms.get_mut(&config.id) else {
+                continue;
+            };
+            let room_id = room.id;
+            for (username, role, previous) in config.apply(room) {
+                role_changes.push(RoleChanged {
+                    room_id,
+                    username,
+                    role,
+                    previous,
+                    by: actor.clone(),
+                });
+            }
+            store::room_changed(&state, room);
+        }
+        drop(rooms);
+        for change in &role_changes {
+            roles::notify_role_changed(&state, change);
+        }
+        applied.push("rooms");
+    }
+
+    audit::record(
+        &state,
+        &actor,
+        "apply_manifest",
+        "server",
+        format!("version {}: {}", manifest.version, applied.join(", ")),
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "version": manifest.version,
+        "applied": applied,
+    })))
+}
@@ -0,0 +1,108 @@
+// `@username` mentions. The server resolves them when a message is appended
+// and keeps the mentioned members on the message; each of them gets a
+// `mention` event on every session they have open, whichever room it is in.
+// A mention is unread until the user's read marker in that room passes it.
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::history::HistoryEntry;
+use crate::keywords::excerpt;
+use crate::notifications;
+use crate::users::owned_username;
+use crate::{ChatMessage, ChatRoom, MessageKind, RoomEvent, SharedState};
+
+const DEFAULT_MENTIONS: usize = 50;
+const MAX_MENTIONS: usize = 200;
+
+// `@name` tokens in a message, before canonicalization
+fn mentioned_names(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .split(|c: char| c.is_whitespace() || matches!(c, ',' | '.' | '!' | '?' | ':' | ';'))
+        .filter_map(|word| word.strip_prefix('@'))
+        .filter(|name| !name.is_empty())
+}
+
+// Members of `room` the message mentions, in order, without the sender
+pub fn resolve(state: &SharedState, room: &ChatRoom, msg: &ChatMessage) -> Vec<String> {
+    if msg.kind != MessageKind::User {
+        return Vec::new();
+    }
+    let members = room.members();
+    let mut mentioned: Vec<String> = Vec::new();
+    for name in mentioned_names(&msg.content) {
+        let name = state.canonical_username(name);
+        if name != msg.sender && members.contains(&name) && !mentioned.contains(&name) {
+            mentioned.push(name);
+        }
+    }
+    mentioned
+}
+
+pub fn message_posted(state: &SharedState, msg: &ChatMessage) {
+    if msg.mentions.is_empty() {
+        return;
+    }
+    let notification = notifications::for_room(state, msg.room_id);
+    for username in &msg.mentions {
+        state.notify_user(
+            username,
+            RoomEvent(serde_json::json!({
+                "type": "mention",
+                "room_id": msg.room_id,
+                "message_id": msg.id,
+                "seq": msg.seq,
+                "sender": msg.sender,
+                "excerpt": excerpt(&msg.content),
+                "notification": notification,
+            })),
+        );
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MentionsQuery {
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+// Newest first, across every room the user is in
+pub async fn unread_mentions(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<String>,
+    user: UserContext,
+    query: web::Query<MentionsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let username = owned_username(&state, &path, &user)?;
+    let limit = query.limit.unwrap_or(DEFAULT_MENTIONS).min(MAX_MENTIONS);
+    let name = username.as_str();
+    let rooms = state.chat_rooms.lock().unwrap();
+    let unread = state.unread.lock().unwrap();
+    let mut mentions: Vec<_> = rooms
+        .values()
+        .filter(|room| room.members().contains(name))
+        .flat_map(|room| {
+            let read = unread.last_read_seq(name, room.id);
+            room.message_log.iter().filter(move |msg| {
+                msg.seq > read
+                    && msg.deleted_at.is_none()
+                    && msg.mentions.iter().any(|mentioned| mentioned == name)
+            })
+        })
+        .collect();
+    drop(unread);
+    mentions.sort_by_key(|msg| std::cmp::Reverse(msg.sent_at));
+    let total = mentions.len();
+    let entries: Vec<_> = mentions
+        .into_iter()
+        .take(limit)
+        .map(HistoryEntry::project)
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "username": username,
+        "total": total,
+        "mentions": entries,
+    })))
+}
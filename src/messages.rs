@@ -0,0 +1,347 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::approvals;
+use crate::attachments::{self, Attachment};
+use crate::auth::UserContext;
+use crate::bans;
+use crate::bots;
+use crate::bridges;
+use crate::cluster;
+use crate::deadletter::{self, Undelivered};
+use crate::deadline;
+use crate::embed;
+use crate::error::ApiError;
+use crate::expiry::MAX_TTL_SECS;
+use crate::invites;
+use crate::keywords;
+use crate::mentions;
+use crate::mutes;
+use crate::probation;
+use crate::ratelimit::Limit;
+use crate::rejections;
+use crate::roles;
+use crate::roomrefs;
+use crate::sanitize;
+use crate::settings;
+use crate::shadowban;
+use crate::store;
+use crate::telemetry;
+use crate::threads;
+use crate::throttle::{self, Admission};
+use crate::typing;
+use crate::uploads;
+use crate::versions::VersionVector;
+use crate::{now_millis, ChatMessage, MessageKind, RoomEvent, SharedState};
+
+// In graphemes, see `sanitize`
+pub const MAX_MESSAGE_LEN: usize = 4000;
+pub const MAX_MESSAGE_BYTES: usize = 32 * 1024;
+
+pub const SEND_LIMIT: Limit = Limit {
+    capacity: 10.0,
+    refill_per_sec: 2.0,
+};
+
+// Staff can flag a message as high priority. It skips the room's broadcast
+// throttle and carries `"priority": "high"` so clients can make it stand out.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn is_normal(&self) -> bool {
+        *self == Priority::Normal
+    }
+}
+
+// A send as the sender asked for it, before validation gives it a seq
+#[derive(Default)]
+pub struct Outgoing {
+    pub content: String,
+    pub ttl_seconds: Option<u64>,
+    pub attachments: Vec<Attachment>,
+    pub priority: Priority,
+    pub parent_message_id: Option<Uuid>,
+}
+
+pub fn send_message(
+    state: &Arc<SharedState>,
+    room_id: Uuid,
+    sender: &str,
+    outgoing: Outgoing,
+) -> Result<ChatMessage, ApiError> {
+    send_with_kind(state, room_id, sender, MessageKind::User, outgoing)
+}
+
+// The single send path shared by WS frames, the REST endpoint and bots:
+// validates, rate-limits, assigns the room sequence number, stores and broadcasts.
+pub fn send_with_kind(
+    state: &Arc<SharedState>,
+    room_id: Uuid,
+    sender: &str,
+    kind: MessageKind,
+    outgoing: Outgoing,
+) -> Result<ChatMessage, ApiError> {
+    let excerpt = rejections::excerpt(&outgoing.content);
+    try_send(state, room_id, sender, kind, outgoing)
+        .inspect_err(|err| rejections::record(state, room_id, sender, kind, excerpt, err))
+}
+
+fn try_send(
+    state: &Arc<SharedState>,
+    room_id: Uuid,
+    sender: &str,
+    kind: MessageKind,
+    outgoing: Outgoing,
+) -> Result<ChatMessage, ApiError> {
+    let Outgoing {
+        content,
+        ttl_seconds,
+        attachments,
+        priority,
+        parent_message_id,
+    } = outgoing;
+    let content = sanitize::clean(&content);
+    if kind != MessageKind::System {
+        invites::check_member(state, room_id, sender)?;
+        bans::check_room(state, room_id, sender)?;
+    }
+    let attachments = uploads::resolve(state, room_id, sender, attachments)?;
+    validate(&content, &attachments, ttl_seconds)?;
+    check_priority(state, room_id, sender, kind, priority)?;
+    mutes::check_send(state, room_id, sender, kind)?;
+    settings::check_send(state, room_id, sender, kind)?;
+    let parent_message_id = parent_message_id
+        .map(|parent| threads::thread_root(state, room_id, parent))
+        .transpose()?;
+    state.rate_limiter.check("send", sender, SEND_LIMIT)?;
+    probation::check_send(state, sender, kind, &content, &attachments)?;
+    let mut draft = compose(room_id, sender, content, ttl_seconds, kind, attachments);
+    draft.priority = priority;
+    draft.parent_message_id = parent_message_id;
+    if shadowban::screen(state, room_id, sender, kind) {
+        rejections::dropped(state, "shadow_banned");
+        return shadowban::echo_to_sender(state, draft);
+    }
+    if approvals::required(state, &draft) {
+        let held = approvals::hold(state, draft)?;
+        uploads::attached(state, &held.attachments);
+        return Ok(held);
+    }
+    let message = publish(state, draft)?;
+    uploads::attached(state, &message.attachments);
+    probation::message_sent(state, sender);
+    Ok(message)
+}
+
+// System messages may jump the queue; users only in rooms they moderate
+fn check_priority(
+    state: &SharedState,
+    room_id: Uuid,
+    sender: &str,
+    kind: MessageKind,
+    priority: Priority,
+) -> Result<(), ApiError> {
+    if priority.is_normal() || kind == MessageKind::System {
+        return Ok(());
+    }
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    if !roles::is_staff(state, room, sender) {
+        return Err(ApiError::NotRoomModerator);
+    }
+    Ok(())
+}
+
+pub fn validate(
+    content: &str,
+    attachments: &[Attachment],
+    ttl_seconds: Option<u64>,
+) -> Result<(), ApiError> {
+    // A message may be just attachments
+    if content.trim().is_empty() && attachments.is_empty() {
+        return Err(ApiError::EmptyMessage);
+    }
+    attachments::validate(attachments)?;
+    if content.len() > MAX_MESSAGE_BYTES || sanitize::grapheme_len(content) > MAX_MESSAGE_LEN {
+        return Err(ApiError::MessageTooLong);
+    }
+    if ttl_seconds.is_some_and(|ttl| ttl == 0 || ttl > MAX_TTL_SECS) {
+        return Err(ApiError::InvalidTtl);
+    }
+    Ok(())
+}
+
+// Stores an already validated draft under the room's next seq and delivers it
+pub fn publish(state: &Arc<SharedState>, draft: ChatMessage) -> Result<ChatMessage, ApiError> {
+    let room_id = draft.room_id;
+    typing::message_sent(state, room_id, &draft.sender);
+    // Holding the session list across the append keeps broadcast order equal to seq order
+    let sessions = deadline::lock_within(&state.active_sessions, state.deadlines.broadcast_wait)
+        .ok_or(ApiError::DeadlineExceeded)?;
+    let message = append_message(state, draft, true)?;
+    embed::message_appended(state, &message);
+    if let Some(expires_at) = message.expires_at {
+        state.expiry_queue.schedule(expires_at, room_id, message.id);
+    }
+
+    match state.broadcast_throttle.admit(&message) {
+        Admission::Deliver => {
+            let mut span = telemetry::span("broadcast.fanout");
+            let recipients = sessions.get(&room_id).map_or(&[][..], Vec::as_slice);
+            span.attr("recipients", recipients.len());
+            let dead = deadletter::fan_out(recipients, &message);
+            drop(sessions);
+            deadletter::record_undelivered(
+                state,
+                room_id,
+                dead,
+                Undelivered::Chat(Box::new(message.clone())),
+            );
+        }
+        Admission::Queued { newly_throttled } => {
+            drop(sessions);
+            if newly_throttled {
+                throttle::notify_moderators(
+                    state,
+                    room_id,
+                    RoomEvent(serde_json::json!({
+                        "type": "room_throttled",
+                        "room_id": room_id,
+                        "queued": state.broadcast_throttle.queued(room_id),
+                    })),
+                );
+            }
+        }
+    }
+    bots::message_posted(state, &message);
+    bridges::message_posted(state, &message);
+    keywords::message_posted(state, &message);
+    mentions::message_posted(state, &message);
+    Ok(message)
+}
+
+// A new message as it will be stored and broadcast, seq is assigned by `publish`
+pub fn compose(
+    room_id: Uuid,
+    sender: &str,
+    content: String,
+    ttl_seconds: Option<u64>,
+    kind: MessageKind,
+    attachments: Vec<Attachment>,
+) -> ChatMessage {
+    let sent_at = now_millis();
+    ChatMessage {
+        id: Uuid::new_v4(),
+        room_id,
+        sender: sender.to_string(),
+        content,
+        sent_at,
+        origin_room_id: None,
+        kind,
+        seq: 0,
+        imported_from: None,
+        expires_at: ttl_seconds.map(|ttl| sent_at + ttl * 1000),
+        edited_at: None,
+        edit_history: Vec::new(),
+        deleted_at: None,
+        reactions: BTreeMap::new(),
+        attachments,
+        version: VersionVector::default(),
+        bridged_from: None,
+        pending_since: None,
+        node_id: None,
+        priority: Priority::Normal,
+        parent_message_id: None,
+        mentions: Vec::new(),
+        room_refs: Vec::new(),
+    }
+}
+
+// Stores a server notice that went out live in a frame of its own. It isn't
+// delivered again and doesn't count as unread.
+pub fn record(state: &SharedState, notice: ChatMessage) -> Result<ChatMessage, ApiError> {
+    let message = append_message(state, notice, false)?;
+    embed::message_appended(state, &message);
+    Ok(message)
+}
+
+fn append_message(
+    state: &SharedState,
+    mut message: ChatMessage,
+    unread: bool,
+) -> Result<ChatMessage, ApiError> {
+    let _span = telemetry::span("storage.append");
+    let allocated = cluster::allocate_seqs(state, message.room_id, 1)?;
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    message.room_refs = roomrefs::resolve(state, &rooms, &message);
+    let room = rooms
+        .get_mut(&message.room_id)
+        .ok_or(ApiError::RoomNotFound)?;
+    message.seq = allocated.unwrap_or(room.next_seq + 1);
+    room.next_seq = room.next_seq.max(message.seq);
+    message.node_id = Some(state.node_id.clone());
+    message.mentions = mentions::resolve(state, room, &message);
+    // A peer's later seq may have landed first
+    let at = room
+        .message_log
+        .partition_point(|msg| msg.seq < message.seq);
+    room.message_log.insert(at, message.clone());
+    store::messages_changed(state, [&message]);
+    if unread {
+        state
+            .unread
+            .lock()
+            .unwrap()
+            .message_appended(room, &message);
+    }
+    Ok(message)
+}
+
+#[derive(Deserialize)]
+pub struct MessageSend {
+    // Optional now that the sender comes from the login token
+    #[serde(default)]
+    sender: Option<String>,
+    content: String,
+    #[serde(default)]
+    ttl_seconds: Option<u64>,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+    #[serde(default)]
+    priority: Priority,
+    // Replies in the thread under this message
+    #[serde(default)]
+    parent_message_id: Option<Uuid>,
+}
+
+pub async fn post_message(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<MessageSend>,
+) -> Result<HttpResponse, ApiError> {
+    user.claims(&state, form.sender.as_deref())?;
+    let room_id = state.resolve_room_id(path.into_inner());
+    let form = form.into_inner();
+    let outgoing = Outgoing {
+        content: form.content,
+        ttl_seconds: form.ttl_seconds,
+        attachments: form.attachments,
+        priority: form.priority,
+        parent_message_id: form.parent_message_id,
+    };
+    let message = send_message(&state, room_id, &user.username, outgoing)?;
+    if message.pending_since.is_some() {
+        return Ok(HttpResponse::Accepted().json(message));
+    }
+    Ok(HttpResponse::Ok().json(message))
+}
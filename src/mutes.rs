@@ -0,0 +1,185 @@
+// Time-limited mutes. Room staff can mute a member for a while; until it
+// lapses the member's posts in that room are refused with the time left.
+// Mutes are part of the room, so they survive restarts, and the expiry
+// scheduler lifts them once they run out.
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::notices::{self, NoticeKind, Severity};
+use crate::roles;
+use crate::store;
+use crate::{audit, now_millis, MessageKind, RoomEvent, SharedState};
+
+pub const MAX_MUTE_SECS: u64 = 30 * 24 * 60 * 60;
+
+// System messages are posted on the server's behalf, so mutes don't apply
+pub fn check_send(
+    state: &SharedState,
+    room_id: Uuid,
+    sender: &str,
+    kind: MessageKind,
+) -> Result<(), ApiError> {
+    if kind == MessageKind::System {
+        return Ok(());
+    }
+    let rooms = state.chat_rooms.lock().unwrap();
+    let Some(&until) = rooms.get(&room_id).and_then(|room| room.mutes.get(sender)) else {
+        return Ok(());
+    };
+    let now = now_millis();
+    if until <= now {
+        return Ok(());
+    }
+    Err(ApiError::Muted {
+        retry_after_ms: until - now,
+    })
+}
+
+fn announce(state: &SharedState, room_id: Uuid, username: &str, until: Option<u64>, by: &str) {
+    let event = match until {
+        Some(until) => serde_json::json!({
+            "type": "user_muted",
+            "room_id": room_id,
+            "username": username,
+            "until": until,
+            "by": by,
+        }),
+        None => serde_json::json!({
+            "type": "user_unmuted",
+            "room_id": room_id,
+            "username": username,
+            "by": by,
+        }),
+    };
+    state.broadcast_event(room_id, RoomEvent(event));
+}
+
+// Run from the expiry scheduler's tick
+pub fn lift_expired(state: &SharedState) {
+    let now = now_millis();
+    let mut lifted = Vec::new();
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    for room in rooms.values_mut() {
+        let before = room.mutes.len();
+        room.mutes.retain(|username, until| {
+            let active = *until > now;
+            if !active {
+                lifted.push((room.id, username.clone()));
+            }
+            active
+        });
+        if room.mutes.len() != before {
+            store::room_changed(state, room);
+        }
+    }
+    drop(rooms);
+
+    for (room_id, username) in lifted {
+        announce(state, room_id, &username, None, "system");
+        audit::record(
+            state,
+            "system",
+            "mute_expired",
+            &username,
+            format!("in room {}", room_id),
+        );
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MuteRequest {
+    username: String,
+    duration_secs: u64,
+}
+
+pub async fn mute_user(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<MuteRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if form.duration_secs == 0 || form.duration_secs > MAX_MUTE_SECS {
+        return Err(ApiError::InvalidMuteDuration);
+    }
+    let room_id = state.resolve_room_id(path.into_inner());
+    let actor = user.username;
+    let username = state.canonical_username(&form.username);
+    let until = now_millis() + form.duration_secs * 1000;
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    if !roles::is_staff(&state, room, &actor) {
+        return Err(ApiError::NotRoomModerator);
+    }
+    if !room.members().contains(&username) {
+        return Err(ApiError::ParticipantNotFound);
+    }
+    if roles::is_staff(&state, room, &username) {
+        return Err(ApiError::MuteNotAllowed);
+    }
+    room.mutes.insert(username.clone(), until);
+    store::room_changed(&state, room);
+    let room_name = room.name.clone();
+    drop(rooms);
+
+    announce(&state, room_id, &username, Some(until), &actor);
+    notices::send(
+        &state,
+        &username,
+        NoticeKind::Policy,
+        Severity::Warning,
+        format!(
+            "A moderator muted you in {} for {}s",
+            room_name, form.duration_secs
+        ),
+        Some(room_id),
+    );
+    audit::record(
+        &state,
+        &actor,
+        "mute_user",
+        &username,
+        format!("in room {} for {}s", room_id, form.duration_secs),
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "username": username,
+        "until": until,
+    })))
+}
+
+pub async fn unmute_user(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, String)>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, username) = path.into_inner();
+    let room_id = state.resolve_room_id(room_id);
+    let actor = user.username;
+    let username = state.canonical_username(&username);
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    if !roles::is_staff(&state, room, &actor) {
+        return Err(ApiError::NotRoomModerator);
+    }
+    let lifted = room.mutes.remove(&username).is_some();
+    if lifted {
+        store::room_changed(&state, room);
+    }
+    drop(rooms);
+
+    if lifted {
+        announce(&state, room_id, &username, None, &actor);
+        audit::record(
+            &state,
+            &actor,
+            "unmute_user",
+            &username,
+            format!("in room {}", room_id),
+        );
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
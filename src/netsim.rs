@@ -0,0 +1,189 @@
+// Simulated network conditions for exercising clients against slow links.
+// Admins attach a latency/bandwidth profile to a session or a whole room and
+// outbound WS frames for matching sessions are held back accordingly. Only
+// built with the `dev` feature; otherwise `Link::delay` is always None.
+use actix_web::web;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::SharedState;
+
+#[cfg_attr(not(feature = "dev"), allow(unused_variables))]
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    #[cfg(feature = "dev")]
+    cfg.route(
+        "/admin/network_profiles",
+        web::get().to(shaping::list_profiles),
+    )
+    .route(
+        "/admin/network_profiles",
+        web::put().to(shaping::set_profile),
+    );
+}
+
+// Per-session view of the simulated link
+#[derive(Default)]
+pub struct Link {
+    // When the last queued frame finishes "arriving"
+    #[cfg(feature = "dev")]
+    busy_until: Option<std::time::Instant>,
+}
+
+impl Link {
+    // How long to hold a frame of `len` bytes back, or None to send it now
+    #[cfg_attr(not(feature = "dev"), allow(unused_variables))]
+    pub fn delay(
+        &mut self,
+        state: &SharedState,
+        session_id: Uuid,
+        room_id: Uuid,
+        len: usize,
+    ) -> Option<Duration> {
+        #[cfg(feature = "dev")]
+        return shaping::delay(self, state, session_id, room_id, len);
+        #[cfg(not(feature = "dev"))]
+        None
+    }
+}
+
+#[cfg(feature = "dev")]
+pub use shaping::NetworkShaper;
+
+#[cfg(feature = "dev")]
+mod shaping {
+    use actix_web::{web, HttpResponse};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use uuid::Uuid;
+
+    use super::Link;
+    use crate::audit;
+    use crate::auth::UserContext;
+    use crate::error::ApiError;
+    use crate::SharedState;
+
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+    pub struct NetworkProfile {
+        #[serde(default)]
+        latency_ms: u64,
+        // Extra random delay of up to this much per frame; frames never reorder
+        #[serde(default)]
+        jitter_ms: u64,
+        #[serde(default)]
+        bandwidth_bytes_per_sec: Option<u64>,
+    }
+
+    // Session profiles take precedence over the profile of the session's room
+    #[derive(Default)]
+    pub struct NetworkShaper {
+        sessions: HashMap<Uuid, NetworkProfile>,
+        rooms: HashMap<Uuid, NetworkProfile>,
+    }
+
+    impl NetworkShaper {
+        fn profile_for(&self, session_id: Uuid, room_id: Uuid) -> Option<NetworkProfile> {
+            self.sessions
+                .get(&session_id)
+                .or_else(|| self.rooms.get(&room_id))
+                .copied()
+        }
+    }
+
+    pub fn delay(
+        link: &mut Link,
+        state: &SharedState,
+        session_id: Uuid,
+        room_id: Uuid,
+        len: usize,
+    ) -> Option<Duration> {
+        let Some(profile) = state
+            .network_shaper
+            .lock()
+            .unwrap()
+            .profile_for(session_id, room_id)
+        else {
+            link.busy_until = None;
+            return None;
+        };
+        let now = Instant::now();
+        let jitter = match profile.jitter_ms {
+            0 => 0,
+            max => (Uuid::new_v4().as_u128() % (max as u128 + 1)) as u64,
+        };
+        let transfer = profile
+            .bandwidth_bytes_per_sec
+            .map_or(Duration::ZERO, |rate| {
+                Duration::from_secs_f64(len as f64 / rate.max(1) as f64)
+            });
+        // Frames queue behind each other like on a real link, so a frame
+        // can't overtake the one before it even with jitter
+        let arrives = (now + Duration::from_millis(profile.latency_ms + jitter))
+            .max(link.busy_until.unwrap_or(now))
+            + transfer;
+        link.busy_until = Some(arrives);
+        Some(arrives - now)
+    }
+
+    #[derive(Deserialize)]
+    pub struct ProfileUpdate {
+        #[serde(default)]
+        session_id: Option<Uuid>,
+        #[serde(default)]
+        room_id: Option<Uuid>,
+        // None removes the profile
+        #[serde(default)]
+        profile: Option<NetworkProfile>,
+    }
+
+    pub async fn list_profiles(
+        state: web::Data<Arc<SharedState>>,
+        user: UserContext,
+    ) -> Result<HttpResponse, ApiError> {
+        if !state.is_admin(&user.username) {
+            return Err(ApiError::AdminRequired);
+        }
+        let shaper = state.network_shaper.lock().unwrap();
+        Ok(HttpResponse::Ok().json(serde_json::json!({
+            "sessions": shaper.sessions,
+            "rooms": shaper.rooms,
+        })))
+    }
+
+    pub async fn set_profile(
+        state: web::Data<Arc<SharedState>>,
+        user: UserContext,
+        form: web::Json<ProfileUpdate>,
+    ) -> Result<HttpResponse, ApiError> {
+        let form = form.into_inner();
+        if !state.is_admin(&user.username) {
+            return Err(ApiError::AdminRequired);
+        }
+        let room_id = form.room_id.map(|room_id| state.resolve_room_id(room_id));
+        let mut shaper = state.network_shaper.lock().unwrap();
+        let (profiles, target) = match (form.session_id, room_id) {
+            (Some(session_id), None) => (&mut shaper.sessions, session_id),
+            (None, Some(room_id)) => (&mut shaper.rooms, room_id),
+            // Exactly one of session_id or room_id
+            _ => return Err(ApiError::InvalidQuery),
+        };
+        match form.profile {
+            Some(profile) => profiles.insert(target, profile),
+            None => profiles.remove(&target),
+        };
+        drop(shaper);
+
+        audit::record(
+            &state,
+            &user.username,
+            "set_network_profile",
+            &target.to_string(),
+            format!("{:?}", form.profile),
+        );
+        Ok(HttpResponse::Ok().json(serde_json::json!({
+            "target": target,
+            "profile": form.profile,
+        })))
+    }
+}
@@ -0,0 +1,171 @@
+// Server notices: messages from the server itself to one user, such as
+// security alerts after a password reset, policy actions like mutes, and
+// maintenance announcements from admins. They are pushed to all of the
+// user's connections as `server_notice` frames, which event subscriptions
+// don't filter, and kept for GET /users/{username}/notices. Unlike feed
+// items they are not about activity in rooms the user follows, and unlike
+// room events nobody else sees them.
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::users::owned_username;
+use crate::{now_millis, RoomEvent, SharedState};
+
+// Oldest notices are dropped once a user has more than this
+const NOTICE_CAPACITY: usize = 100;
+const MAX_NOTICE_CHARS: usize = 2000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NoticeKind {
+    Security,
+    Policy,
+    Maintenance,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Notice {
+    id: Uuid,
+    at: u64,
+    kind: NoticeKind,
+    severity: Severity,
+    text: String,
+    // Set on notices about something that happened in one room
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_id: Option<Uuid>,
+}
+
+// Per-user mailbox of notices, newest last
+#[derive(Default)]
+pub struct Notices {
+    items: HashMap<String, VecDeque<Notice>>,
+}
+
+impl Notices {
+    fn push(&mut self, username: &str, notice: Notice) {
+        let notices = self.items.entry(username.to_string()).or_default();
+        if notices.len() >= NOTICE_CAPACITY {
+            notices.pop_front();
+        }
+        notices.push_back(notice);
+    }
+}
+
+// Stores the notice and delivers it to whichever connections the user has
+pub fn send(
+    state: &SharedState,
+    username: &str,
+    kind: NoticeKind,
+    severity: Severity,
+    text: String,
+    room_id: Option<Uuid>,
+) {
+    let notice = Notice {
+        id: Uuid::new_v4(),
+        at: now_millis(),
+        kind,
+        severity,
+        text,
+        room_id,
+    };
+    state.notices.lock().unwrap().push(username, notice.clone());
+    state.notify_user(
+        username,
+        RoomEvent(serde_json::json!({ "type": "server_notice", "notice": notice })),
+    );
+}
+
+#[derive(Deserialize)]
+pub struct NoticeQuery {
+    // Only notices after this time, for clients catching up after a reconnect
+    #[serde(default)]
+    since: Option<u64>,
+}
+
+pub async fn list_notices(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<String>,
+    user: UserContext,
+    query: web::Query<NoticeQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let username = owned_username(&state, &path, &user)?;
+    let since = query.since.unwrap_or(0);
+    let notices = state.notices.lock().unwrap();
+    let items: Vec<_> = notices
+        .items
+        .get(&username)
+        .into_iter()
+        .flat_map(|notices| notices.iter().rev())
+        .filter(|notice| notice.at > since)
+        .collect();
+    Ok(HttpResponse::Ok().json(items))
+}
+
+#[derive(Deserialize)]
+pub struct NoticeBroadcast {
+    text: String,
+    #[serde(default)]
+    severity: Severity,
+    // Everyone with an account when left out
+    #[serde(default)]
+    usernames: Option<Vec<String>>,
+}
+
+// Maintenance announcements from an admin
+pub async fn broadcast_notice(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+    form: web::Json<NoticeBroadcast>,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let text = form.text.trim();
+    if text.is_empty() || text.chars().count() > MAX_NOTICE_CHARS {
+        return Err(ApiError::InvalidNotice);
+    }
+    let accounts = state.user_accounts.lock().unwrap();
+    let recipients: Vec<String> = match &form.usernames {
+        Some(usernames) => usernames
+            .iter()
+            .map(|name| state.canonical_username(name))
+            .filter(|name| accounts.contains_key(name))
+            .collect(),
+        None => accounts.keys().cloned().collect(),
+    };
+    drop(accounts);
+
+    for username in &recipients {
+        send(
+            &state,
+            username,
+            NoticeKind::Maintenance,
+            form.severity,
+            text.to_string(),
+            None,
+        );
+    }
+    audit::record(
+        &state,
+        &user.username,
+        "broadcast_notice",
+        &format!("{} users", recipients.len()),
+        text.to_string(),
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "recipients": recipients.len() })))
+}
@@ -0,0 +1,103 @@
+// How clients should notify users about a room: a sound to play and an
+// importance level. The server doesn't act on either; it returns them with
+// the room and attaches them to the notifications it sends about the room's
+// messages (mention and keyword alerts, email relays), so apps can treat
+// each room the way its owner configured.
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::roles::{self, Permission};
+use crate::store;
+use crate::SharedState;
+
+const MAX_SOUND_CHARS: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Importance {
+    // Clients should notify silently, if at all
+    Low,
+    #[default]
+    Normal,
+    // Clients may notify even where users silenced other rooms
+    High,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct NotificationSettings {
+    // A name from the client's own sound set; None means the client's default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sound: Option<String>,
+    #[serde(default)]
+    pub importance: Importance,
+}
+
+// Sound names are looked up by clients, not served, so plain identifiers only
+fn valid_sound(sound: &str) -> bool {
+    !sound.is_empty()
+        && sound.chars().count() <= MAX_SOUND_CHARS
+        && sound
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+// For payloads sent after the room lock is released
+pub fn for_room(state: &SharedState, room_id: Uuid) -> NotificationSettings {
+    state
+        .chat_rooms
+        .lock()
+        .unwrap()
+        .get(&room_id)
+        .map(|room| room.notifications.clone())
+        .unwrap_or_default()
+}
+
+pub async fn set_room_notifications(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<NotificationSettings>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let actor = user.username;
+    let settings = form.into_inner();
+    if settings
+        .sound
+        .as_deref()
+        .is_some_and(|sound| !valid_sound(sound))
+    {
+        return Err(ApiError::InvalidNotificationSound);
+    }
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    roles::check(&state, room, &actor, Permission::ChangeSettings)?;
+    let changed = room.notifications != settings;
+    room.notifications = settings.clone();
+    store::room_changed(&state, room);
+    let members = room.members();
+    drop(rooms);
+
+    if changed {
+        state.notify_room_list_changed(&members, "updated", room_id);
+        audit::record(
+            &state,
+            &actor,
+            "set_room_notifications",
+            &room_id.to_string(),
+            format!(
+                "sound: {}, importance: {:?}",
+                settings.sound.as_deref().unwrap_or("default"),
+                settings.importance
+            ),
+        );
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "notifications": settings,
+    })))
+}
@@ -0,0 +1,373 @@
+// Outbound HTTP. Webhooks, bridges, S3, replication and trace export all get
+// their awc client from here, so every request the server makes honors the
+// same proxy and trust settings. HTTPS_PROXY and HTTP_PROXY (or their
+// lowercase forms) name an http:// proxy for https and http targets, with
+// the percent-decoded user:password from the URL as basic auth. For https it
+// is asked to CONNECT to the target and TLS runs through the tunnel as it
+// would directly; plain http requests go to it in absolute form, the way
+// forward proxies expect, since many refuse CONNECT to ports other than
+// 443. NO_PROXY lists
+// hosts, and their subdomains, reached without it ("*" for all of them).
+// OUTBOUND_CA_FILE adds the PEM certificates in it to the system roots, for
+// intercepting proxies and services signed by a private CA.
+use actix_http::{RequestHead, RequestHeadType};
+use actix_service::Service;
+use actix_tls::connect::{ConnectError, ConnectInfo, Connection};
+use actix_web::rt::net::TcpStream;
+use awc::error::SendRequestError;
+use awc::http::header::{HeaderValue, PROXY_AUTHORIZATION};
+use awc::http::{uri, Uri};
+use awc::middleware::Transform;
+use awc::{ConnectRequest, ConnectResponse};
+use base64::Engine;
+use openssl::ssl::{SslConnector, SslMethod};
+use openssl::x509::X509;
+use percent_encoding::percent_decode_str;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::config::OutboundConfig;
+
+// Plenty for a status line and a few headers
+const MAX_CONNECT_RESPONSE: usize = 8 * 1024;
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+struct Settings {
+    http_proxy: Option<Proxy>,
+    https_proxy: Option<Proxy>,
+    no_proxy: Vec<String>,
+    // Built once; the OpenSSL context is slow to set up
+    tls: SslConnector,
+}
+
+struct Proxy {
+    host: String,
+    port: u16,
+    // Proxy-Authorization header value
+    auth: Option<String>,
+}
+
+impl Proxy {
+    fn parse(url: &str) -> io::Result<Proxy> {
+        let invalid = |why: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid proxy {:?}: {}", url, why),
+            )
+        };
+        // A bare host:port is taken as http://
+        let uri: Uri = if url.contains("://") {
+            url.parse()
+        } else {
+            format!("http://{}", url).parse()
+        }
+        .map_err(|_| invalid("not a URL"))?;
+        if uri.scheme_str() != Some("http") {
+            return Err(invalid("only http:// proxies are supported"));
+        }
+        let authority = uri.authority().ok_or_else(|| invalid("no host"))?;
+        let auth = match authority.as_str().rsplit_once('@') {
+            Some((userinfo, _)) => {
+                let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                let decode = |part: &str| {
+                    percent_decode_str(part)
+                        .decode_utf8()
+                        .map(|part| part.into_owned())
+                        .map_err(|_| invalid("credentials are not UTF-8"))
+                };
+                let credentials = format!("{}:{}", decode(user)?, decode(password)?);
+                let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+                Some(format!("Basic {}", encoded))
+            }
+            None => None,
+        };
+        Ok(Proxy {
+            host: authority.host().to_string(),
+            port: authority.port_u16().unwrap_or(80),
+            auth,
+        })
+    }
+}
+
+impl Settings {
+    fn new(config: &OutboundConfig) -> io::Result<Settings> {
+        let proxy = |url: &Option<String>| url.as_deref().map(Proxy::parse).transpose();
+        let mut tls = SslConnector::builder(SslMethod::tls()).map_err(io::Error::other)?;
+        if let Err(err) = tls.set_alpn_protos(b"\x02h2\x08http/1.1") {
+            log::error!("cannot set ALPN protocols: {}", err);
+        }
+        if let Some(path) = &config.ca_file {
+            let pem = std::fs::read(path).map_err(|err| {
+                io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+            })?;
+            let certs = X509::stack_from_pem(&pem).map_err(io::Error::other)?;
+            if certs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no certificates in {}", path.display()),
+                ));
+            }
+            for cert in certs {
+                tls.cert_store_mut()
+                    .add_cert(cert)
+                    .map_err(io::Error::other)?;
+            }
+        }
+        Ok(Settings {
+            http_proxy: proxy(&config.http_proxy)?,
+            https_proxy: proxy(&config.https_proxy)?,
+            no_proxy: config
+                .no_proxy
+                .iter()
+                .map(|host| host.trim_start_matches('.').to_ascii_lowercase())
+                .collect(),
+            tls: tls.build(),
+        })
+    }
+
+    fn proxy_for(&self, uri: &Uri) -> Option<&Proxy> {
+        let proxy = match uri.scheme_str() {
+            Some("https") => self.https_proxy.as_ref(),
+            _ => self.http_proxy.as_ref(),
+        }?;
+        let host = uri.host().unwrap_or("").to_ascii_lowercase();
+        let bypassed = self.no_proxy.iter().any(|entry| {
+            entry == "*"
+                || host == *entry
+                || host
+                    .strip_suffix(entry.as_str())
+                    .is_some_and(|rest| rest.ends_with('.'))
+        });
+        (!bypassed).then_some(proxy)
+    }
+
+    // The proxy a plain http request is forwarded through, rather than
+    // tunnelled
+    fn forwarding_proxy(&self, uri: &Uri) -> Option<&Proxy> {
+        self.proxy_for(uri)
+            .filter(|_| uri.scheme_str() == Some("http"))
+    }
+}
+
+fn settings() -> &'static Settings {
+    SETTINGS
+        .get_or_init(|| Settings::new(&OutboundConfig::default()).expect("default TLS settings"))
+}
+
+// At startup, before anything makes a request
+pub fn configure(config: &OutboundConfig) -> io::Result<()> {
+    let settings = Settings::new(config)?;
+    for (scheme, proxy) in [
+        ("http", &settings.http_proxy),
+        ("https", &settings.https_proxy),
+    ] {
+        if let Some(proxy) = proxy {
+            log::info!(
+                "outbound {} requests go through {}:{}",
+                scheme,
+                proxy.host,
+                proxy.port
+            );
+        }
+    }
+    if SETTINGS.set(settings).is_err() {
+        log::warn!("outbound HTTP settings were already in use; keeping them");
+    }
+    Ok(())
+}
+
+pub fn client() -> awc::Client {
+    build(None)
+}
+
+// For long polls and other requests that may outlast awc's default timeout
+pub fn client_with_timeout(timeout: Duration) -> awc::Client {
+    build(Some(timeout))
+}
+
+fn build(timeout: Option<Duration>) -> awc::Client {
+    let settings = settings();
+    let mut builder = awc::Client::builder();
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    let connector = awc::Connector::new().openssl(settings.tls.clone());
+    if settings.http_proxy.is_none() && settings.https_proxy.is_none() {
+        builder.connector(connector).finish()
+    } else {
+        builder
+            .connector(connector.connector(Tunnel { settings }))
+            .wrap(AbsoluteForm { settings })
+            .finish()
+    }
+}
+
+// Opens TCP connections for awc, through the proxy where one applies
+#[derive(Clone)]
+struct Tunnel {
+    settings: &'static Settings,
+}
+
+impl Service<ConnectInfo<Uri>> for Tunnel {
+    type Response = Connection<Uri, TcpStream>;
+    type Error = ConnectError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    actix_service::always_ready!();
+
+    fn call(&self, req: ConnectInfo<Uri>) -> Self::Future {
+        let settings = self.settings;
+        Box::pin(async move {
+            let uri = req.request().clone();
+            let target = format!("{}:{}", req.hostname(), req.port());
+            let stream = match settings.proxy_for(&uri) {
+                Some(proxy) if settings.forwarding_proxy(&uri).is_some() => {
+                    TcpStream::connect((proxy.host.as_str(), proxy.port)).await
+                }
+                Some(proxy) => connect_via(proxy, &target).await,
+                None => TcpStream::connect(&target).await,
+            }
+            .map_err(ConnectError::Io)?;
+            Ok(Connection::new(uri, stream))
+        })
+    }
+}
+
+// Rewrites plain http requests bound for the proxy into absolute form:
+// awc writes the path and query of the URI as the request target, so the
+// whole URL goes there, and the credentials go in Proxy-Authorization
+struct AbsoluteForm {
+    settings: &'static Settings,
+}
+
+impl<S> Transform<S, ConnectRequest> for AbsoluteForm
+where
+    S: Service<ConnectRequest, Response = ConnectResponse, Error = SendRequestError>,
+{
+    type Transform = AbsoluteFormService<S>;
+
+    fn new_transform(self, service: S) -> Self::Transform {
+        AbsoluteFormService {
+            settings: self.settings,
+            service,
+        }
+    }
+}
+
+struct AbsoluteFormService<S> {
+    settings: &'static Settings,
+    service: S,
+}
+
+impl<S> Service<ConnectRequest> for AbsoluteFormService<S>
+where
+    S: Service<ConnectRequest, Response = ConnectResponse, Error = SendRequestError>,
+{
+    type Response = ConnectResponse;
+    type Error = SendRequestError;
+    type Future = S::Future;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ConnectRequest) -> Self::Future {
+        let req = match req {
+            ConnectRequest::Client(head, body, addr) => {
+                let uri = match &head {
+                    RequestHeadType::Owned(head) => &head.uri,
+                    RequestHeadType::Rc(head, _) => &head.uri,
+                };
+                match self.settings.forwarding_proxy(uri) {
+                    Some(proxy) => {
+                        let head = absolute_form(owned(head), proxy);
+                        ConnectRequest::Client(RequestHeadType::Owned(head), body, addr)
+                    }
+                    None => ConnectRequest::Client(head, body, addr),
+                }
+            }
+            tunnel => tunnel,
+        };
+        self.service.call(req)
+    }
+}
+
+fn owned(head: RequestHeadType) -> RequestHead {
+    match head {
+        RequestHeadType::Owned(head) => head,
+        RequestHeadType::Rc(head, extra) => {
+            let mut head = (*head).clone();
+            // Extra headers replace the shared ones of the same name
+            if let Some(extra) = extra {
+                for name in extra.keys() {
+                    head.headers.remove(name);
+                }
+                for (name, value) in extra {
+                    head.headers.append(name, value);
+                }
+            }
+            head
+        }
+    }
+}
+
+fn absolute_form(mut head: RequestHead, proxy: &Proxy) -> RequestHead {
+    let target = head.uri.to_string();
+    let mut parts = head.uri.clone().into_parts();
+    parts.path_and_query = target.parse::<uri::PathAndQuery>().ok();
+    match Uri::from_parts(parts) {
+        Ok(uri) => head.uri = uri,
+        Err(err) => log::warn!(
+            "cannot send {} to the proxy in absolute form: {}",
+            target,
+            err
+        ),
+    }
+    if let Some(value) = proxy
+        .auth
+        .as_deref()
+        .and_then(|auth| HeaderValue::from_str(auth).ok())
+    {
+        head.headers.insert(PROXY_AUTHORIZATION, value);
+    }
+    head
+}
+
+async fn connect_via(proxy: &Proxy, target: &str) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port)).await?;
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some(auth) = &proxy.auth {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", auth));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Byte by byte, so nothing meant for the tunnel is read along with it
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_CONNECT_RESPONSE {
+            return Err(io::Error::other("proxy response too long"));
+        }
+        let mut byte = [0u8];
+        if stream.read(&mut byte).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "proxy closed the connection",
+            ));
+        }
+        response.push(byte[0]);
+    }
+    let status_line = String::from_utf8_lossy(&response);
+    let status_line = status_line.lines().next().unwrap_or("");
+    let status = status_line.split_whitespace().nth(1);
+    if status != Some("200") {
+        return Err(io::Error::other(format!(
+            "proxy refused CONNECT to {}: {}",
+            target, status_line
+        )));
+    }
+    Ok(stream)
+}
@@ -0,0 +1,68 @@
+// Password storage. `user_accounts` holds Argon2id hashes in PHC string
+// format; anything else found there is a plaintext password from before
+// hashing, accepted once and replaced with a hash on the next login.
+//
+// Argon2 is slow on purpose, so handlers call `hash_blocking` and
+// `verify_blocking`, which run it on the blocking pool instead of stalling
+// a worker that serves other requests.
+use actix_web::web;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+
+pub enum Verified {
+    Match,
+    // Correct, but stored in plaintext; the caller should store `hash` instead
+    LegacyMatch { hash: String },
+    Mismatch,
+}
+
+pub fn is_hashed(stored: &str) -> bool {
+    stored.starts_with("$argon2")
+}
+
+pub fn hash(password: &str) -> String {
+    // A v4 uuid is 122 random bits, plenty for a salt
+    let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes()).expect("16 bytes is a valid salt");
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("default Argon2 parameters accept any password")
+        .to_string()
+}
+
+pub fn verify(stored: &str, password: &str) -> Verified {
+    if !is_hashed(stored) {
+        return if stored == password {
+            Verified::LegacyMatch {
+                hash: hash(password),
+            }
+        } else {
+            Verified::Mismatch
+        };
+    }
+    let matched = PasswordHash::new(stored).is_ok_and(|parsed| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok()
+    });
+    if matched {
+        Verified::Match
+    } else {
+        Verified::Mismatch
+    }
+}
+
+// A blocking pool that can't take the job means the server is shutting down
+pub async fn hash_blocking(password: String) -> Result<String, ApiError> {
+    web::block(move || hash(&password))
+        .await
+        .map_err(|_| ApiError::DeadlineExceeded)
+}
+
+pub async fn verify_blocking(stored: String, password: String) -> Result<Verified, ApiError> {
+    web::block(move || verify(&stored, &password))
+        .await
+        .map_err(|_| ApiError::DeadlineExceeded)
+}
@@ -0,0 +1,138 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::history;
+use crate::invites;
+use crate::roles::{self, Permission};
+use crate::store;
+use crate::visibility;
+use crate::{audit, now_millis, ChatMessage, SharedState};
+
+const DEFAULT_EDIT_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    #[default]
+    User,
+    System,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mutation {
+    React,
+    Reply,
+    Edit,
+}
+
+// Which message kinds accept which mutations in a room. Every handler that
+// changes an existing message must go through `RoomPolicy::check`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomPolicy {
+    pub reactable_kinds: HashSet<MessageKind>,
+    pub repliable_kinds: HashSet<MessageKind>,
+    pub editable_kinds: HashSet<MessageKind>,
+    // None means messages stay editable forever
+    pub edit_window_secs: Option<u64>,
+}
+
+impl Default for RoomPolicy {
+    fn default() -> Self {
+        let user_only: HashSet<_> = [MessageKind::User].into();
+        RoomPolicy {
+            reactable_kinds: user_only.clone(),
+            repliable_kinds: user_only.clone(),
+            editable_kinds: user_only,
+            edit_window_secs: Some(DEFAULT_EDIT_WINDOW_SECS),
+        }
+    }
+}
+
+impl RoomPolicy {
+    pub fn check(&self, msg: &ChatMessage, mutation: Mutation) -> Result<(), ApiError> {
+        let kinds = match mutation {
+            Mutation::React => &self.reactable_kinds,
+            Mutation::Reply => &self.repliable_kinds,
+            Mutation::Edit => &self.editable_kinds,
+        };
+        // Tombstones keep their place in history but take no further changes
+        if msg.deleted_at.is_some() || !kinds.contains(&msg.kind) {
+            return Err(ApiError::MutationNotAllowed);
+        }
+        if mutation == Mutation::Edit {
+            if let Some(window) = self.edit_window_secs {
+                if now_millis().saturating_sub(msg.sent_at) > window * 1000 {
+                    return Err(ApiError::EditWindowExpired);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PolicyUpdate {
+    policy: RoomPolicy,
+}
+
+#[derive(Serialize)]
+struct AllowedActions {
+    react: bool,
+    reply: bool,
+    edit: bool,
+}
+
+pub async fn set_room_policy(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<PolicyUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let form = form.into_inner();
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    roles::check(&state, room, &user.username, Permission::ChangeSettings)?;
+
+    room.policy = form.policy;
+    store::room_changed(&state, room);
+    let detail = format!("{:?}", room.policy);
+    let room = room.clone();
+    drop(rooms);
+
+    audit::record(
+        &state,
+        &user.username,
+        "set_policy",
+        &room_id.to_string(),
+        detail,
+    );
+    Ok(HttpResponse::Ok().json(history::room_view(&room)))
+}
+
+pub async fn message_allowed_actions(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, Uuid)>,
+    user: Option<UserContext>,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, message_id) = path.into_inner();
+    let room_id = state.resolve_room_id(room_id);
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    invites::check_reader(&state, room, user.as_ref())?;
+    let msg = visibility::visible_log(&state, room, history::username(user.as_ref()))
+        .iter()
+        .find(|msg| msg.id == message_id)
+        .ok_or(ApiError::MessageNotFound)?;
+    Ok(HttpResponse::Ok().json(AllowedActions {
+        react: room.policy.check(msg, Mutation::React).is_ok(),
+        reply: room.policy.check(msg, Mutation::Reply).is_ok(),
+        edit: room.policy.check(msg, Mutation::Edit).is_ok(),
+    }))
+}
@@ -0,0 +1,220 @@
+// "My data" exports: everything the server holds about one account, as a
+// zip streamed to the client. The archive is written entry by entry, so only
+// one file is ever held compressed in memory.
+use actix_web::rt;
+use actix_web::web::{self, Bytes};
+use actix_web::HttpResponse;
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::io::Write;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::export::utc_datetime;
+use crate::history::HistoryEntry;
+use crate::keywords;
+use crate::roles::RoomRole;
+use crate::users::owned_username;
+use crate::{now_millis, SharedState};
+
+// Chunks queued ahead of a slow client before the writer waits
+const STREAM_BUFFER: usize = 8;
+
+struct ZipEntry {
+    name: String,
+    data: Vec<u8>,
+}
+
+// Minimal zip writer: deflated entries with sizes known up front, so no data
+// descriptors or zip64 are needed for an export of this size
+struct ZipStream {
+    tx: mpsc::Sender<Result<Bytes, std::io::Error>>,
+    offset: u32,
+    central: Vec<u8>,
+    entries: u16,
+    dos_time: u16,
+    dos_date: u16,
+}
+
+impl ZipStream {
+    fn new(tx: mpsc::Sender<Result<Bytes, std::io::Error>>, now: u64) -> Self {
+        let (y, mo, d, h, mi, s) = utc_datetime(now);
+        ZipStream {
+            tx,
+            offset: 0,
+            central: Vec::new(),
+            entries: 0,
+            dos_time: ((h << 11) | (mi << 5) | (s / 2)) as u16,
+            dos_date: ((((y - 1980).max(0) as u32) << 9) | (mo << 5) | d) as u16,
+        }
+    }
+
+    // False once the client has gone away
+    fn emit(&mut self, chunk: Vec<u8>) -> bool {
+        self.offset += chunk.len() as u32;
+        self.tx.blocking_send(Ok(Bytes::from(chunk))).is_ok()
+    }
+
+    fn add(&mut self, entry: ZipEntry) -> bool {
+        let mut crc = Crc::new();
+        crc.update(&entry.data);
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&entry.data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let name = entry.name.as_bytes();
+
+        // Fields shared by the local header and the central directory record
+        let mut common = Vec::with_capacity(26);
+        common.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        common.extend_from_slice(&0x0800u16.to_le_bytes()); // UTF-8 names
+        common.extend_from_slice(&8u16.to_le_bytes()); // deflate
+        common.extend_from_slice(&self.dos_time.to_le_bytes());
+        common.extend_from_slice(&self.dos_date.to_le_bytes());
+        common.extend_from_slice(&crc.sum().to_le_bytes());
+        common.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(entry.data.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+
+        self.central
+            .extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        self.central.extend_from_slice(&20u16.to_le_bytes()); // version made by
+        self.central.extend_from_slice(&common);
+        self.central.extend_from_slice(&[0; 6]); // comment length, disk, internal attrs
+        self.central.extend_from_slice(&0u32.to_le_bytes()); // external attrs
+        self.central.extend_from_slice(&self.offset.to_le_bytes());
+        self.central.extend_from_slice(name);
+        self.entries += 1;
+
+        let mut local = Vec::with_capacity(30 + name.len() + compressed.len());
+        local.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        local.extend_from_slice(&common);
+        local.extend_from_slice(name);
+        local.extend_from_slice(&compressed);
+        self.emit(local)
+    }
+
+    fn finish(mut self) {
+        let central = std::mem::take(&mut self.central);
+        let mut end = Vec::with_capacity(22);
+        end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        end.extend_from_slice(&[0; 4]); // disk numbers
+        end.extend_from_slice(&self.entries.to_le_bytes());
+        end.extend_from_slice(&self.entries.to_le_bytes());
+        end.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        end.extend_from_slice(&self.offset.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes()); // comment length
+        if self.emit(central) {
+            self.emit(end);
+        }
+    }
+}
+
+fn json_entry(name: &str, value: &serde_json::Value) -> ZipEntry {
+    ZipEntry {
+        name: name.to_string(),
+        data: serde_json::to_vec_pretty(value).unwrap(),
+    }
+}
+
+// Snapshot of the account taken under the locks; serialized once released
+fn collect(state: &SharedState, username: &str) -> Vec<ZipEntry> {
+    let email = state.user_emails.lock().unwrap().get(username).cloned();
+    let settings = state.user_settings(username);
+    let bookmarks = state
+        .bookmarks
+        .lock()
+        .unwrap()
+        .get(username)
+        .cloned()
+        .unwrap_or_default();
+    let keywords = keywords::watched_by(state, username);
+
+    let mut rooms_summary = Vec::new();
+    let mut message_files = Vec::new();
+    {
+        let rooms = state.chat_rooms.lock().unwrap();
+        let mut rooms: Vec<_> = rooms.values().collect();
+        rooms.sort_by_key(|room| room.id);
+        for room in rooms {
+            let sent: Vec<_> = room
+                .message_log
+                .iter()
+                .filter(|msg| msg.sender == username)
+                .collect();
+            let member = room.members().contains(username);
+            if !member && sent.is_empty() {
+                continue;
+            }
+            rooms_summary.push(serde_json::json!({
+                "id": room.id,
+                "name": room.name,
+                "member": member,
+                "role": member.then(|| RoomRole::of(room, username)),
+                "messages_sent": sent.len(),
+            }));
+            if !sent.is_empty() {
+                let mut ndjson = Vec::new();
+                for msg in sent {
+                    serde_json::to_writer(&mut ndjson, &HistoryEntry::project(msg)).unwrap();
+                    ndjson.push(b'\n');
+                }
+                message_files.push(ZipEntry {
+                    name: format!("messages/{}.ndjson", room.id),
+                    data: ndjson,
+                });
+            }
+        }
+    }
+
+    let mut entries = vec![
+        json_entry(
+            "profile.json",
+            &serde_json::json!({
+                "username": username,
+                "email": email,
+                "admin": state.is_admin(username),
+                "exported_at": now_millis(),
+            }),
+        ),
+        json_entry("settings.json", &serde_json::to_value(settings).unwrap()),
+        json_entry("bookmarks.json", &serde_json::to_value(bookmarks).unwrap()),
+        json_entry("keywords.json", &serde_json::to_value(keywords).unwrap()),
+        json_entry("rooms.json", &serde_json::Value::Array(rooms_summary)),
+    ];
+    entries.extend(message_files);
+    entries
+}
+
+pub async fn export_user_data(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<String>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let username = owned_username(&state, &path, &user)?;
+    let entries = collect(&state, &username);
+
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+    rt::task::spawn_blocking(move || {
+        let mut zip = ZipStream::new(tx, now_millis());
+        for entry in entries {
+            if !zip.add(entry) {
+                return;
+            }
+        }
+        zip.finish();
+    });
+
+    let body = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header((
+            actix_web::http::header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}-export.zip\"", username),
+        ))
+        .streaming(body))
+}
@@ -0,0 +1,343 @@
+// Presence. A user is online while they have any WS session open. Going
+// online or offline is announced with `user_online`/`user_offline` to every
+// room they belong to, unless they turned `share_presence` off; when
+// ephemeral events are coalesced, the announcements go out with the next
+// flush and a quick reconnect doesn't announce anything. GET /users/
+// {username}/presence shows one user, GET /rooms/{id}/presence a room's
+// members who are online, and GET /rooms/{id}/participants every member with
+// their role and whether they're connected to the room.
+//
+// Last seen is recorded, and stored, whenever a session closes or has sent
+// nothing for IDLE_AFTER_MS, so room views can show when offline members
+// were last around.
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::deadletter;
+use crate::error::ApiError;
+use crate::invites;
+use crate::ratelimit::Limit;
+use crate::roles::RoomRole;
+use crate::store;
+use crate::{now_millis, ChatRoom, RoomEvent, SharedState};
+
+pub const IDLE_AFTER_MS: u64 = 5 * 60 * 1000;
+
+const PRESENCE_LIMIT: Limit = Limit {
+    capacity: 30.0,
+    refill_per_sec: 1.0,
+};
+
+// Live WS session counts per user, kept in step with `active_sessions`
+#[derive(Default)]
+pub struct PresenceTracker {
+    online: HashMap<String, HashMap<Uuid, usize>>, // username -> room_id -> session count
+    last_seen: HashMap<String, u64>,
+}
+
+impl PresenceTracker {
+    // Whether this was the user's first session, i.e. they just came online
+    pub fn connected(&mut self, username: &str, room_id: Uuid) -> bool {
+        let rooms = self.online.entry(username.to_string()).or_default();
+        let first = rooms.is_empty();
+        *rooms.entry(room_id).or_default() += 1;
+        first
+    }
+
+    // Whether this was the user's last session
+    pub fn disconnected(&mut self, username: &str, room_id: Uuid) -> bool {
+        let Some(rooms) = self.online.get_mut(username) else {
+            return false;
+        };
+        if let Some(count) = rooms.get_mut(&room_id) {
+            *count -= 1;
+            if *count == 0 {
+                rooms.remove(&room_id);
+            }
+        }
+        if !rooms.is_empty() {
+            return false;
+        }
+        self.online.remove(username);
+        true
+    }
+
+    fn seen(&mut self, username: &str, at: u64) {
+        let last_seen = self.last_seen.entry(username.to_string()).or_default();
+        *last_seen = (*last_seen).max(at);
+    }
+
+    // From the store at startup
+    pub fn restore(&mut self, last_seen: HashMap<String, u64>) {
+        for (username, at) in last_seen {
+            self.seen(&username, at);
+        }
+    }
+
+    pub fn rooms_of(&self, username: &str) -> Vec<Uuid> {
+        self.online
+            .get(username)
+            .map(|rooms| rooms.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn sessions_in(&self, username: &str, room_id: Uuid) -> usize {
+        self.online
+            .get(username)
+            .and_then(|rooms| rooms.get(&room_id))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn last_seen(&self, username: &str) -> Option<u64> {
+        self.last_seen.get(username).copied()
+    }
+}
+
+// When a session closes or goes idle
+pub fn record_seen(state: &SharedState, username: &str, at: u64) {
+    state.presence.lock().unwrap().seen(username, at);
+    store::last_seen_changed(state, username, at);
+}
+
+// When each offline member of the room was last seen, for those who share
+// their presence and have been seen at all
+pub fn members_last_seen(state: &SharedState, room: &ChatRoom) -> HashMap<String, u64> {
+    let shared: Vec<String> = room
+        .members()
+        .into_iter()
+        .filter(|username| state.user_settings(username).share_presence)
+        .collect();
+    let presence = state.presence.lock().unwrap();
+    shared
+        .into_iter()
+        .filter(|username| !presence.online.contains_key(username))
+        .filter_map(|username| {
+            let at = presence.last_seen(&username)?;
+            Some((username, at))
+        })
+        .collect()
+}
+
+// Call after `connected`/`disconnected` report a change
+pub fn changed(state: &SharedState, username: &str, online: bool) {
+    if state.coalescer.enabled() {
+        state.coalescer.presence_changed(username, online);
+    } else {
+        announce(state, username, online);
+    }
+}
+
+// Tells the sessions in each of the user's rooms
+pub fn announce(state: &SharedState, username: &str, online: bool) {
+    if !state.user_settings(username).share_presence {
+        return;
+    }
+    let room_ids: Vec<Uuid> = {
+        let rooms = state.chat_rooms.lock().unwrap();
+        rooms
+            .values()
+            .filter(|room| room.members().contains(username))
+            .map(|room| room.id)
+            .collect()
+    };
+    let at = now_millis();
+    for room_id in room_ids {
+        let event = RoomEvent(serde_json::json!({
+            "type": if online { "user_online" } else { "user_offline" },
+            "room_id": room_id,
+            "username": username,
+            "at": at,
+        }));
+        let sessions = state.active_sessions.lock().unwrap();
+        let recipients = sessions.get(&room_id).cloned().unwrap_or_default();
+        drop(sessions);
+        // Stale by the time anyone could replay it
+        deadletter::fan_out(&recipients, &event);
+    }
+}
+
+#[derive(Serialize)]
+struct UserPresence {
+    username: String,
+    online: bool,
+    rooms: Vec<Uuid>,
+    last_seen: Option<u64>,
+}
+
+pub async fn get_user_presence(
+    req: HttpRequest,
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<String>,
+    user: Option<UserContext>,
+) -> Result<HttpResponse, ApiError> {
+    let client = req
+        .connection_info()
+        .realip_remote_addr()
+        .unwrap_or("unknown")
+        .to_string();
+    state
+        .rate_limiter
+        .check("presence", &client, PRESENCE_LIMIT)?;
+
+    let username = state.canonical_username(&path.into_inner());
+    if !state.user_accounts.lock().unwrap().contains_key(&username) {
+        return Err(ApiError::UserNotFound);
+    }
+    let caller = user.as_ref().map(|user| user.username.as_str());
+    let is_self = caller == Some(username.as_str());
+    if !is_self && !state.user_settings(&username).share_presence {
+        return Err(ApiError::PresenceHidden);
+    }
+
+    let (rooms, last_seen) = {
+        let presence = state.presence.lock().unwrap();
+        (presence.rooms_of(&username), presence.last_seen(&username))
+    };
+    let online = !rooms.is_empty();
+    // Rooms the caller couldn't read themselves stay out of the list, though
+    // they still count towards being online
+    let rooms = {
+        let chat_rooms = state.chat_rooms.lock().unwrap();
+        rooms
+            .into_iter()
+            .filter(|room_id| {
+                chat_rooms
+                    .get(room_id)
+                    .is_some_and(|room| invites::check_access(&state, room, caller).is_ok())
+            })
+            .collect()
+    };
+    Ok(HttpResponse::Ok().json(UserPresence {
+        last_seen: if online { None } else { last_seen },
+        username,
+        online,
+        rooms,
+    }))
+}
+
+#[derive(Serialize)]
+struct MemberPresence {
+    username: String,
+    // Connected to this room rather than only to others
+    in_room: bool,
+}
+
+pub async fn get_room_presence(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: Option<UserContext>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let members = {
+        let rooms = state.chat_rooms.lock().unwrap();
+        let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+        invites::check_reader(&state, room, user.as_ref())?;
+        room.members()
+    };
+    let caller = user.as_ref().map(|user| user.username.as_str());
+    let mut shown: Vec<String> = members
+        .into_iter()
+        .filter(|username| {
+            Some(username.as_str()) == caller || state.user_settings(username).share_presence
+        })
+        .collect();
+    shown.sort();
+
+    let presence = state.presence.lock().unwrap();
+    let online: Vec<MemberPresence> = shown
+        .into_iter()
+        .filter_map(|username| {
+            let rooms = presence.online.get(&username)?;
+            Some(MemberPresence {
+                in_room: rooms.contains_key(&room_id),
+                username,
+            })
+        })
+        .collect();
+    drop(presence);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "online": online,
+    })))
+}
+
+#[derive(Serialize)]
+struct Participant {
+    username: String,
+    role: RoomRole,
+    joined_at: Option<u64>,
+    // Has a session open in this room; hidden like last_seen for members
+    // who don't share their presence
+    online: Option<bool>,
+    last_seen: Option<u64>,
+}
+
+pub async fn get_room_participants(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: Option<UserContext>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let mut members: Vec<(String, RoomRole, Option<u64>)> = {
+        let rooms = state.chat_rooms.lock().unwrap();
+        let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+        invites::check_reader(&state, room, user.as_ref())?;
+        room.members()
+            .into_iter()
+            .map(|username| {
+                let role = RoomRole::of(room, &username);
+                let joined_at = room.joined_at.get(&username).copied();
+                (username, role, joined_at)
+            })
+            .collect()
+    };
+    members.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let connected: HashSet<String> = {
+        let sessions = state.active_sessions.lock().unwrap();
+        let user_sessions = state.user_sessions.lock().unwrap();
+        let in_room = sessions.get(&room_id).map_or(&[][..], Vec::as_slice);
+        user_sessions
+            .iter()
+            .filter(|(_, addrs)| addrs.iter().any(|addr| in_room.contains(addr)))
+            .map(|(username, _)| username.clone())
+            .collect()
+    };
+    let caller = user.as_ref().map(|user| user.username.as_str());
+    let shared: Vec<bool> = members
+        .iter()
+        .map(|(username, _, _)| {
+            Some(username.as_str()) == caller || state.user_settings(username).share_presence
+        })
+        .collect();
+
+    let presence = state.presence.lock().unwrap();
+    let participants: Vec<Participant> = members
+        .into_iter()
+        .zip(shared)
+        .map(|((username, role, joined_at), shared)| {
+            let online = connected.contains(&username);
+            Participant {
+                online: shared.then_some(online),
+                last_seen: if shared && !online {
+                    presence.last_seen(&username)
+                } else {
+                    None
+                },
+                username,
+                role,
+                joined_at,
+            }
+        })
+        .collect();
+    drop(presence);
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "participants": participants,
+    })))
+}
@@ -0,0 +1,228 @@
+// Probation for new accounts: until an account is old enough and has posted
+// enough, it sends under a tighter rate limit, cannot create rooms and cannot
+// post links. Only accounts registered on this server are enrolled; seeded
+// and imported users never are.
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::attachments::Attachment;
+use crate::audit;
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::ratelimit::Limit;
+use crate::{now_millis, MessageKind, SharedState};
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct ProbationPolicy {
+    // Zero turns probation off
+    #[serde(
+        rename = "min_age_secs",
+        serialize_with = "secs",
+        deserialize_with = "from_secs"
+    )]
+    pub min_age: Duration,
+    pub min_messages: u64,
+    pub send_limit: Limit,
+}
+
+fn secs<S: serde::Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(value.as_secs())
+}
+
+fn from_secs<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+impl Default for ProbationPolicy {
+    fn default() -> Self {
+        ProbationPolicy {
+            min_age: Duration::from_secs(24 * 60 * 60),
+            min_messages: 10,
+            send_limit: Limit {
+                capacity: 3.0,
+                refill_per_sec: 0.1,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Probationer {
+    since: u64,
+    messages: u64,
+}
+
+#[derive(Default)]
+pub struct Probation {
+    policy: ProbationPolicy,
+    accounts: HashMap<String, Probationer>, // username -> progress towards release
+}
+
+impl Probation {
+    pub fn new(policy: ProbationPolicy) -> Self {
+        Probation {
+            policy,
+            accounts: HashMap::new(),
+        }
+    }
+
+    pub fn policy(&self) -> ProbationPolicy {
+        self.policy
+    }
+
+    // Accounts already enrolled stay on probation, now under the new terms
+    pub fn set_policy(&mut self, policy: ProbationPolicy) {
+        self.policy = policy;
+    }
+
+    fn served(&self, probationer: &Probationer, now: u64) -> bool {
+        now.saturating_sub(probationer.since) >= self.policy.min_age.as_millis() as u64
+            && probationer.messages >= self.policy.min_messages
+    }
+}
+
+pub fn enroll(state: &SharedState, username: &str) {
+    if state.is_admin(username) {
+        return;
+    }
+    let mut probation = state.probation.lock().unwrap();
+    if !probation.policy.min_age.is_zero() {
+        probation.accounts.insert(
+            username.to_string(),
+            Probationer {
+                since: now_millis(),
+                messages: 0,
+            },
+        );
+    }
+}
+
+// Whether `username` is still on probation; releases them once both the age
+// and the activity thresholds are met
+pub fn restricted(state: &SharedState, username: &str) -> bool {
+    let mut probation = state.probation.lock().unwrap();
+    let Some(probationer) = probation.accounts.get(username).copied() else {
+        return false;
+    };
+    if !probation.served(&probationer, now_millis()) {
+        return true;
+    }
+    probation.accounts.remove(username);
+    drop(probation);
+    audit::record(
+        state,
+        "system",
+        "probation_lifted",
+        username,
+        format!("after {} messages", probationer.messages),
+    );
+    false
+}
+
+pub fn contains_link(content: &str) -> bool {
+    let content = content.to_lowercase();
+    content.contains("://") || content.contains("www.")
+}
+
+// Called on the send path before anything is stored
+pub fn check_send(
+    state: &SharedState,
+    sender: &str,
+    kind: MessageKind,
+    content: &str,
+    attachments: &[Attachment],
+) -> Result<(), ApiError> {
+    if kind != MessageKind::User || !restricted(state, sender) {
+        return Ok(());
+    }
+    if contains_link(content) || attachments.iter().any(|a| a.url.is_some()) {
+        return Err(ApiError::ProbationRestricted);
+    }
+    let limit = state.probation.lock().unwrap().policy.send_limit;
+    state.rate_limiter.check("send_probation", sender, limit)
+}
+
+pub fn message_sent(state: &SharedState, sender: &str) {
+    if let Some(probationer) = state.probation.lock().unwrap().accounts.get_mut(sender) {
+        probationer.messages += 1;
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ProbationUpdate {
+    username: String,
+    on_probation: bool,
+}
+
+pub async fn list_probation(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let probation = state.probation.lock().unwrap();
+    let min_age_ms = probation.policy.min_age.as_millis() as u64;
+    let mut accounts: Vec<_> = probation
+        .accounts
+        .iter()
+        .map(|(username, probationer)| {
+            serde_json::json!({
+                "username": username,
+                "since": probationer.since,
+                "messages": probationer.messages,
+                "eligible_at": probationer.since + min_age_ms,
+            })
+        })
+        .collect();
+    accounts.sort_by(|a, b| a["username"].as_str().cmp(&b["username"].as_str()));
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "policy": probation.policy,
+        "accounts": accounts,
+    })))
+}
+
+// Lets admins release an account early, or put one back on probation from scratch
+pub async fn set_probation(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+    form: web::Json<ProbationUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let username = state.canonical_username(&form.username);
+    if !state.user_accounts.lock().unwrap().contains_key(&username) {
+        return Err(ApiError::UserNotFound);
+    }
+    let changed = {
+        let mut probation = state.probation.lock().unwrap();
+        if form.on_probation {
+            probation.accounts.insert(
+                username.clone(),
+                Probationer {
+                    since: now_millis(),
+                    messages: 0,
+                },
+            );
+            true
+        } else {
+            probation.accounts.remove(&username).is_some()
+        }
+    };
+    if changed {
+        let action = if form.on_probation {
+            "probation_imposed"
+        } else {
+            "probation_lifted"
+        };
+        audit::record(&state, &user.username, action, &username, String::new());
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "username": username,
+        "on_probation": form.on_probation,
+    })))
+}
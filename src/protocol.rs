@@ -0,0 +1,367 @@
+use actix_http::ws::Item;
+use actix_web_actors::ws;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use uuid::Uuid;
+
+use crate::attachments::Attachment;
+use crate::error::ApiError;
+use crate::messages::Priority;
+use crate::roles::RoomRole;
+use crate::ChatMessage;
+
+// Groups of server pushes a session can opt in to. System notices (errors,
+// room changes, moderation) are always delivered.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EventCategory {
+    Messages,
+    Presence,
+    Typing,
+    Reactions,
+    Receipts,
+}
+
+impl EventCategory {
+    pub const ALL: [EventCategory; 5] = [
+        EventCategory::Messages,
+        EventCategory::Presence,
+        EventCategory::Typing,
+        EventCategory::Reactions,
+        EventCategory::Receipts,
+    ];
+
+    // Maps a pushed event's `type` to the category it is filtered under
+    pub fn of_event(event_type: &str) -> Option<EventCategory> {
+        if event_type.starts_with("typing") {
+            Some(EventCategory::Typing)
+        } else if event_type.starts_with("user_online")
+            || event_type.starts_with("user_offline")
+            || event_type.starts_with("presence")
+        {
+            Some(EventCategory::Presence)
+        } else if event_type.contains("reaction") {
+            Some(EventCategory::Reactions)
+        } else if event_type.starts_with("read_receipt")
+            || event_type.starts_with("delivery_receipt")
+        {
+            Some(EventCategory::Receipts)
+        } else if event_type.starts_with("message") {
+            Some(EventCategory::Messages)
+        } else {
+            None
+        }
+    }
+}
+
+// Frames the server pushes, tagged by `type` like the client's. Room events
+// are already tagged JSON and are sent as they are.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame<'a> {
+    Message {
+        message: &'a ChatMessage,
+    },
+    // First frame on a new connection
+    Join {
+        room_id: Uuid,
+        username: &'a str,
+        role: RoomRole,
+        node_id: &'a str,
+    },
+    Error {
+        code: &'static str,
+        message: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+    },
+}
+
+impl ServerFrame<'_> {
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).expect("frames serialize")
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Message {
+        content: String,
+        #[serde(default)]
+        ttl_seconds: Option<u64>,
+        #[serde(default)]
+        attachments: Vec<Attachment>,
+        // Only honoured for room staff
+        #[serde(default)]
+        priority: Priority,
+        // Replies in the thread under this message
+        #[serde(default)]
+        parent_message_id: Option<Uuid>,
+    },
+    Subscribe {
+        events: HashSet<EventCategory>,
+    },
+    EditMessage {
+        message_id: Uuid,
+        content: String,
+    },
+    DeleteMessage {
+        message_id: Uuid,
+    },
+    AddReaction {
+        message_id: Uuid,
+        emoji: String,
+    },
+    RemoveReaction {
+        message_id: Uuid,
+        emoji: String,
+    },
+    // Swaps in a fresh login token before the current one lapses
+    RefreshToken {
+        token: String,
+    },
+    // Repeated while the user types; relayed to the room at most every few seconds
+    TypingStart,
+    TypingStop,
+    // Moves the read marker; the newest message when `seq` is left out
+    MarkRead {
+        #[serde(default)]
+        seq: Option<u64>,
+    },
+    // Messages up to `seq` reached this device, see `receipts`
+    Ack {
+        seq: u64,
+    },
+    // One page of history, newest first, answered with a `history_page`
+    HistoryRequest {
+        // The `next_before` of the previous page; the newest messages when left out
+        #[serde(default)]
+        before_seq: Option<u64>,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+}
+
+// Older clients send `{"content": ...}` without a type, or just plain text
+#[derive(Deserialize)]
+struct UntypedSend {
+    content: String,
+    #[serde(default)]
+    ttl_seconds: Option<u64>,
+}
+
+pub fn parse_frame(text: String) -> Result<ClientFrame, ApiError> {
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(&text) else {
+        return Ok(ClientFrame::Message {
+            content: text,
+            ttl_seconds: None,
+            attachments: Vec::new(),
+            priority: Priority::Normal,
+            parent_message_id: None,
+        });
+    };
+    if value.get("type").is_some() {
+        return serde_json::from_value(value).map_err(|_| ApiError::InvalidFrame);
+    }
+    match serde_json::from_value::<UntypedSend>(value) {
+        Ok(send) => Ok(ClientFrame::Message {
+            content: send.content,
+            ttl_seconds: send.ttl_seconds,
+            attachments: Vec::new(),
+            priority: Priority::Normal,
+            parent_message_id: None,
+        }),
+        // Valid JSON that isn't a send frame (e.g. a number) is still chat text
+        Err(_) => Ok(ClientFrame::Message {
+            content: text,
+            ttl_seconds: None,
+            attachments: Vec::new(),
+            priority: Priority::Normal,
+            parent_message_id: None,
+        }),
+    }
+}
+
+// Same as the codec's own limit on a single frame
+pub const MAX_FRAME_BYTES: usize = 65_536;
+
+pub enum Assembled {
+    Text(String),
+    Binary,
+}
+
+// Collects a fragmented message from its continuation frames. The codec
+// already refuses fragments out of order, so this only has to join them and
+// keep the total within one frame's limit.
+#[derive(Default)]
+pub struct Reassembly {
+    partial: Option<(bool, Vec<u8>)>, // (is text, bytes so far)
+}
+
+impl Reassembly {
+    pub fn in_progress(&self) -> bool {
+        self.partial.is_some()
+    }
+
+    pub fn push(&mut self, item: Item) -> Result<Option<Assembled>, ws::CloseCode> {
+        let (bytes, last) = match item {
+            Item::FirstText(bytes) => {
+                self.partial = Some((true, Vec::new()));
+                (bytes, false)
+            }
+            Item::FirstBinary(bytes) => {
+                self.partial = Some((false, Vec::new()));
+                (bytes, false)
+            }
+            Item::Continue(bytes) => (bytes, false),
+            Item::Last(bytes) => (bytes, true),
+        };
+        let Some((_, so_far)) = self.partial.as_mut() else {
+            return Err(ws::CloseCode::Protocol);
+        };
+        if so_far.len() + bytes.len() > MAX_FRAME_BYTES {
+            self.partial = None;
+            return Err(ws::CloseCode::Size);
+        }
+        so_far.extend_from_slice(&bytes);
+        if !last {
+            return Ok(None);
+        }
+        match self.partial.take() {
+            Some((true, bytes)) => String::from_utf8(bytes)
+                .map(|text| Some(Assembled::Text(text)))
+                .map_err(|_| ws::CloseCode::Invalid),
+            _ => Ok(Some(Assembled::Binary)),
+        }
+    }
+}
+
+// A small mutation fuzzer for the frame parser and reassembly, run with the
+// rest of the tests. FUZZ_ITERATIONS raises the iteration count for a longer
+// soak; a failure prints the seed and input so it can be replayed.
+// fuzz/frame_parser is the coverage-guided version, run with
+// `cargo +nightly fuzz run frame_parser`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::web::Bytes;
+
+    const SEEDS: &[&str] = &[
+        r#"{"type":"message","content":"hi","ttl_seconds":30,"priority":"high"}"#,
+        r#"{"type":"message","content":"x","attachments":[{"name":"a.png","content_type":"image/png","size":3}]}"#,
+        r#"{"type":"subscribe","events":["messages","typing","receipts"]}"#,
+        r#"{"type":"refresh_token","token":"abc"}"#,
+        r#"{"type":"typing_start"}"#,
+        r#"{"type":"typing_stop"}"#,
+        r#"{"type":"mark_read","seq":7}"#,
+        r#"{"type":"delete_message","message_id":"67e55044-10b1-426f-9247-bb680e5fe0c8"}"#,
+        r#"{"type":"edit_message","message_id":"67e55044-10b1-426f-9247-bb680e5fe0c8","content":"fixed"}"#,
+        r#"{"content":"untyped","ttl_seconds":5}"#,
+        "plain text",
+    ];
+    const INTERESTING: &[u8] = b"{}[]\":,\\ 0-1eE.nulltruefalse\x00\x7f\xc3\xa9\xff";
+
+    // xorshift64*, so runs are reproducible from their seed
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n.max(1) as u64) as usize
+        }
+    }
+
+    fn iterations() -> usize {
+        std::env::var("FUZZ_ITERATIONS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(20_000)
+    }
+
+    fn mutate(rng: &mut Rng, input: &mut Vec<u8>) {
+        let at = rng.below(input.len() + 1);
+        match rng.below(6) {
+            0 if at < input.len() => input[at] ^= 1 << rng.below(8),
+            1 => input.insert(at, INTERESTING[rng.below(INTERESTING.len())]),
+            2 => {
+                let end = (at + rng.below(8)).min(input.len());
+                input.drain(at..end);
+            }
+            3 => {
+                let end = (at + rng.below(16)).min(input.len());
+                let copy = input[at..end].to_vec();
+                input.splice(at..at, copy);
+            }
+            4 => {
+                let other = SEEDS[rng.below(SEEDS.len())].as_bytes();
+                let from = rng.below(other.len());
+                input.splice(at..at, other[from..].iter().copied());
+            }
+            _ => input.truncate(at),
+        }
+    }
+
+    #[test]
+    fn seeds_parse() {
+        for seed in SEEDS {
+            assert!(parse_frame(seed.to_string()).is_ok(), "{}", seed);
+        }
+    }
+
+    #[test]
+    fn parse_frame_only_fails_with_invalid_frame() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for round in 0..iterations() {
+            let mut input = SEEDS[rng.below(SEEDS.len())].as_bytes().to_vec();
+            for _ in 0..=rng.below(8) {
+                mutate(&mut rng, &mut input);
+            }
+            let text = String::from_utf8_lossy(&input).into_owned();
+            let result = std::panic::catch_unwind(|| parse_frame(text.clone()));
+            match result {
+                Ok(Ok(_)) | Ok(Err(ApiError::InvalidFrame)) => {}
+                Ok(Err(err)) => panic!("round {}: {:?} failed with {}", round, text, err.code()),
+                Err(_) => panic!("round {}: parse_frame panicked on {:?}", round, text),
+            }
+        }
+    }
+
+    #[test]
+    fn reassembly_stays_within_the_frame_limit() {
+        let mut rng = Rng(0xd1b5_4a32_d192_ed03);
+        let mut reassembly = Reassembly::default();
+        for round in 0..iterations() {
+            let len = match rng.below(4) {
+                0 => rng.below(MAX_FRAME_BYTES),
+                _ => rng.below(64),
+            };
+            let mut bytes = vec![b'a'; len];
+            if len > 0 && rng.below(8) == 0 {
+                bytes[rng.below(len)] = 0xc3;
+            }
+            let bytes = Bytes::from(bytes);
+            let item = match rng.below(4) {
+                0 => Item::FirstText(bytes),
+                1 => Item::FirstBinary(bytes),
+                2 => Item::Continue(bytes),
+                _ => Item::Last(bytes),
+            };
+            match reassembly.push(item) {
+                Ok(Some(Assembled::Text(text))) => {
+                    assert!(text.len() <= MAX_FRAME_BYTES, "round {}", round);
+                    assert!(!reassembly.in_progress(), "round {}", round);
+                }
+                Ok(Some(Assembled::Binary)) => assert!(!reassembly.in_progress()),
+                Ok(None) => assert!(reassembly.in_progress(), "round {}", round),
+                Err(_) => assert!(!reassembly.in_progress(), "round {}", round),
+            }
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::ApiError;
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Limit {
+    pub capacity: f64,
+    pub refill_per_sec: f64,
+}
+
+// Violations closer together than this escalate the penalty
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+const MAX_PENALTY: Duration = Duration::from_secs(300);
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+    strikes: u32,
+    last_violation: Option<Instant>,
+    blocked_until: Option<Instant>,
+}
+
+// Token buckets keyed by "<scope>:<client>"
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn check(&self, scope: &str, client: &str, limit: Limit) -> Result<(), ApiError> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .entry(format!("{}:{}", scope, client))
+            .or_insert(TokenBucket {
+                tokens: limit.capacity,
+                last_refill: now,
+                strikes: 0,
+                last_violation: None,
+                blocked_until: None,
+            });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.refill_per_sec).min(limit.capacity);
+        bucket.last_refill = now;
+
+        if let Some(until) = bucket.blocked_until.filter(|until| *until > now) {
+            return Err(ApiError::RateLimited {
+                retry_after_ms: millis_ceil(until - now),
+            });
+        }
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        // Each violation within the strike window doubles the wait on top of
+        // the time the bucket needs to refill a single token
+        if bucket
+            .last_violation
+            .is_none_or(|last| now.duration_since(last) > STRIKE_WINDOW)
+        {
+            bucket.strikes = 0;
+        }
+        bucket.strikes = bucket.strikes.saturating_add(1);
+        bucket.last_violation = Some(now);
+        let refill_wait = Duration::from_secs_f64((1.0 - bucket.tokens) / limit.refill_per_sec);
+        let penalty = refill_wait
+            .saturating_mul(1 << (bucket.strikes - 1).min(16))
+            .min(MAX_PENALTY);
+        bucket.blocked_until = Some(now + penalty);
+        Err(ApiError::RateLimited {
+            retry_after_ms: millis_ceil(penalty),
+        })
+    }
+}
+
+fn millis_ceil(duration: Duration) -> u64 {
+    duration.as_micros().div_ceil(1000) as u64
+}
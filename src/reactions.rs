@@ -0,0 +1,117 @@
+// Emoji reactions. Each message keeps, per emoji, the members who reacted
+// with it; adding a reaction twice or removing one that isn't there changes
+// nothing. History shows the counts, and the room gets `reaction_added` and
+// `reaction_removed` events with the emoji's new count.
+use actix_web::{web, HttpResponse};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::messages::SEND_LIMIT;
+use crate::policy::Mutation;
+use crate::store;
+use crate::{RoomEvent, SharedState};
+
+// Long enough for flags, skin tones and ZWJ sequences
+const MAX_EMOJI_CHARS: usize = 16;
+
+fn validate(emoji: &str) -> Result<(), ApiError> {
+    let chars = emoji.chars().count();
+    let looks_like_emoji = (1..=MAX_EMOJI_CHARS).contains(&chars)
+        && !emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+        && !emoji.is_ascii();
+    if looks_like_emoji {
+        Ok(())
+    } else {
+        Err(ApiError::InvalidReaction)
+    }
+}
+
+// Shared by the WS frames and the REST endpoints
+pub fn set_reaction(
+    state: &SharedState,
+    room_id: Uuid,
+    username: &str,
+    message_id: Uuid,
+    emoji: &str,
+    add: bool,
+) -> Result<(), ApiError> {
+    validate(emoji)?;
+    state.rate_limiter.check("react", username, SEND_LIMIT)?;
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    if !room.members().contains(username) {
+        return Err(ApiError::ParticipantNotFound);
+    }
+    let msg = room
+        .message_log
+        .iter_mut()
+        .find(|msg| msg.id == message_id)
+        .ok_or(ApiError::MessageNotFound)?;
+    room.policy.check(msg, Mutation::React)?;
+    let changed = if add {
+        msg.reactions
+            .entry(emoji.to_string())
+            .or_default()
+            .insert(username.to_string())
+    } else {
+        match msg.reactions.get_mut(emoji) {
+            Some(users) => {
+                let removed = users.remove(username);
+                if users.is_empty() {
+                    msg.reactions.remove(emoji);
+                }
+                removed
+            }
+            None => false,
+        }
+    };
+    if !changed {
+        return Ok(());
+    }
+    msg.version.bump(&state.node_id);
+    store::reactions_changed(state, msg);
+    let count = msg.reactions.get(emoji).map_or(0, |users| users.len());
+    drop(rooms);
+
+    state.broadcast_event(
+        room_id,
+        RoomEvent(serde_json::json!({
+            "type": if add { "reaction_added" } else { "reaction_removed" },
+            "room_id": room_id,
+            "message_id": message_id,
+            "emoji": emoji,
+            "username": username,
+            "count": count,
+        })),
+    );
+    Ok(())
+}
+
+pub async fn add_reaction(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, Uuid, String)>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    update(&state, path.into_inner(), &user.username, true)
+}
+
+pub async fn remove_reaction(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, Uuid, String)>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    update(&state, path.into_inner(), &user.username, false)
+}
+
+fn update(
+    state: &SharedState,
+    (room_id, message_id, emoji): (Uuid, Uuid, String),
+    username: &str,
+    add: bool,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(room_id);
+    set_reaction(state, room_id, username, message_id, &emoji, add)?;
+    Ok(HttpResponse::NoContent().finish())
+}
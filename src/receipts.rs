@@ -0,0 +1,224 @@
+// Read receipts, built on the read markers in `unread`: a user has read every
+// message up to their marker's seq. Members see who has read what, and get a
+// `read_receipt` event whenever someone's marker in the room moves forward.
+//
+// Delivery receipts work the same way with a marker of their own, moved by
+// `ack` frames from a client once messages have reached the device. Each
+// message is then sent, delivered or read for each recipient. Direct rooms
+// show that per message and get a `delivery_receipt` event as the marker
+// moves; group rooms only count how many members each stage has reached.
+use actix_web::{web, HttpResponse};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::invites;
+use crate::{RoomEvent, SharedState};
+
+#[derive(Default)]
+pub struct Deliveries {
+    rooms: HashMap<Uuid, HashMap<String, u64>>, // room_id -> username -> highest delivered seq
+}
+
+impl Deliveries {
+    // Whether the marker moved; it never goes back
+    fn advance(&mut self, room_id: Uuid, username: &str, seq: u64) -> bool {
+        let marker = self
+            .rooms
+            .entry(room_id)
+            .or_default()
+            .entry(username.to_string())
+            .or_default();
+        if seq <= *marker {
+            return false;
+        }
+        *marker = seq;
+        true
+    }
+
+    fn markers(&self, room_id: Uuid) -> BTreeMap<String, u64> {
+        self.rooms
+            .get(&room_id)
+            .map(|markers| {
+                markers
+                    .iter()
+                    .map(|(username, seq)| (username.clone(), *seq))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn room_removed(&mut self, room_id: Uuid) {
+        self.rooms.remove(&room_id);
+    }
+
+    // Same rule as `UnreadTracker::room_merged`: the nearer of the two markers
+    pub fn room_merged(
+        &mut self,
+        source_id: Uuid,
+        target_id: Uuid,
+        remap: impl Fn(Uuid, u64) -> u64,
+    ) {
+        let mut merged: HashMap<String, u64> = HashMap::new();
+        for room_id in [target_id, source_id] {
+            for (username, seq) in self.rooms.remove(&room_id).unwrap_or_default() {
+                let seq = remap(room_id, seq);
+                merged
+                    .entry(username)
+                    .and_modify(|marker| *marker = (*marker).min(seq))
+                    .or_insert(seq);
+            }
+        }
+        if !merged.is_empty() {
+            self.rooms.insert(target_id, merged);
+        }
+    }
+}
+
+// A message's stage for one recipient; reading it implies it was delivered
+fn status(seq: u64, delivered: Option<u64>, read: Option<u64>) -> &'static str {
+    if read.is_some_and(|read| read >= seq) {
+        "read"
+    } else if delivered.is_some_and(|delivered| delivered >= seq) {
+        "delivered"
+    } else {
+        "sent"
+    }
+}
+
+// From a session's `ack` frame: everything up to `seq` reached the device
+pub fn delivered(
+    state: &SharedState,
+    room_id: Uuid,
+    username: &str,
+    seq: u64,
+) -> Result<(), ApiError> {
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    if !room.members().contains(username) {
+        return Err(ApiError::ParticipantNotFound);
+    }
+    let seq = seq.min(room.next_seq);
+    let direct = room.direct;
+    let advanced = state
+        .deliveries
+        .lock()
+        .unwrap()
+        .advance(room_id, username, seq);
+    drop(rooms);
+    if advanced && direct {
+        state.broadcast_event(
+            room_id,
+            RoomEvent(serde_json::json!({
+                "type": "delivery_receipt",
+                "room_id": room_id,
+                "username": username,
+                "seq": seq,
+            })),
+        );
+    }
+    Ok(())
+}
+
+pub fn marker_advanced(state: &SharedState, room_id: Uuid, username: &str, seq: u64) {
+    state.broadcast_event(
+        room_id,
+        RoomEvent(serde_json::json!({
+            "type": "read_receipt",
+            "room_id": room_id,
+            "username": username,
+            "seq": seq,
+        })),
+    );
+}
+
+// Every member's read marker in the room
+pub async fn room_receipts(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    invites::check_reader(&state, room, Some(&user))?;
+    if !room.members().contains(&user.username) {
+        return Err(ApiError::ParticipantNotFound);
+    }
+    let direct = room.direct;
+    drop(rooms);
+    let markers = state.unread.lock().unwrap().read_markers(room_id);
+    let mut receipts = serde_json::json!({
+        "room_id": room_id,
+        "read_markers": markers,
+    });
+    if direct {
+        receipts["delivered_markers"] =
+            serde_json::json!(state.deliveries.lock().unwrap().markers(room_id));
+    }
+    Ok(HttpResponse::Ok().json(receipts))
+}
+
+// Which members other than the sender have read one message
+pub async fn message_receipts(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, Uuid)>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, message_id) = path.into_inner();
+    let room_id = state.resolve_room_id(room_id);
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    invites::check_reader(&state, room, Some(&user))?;
+    let members = room.members();
+    if !members.contains(&user.username) {
+        return Err(ApiError::ParticipantNotFound);
+    }
+    let msg = room
+        .message_log
+        .iter()
+        .find(|msg| msg.id == message_id && msg.deleted_at.is_none())
+        .ok_or(ApiError::MessageNotFound)?;
+    let (seq, sender) = (msg.seq, msg.sender.clone());
+    let direct = room.direct;
+    drop(rooms);
+
+    let markers = state.unread.lock().unwrap().read_markers(room_id);
+    let delivered = state.deliveries.lock().unwrap().markers(room_id);
+    let recipients: Vec<String> = members
+        .into_iter()
+        .filter(|member| *member != sender)
+        .collect();
+    let statuses: Vec<&str> = recipients
+        .iter()
+        .map(|member| {
+            status(
+                seq,
+                delivered.get(member).copied(),
+                markers.get(member).copied(),
+            )
+        })
+        .collect();
+    let (mut read_by, mut unread_by): (Vec<_>, Vec<_>) = recipients
+        .into_iter()
+        .partition(|member| markers.get(member).is_some_and(|&read| read >= seq));
+    read_by.sort();
+    unread_by.sort();
+    let mut receipts = serde_json::json!({
+        "room_id": room_id,
+        "message_id": message_id,
+        "seq": seq,
+        "read_by": read_by,
+        "unread_by": unread_by,
+    });
+    if direct {
+        // One recipient, so their stage is the message's
+        receipts["status"] = statuses.first().copied().unwrap_or("sent").into();
+    } else {
+        receipts["delivered_count"] = statuses.iter().filter(|s| **s != "sent").count().into();
+        receipts["read_count"] = statuses.iter().filter(|s| **s == "read").count().into();
+    }
+    Ok(HttpResponse::Ok().json(receipts))
+}
@@ -0,0 +1,162 @@
+use actix_web::{rt, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::notices::{self, NoticeKind, Severity};
+use crate::passwords;
+use crate::ratelimit::Limit;
+use crate::store;
+use crate::{now_millis, SharedState};
+
+// Every recovery lookup takes at least this long, whether or not it matched
+const RESPONSE_FLOOR: Duration = Duration::from_millis(400);
+const TOKEN_TTL_MILLIS: u64 = 30 * 60 * 1000;
+const RECOVERY_LIMIT: Limit = Limit {
+    capacity: 5.0,
+    refill_per_sec: 1.0 / 60.0,
+};
+const GENERIC_REPLY: &str =
+    "If an account matches, recovery instructions were sent to its registered contact";
+const RESET_NOTICE: &str =
+    "Your password was reset and all sessions were signed out. If this wasn't you, contact an admin";
+
+struct RecoveryToken {
+    username: String,
+    expires_at: u64,
+}
+
+#[derive(Default)]
+pub struct RecoveryTokens {
+    tokens: HashMap<Uuid, RecoveryToken>,
+}
+
+#[derive(Deserialize)]
+pub struct RecoveryLookup {
+    // Username or registered email
+    identifier: String,
+}
+
+#[derive(Deserialize)]
+pub struct RecoveryReset {
+    token: Uuid,
+    new_password: String,
+}
+
+fn find_account(state: &SharedState, identifier: &str) -> Option<(String, String)> {
+    let username = state.canonical_username(identifier);
+    let emails = state.user_emails.lock().unwrap();
+    if let Some(email) = emails.get(&username) {
+        return Some((username, email.clone()));
+    }
+    emails
+        .iter()
+        .find(|(_, email)| email.eq_ignore_ascii_case(identifier))
+        .map(|(username, email)| (username.clone(), email.clone()))
+}
+
+// Without a mail transport, messages are written as .eml files to
+// RECOVERY_OUTBOX_DIR so development setups can still complete the flow
+fn deliver_recovery_email(email: &str, token: Uuid) {
+    let Ok(dir) = std::env::var("RECOVERY_OUTBOX_DIR") else {
+        log::warn!("account recovery requested but RECOVERY_OUTBOX_DIR is not set");
+        return;
+    };
+    let body = format!(
+        "To: {}\r\nSubject: Account recovery\r\n\r\nUse this token to reset your password within 30 minutes:\r\n{}\r\n",
+        email, token
+    );
+    let path = std::path::Path::new(&dir).join(format!("{}-{}.eml", now_millis(), token));
+    if let Err(err) = std::fs::write(&path, body) {
+        log::error!(
+            "failed to write recovery email to {}: {}",
+            path.display(),
+            err
+        );
+    }
+}
+
+pub async fn request_recovery(
+    req: HttpRequest,
+    state: web::Data<Arc<SharedState>>,
+    form: web::Json<RecoveryLookup>,
+) -> Result<HttpResponse, ApiError> {
+    let started = Instant::now();
+    let client = req
+        .connection_info()
+        .realip_remote_addr()
+        .unwrap_or("unknown")
+        .to_string();
+    state
+        .rate_limiter
+        .check("recovery", &client, RECOVERY_LIMIT)?;
+
+    // The lookup and delivery run off the request path so their cost can't be measured
+    let state = state.get_ref().clone();
+    let identifier = form.into_inner().identifier;
+    rt::spawn(async move {
+        let Some((username, email)) = find_account(&state, identifier.trim()) else {
+            return;
+        };
+        let token = Uuid::new_v4();
+        state.recovery_tokens.lock().unwrap().tokens.insert(
+            token,
+            RecoveryToken {
+                username,
+                expires_at: now_millis() + TOKEN_TTL_MILLIS,
+            },
+        );
+        deliver_recovery_email(&email, token);
+    });
+
+    if let Some(remaining) = RESPONSE_FLOOR.checked_sub(started.elapsed()) {
+        rt::time::sleep(remaining).await;
+    }
+    Ok(HttpResponse::Accepted().body(GENERIC_REPLY))
+}
+
+pub async fn reset_password(
+    state: web::Data<Arc<SharedState>>,
+    form: web::Json<RecoveryReset>,
+) -> Result<HttpResponse, ApiError> {
+    let form = form.into_inner();
+    if form.new_password.is_empty() {
+        return Err(ApiError::EmptyPassword);
+    }
+    // Tokens are single use: removed whether or not they are still valid
+    let token = state
+        .recovery_tokens
+        .lock()
+        .unwrap()
+        .tokens
+        .remove(&form.token)
+        .filter(|token| token.expires_at >= now_millis())
+        .ok_or(ApiError::InvalidRecoveryToken)?;
+
+    let password_hash = passwords::hash_blocking(form.new_password).await?;
+    let mut accounts = state.user_accounts.lock().unwrap();
+    let password = accounts
+        .get_mut(&token.username)
+        .ok_or(ApiError::InvalidRecoveryToken)?;
+    *password = password_hash;
+    drop(accounts);
+    store::user_changed(&state, &token.username);
+    // A reset usually means the old password leaked, so end existing logins
+    state
+        .auth_tokens
+        .lock()
+        .unwrap()
+        .revoke_user(&token.username);
+    notices::send(
+        &state,
+        &token.username,
+        NoticeKind::Security,
+        Severity::Warning,
+        RESET_NOTICE.to_string(),
+        None,
+    );
+    Ok(HttpResponse::Ok().body("Password updated"))
+}
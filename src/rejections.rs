@@ -0,0 +1,154 @@
+// Counts of messages the send path refused, labelled with the error code the
+// sender got back, plus messages that were accepted but silently dropped.
+// Both are exposed in Prometheus text format at /metrics. Each room also
+// keeps its most recent rejections for the room's staff to inspect.
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::roles::is_staff;
+use crate::sanitize;
+use crate::{now_millis, MessageKind, SharedState};
+
+const RECENT_PER_ROOM: usize = 50;
+const EXCERPT_CHARS: usize = 140;
+
+#[derive(Serialize, Clone)]
+pub struct Rejection {
+    at: u64,
+    sender: String,
+    kind: MessageKind,
+    reason: &'static str,
+    excerpt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_ms: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct Rejections {
+    rejected: BTreeMap<(&'static str, &'static str), u64>, // (reason, kind) -> count
+    dropped: BTreeMap<&'static str, u64>,                  // reason -> count
+    recent: HashMap<Uuid, VecDeque<Rejection>>,            // room_id -> newest last
+}
+
+fn kind_label(kind: MessageKind) -> &'static str {
+    match kind {
+        MessageKind::User => "user",
+        MessageKind::System => "system",
+    }
+}
+
+pub fn excerpt(content: &str) -> String {
+    sanitize::truncate(content, EXCERPT_CHARS).to_string()
+}
+
+// Called with the error a send attempt is about to return
+pub fn record(
+    state: &SharedState,
+    room_id: Uuid,
+    sender: &str,
+    kind: MessageKind,
+    excerpt: String,
+    err: &ApiError,
+) {
+    let reason = err.code();
+    // Rooms that don't exist get counted but keep no buffer
+    let room_exists = state.chat_rooms.lock().unwrap().contains_key(&room_id);
+    let mut rejections = state.rejections.lock().unwrap();
+    *rejections
+        .rejected
+        .entry((reason, kind_label(kind)))
+        .or_default() += 1;
+    if !room_exists {
+        return;
+    }
+    let recent = rejections.recent.entry(room_id).or_default();
+    if recent.len() == RECENT_PER_ROOM {
+        recent.pop_front();
+    }
+    recent.push_back(Rejection {
+        at: now_millis(),
+        sender: sender.to_string(),
+        kind,
+        reason,
+        excerpt,
+        retry_after_ms: err.retry_after_ms(),
+    });
+}
+
+// Accepted from the sender's point of view but never delivered to the room
+pub fn dropped(state: &SharedState, reason: &'static str) {
+    *state
+        .rejections
+        .lock()
+        .unwrap()
+        .dropped
+        .entry(reason)
+        .or_default() += 1;
+}
+
+pub fn room_removed(state: &SharedState, room_id: Uuid) {
+    state.rejections.lock().unwrap().recent.remove(&room_id);
+}
+
+pub async fn metrics(state: web::Data<Arc<SharedState>>) -> HttpResponse {
+    let rejections = state.rejections.lock().unwrap();
+    let mut body = String::new();
+    body.push_str("# HELP chat_messages_rejected_total Messages refused by the send path.\n");
+    body.push_str("# TYPE chat_messages_rejected_total counter\n");
+    for ((reason, kind), count) in &rejections.rejected {
+        let _ = writeln!(
+            body,
+            "chat_messages_rejected_total{{reason=\"{}\",kind=\"{}\"}} {}",
+            reason, kind, count
+        );
+    }
+    body.push_str(
+        "# HELP chat_messages_dropped_total Messages accepted from the sender but not delivered.\n",
+    );
+    body.push_str("# TYPE chat_messages_dropped_total counter\n");
+    for (reason, count) in &rejections.dropped {
+        let _ = writeln!(
+            body,
+            "chat_messages_dropped_total{{reason=\"{}\"}} {}",
+            reason, count
+        );
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(body)
+}
+
+// Newest first
+pub async fn list_rejections(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let actor = user.username.clone();
+    {
+        let rooms = state.chat_rooms.lock().unwrap();
+        let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+        if !is_staff(&state, room, &actor) {
+            return Err(ApiError::NotRoomModerator);
+        }
+    }
+    let recent: Vec<Rejection> = state
+        .rejections
+        .lock()
+        .unwrap()
+        .recent
+        .get(&room_id)
+        .into_iter()
+        .flatten()
+        .rev()
+        .cloned()
+        .collect();
+    Ok(HttpResponse::Ok().json(recent))
+}
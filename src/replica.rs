@@ -0,0 +1,199 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{rt, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::AuthTokens;
+use crate::config::ReplicaConfig;
+use crate::error::ApiError;
+use crate::outbound;
+use crate::{audit, external, search, standby, versions, ChatRoom, SharedState};
+
+pub const TOKEN_HEADER: &str = "x-replication-token";
+const MAX_SNAPSHOT_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    // The primary's node id; empty from primaries that predate it
+    #[serde(default)]
+    node_id: String,
+    rooms: Vec<ChatRoom>,
+    redirects: HashMap<Uuid, Uuid>,
+    // Every replica needs the login tokens to serve authenticated reads, and
+    // a standby the rest to take over. Absent from primaries that predate it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    accounts: Option<Accounts>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Accounts {
+    passwords: HashMap<String, String>, // username -> password hash
+    emails: HashMap<String, String>,
+    tokens: AuthTokens,
+}
+
+pub fn check_token(req: &HttpRequest, state: &SharedState) -> Result<(), ApiError> {
+    let presented = req
+        .headers()
+        .get(TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match (&state.replication_token, presented) {
+        (Some(expected), Some(presented)) if expected == presented => Ok(()),
+        _ => Err(ApiError::InvalidReplicationToken),
+    }
+}
+
+// Primary side: full copy of room state for replicas holding the shared token
+pub async fn snapshot(
+    req: HttpRequest,
+    state: web::Data<Arc<SharedState>>,
+) -> Result<HttpResponse, ApiError> {
+    check_token(&req, &state)?;
+    let accounts = Some(Accounts {
+        passwords: state.user_accounts.lock().unwrap().clone(),
+        emails: state.user_emails.lock().unwrap().clone(),
+        tokens: state.auth_tokens.lock().unwrap().clone(),
+    });
+    let redirects = state.room_redirects.lock().unwrap().clone();
+    let rooms = state.chat_rooms.lock().unwrap().values().cloned().collect();
+    Ok(HttpResponse::Ok().json(Snapshot {
+        node_id: state.node_id.clone(),
+        rooms,
+        redirects,
+        accounts,
+    }))
+}
+
+// Replicas only answer room and history reads; the WS fan-out and every
+// write stay on the primary. A standby also takes its promotion.
+fn replica_serves(req: &ServiceRequest) -> bool {
+    let path = req.path();
+    if path == standby::PROMOTE_PATH {
+        return true;
+    }
+    matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+        && (path.starts_with("/rooms/")
+            || path == "/list_rooms"
+            || path == "/search"
+            || path == "/capabilities")
+}
+
+pub async fn read_only_guard(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let primary = req
+        .app_data::<web::Data<Arc<SharedState>>>()
+        .and_then(|state| state.replica_of.lock().unwrap().clone());
+    match primary {
+        Some(primary) if !replica_serves(&req) => {
+            let mut res = req.error_response(ApiError::ReadOnlyReplica);
+            if let Ok(value) = primary.parse() {
+                res.headers_mut().insert(
+                    actix_web::http::header::HeaderName::from_static("x-primary-url"),
+                    value,
+                );
+            }
+            Ok(res)
+        }
+        _ => Ok(next.call(req).await?.map_into_boxed_body()),
+    }
+}
+
+pub async fn pull(client: &awc::Client, config: &ReplicaConfig) -> Result<Snapshot, String> {
+    let mut request = client.get(format!("{}/replication/snapshot", config.primary));
+    if let Some(token) = &config.token {
+        request = request.insert_header((TOKEN_HEADER, token.as_str()));
+    }
+    let mut resp = request.send().await.map_err(|err| err.to_string())?;
+    if !resp.status().is_success() {
+        return Err(format!("primary returned {}", resp.status()));
+    }
+    resp.json::<Snapshot>()
+        .limit(MAX_SNAPSHOT_BYTES)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+// Takes the primary's rooms, folding each message into any local copy by
+// version vector so that changes made on either side converge
+pub fn apply(state: &SharedState, snapshot: Snapshot) {
+    if let Some(accounts) = snapshot.accounts {
+        *state.user_accounts.lock().unwrap() = accounts.passwords;
+        *state.user_emails.lock().unwrap() = accounts.emails;
+        *state.auth_tokens.lock().unwrap() = accounts.tokens;
+    }
+    let mut conflicts = Vec::new();
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let mut next = HashMap::with_capacity(snapshot.rooms.len());
+    for mut room in snapshot.rooms {
+        if let Some(local) = rooms.get(&room.id) {
+            let local: HashMap<_, _> = local.message_log.iter().map(|msg| (msg.id, msg)).collect();
+            for msg in room.message_log.iter_mut() {
+                if let Some(mine) = local.get(&msg.id) {
+                    let mut merged = (*mine).clone();
+                    conflicts.extend(versions::reconcile(&mut merged, msg.clone()));
+                    *msg = merged;
+                }
+            }
+        }
+        next.insert(room.id, room);
+    }
+    *rooms = next;
+    external::rebuild(state, &rooms);
+    drop(rooms);
+    search::reset_all(state);
+    *state.room_redirects.lock().unwrap() = snapshot.redirects;
+
+    for conflict in conflicts {
+        audit::record(
+            state,
+            "system",
+            "edit_conflict",
+            &conflict.message_id.to_string(),
+            format!(
+                "kept {:?}, discarded {:?}",
+                conflict.kept, conflict.discarded
+            ),
+        );
+    }
+}
+
+pub fn spawn_follower(state: Arc<SharedState>, config: ReplicaConfig) {
+    rt::spawn(async move {
+        let client = outbound::client();
+        let mut interval = rt::time::interval(config.interval);
+        let mut primary_node = String::new();
+        loop {
+            interval.tick().await;
+            // Promoted standbys stop following
+            if state.replica_of.lock().unwrap().is_none() {
+                break;
+            }
+            match pull(&client, &config).await {
+                Ok(snapshot) => {
+                    if snapshot.node_id != primary_node {
+                        log::info!(
+                            "replicating from {} (node {})",
+                            config.primary,
+                            snapshot.node_id
+                        );
+                        primary_node = snapshot.node_id.clone();
+                    }
+                    // Held across the apply, so a promotion can't interleave
+                    let replica_of = state.replica_of.lock().unwrap();
+                    if replica_of.is_none() {
+                        break;
+                    }
+                    apply(&state, snapshot);
+                }
+                Err(err) => log::warn!("replica sync from {} failed: {}", config.primary, err),
+            }
+        }
+    });
+}
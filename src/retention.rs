@@ -0,0 +1,141 @@
+use actix_web::{rt, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::attachments;
+use crate::auth::UserContext;
+use crate::cold;
+use crate::error::ApiError;
+use crate::history;
+use crate::roles::{self, Permission};
+use crate::store;
+use crate::uploads;
+use crate::{audit, now_millis, ChatRoom, SharedState};
+
+const JANITOR_INTERVAL: Duration = Duration::from_secs(60);
+const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RetentionClass {
+    #[serde(rename = "30d")]
+    Days30,
+    #[serde(rename = "90d")]
+    Days90,
+    #[serde(rename = "365d")]
+    Days365,
+    #[default]
+    #[serde(rename = "forever")]
+    Forever,
+}
+
+impl RetentionClass {
+    pub const ALL: [RetentionClass; 4] = [
+        RetentionClass::Days30,
+        RetentionClass::Days90,
+        RetentionClass::Days365,
+        RetentionClass::Forever,
+    ];
+
+    pub fn max_age_millis(self) -> Option<u64> {
+        match self {
+            RetentionClass::Days30 => Some(30 * DAY_MILLIS),
+            RetentionClass::Days90 => Some(90 * DAY_MILLIS),
+            RetentionClass::Days365 => Some(365 * DAY_MILLIS),
+            RetentionClass::Forever => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RetentionUpdate {
+    #[serde(default)]
+    retention: Option<RetentionClass>,
+    #[serde(default)]
+    attachment_retention: Option<RetentionClass>,
+}
+
+pub async fn set_room_retention(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<RetentionUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let Some(room) = rooms.get_mut(&room_id) else {
+        return Err(ApiError::RoomNotFound);
+    };
+    roles::check(&state, room, &user.username, Permission::ChangeSettings)?;
+
+    let previous = (room.retention, room.attachment_retention);
+    room.retention = form.retention.unwrap_or(room.retention);
+    room.attachment_retention = form
+        .attachment_retention
+        .unwrap_or(room.attachment_retention);
+    store::room_changed(&state, room);
+    let detail = format!(
+        "{:?} -> {:?}",
+        previous,
+        (room.retention, room.attachment_retention)
+    );
+    let room = room.clone();
+    drop(rooms);
+
+    audit::record(
+        &state,
+        &user.username,
+        "set_retention",
+        &room_id.to_string(),
+        detail,
+    );
+    Ok(HttpResponse::Ok().json(history::room_view(&room)))
+}
+
+// Drops messages older than each room's retention window, except held ones.
+// Rooms offloaded to cold storage are swept in their archives.
+pub fn sweep(state: &SharedState) {
+    let now = now_millis();
+    let holds = state.legal_holds.lock().unwrap().clone();
+    let cutoff = |room: &ChatRoom| {
+        room.retention
+            .max_age_millis()
+            .map(|max_age| now.saturating_sub(max_age))
+    };
+    let mut pruned = Vec::new();
+    let mut prune = |room: &mut ChatRoom| {
+        let Some(cutoff) = cutoff(room) else {
+            return false;
+        };
+        let before = pruned.len();
+        room.message_log.retain(|msg| {
+            let keep = msg.sent_at >= cutoff || holds.covers(msg);
+            if !keep {
+                uploads::discard(state, &msg.attachments);
+                pruned.push(msg.id);
+            }
+            keep
+        });
+        pruned.len() > before
+    };
+    cold::sweep_archived(state, cutoff, &mut prune);
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    for room in rooms.values_mut() {
+        prune(room);
+    }
+    drop(rooms);
+    store::messages_removed(state, pruned);
+}
+
+pub fn spawn_janitor(state: Arc<SharedState>) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(JANITOR_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweep(&state);
+            attachments::sweep(&state);
+            uploads::sweep_unclaimed(&state);
+        }
+    });
+}
@@ -0,0 +1,241 @@
+use actix::prelude::*;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::deadletter::{self, Undelivered};
+use crate::error::ApiError;
+use crate::store;
+use crate::{audit, ChatRoom, ClientSession, SharedState};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomRole {
+    Owner,
+    Moderator,
+    Member,
+}
+
+impl RoomRole {
+    pub fn of(room: &ChatRoom, username: &str) -> RoomRole {
+        if room.created_by == username {
+            RoomRole::Owner
+        } else if room.moderators.contains(username) {
+            RoomRole::Moderator
+        } else {
+            RoomRole::Member
+        }
+    }
+}
+
+// Owners, moderators and server admins
+pub fn is_staff(state: &SharedState, room: &ChatRoom, username: &str) -> bool {
+    RoomRole::of(room, username) != RoomRole::Member || state.is_admin(username)
+}
+
+// What a role lets its holder do to a room beyond posting in it. Server
+// admins may do everything in every room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    // Other people's messages; everyone may delete their own
+    DeleteMessages,
+    AddParticipants,
+    RemoveParticipants,
+    ChangeSettings,
+    ManageModerators,
+}
+
+impl RoomRole {
+    pub fn allows(self, permission: Permission) -> bool {
+        match self {
+            RoomRole::Owner => true,
+            RoomRole::Moderator => matches!(
+                permission,
+                Permission::DeleteMessages
+                    | Permission::AddParticipants
+                    | Permission::RemoveParticipants
+            ),
+            RoomRole::Member => false,
+        }
+    }
+}
+
+pub fn has_permission(
+    state: &SharedState,
+    room: &ChatRoom,
+    username: &str,
+    permission: Permission,
+) -> bool {
+    RoomRole::of(room, &state.canonical_username(username)).allows(permission)
+        || state.is_admin(username)
+}
+
+pub fn check(
+    state: &SharedState,
+    room: &ChatRoom,
+    username: &str,
+    permission: Permission,
+) -> Result<(), ApiError> {
+    if has_permission(state, room, username, permission) {
+        return Ok(());
+    }
+    Err(match permission {
+        Permission::ChangeSettings | Permission::ManageModerators => ApiError::NotRoomManager,
+        _ => ApiError::NotRoomModerator,
+    })
+}
+
+// Pushed to the room and to every session of the affected user, so live
+// sessions swap their cached role without reconnecting
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct RoleChanged {
+    pub room_id: Uuid,
+    pub username: String,
+    pub role: RoomRole,
+    pub previous: RoomRole,
+    pub by: String,
+}
+
+impl RoleChanged {
+    pub fn frame(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": "role_changed",
+            "room_id": self.room_id,
+            "username": self.username,
+            "role": self.role,
+            "previous": self.previous,
+            "by": self.by,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RoleUpdate {
+    username: String,
+    role: RoomRole,
+}
+
+pub async fn set_participant_role(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<RoleUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let username = state.canonical_username(&form.username);
+    change_role(&state, room_id, &user.username, &username, form.role)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "username": username,
+        "role": form.role,
+    })))
+}
+
+// PUT /rooms/{id}/moderators/{username}
+pub async fn grant_moderator(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, String)>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    set_moderator(&state, path.into_inner(), &user, RoomRole::Moderator)
+}
+
+// DELETE /rooms/{id}/moderators/{username}
+pub async fn revoke_moderator(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, String)>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    set_moderator(&state, path.into_inner(), &user, RoomRole::Member)
+}
+
+fn set_moderator(
+    state: &SharedState,
+    (room_id, username): (Uuid, String),
+    user: &UserContext,
+    role: RoomRole,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(room_id);
+    let username = state.canonical_username(&username);
+    let previous = change_role(state, room_id, &user.username, &username, role)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "username": username,
+        "role": role,
+        "previous": previous,
+    })))
+}
+
+// Makes `username` a moderator or a plain member, telling live sessions and
+// recording it when that's a change; returns the role they had before
+fn change_role(
+    state: &SharedState,
+    room_id: Uuid,
+    actor: &str,
+    username: &str,
+    role: RoomRole,
+) -> Result<RoomRole, ApiError> {
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    check(state, room, actor, Permission::ManageModerators)?;
+    if role == RoomRole::Owner || room.created_by == username {
+        return Err(ApiError::OwnerRoleFixed);
+    }
+    if !room.participants.contains(username) {
+        return Err(ApiError::ParticipantNotFound);
+    }
+    let previous = RoomRole::of(room, username);
+    match role {
+        RoomRole::Moderator => room.moderators.insert(username.to_string()),
+        _ => room.moderators.remove(username),
+    };
+    store::room_changed(state, room);
+    drop(rooms);
+
+    if previous != role {
+        let change = RoleChanged {
+            room_id,
+            username: username.to_string(),
+            role,
+            previous,
+            by: state.canonical_username(actor),
+        };
+        notify_role_changed(state, &change);
+        audit::record(
+            state,
+            actor,
+            "set_participant_role",
+            &room_id.to_string(),
+            format!("{} {:?} -> {:?}", username, previous, role),
+        );
+    }
+    Ok(previous)
+}
+
+pub fn notify_role_changed(state: &SharedState, change: &RoleChanged) {
+    let sessions = state.active_sessions.lock().unwrap();
+    let user_sessions = state.user_sessions.lock().unwrap();
+    let mut recipients: Vec<Addr<ClientSession>> = Vec::new();
+    for addr in sessions
+        .get(&change.room_id)
+        .into_iter()
+        .chain(user_sessions.get(&change.username))
+        .flatten()
+    {
+        if !recipients.contains(addr) {
+            recipients.push(addr.clone());
+        }
+    }
+    let dead = deadletter::fan_out(&recipients, change);
+    drop(user_sessions);
+    drop(sessions);
+    deadletter::record_undelivered(
+        state,
+        change.room_id,
+        dead,
+        Undelivered::Event(change.frame()),
+    );
+}
@@ -0,0 +1,85 @@
+// `#room-slug` references. A room's slug is its name lowercased, with every
+// run of other characters than letters and digits turned into one dash, so
+// "Release planning" is `#release-planning`. When a message is appended the
+// server resolves its references against the rooms the sender can see and
+// keeps them on the message as `room_refs`, so clients can link them without
+// looking rooms up. A slug several such rooms share links to none of them.
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::invites;
+use crate::{ChatMessage, ChatRoom, MessageKind, SharedState};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RoomRef {
+    slug: String,
+    room_id: Uuid,
+}
+
+pub fn slug(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in name.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(slug.trim_end_matches('-').len());
+    slug
+}
+
+// `#slug` tokens in a message, lowercased, in order
+fn referenced_slugs(content: &str) -> Vec<String> {
+    let mut slugs: Vec<String> = Vec::new();
+    let words = content
+        .split(|c: char| c.is_whitespace() || matches!(c, ',' | '.' | '!' | '?' | ':' | ';'))
+        .filter_map(|word| word.strip_prefix('#'))
+        .filter(|slug| !slug.is_empty());
+    for word in words {
+        let word = word.to_lowercase();
+        if !slugs.contains(&word) {
+            slugs.push(word);
+        }
+    }
+    slugs
+}
+
+// Call with every room, before the message's own is borrowed for the append
+pub fn resolve(
+    state: &SharedState,
+    rooms: &HashMap<Uuid, ChatRoom>,
+    msg: &ChatMessage,
+) -> Vec<RoomRef> {
+    if msg.kind != MessageKind::User {
+        return Vec::new();
+    }
+    let wanted = referenced_slugs(&msg.content);
+    if wanted.is_empty() {
+        return Vec::new();
+    }
+    let mut matches: HashMap<String, Vec<Uuid>> = HashMap::new();
+    for room in rooms.values() {
+        if room.direct || room.bans.contains_key(&msg.sender) {
+            continue;
+        }
+        let room_slug = slug(&room.name);
+        if !wanted.contains(&room_slug) {
+            continue;
+        }
+        if invites::check_access(state, room, Some(&msg.sender)).is_ok() {
+            matches.entry(room_slug).or_default().push(room.id);
+        }
+    }
+    wanted
+        .into_iter()
+        .filter_map(|wanted| match matches.get(&wanted).map(Vec::as_slice) {
+            Some(&[room_id]) => Some(RoomRef {
+                slug: wanted,
+                room_id,
+            }),
+            _ => None,
+        })
+        .collect()
+}
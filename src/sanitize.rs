@@ -0,0 +1,135 @@
+// Message text as it is stored and shown. Everything a user or bridge sends
+// goes through `clean` first: bidi controls that could reorder what others
+// see are stripped along with other invisible control characters, the text
+// is normalized to NFC, and each grapheme is cut down to a few combining
+// marks, so a zalgo stack renders as an ordinary letter. Lengths are counted
+// in graphemes, what a reader would call characters, with a byte cap on top
+// since one grapheme can hold many code points.
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+// Enough for any script's diacritics, not for zalgo
+const MAX_MARKS_PER_GRAPHEME: usize = 4;
+// Long enough for emoji ZWJ and tag sequences
+const MAX_CHARS_PER_GRAPHEME: usize = 32;
+
+// Embeddings, overrides, isolates and the implicit marks
+fn is_bidi_control(c: char) -> bool {
+    matches!(
+        c,
+        '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}'
+    )
+}
+
+fn is_stripped(c: char) -> bool {
+    is_bidi_control(c) || (c.is_control() && c != '\n' && c != '\t')
+}
+
+pub fn clean(text: &str) -> String {
+    let normalized: String = text.chars().filter(|c| !is_stripped(*c)).nfc().collect();
+    let mut cleaned = String::with_capacity(normalized.len());
+    for grapheme in normalized.graphemes(true) {
+        let mut marks = 0;
+        for c in grapheme.chars().take(MAX_CHARS_PER_GRAPHEME) {
+            if is_combining_mark(c) {
+                marks += 1;
+                if marks > MAX_MARKS_PER_GRAPHEME {
+                    continue;
+                }
+            }
+            cleaned.push(c);
+        }
+    }
+    cleaned
+}
+
+pub fn grapheme_len(text: &str) -> usize {
+    text.graphemes(true).count()
+}
+
+// At most `max` graphemes, none of them split
+pub fn truncate(text: &str, max: usize) -> &str {
+    match text.grapheme_indices(true).nth(max) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_bidi_controls() {
+        let spoofed = "invoice\u{202E}fdp.exe\u{202C} and \u{2067}isolated\u{2069}\u{200F}";
+        assert_eq!(clean(spoofed), "invoicefdp.exe and isolated");
+    }
+
+    #[test]
+    fn strips_controls_but_keeps_line_breaks_and_tabs() {
+        assert_eq!(clean("a\u{0}b\u{7}c\r\nd\te\u{9b}"), "abc\nd\te");
+    }
+
+    #[test]
+    fn normalizes_to_nfc() {
+        assert_eq!(clean("cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(clean("\u{439}"), clean("\u{438}\u{306}"));
+    }
+
+    #[test]
+    fn leaves_ordinary_text_alone() {
+        for text in [
+            "Привіт, як справи?",
+            "Tiếng Việt có dấu",
+            "नमस्ते दुनिया",
+            "שָׁלוֹם",
+            "مرحبا بالعالم",
+            "👨‍👩‍👧‍👦 🏳️‍🌈 👍🏽 1️⃣",
+            "line one\n\tline two",
+        ] {
+            let normalized: String = text.nfc().collect();
+            assert_eq!(clean(text), normalized, "{}", text);
+        }
+    }
+
+    #[test]
+    fn caps_combining_marks_per_grapheme() {
+        let zalgo = format!(
+            "Z{}a{}",
+            "\u{336}\u{35b}\u{321}".repeat(20),
+            "\u{300}".repeat(3)
+        );
+        let cleaned = clean(&zalgo);
+        assert_eq!(grapheme_len(&cleaned), 2);
+        let graphemes: Vec<&str> = cleaned.graphemes(true).collect();
+        assert_eq!(graphemes[0].chars().count(), 1 + MAX_MARKS_PER_GRAPHEME);
+        // NFC composes the first mark into the letter
+        assert_eq!(graphemes[1], "\u{e0}\u{300}\u{300}");
+    }
+
+    #[test]
+    fn caps_code_points_per_grapheme() {
+        let chain = format!("\u{1f469}{}", "\u{200d}\u{1f469}".repeat(100));
+        let cleaned = clean(&chain);
+        assert_eq!(cleaned.chars().count(), MAX_CHARS_PER_GRAPHEME);
+    }
+
+    #[test]
+    fn counts_graphemes_not_code_points() {
+        assert_eq!(grapheme_len("👨‍👩‍👧‍👦"), 1);
+        assert_eq!(grapheme_len("e\u{301}e"), 2);
+        assert_eq!(grapheme_len("🇺🇦🇵🇱"), 2);
+        assert_eq!(grapheme_len(""), 0);
+    }
+
+    #[test]
+    fn truncates_on_grapheme_boundaries() {
+        let text = "a👨‍👩‍👧‍👦e\u{301}b";
+        assert_eq!(truncate(text, 0), "");
+        assert_eq!(truncate(text, 1), "a");
+        assert_eq!(truncate(text, 2), "a👨‍👩‍👧‍👦");
+        assert_eq!(truncate(text, 3), "a👨‍👩‍👧‍👦e\u{301}");
+        assert_eq!(truncate(text, 10), text);
+    }
+}
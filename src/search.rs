@@ -0,0 +1,449 @@
+// Full-text search over room messages, backed by an in-memory inverted index
+// per room. A room is indexed in full the first time it is searched; after
+// that the index follows every change that goes through `store` (sends,
+// edits, deletions, expiry, retention, imports, merges). Changes that arrive
+// any other way, such as histories loaded from the database or a replica's
+// snapshot, drop the room's index so the next search rebuilds it. Hits are
+// checked against the live log before they are returned, so a stale entry
+// can hide a match for one search but never show a message that doesn't
+// match or no longer exists.
+//
+// Messages are ranked by BM25 over their words, compared after NFKC
+// normalisation and lowercasing. Every word in the query must appear.
+//
+// Lock order: the index is locked after `chat_rooms`.
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use unicode_normalization::UnicodeNormalization;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::bans;
+use crate::error::ApiError;
+use crate::invites;
+use crate::visibility;
+use crate::{ChatMessage, ChatRoom, MessageKind, SharedState};
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+const MAX_QUERY_TERMS: usize = 16;
+const MAX_TERM_CHARS: usize = 64;
+// Characters of context shown around the first hit
+const SNIPPET_CHARS: usize = 160;
+// BM25 parameters
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+struct Doc {
+    seq: u64,
+    terms: HashMap<String, u32>, // term -> occurrences
+    len: u32,
+}
+
+#[derive(Default)]
+struct RoomIndex {
+    postings: HashMap<String, HashSet<Uuid>>, // term -> message ids
+    docs: HashMap<Uuid, Doc>,
+    total_len: u64,
+}
+
+impl RoomIndex {
+    fn build(room: &ChatRoom) -> Self {
+        let mut index = RoomIndex::default();
+        for msg in &room.message_log {
+            index.insert(msg);
+        }
+        index
+    }
+
+    fn insert(&mut self, msg: &ChatMessage) {
+        self.remove(msg.id);
+        if !searchable(msg) {
+            return;
+        }
+        let mut terms: HashMap<String, u32> = HashMap::new();
+        let mut len = 0;
+        for (_, term) in tokenize(&msg.content) {
+            *terms.entry(term).or_default() += 1;
+            len += 1;
+        }
+        for term in terms.keys() {
+            self.postings
+                .entry(term.clone())
+                .or_default()
+                .insert(msg.id);
+        }
+        self.total_len += u64::from(len);
+        self.docs.insert(
+            msg.id,
+            Doc {
+                seq: msg.seq,
+                terms,
+                len,
+            },
+        );
+    }
+
+    fn remove(&mut self, message_id: Uuid) {
+        let Some(doc) = self.docs.remove(&message_id) else {
+            return;
+        };
+        self.total_len -= u64::from(doc.len);
+        for term in doc.terms.keys() {
+            if let Some(ids) = self.postings.get_mut(term) {
+                ids.remove(&message_id);
+                if ids.is_empty() {
+                    self.postings.remove(term);
+                }
+            }
+        }
+    }
+
+    // Messages holding every term, best first
+    fn rank(&self, terms: &[String]) -> Vec<(Uuid, f64)> {
+        let mut lists: Vec<&HashSet<Uuid>> = Vec::with_capacity(terms.len());
+        for term in terms {
+            match self.postings.get(term) {
+                Some(ids) => lists.push(ids),
+                None => return Vec::new(),
+            }
+        }
+        lists.sort_by_key(|ids| ids.len());
+        let (rarest, rest) = lists.split_first().expect("at least one term");
+        let count = self.docs.len() as f64;
+        let average_len = (self.total_len as f64 / count).max(1.0);
+        rarest
+            .iter()
+            .filter(|id| rest.iter().all(|ids| ids.contains(id)))
+            .map(|id| {
+                let doc = &self.docs[id];
+                let score = terms
+                    .iter()
+                    .map(|term| {
+                        let frequency = f64::from(doc.terms[term]);
+                        let matching = self.postings[term].len() as f64;
+                        let idf = (1.0 + (count - matching + 0.5) / (matching + 0.5)).ln();
+                        let norm = K1 * (1.0 - B + B * f64::from(doc.len) / average_len);
+                        idf * frequency * (K1 + 1.0) / (frequency + norm)
+                    })
+                    .sum();
+                (*id, score)
+            })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct SearchIndex {
+    rooms: HashMap<Uuid, RoomIndex>, // only rooms searched since they last changed wholesale
+    owners: HashMap<Uuid, Uuid>,     // message id -> room id, for removals by id
+}
+
+impl SearchIndex {
+    fn room(&mut self, room: &ChatRoom) -> &RoomIndex {
+        if !self.rooms.contains_key(&room.id) {
+            let index = RoomIndex::build(room);
+            self.owners
+                .extend(index.docs.keys().map(|message_id| (*message_id, room.id)));
+            self.rooms.insert(room.id, index);
+        }
+        &self.rooms[&room.id]
+    }
+
+    fn forget(&mut self, room_id: Uuid) {
+        if let Some(index) = self.rooms.remove(&room_id) {
+            for message_id in index.docs.keys() {
+                self.owners.remove(message_id);
+            }
+        }
+    }
+}
+
+// User messages only; deleted ones leave the index with their content
+fn searchable(msg: &ChatMessage) -> bool {
+    msg.kind == MessageKind::User && msg.deleted_at.is_none()
+}
+
+// Words with their char offsets in `text`
+fn tokenize(text: &str) -> Vec<(std::ops::Range<usize>, String)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let chars: Vec<char> = text.chars().collect();
+    for (at, c) in chars.iter().chain(std::iter::once(&' ')).enumerate() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(at),
+            (false, Some(from)) => {
+                start = None;
+                let word: String = chars[from..at].iter().collect();
+                let term: String = word.nfkc().flat_map(char::to_lowercase).collect();
+                if term.chars().count() <= MAX_TERM_CHARS {
+                    words.push((from..at, term));
+                }
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+// Kept current from `store`, which sees every message change
+pub fn messages_changed(state: &SharedState, messages: &[ChatMessage]) {
+    let mut index = state.search.lock().unwrap();
+    let index = &mut *index;
+    for msg in messages {
+        if let Some(previous) = index.owners.remove(&msg.id) {
+            if let Some(room) = index.rooms.get_mut(&previous) {
+                room.remove(msg.id);
+            }
+        }
+        // Rooms not indexed yet are read in full when first searched
+        if let Some(room) = index.rooms.get_mut(&msg.room_id) {
+            room.insert(msg);
+            if room.docs.contains_key(&msg.id) {
+                index.owners.insert(msg.id, msg.room_id);
+            }
+        }
+    }
+}
+
+pub fn messages_removed(state: &SharedState, ids: &[Uuid]) {
+    let mut index = state.search.lock().unwrap();
+    let index = &mut *index;
+    for message_id in ids {
+        if let Some(room_id) = index.owners.remove(message_id) {
+            if let Some(room) = index.rooms.get_mut(&room_id) {
+                room.remove(*message_id);
+            }
+        }
+    }
+}
+
+// For logs replaced or filled in outside `store`, and rooms that are gone
+pub fn room_reset(state: &SharedState, room_id: Uuid) {
+    state.search.lock().unwrap().forget(room_id);
+}
+
+pub fn reset_all(state: &SharedState) {
+    *state.search.lock().unwrap() = SearchIndex::default();
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    #[serde(default)]
+    sender: Option<String>,
+    // Unix millis, inclusive
+    #[serde(default)]
+    since: Option<u64>,
+    #[serde(default)]
+    until: Option<u64>,
+    #[serde(default)]
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Hit {
+    room_id: Uuid,
+    message_id: Uuid,
+    seq: u64,
+    sender: String,
+    sent_at: u64,
+    score: f64,
+    snippet: String,
+    // Char ranges of matched words within `snippet`
+    highlights: Vec<[usize; 2]>,
+}
+
+struct Filters {
+    terms: Vec<String>,
+    sender: Option<String>,
+    since: u64,
+    until: u64,
+}
+
+impl Filters {
+    fn parse(state: &SharedState, query: &SearchQuery) -> Result<Self, ApiError> {
+        let mut terms: Vec<String> = Vec::new();
+        for (_, term) in tokenize(&query.q) {
+            if !terms.contains(&term) {
+                terms.push(term);
+            }
+        }
+        if terms.is_empty() || terms.len() > MAX_QUERY_TERMS {
+            return Err(ApiError::InvalidSearchQuery);
+        }
+        Ok(Filters {
+            terms,
+            sender: query
+                .sender
+                .as_deref()
+                .map(|sender| state.canonical_username(sender)),
+            since: query.since.unwrap_or(0),
+            until: query.until.unwrap_or(u64::MAX),
+        })
+    }
+
+    fn admits(&self, msg: &ChatMessage) -> bool {
+        self.sender
+            .as_ref()
+            .is_none_or(|sender| msg.sender == *sender)
+            && (self.since..=self.until).contains(&msg.sent_at)
+    }
+}
+
+// The room's hits, checked against its log
+fn search_room(index: &mut SearchIndex, room: &ChatRoom, filters: &Filters) -> Vec<Hit> {
+    let ranked = index.room(room).rank(&filters.terms);
+    let mut stale = false;
+    let mut hits = Vec::new();
+    for (message_id, score) in ranked {
+        let seq = index.rooms[&room.id].docs[&message_id].seq;
+        let log = &room.message_log;
+        let found = log
+            .binary_search_by_key(&seq, |msg| msg.seq)
+            .ok()
+            .map(|at| &log[at])
+            .filter(|msg| msg.id == message_id && searchable(msg));
+        let Some(msg) = found else {
+            stale = true;
+            continue;
+        };
+        if !filters.admits(msg) {
+            continue;
+        }
+        let Some((snippet, highlights)) = snippet(&msg.content, &filters.terms) else {
+            stale = true;
+            continue;
+        };
+        hits.push(Hit {
+            room_id: room.id,
+            message_id,
+            seq,
+            sender: msg.sender.clone(),
+            sent_at: msg.sent_at,
+            score,
+            snippet,
+            highlights,
+        });
+    }
+    if stale {
+        index.forget(room.id);
+    }
+    hits
+}
+
+// None when the content no longer holds every term
+fn snippet(content: &str, terms: &[String]) -> Option<(String, Vec<[usize; 2]>)> {
+    let words = tokenize(content);
+    let matched: Vec<_> = words
+        .iter()
+        .filter(|(_, term)| terms.contains(term))
+        .collect();
+    if !terms
+        .iter()
+        .all(|term| matched.iter().any(|(_, found)| found == term))
+    {
+        return None;
+    }
+    let chars: Vec<char> = content.chars().collect();
+    let first = matched[0].0.start;
+    let start = first.saturating_sub(SNIPPET_CHARS / 4);
+    let end = (start + SNIPPET_CHARS).min(chars.len());
+    let lead = if start > 0 { "\u{2026}" } else { "" };
+    let trail = if end < chars.len() { "\u{2026}" } else { "" };
+    let shift = lead.chars().count();
+    let snippet = format!(
+        "{}{}{}",
+        lead,
+        chars[start..end].iter().collect::<String>(),
+        trail
+    );
+    let highlights = matched
+        .iter()
+        .filter(|(range, _)| range.start >= start && range.end <= end)
+        .map(|(range, _)| [range.start - start + shift, range.end - start + shift])
+        .collect();
+    Some((snippet, highlights))
+}
+
+fn respond(mut hits: Vec<Hit>, query: &SearchQuery) -> HttpResponse {
+    hits.sort_by(|a, b| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(Ordering::Equal)
+            .then(b.sent_at.cmp(&a.sent_at))
+    });
+    let total = hits.len();
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let hits: Vec<Hit> = hits.into_iter().skip(offset).take(limit).collect();
+    HttpResponse::Ok().json(serde_json::json!({
+        "total": total,
+        "has_more": offset + hits.len() < total,
+        "hits": hits,
+    }))
+}
+
+pub async fn search_room_messages(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    query: web::Query<SearchQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let filters = Filters::parse(&state, &query)?;
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    invites::check_reader(&state, room, Some(&user))?;
+    bans::check(room, &user.username)?;
+    let mut hits = search_room(&mut state.search.lock().unwrap(), room, &filters);
+    let first_seq = visibility::first_visible_seq(&state, room, Some(&user.username));
+    hits.retain(|hit| hit.seq >= first_seq);
+    drop(rooms);
+    Ok(respond(hits, &query))
+}
+
+// Every room the caller belongs to
+pub async fn search_all_rooms(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+    query: web::Query<SearchQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let actor = &user.username;
+    let filters = Filters::parse(&state, &query)?;
+    let joined: Vec<Uuid> = {
+        let cold = state.cold_storage.lock().unwrap();
+        let rooms = state.chat_rooms.lock().unwrap();
+        rooms
+            .values()
+            .chain(cold.archived())
+            .filter(|room| room.members().contains(actor) && !room.bans.contains_key(actor))
+            .map(|room| room.id)
+            .collect()
+    };
+    // Loads rooms back from cold storage and any history still only in the database
+    for room_id in &joined {
+        state.resolve_room_id(*room_id);
+    }
+    let rooms = state.chat_rooms.lock().unwrap();
+    let mut index = state.search.lock().unwrap();
+    let mut hits = Vec::new();
+    for room_id in joined {
+        if let Some(room) = rooms.get(&room_id) {
+            let first_seq = visibility::first_visible_seq(&state, room, Some(actor));
+            hits.extend(
+                search_room(&mut index, room, &filters)
+                    .into_iter()
+                    .filter(|hit| hit.seq >= first_seq),
+            );
+        }
+    }
+    drop(index);
+    drop(rooms);
+    Ok(respond(hits, &query))
+}
@@ -0,0 +1,184 @@
+// Fixture loading for integration and UI tests. `POST /test/seed` only exists
+// in builds with the `dev` feature, so release binaries never expose it.
+use actix_web::web;
+
+#[cfg_attr(not(feature = "dev"), allow(unused_variables))]
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    #[cfg(feature = "dev")]
+    cfg.route("/test/seed", web::post().to(fixture::seed));
+}
+
+#[cfg(feature = "dev")]
+mod fixture {
+    use actix_web::{web, HttpResponse};
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    use crate::attachments::Attachment;
+    use crate::error::ApiError;
+    use crate::messages::Priority;
+    use crate::passwords;
+    use crate::search;
+    use crate::versions::VersionVector;
+    use crate::{ChatMessage, ChatRoom, MessageKind, SharedState};
+
+    // Everything is spelled out (ids, timestamps) so that two runs against
+    // the same fixture produce byte-identical responses
+    #[derive(Deserialize)]
+    pub struct Fixture {
+        // Drop existing accounts and rooms before loading
+        #[serde(default)]
+        reset: bool,
+        #[serde(default)]
+        users: Vec<SeedUser>,
+        #[serde(default)]
+        rooms: Vec<SeedRoom>,
+    }
+
+    #[derive(Deserialize)]
+    struct SeedUser {
+        username: String,
+        password: String,
+        #[serde(default)]
+        email: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct SeedRoom {
+        id: Uuid,
+        name: String,
+        created_by: String,
+        #[serde(default)]
+        participants: Vec<String>,
+        #[serde(default)]
+        moderators: Vec<String>,
+        #[serde(default)]
+        tags: Vec<String>,
+        #[serde(default)]
+        messages: Vec<SeedMessage>,
+    }
+
+    #[derive(Deserialize)]
+    struct SeedMessage {
+        id: Uuid,
+        sender: String,
+        content: String,
+        sent_at: u64,
+        #[serde(default)]
+        kind: MessageKind,
+        #[serde(default)]
+        attachments: Vec<Attachment>,
+    }
+
+    fn reset(state: &SharedState) {
+        state.chat_rooms.lock().unwrap().clear();
+        search::reset_all(state);
+        *state.external_ids.lock().unwrap() = Default::default();
+        state.room_redirects.lock().unwrap().clear();
+        state.user_accounts.lock().unwrap().clear();
+        state.user_emails.lock().unwrap().clear();
+        state.user_settings.lock().unwrap().clear();
+        state.bookmarks.lock().unwrap().clear();
+        *state.keywords.lock().unwrap() = Default::default();
+        *state.rejections.lock().unwrap() = Default::default();
+        *state.unread.lock().unwrap() = Default::default();
+        *state.deliveries.lock().unwrap() = Default::default();
+    }
+
+    // Loads accounts and rooms directly, without registration hooks, bots or
+    // broadcasts, so the resulting state is exactly what the fixture describes
+    pub async fn seed(
+        state: web::Data<Arc<SharedState>>,
+        fixture: web::Json<Fixture>,
+    ) -> Result<HttpResponse, ApiError> {
+        let fixture = fixture.into_inner();
+        if fixture.reset {
+            reset(&state);
+        }
+
+        let users = fixture.users.len();
+        for user in fixture.users {
+            let username = state.canonical_username(&user.username);
+            if let Some(email) = user.email {
+                state
+                    .user_emails
+                    .lock()
+                    .unwrap()
+                    .insert(username.clone(), email);
+            }
+            // Fixtures may carry ready-made hashes to skip the hashing cost
+            let password = if passwords::is_hashed(&user.password) {
+                user.password
+            } else {
+                passwords::hash(&user.password)
+            };
+            state
+                .user_accounts
+                .lock()
+                .unwrap()
+                .insert(username, password);
+        }
+
+        let mut messages = 0;
+        let mut rooms = state.chat_rooms.lock().unwrap();
+        for seed in &fixture.rooms {
+            let mut room = ChatRoom::new(
+                seed.name.clone(),
+                state.canonical_username(&seed.created_by),
+            );
+            room.id = seed.id;
+            room.tags = seed.tags.clone();
+            room.participants = seed
+                .participants
+                .iter()
+                .map(|name| state.canonical_username(name))
+                .collect();
+            room.moderators = seed
+                .moderators
+                .iter()
+                .map(|name| state.canonical_username(name))
+                .collect();
+            room.message_log = seed
+                .messages
+                .iter()
+                .map(|msg| ChatMessage {
+                    id: msg.id,
+                    room_id: seed.id,
+                    sender: state.canonical_username(&msg.sender),
+                    content: msg.content.clone(),
+                    sent_at: msg.sent_at,
+                    origin_room_id: None,
+                    kind: msg.kind,
+                    seq: 0,
+                    imported_from: None,
+                    expires_at: None,
+                    edited_at: None,
+                    edit_history: Vec::new(),
+                    deleted_at: None,
+                    reactions: BTreeMap::new(),
+                    attachments: msg.attachments.clone(),
+                    version: VersionVector::default(),
+                    bridged_from: None,
+                    pending_since: None,
+                    node_id: None,
+                    priority: Priority::Normal,
+                    parent_message_id: None,
+                    mentions: Vec::new(),
+                    room_refs: Vec::new(),
+                })
+                .collect();
+            room.resequence();
+            messages += room.message_log.len();
+            rooms.insert(room.id, room);
+        }
+        drop(rooms);
+
+        Ok(HttpResponse::Ok().json(serde_json::json!({
+            "users": users,
+            "rooms": fixture.rooms.len(),
+            "messages": messages,
+        })))
+    }
+}
@@ -0,0 +1,162 @@
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize, Serializer};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::roles::RoomRole;
+use crate::{now_millis, SharedState};
+
+// WS subprotocols the server understands, newest first. chat.v2 and
+// connections that name no protocol get typed JSON frames for everything;
+// chat.v1 clients receive chat messages as their bare text.
+pub const SUPPORTED_PROTOCOLS: [&str; 2] = ["chat.v2", "chat.v1"];
+pub const PLAIN_TEXT_PROTOCOL: &str = "chat.v1";
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ConnectionMeta {
+    pub user_agent: Option<String>,
+    pub protocol: Option<String>,
+    pub remote_addr: Option<String>,
+    pub connected_at: u64,
+}
+
+impl ConnectionMeta {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let header_value = |name: header::HeaderName| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        // Mirrors the subprotocol selection done by WsResponseBuilder
+        let protocol = header_value(header::SEC_WEBSOCKET_PROTOCOL).and_then(|offered| {
+            offered
+                .split(',')
+                .map(str::trim)
+                .find(|p| SUPPORTED_PROTOCOLS.contains(p))
+                .map(str::to_string)
+        });
+        ConnectionMeta {
+            user_agent: header_value(header::USER_AGENT),
+            protocol,
+            remote_addr: req
+                .connection_info()
+                .realip_remote_addr()
+                .map(str::to_string),
+            connected_at: now_millis(),
+        }
+    }
+}
+
+const DEFAULT_PER_PAGE: usize = 50;
+const MAX_PER_PAGE: usize = 200;
+
+// Frame and byte counts for one connection, shared between the session actor
+// and its registry entry so counting a frame takes no lock
+#[derive(Default, Debug)]
+pub struct Traffic {
+    frames_in: AtomicU64,
+    frames_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    last_frame_at: AtomicU64,
+}
+
+impl Traffic {
+    pub fn received(&self, bytes: usize) {
+        self.frames_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_frame_at.store(now_millis(), Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.frames_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl Serialize for Traffic {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let last_frame_at = self.last_frame_at.load(Ordering::Relaxed);
+        serde_json::json!({
+            "frames_in": self.frames_in.load(Ordering::Relaxed),
+            "frames_out": self.frames_out.load(Ordering::Relaxed),
+            "bytes_in": self.bytes_in.load(Ordering::Relaxed),
+            "bytes_out": self.bytes_out.load(Ordering::Relaxed),
+            // Last frame from the client; null until it sends one
+            "last_frame_at": (last_frame_at > 0).then_some(last_frame_at),
+        })
+        .serialize(serializer)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SessionInfo {
+    pub session_id: Uuid,
+    pub room_id: Uuid,
+    pub username: String,
+    pub role: RoomRole,
+    #[serde(flatten)]
+    pub meta: ConnectionMeta,
+    pub traffic: Arc<Traffic>,
+}
+
+#[derive(Deserialize)]
+pub struct SessionQuery {
+    #[serde(default)]
+    room: Option<Uuid>,
+    #[serde(default)]
+    user: Option<String>,
+    // 1-based
+    #[serde(default)]
+    page: Option<usize>,
+    #[serde(default)]
+    per_page: Option<usize>,
+}
+
+// Oldest connections first
+pub async fn list_sessions(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+    query: web::Query<SessionQuery>,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let page = query.page.unwrap_or(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PER_PAGE);
+    if page == 0 || !(1..=MAX_PER_PAGE).contains(&per_page) {
+        return Err(ApiError::InvalidQuery);
+    }
+    let room = query.room.map(|room_id| state.follow_redirects(room_id));
+    let user = query
+        .user
+        .as_deref()
+        .map(|user| state.canonical_username(user));
+    let mut sessions: Vec<SessionInfo> = state
+        .session_registry
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|s| room.is_none_or(|room_id| s.room_id == room_id))
+        .filter(|s| user.as_ref().is_none_or(|user| s.username == *user))
+        .cloned()
+        .collect();
+    sessions.sort_by_key(|s| (s.meta.connected_at, s.session_id));
+    let total = sessions.len();
+    let sessions: Vec<SessionInfo> = sessions
+        .into_iter()
+        .skip((page - 1) * per_page)
+        .take(per_page)
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "sessions": sessions,
+        "page": page,
+        "per_page": per_page,
+        "total": total,
+    })))
+}
@@ -0,0 +1,146 @@
+// Room settings. PATCH /rooms/{id} lets the owner (or an admin) rename a
+// room, set its description, switch the `private` and `read_only` flags and
+// choose how much history late joiners see (see `visibility`). Fields left
+// out stay as they are, and an empty description clears it. Connected
+// sessions get a `room_updated` event with the new metadata, and members'
+// room lists are told to refresh. In a read-only room only staff can post.
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::admin::MAX_ROOM_NAME_LEN;
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::roles::{self, Permission};
+use crate::store;
+use crate::visibility::HistoryVisibility;
+use crate::{audit, ChatRoom, MessageKind, RoomEvent, SharedState};
+
+const MAX_DESCRIPTION_CHARS: usize = 500;
+
+#[derive(Deserialize)]
+pub struct RoomSettingsUpdate {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    private: Option<bool>,
+    #[serde(default)]
+    read_only: Option<bool>,
+    #[serde(default)]
+    history_visibility: Option<HistoryVisibility>,
+}
+
+// Announcements and the like come from the server, so they still get through
+pub fn check_send(
+    state: &SharedState,
+    room_id: Uuid,
+    sender: &str,
+    kind: MessageKind,
+) -> Result<(), ApiError> {
+    if kind == MessageKind::System {
+        return Ok(());
+    }
+    let rooms = state.chat_rooms.lock().unwrap();
+    match rooms.get(&room_id) {
+        Some(room) if room.read_only && !roles::is_staff(state, room, sender) => {
+            Err(ApiError::RoomReadOnly)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn settings_json(room: &ChatRoom) -> serde_json::Value {
+    serde_json::json!({
+        "room_id": room.id,
+        "name": room.name,
+        "description": room.description,
+        "private": room.private,
+        "read_only": room.read_only,
+        "history_visibility": room.history_visibility,
+    })
+}
+
+pub async fn update_room(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    form: web::Json<RoomSettingsUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let update = form.into_inner();
+    let name = update.name.as_deref().map(str::trim);
+    if name.is_some_and(|name| name.is_empty() || name.chars().count() > MAX_ROOM_NAME_LEN) {
+        return Err(ApiError::InvalidRoomSettings);
+    }
+    let description = update.description.as_deref().map(str::trim);
+    if description.is_some_and(|description| description.chars().count() > MAX_DESCRIPTION_CHARS) {
+        return Err(ApiError::InvalidRoomSettings);
+    }
+    if update
+        .history_visibility
+        .is_some_and(|visibility| !visibility.is_valid())
+    {
+        return Err(ApiError::InvalidRoomSettings);
+    }
+
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get_mut(&room_id).ok_or(ApiError::RoomNotFound)?;
+    roles::check(&state, room, &user.username, Permission::ChangeSettings)?;
+    // A conversation between two people has nothing to configure
+    if room.direct {
+        return Err(ApiError::DirectRoomFixed);
+    }
+    let mut changes = Vec::new();
+    if let Some(name) = name.filter(|name| *name != room.name) {
+        changes.push(format!("name: {} -> {}", room.name, name));
+        room.name = name.to_string();
+    }
+    if let Some(description) = description {
+        let description = (!description.is_empty()).then(|| description.to_string());
+        if description != room.description {
+            changes.push("description".to_string());
+            room.description = description;
+        }
+    }
+    if let Some(private) = update.private.filter(|private| *private != room.private) {
+        changes.push(format!("private: {}", private));
+        room.private = private;
+    }
+    if let Some(read_only) = update
+        .read_only
+        .filter(|read_only| *read_only != room.read_only)
+    {
+        changes.push(format!("read_only: {}", read_only));
+        room.read_only = read_only;
+    }
+    if let Some(visibility) = update
+        .history_visibility
+        .filter(|visibility| *visibility != room.history_visibility)
+    {
+        changes.push(format!("history_visibility: {:?}", visibility));
+        room.history_visibility = visibility;
+    }
+    let settings = settings_json(room);
+    if changes.is_empty() {
+        return Ok(HttpResponse::Ok().json(settings));
+    }
+    store::room_changed(&state, room);
+    let members = room.members();
+    drop(rooms);
+
+    let mut event = settings.clone();
+    event["type"] = "room_updated".into();
+    state.broadcast_event(room_id, RoomEvent(event));
+    state.notify_room_list_changed(&members, "updated", room_id);
+    audit::record(
+        &state,
+        &user.username,
+        "update_room",
+        &room_id.to_string(),
+        changes.join(", "),
+    );
+    Ok(HttpResponse::Ok().json(settings))
+}
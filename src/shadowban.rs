@@ -0,0 +1,150 @@
+// Shadow bans: a banned user's messages look delivered to them but nobody
+// else sees them and they never reach the room log. Honeypot rooms, which
+// regular users have no reason to post in, shadow-ban whoever posts there.
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::auth::UserContext;
+use crate::deadletter;
+use crate::error::ApiError;
+use crate::{ChatMessage, MessageKind, SharedState};
+
+#[derive(Default)]
+pub struct ShadowBans {
+    users: HashSet<String>,
+    honeypot_rooms: HashSet<Uuid>,
+}
+
+impl ShadowBans {
+    pub fn with_honeypots(rooms: impl IntoIterator<Item = Uuid>) -> Self {
+        ShadowBans {
+            honeypot_rooms: rooms.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn honeypot_rooms(&self) -> BTreeSet<Uuid> {
+        self.honeypot_rooms.iter().copied().collect()
+    }
+
+    pub fn set_honeypot_rooms(&mut self, rooms: impl IntoIterator<Item = Uuid>) {
+        self.honeypot_rooms = rooms.into_iter().collect();
+    }
+}
+
+pub fn is_banned(state: &SharedState, username: &str) -> bool {
+    state.shadow_bans.lock().unwrap().users.contains(username)
+}
+
+// True when the message must only be echoed back; a first post to a
+// honeypot room bans the sender on the spot
+pub fn screen(state: &SharedState, room_id: Uuid, sender: &str, kind: MessageKind) -> bool {
+    let mut bans = state.shadow_bans.lock().unwrap();
+    if bans.users.contains(sender) {
+        return true;
+    }
+    let trapped = kind == MessageKind::User
+        && bans.honeypot_rooms.contains(&room_id)
+        && !state.is_admin(sender);
+    if trapped {
+        bans.users.insert(sender.to_string());
+        drop(bans);
+        audit::record(
+            state,
+            "system",
+            "shadow_ban_added",
+            sender,
+            format!("posted in honeypot room {}", room_id),
+        );
+    }
+    trapped
+}
+
+// Delivers `draft` to the sender's own sessions in the room with the seq it
+// would have had, so their client can't tell it apart from a real send
+pub fn echo_to_sender(
+    state: &SharedState,
+    mut draft: ChatMessage,
+) -> Result<ChatMessage, ApiError> {
+    let room_id = draft.room_id;
+    draft.seq = state
+        .chat_rooms
+        .lock()
+        .unwrap()
+        .get(&room_id)
+        .ok_or(ApiError::RoomNotFound)?
+        .next_seq
+        + 1;
+    draft.node_id = Some(state.node_id.clone());
+    let sessions = state.active_sessions.lock().unwrap();
+    let user_sessions = state.user_sessions.lock().unwrap();
+    let room_sessions = sessions.get(&room_id).map_or(&[][..], Vec::as_slice);
+    let own: Vec<_> = user_sessions
+        .get(&draft.sender)
+        .into_iter()
+        .flatten()
+        .filter(|addr| room_sessions.contains(addr))
+        .cloned()
+        .collect();
+    drop(user_sessions);
+    drop(sessions);
+    deadletter::fan_out(&own, &draft);
+    Ok(draft)
+}
+
+#[derive(Deserialize)]
+pub struct ShadowBanUpdate {
+    username: String,
+    banned: bool,
+}
+
+pub async fn list_shadow_bans(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let bans = state.shadow_bans.lock().unwrap();
+    let mut users: Vec<_> = bans.users.iter().cloned().collect();
+    users.sort();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "users": users,
+        "honeypot_rooms": bans.honeypot_rooms,
+    })))
+}
+
+pub async fn set_shadow_ban(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+    form: web::Json<ShadowBanUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let username = state.canonical_username(&form.username);
+    let changed = {
+        let mut bans = state.shadow_bans.lock().unwrap();
+        if form.banned {
+            bans.users.insert(username.clone())
+        } else {
+            bans.users.remove(&username)
+        }
+    };
+    if changed {
+        let action = if form.banned {
+            "shadow_ban_added"
+        } else {
+            "shadow_ban_removed"
+        };
+        audit::record(&state, &user.username, action, &username, String::new());
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "username": username,
+        "banned": form.banned,
+    })))
+}
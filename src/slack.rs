@@ -0,0 +1,396 @@
+// Slack side of the bridge: Events API callbacks come in on
+// /bridges/slack/events, room messages go out through each channel's
+// incoming webhook.
+use actix_web::{rt, web, HttpRequest, HttpResponse};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::attachments::{Attachment, MAX_ATTACHMENTS};
+use crate::bridges::{self, BridgeOrigin};
+use crate::config::SlackConfig;
+use crate::error::ApiError;
+use crate::outbound;
+use crate::{ChatMessage, SharedState};
+
+const NETWORK: &str = "slack";
+// Slack's own guidance for rejecting replayed callbacks
+const MAX_CLOCK_SKEW_SECS: u64 = 300;
+const REMEMBERED_EVENTS: usize = 1000;
+// Appended to everything relayed out; Slack echoes our webhook posts back as
+// events, and the marker lets us drop them even when bot_id is missing
+const LOOP_MARKER: char = '\u{2063}';
+
+struct SlackLink {
+    room_id: Uuid,
+    webhook_url: String,
+}
+
+#[derive(Default)]
+pub struct SlackBridge {
+    config: Option<SlackConfig>,
+    links: HashMap<String, SlackLink>, // channel id -> room
+    // Slack redelivers callbacks it thinks timed out
+    seen_events: VecDeque<String>,
+    seen_index: HashSet<String>,
+    user_names: HashMap<String, String>, // Slack user id -> display name
+}
+
+impl SlackBridge {
+    pub fn new(config: Option<SlackConfig>) -> Self {
+        SlackBridge {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn link(&mut self, channel: String, room_id: Uuid, webhook_url: String) {
+        self.links.insert(
+            channel,
+            SlackLink {
+                room_id,
+                webhook_url,
+            },
+        );
+    }
+
+    pub fn unlink(&mut self, channel: &str) -> bool {
+        self.links.remove(channel).is_some()
+    }
+
+    // Webhook URLs are credentials, so only their host is shown
+    pub fn describe(&self) -> serde_json::Value {
+        let mut links: Vec<_> = self
+            .links
+            .iter()
+            .map(|(channel, link)| {
+                let host = link
+                    .webhook_url
+                    .split_once("://")
+                    .map_or(link.webhook_url.as_str(), |(_, rest)| rest)
+                    .split('/')
+                    .next()
+                    .unwrap_or_default();
+                serde_json::json!({
+                    "channel": channel,
+                    "room_id": link.room_id,
+                    "webhook_host": host,
+                })
+            })
+            .collect();
+        links.sort_by(|a, b| a["channel"].as_str().cmp(&b["channel"].as_str()));
+        serde_json::json!({
+            "enabled": self.config.is_some(),
+            "links": links,
+        })
+    }
+
+    fn first_sight(&mut self, event_id: &str) -> bool {
+        if !self.seen_index.insert(event_id.to_string()) {
+            return false;
+        }
+        self.seen_events.push_back(event_id.to_string());
+        if self.seen_events.len() > REMEMBERED_EVENTS {
+            if let Some(oldest) = self.seen_events.pop_front() {
+                self.seen_index.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// X-Slack-Signature is "v0=" + hex HMAC-SHA256 of "v0:<timestamp>:<body>"
+fn verify(config: &SlackConfig, req: &HttpRequest, body: &[u8]) -> Result<(), ApiError> {
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let timestamp = header("x-slack-request-timestamp").ok_or(ApiError::InvalidBridgeSignature)?;
+    let sent_at: u64 = timestamp
+        .parse()
+        .map_err(|_| ApiError::InvalidBridgeSignature)?;
+    let now = crate::now_millis() / 1000;
+    if now.abs_diff(sent_at) > MAX_CLOCK_SKEW_SECS {
+        return Err(ApiError::InvalidBridgeSignature);
+    }
+    let signature = header("x-slack-signature")
+        .and_then(|value| value.strip_prefix("v0="))
+        .and_then(decode_hex)
+        .ok_or(ApiError::InvalidBridgeSignature)?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(config.signing_secret.as_bytes())
+        .expect("HMAC accepts any key length");
+    mac.update(format!("v0:{}:", timestamp).as_bytes());
+    mac.update(body);
+    mac.verify_slice(&signature)
+        .map_err(|_| ApiError::InvalidBridgeSignature)
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    challenge: Option<String>,
+    #[serde(default)]
+    event_id: Option<String>,
+    #[serde(default)]
+    event: Option<Event>,
+}
+
+#[derive(Deserialize)]
+struct Event {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    subtype: Option<String>,
+    #[serde(default)]
+    channel: Option<String>,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    bot_id: Option<String>,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    user_profile: Option<Profile>,
+    #[serde(default)]
+    files: Vec<SlackFile>,
+}
+
+#[derive(Deserialize, Default)]
+struct Profile {
+    #[serde(default)]
+    display_name: String,
+    #[serde(default)]
+    real_name: String,
+    #[serde(default)]
+    name: String,
+}
+
+impl Profile {
+    fn best_name(&self) -> Option<String> {
+        [&self.display_name, &self.real_name, &self.name]
+            .into_iter()
+            .find(|name| !name.trim().is_empty())
+            .map(|name| name.trim().to_string())
+    }
+}
+
+#[derive(Deserialize)]
+struct SlackFile {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    mimetype: Option<String>,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    permalink: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UserInfo {
+    ok: bool,
+    #[serde(default)]
+    user: Option<UserInfoUser>,
+}
+
+#[derive(Deserialize)]
+struct UserInfoUser {
+    #[serde(default)]
+    profile: Profile,
+    #[serde(default)]
+    name: String,
+}
+
+// Message events rarely carry a profile; users.info fills the gap when a bot
+// token is configured, and the raw user id is the last resort
+async fn author_name(
+    state: &SharedState,
+    config: &SlackConfig,
+    event: &Event,
+    user: &str,
+) -> String {
+    if let Some(name) = event.user_profile.as_ref().and_then(Profile::best_name) {
+        return name;
+    }
+    if let Some(name) = state.bridges.lock().unwrap().slack.user_names.get(user) {
+        return name.clone();
+    }
+    let Some(token) = &config.bot_token else {
+        return user.to_string();
+    };
+    let looked_up = async {
+        let mut resp = outbound::client()
+            .get(format!("https://slack.com/api/users.info?user={}", user))
+            .bearer_auth(token)
+            .send()
+            .await
+            .ok()?;
+        let info: UserInfo = resp.json().await.ok()?;
+        let user = info.user.filter(|_| info.ok)?;
+        user.profile
+            .best_name()
+            .or_else(|| (!user.name.is_empty()).then_some(user.name))
+    };
+    match looked_up.await {
+        Some(name) => {
+            state
+                .bridges
+                .lock()
+                .unwrap()
+                .slack
+                .user_names
+                .insert(user.to_string(), name.clone());
+            name
+        }
+        None => user.to_string(),
+    }
+}
+
+// Slack escapes these three in message text and expects the same from us
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+pub async fn events(
+    req: HttpRequest,
+    state: web::Data<Arc<SharedState>>,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let config = state.bridges.lock().unwrap().slack.config.clone();
+    let config = config.ok_or(ApiError::InvalidBridgeSignature)?;
+    verify(&config, &req, &body)?;
+    let envelope: Envelope = serde_json::from_slice(&body).map_err(|_| ApiError::InvalidQuery)?;
+    if envelope.kind == "url_verification" {
+        return Ok(HttpResponse::Ok().json(serde_json::json!({
+            "challenge": envelope.challenge,
+        })));
+    }
+    // Anything we don't act on is still acknowledged, or Slack keeps retrying
+    let ack = HttpResponse::Ok().finish();
+    let (Some(event_id), Some(event)) = (envelope.event_id, envelope.event) else {
+        return Ok(ack);
+    };
+    if !state.bridges.lock().unwrap().slack.first_sight(&event_id) {
+        return Ok(ack);
+    }
+    let plain = matches!(event.subtype.as_deref(), None | Some("file_share"));
+    if event.kind != "message" || !plain || event.bot_id.is_some() {
+        return Ok(ack);
+    }
+    if event.text.ends_with(LOOP_MARKER) {
+        return Ok(ack);
+    }
+    let (Some(channel), Some(user)) = (event.channel.clone(), event.user.clone()) else {
+        return Ok(ack);
+    };
+    let room_id = state
+        .bridges
+        .lock()
+        .unwrap()
+        .slack
+        .links
+        .get(&channel)
+        .map(|link| link.room_id);
+    let Some(room_id) = room_id else {
+        return Ok(ack);
+    };
+
+    let author = author_name(&state, &config, &event, &user).await;
+    let attachments = event
+        .files
+        .iter()
+        .filter_map(|file| {
+            Some(Attachment {
+                id: None,
+                name: file.name.clone().unwrap_or_else(|| "file".to_string()),
+                content_type: file
+                    .mimetype
+                    .clone()
+                    .unwrap_or_else(|| "application/octet-stream".to_string()),
+                size: file.size,
+                url: Some(file.permalink.clone()?),
+                expired_at: None,
+            })
+        })
+        .take(MAX_ATTACHMENTS)
+        .collect();
+    let origin = BridgeOrigin {
+        network: NETWORK.to_string(),
+        conversation: channel.clone(),
+        author_id: user,
+    };
+    if let Err(err) = bridges::post_inbound(
+        &state,
+        room_id,
+        &author,
+        origin,
+        &unescape(&event.text),
+        attachments,
+    ) {
+        log::warn!("dropped Slack message from {}: {}", channel, err.code());
+    }
+    Ok(ack)
+}
+
+// Sends `msg` to every channel linked to its room except the one it came from
+pub fn relay(state: &Arc<SharedState>, msg: &ChatMessage) {
+    let candidates: Vec<(String, Uuid, String)> = state
+        .bridges
+        .lock()
+        .unwrap()
+        .slack
+        .links
+        .iter()
+        .filter(|(channel, _)| bridges::should_relay(msg, NETWORK, channel))
+        .map(|(channel, link)| (channel.clone(), link.room_id, link.webhook_url.clone()))
+        .collect();
+    let targets: Vec<(String, String)> = candidates
+        .into_iter()
+        .filter(|(_, room_id, _)| state.follow_redirects(*room_id) == msg.room_id)
+        .map(|(channel, _, url)| (channel, url))
+        .collect();
+    if targets.is_empty() {
+        return;
+    }
+
+    let mut text = format!("*{}*: {}", escape(&msg.sender), escape(&msg.content));
+    for attachment in &msg.attachments {
+        if let Some(url) = &attachment.url {
+            text.push_str(&format!("\n<{}|{}>", url, escape(&attachment.name)));
+        }
+    }
+    text.push(LOOP_MARKER);
+    for (channel, url) in targets {
+        let payload = serde_json::json!({ "text": text });
+        let state = state.clone();
+        let msg = msg.clone();
+        rt::spawn(async move {
+            match outbound::client().post(&url).send_json(&payload).await {
+                Ok(resp) if resp.status().is_success() => return,
+                Ok(resp) => log::warn!("Slack webhook returned {}", resp.status()),
+                Err(err) => log::warn!("Slack webhook failed: {}", err),
+            }
+            bridges::relay_failed(&state, NETWORK, &channel, msg);
+        });
+    }
+}
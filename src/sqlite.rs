@@ -0,0 +1,232 @@
+// SQLite backend for the store (DATABASE_URL=sqlite://chat.db). The file is
+// created on first start.
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePool, SqlitePoolOptions};
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::store::{self, Change, Loaded, Storage, StorageFuture, StoredRows};
+use crate::{ChatMessage, ChatRoom};
+
+const SCHEMA: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS users (
+        username TEXT PRIMARY KEY,
+        password_hash TEXT NOT NULL,
+        email TEXT
+    )",
+    // `data` is the room without its participants and message log
+    "CREATE TABLE IF NOT EXISTS rooms (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        created_by TEXT NOT NULL,
+        data TEXT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS room_participants (
+        room_id TEXT NOT NULL,
+        username TEXT NOT NULL,
+        PRIMARY KEY (room_id, username)
+    )",
+    "CREATE TABLE IF NOT EXISTS messages (
+        id TEXT PRIMARY KEY,
+        room_id TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        body TEXT NOT NULL
+    )",
+    "CREATE INDEX IF NOT EXISTS messages_by_room ON messages (room_id, seq)",
+    "CREATE TABLE IF NOT EXISTS last_seen (
+        username TEXT PRIMARY KEY,
+        at INTEGER NOT NULL
+    )",
+];
+
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    pub async fn connect(url: &str) -> sqlx::Result<SqliteStorage> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(4)
+            .connect_with(options)
+            .await?;
+        for statement in SCHEMA {
+            sqlx::query(statement).execute(&pool).await?;
+        }
+        Ok(SqliteStorage { pool })
+    }
+}
+
+// Writes take a connection rather than the pool, so a batch of them can run
+// inside one transaction.
+
+async fn upsert_user(
+    conn: &mut SqliteConnection,
+    username: &str,
+    password_hash: &str,
+    email: Option<&str>,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "INSERT INTO users (username, password_hash, email) VALUES (?, ?, ?)
+         ON CONFLICT (username) DO UPDATE SET password_hash = excluded.password_hash,
+         email = excluded.email",
+    )
+    .bind(username)
+    .bind(password_hash)
+    .bind(email)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+async fn upsert_room(conn: &mut SqliteConnection, room: ChatRoom) -> sqlx::Result<()> {
+    let name = room.name.clone();
+    let created_by = room.created_by.clone();
+    let (id, data, participants) = store::room_row(room);
+    sqlx::query(
+        "INSERT INTO rooms (id, name, created_by, data) VALUES (?, ?, ?, ?)
+         ON CONFLICT (id) DO UPDATE SET name = excluded.name,
+         created_by = excluded.created_by, data = excluded.data",
+    )
+    .bind(&id)
+    .bind(name)
+    .bind(created_by)
+    .bind(data)
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query("DELETE FROM room_participants WHERE room_id = ?")
+        .bind(&id)
+        .execute(&mut *conn)
+        .await?;
+    for username in participants {
+        sqlx::query("INSERT INTO room_participants (room_id, username) VALUES (?, ?)")
+            .bind(&id)
+            .bind(username)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn delete_room(conn: &mut SqliteConnection, room_id: Uuid) -> sqlx::Result<()> {
+    let id = room_id.to_string();
+    sqlx::query("DELETE FROM room_participants WHERE room_id = ?")
+        .bind(&id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("DELETE FROM rooms WHERE id = ?")
+        .bind(&id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+async fn upsert_message(conn: &mut SqliteConnection, msg: &ChatMessage) -> sqlx::Result<()> {
+    sqlx::query(
+        "INSERT INTO messages (id, room_id, seq, body) VALUES (?, ?, ?, ?)
+         ON CONFLICT (id) DO UPDATE SET room_id = excluded.room_id,
+         seq = excluded.seq, body = excluded.body",
+    )
+    .bind(msg.id.to_string())
+    .bind(msg.room_id.to_string())
+    .bind(msg.seq as i64)
+    .bind(store::message_body(msg))
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+async fn delete_message(conn: &mut SqliteConnection, message_id: Uuid) -> sqlx::Result<()> {
+    sqlx::query("DELETE FROM messages WHERE id = ?")
+        .bind(message_id.to_string())
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+async fn upsert_last_seen(
+    conn: &mut SqliteConnection,
+    username: &str,
+    at: u64,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "INSERT INTO last_seen (username, at) VALUES (?, ?)
+         ON CONFLICT (username) DO UPDATE SET at = MAX(at, excluded.at)",
+    )
+    .bind(username)
+    .bind(at as i64)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+async fn apply_change(conn: &mut SqliteConnection, change: Change) -> sqlx::Result<()> {
+    match change {
+        Change::User {
+            username,
+            password_hash,
+            email,
+        } => upsert_user(conn, &username, &password_hash, email.as_deref()).await,
+        Change::Room(room) => upsert_room(conn, *room).await,
+        Change::RoomRemoved(room_id) => delete_room(conn, room_id).await,
+        Change::Messages(messages) => {
+            for msg in &messages {
+                upsert_message(conn, msg).await?;
+            }
+            Ok(())
+        }
+        Change::MessagesRemoved(ids) => {
+            for id in ids {
+                delete_message(conn, id).await?;
+            }
+            Ok(())
+        }
+        Change::LastSeen { username, at } => upsert_last_seen(conn, &username, at).await,
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load_all(&self) -> StorageFuture<'_, Loaded> {
+        Box::pin(async move {
+            let pool = &self.pool;
+            let rows = StoredRows {
+                users: sqlx::query_as("SELECT username, password_hash, email FROM users")
+                    .fetch_all(pool)
+                    .await?,
+                participants: sqlx::query_as("SELECT room_id, username FROM room_participants")
+                    .fetch_all(pool)
+                    .await?,
+                last_seq: sqlx::query_as("SELECT room_id, MAX(seq) FROM messages GROUP BY room_id")
+                    .fetch_all(pool)
+                    .await?,
+                rooms: sqlx::query_as("SELECT id, data FROM rooms")
+                    .fetch_all(pool)
+                    .await?,
+                last_seen: sqlx::query_as("SELECT username, at FROM last_seen")
+                    .fetch_all(pool)
+                    .await?,
+            };
+            Ok(Loaded::from_rows(rows))
+        })
+    }
+
+    fn load_history(&self, room_id: Uuid) -> StorageFuture<'_, Vec<ChatMessage>> {
+        Box::pin(async move {
+            let bodies: Vec<String> =
+                sqlx::query_scalar("SELECT body FROM messages WHERE room_id = ? ORDER BY seq")
+                    .bind(room_id.to_string())
+                    .fetch_all(&self.pool)
+                    .await?;
+            Ok(store::decode_history(room_id, bodies))
+        })
+    }
+
+    fn apply(&self, batch: Vec<Change>) -> StorageFuture<'_, ()> {
+        Box::pin(async move {
+            let mut tx = self.pool.begin().await?;
+            for change in batch {
+                apply_change(&mut tx, change).await?;
+            }
+            tx.commit().await
+        })
+    }
+}
@@ -0,0 +1,179 @@
+// Warm standby. An instance started with STANDBY_OF follows the primary like
+// any read replica, rooms, accounts and login tokens alike, but an admin can
+// also promote it with POST /admin/standby/promote when the primary fails.
+// Promotion takes one last snapshot if the primary still answers,
+// writes everything to this instance's own store, starts the jobs only a
+// primary runs, and asks the old primary to step down. A primary that steps
+// down answers like a replica of the new one and tells its WebSocket clients
+// where to reconnect; their login tokens work there unchanged. It should be
+// restarted as a standby before it is trusted with writes again.
+//
+// Lock order: `replica_of` is taken before everything `replica::apply`
+// locks, and never while holding any other lock.
+use actix::Message;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::auth::UserContext;
+use crate::config::ReplicaConfig;
+use crate::error::ApiError;
+use crate::outbound;
+use crate::{audit, replica, store, PrimaryJobs, SharedState};
+
+pub const PROMOTE_PATH: &str = "/admin/standby/promote";
+// The primary is presumed unwell, so neither call waits on it for long
+const PRIMARY_TIMEOUT: Duration = Duration::from_secs(3);
+
+// What a standby needs to become the primary
+pub struct Standby {
+    pub replica: ReplicaConfig,
+    pub jobs: PrimaryJobs,
+}
+
+// Sent to every WS session of a primary that stepped down
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct Failover {
+    pub primary: String,
+}
+
+pub async fn promote(
+    state: web::Data<Arc<SharedState>>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    if !state.is_admin(&user.username) {
+        return Err(ApiError::AdminRequired);
+    }
+    let Standby { replica, jobs } = state
+        .standby
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or(ApiError::NotStandby)?;
+    let client = outbound::client_with_timeout(PRIMARY_TIMEOUT);
+    let caught_up = match replica::pull(&client, &replica).await {
+        Ok(snapshot) => {
+            let mut replica_of = state.replica_of.lock().unwrap();
+            replica::apply(&state, snapshot);
+            *replica_of = None;
+            true
+        }
+        Err(err) => {
+            log::warn!(
+                "promoting without a final sync from {}: {}",
+                replica.primary,
+                err
+            );
+            *state.replica_of.lock().unwrap() = None;
+            false
+        }
+    };
+    persist_all(&state);
+    crate::spawn_primary_jobs(state.get_ref().clone(), jobs);
+
+    let public_url = replica.standby.clone().unwrap_or_default();
+    let demoted = match demote_old_primary(&client, &replica, &public_url).await {
+        Ok(()) => true,
+        Err(err) => {
+            log::warn!("old primary {} did not step down: {}", replica.primary, err);
+            false
+        }
+    };
+    log::info!("promoted to primary, replacing {}", replica.primary);
+    audit::record(
+        &state,
+        &user.username,
+        "promote_standby",
+        &state.node_id,
+        format!(
+            "replacing {}; final sync {}, old primary {}",
+            replica.primary,
+            if caught_up { "done" } else { "skipped" },
+            if demoted {
+                "stepped down"
+            } else {
+                "unreachable"
+            }
+        ),
+    );
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "node_id": state.node_id,
+        "previous_primary": replica.primary,
+        "caught_up": caught_up,
+        "previous_primary_demoted": demoted,
+    })))
+}
+
+// Snapshots bypass the store, so it only learns the state now
+fn persist_all(state: &SharedState) {
+    let usernames: Vec<String> = state
+        .user_accounts
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect();
+    for username in usernames {
+        store::user_changed(state, &username);
+    }
+    let rooms: Vec<_> = state.chat_rooms.lock().unwrap().values().cloned().collect();
+    for room in rooms {
+        store::room_changed(state, &room);
+        store::messages_changed(state, &room.message_log);
+    }
+}
+
+async fn demote_old_primary(
+    client: &awc::Client,
+    replica: &ReplicaConfig,
+    public_url: &str,
+) -> Result<(), String> {
+    let mut request = client.post(format!("{}/replication/demote", replica.primary));
+    if let Some(token) = &replica.token {
+        request = request.insert_header((replica::TOKEN_HEADER, token.as_str()));
+    }
+    let resp = request
+        .send_json(&serde_json::json!({ "primary": public_url }))
+        .await
+        .map_err(|err| err.to_string())?;
+    if !resp.status().is_success() {
+        return Err(format!("returned {}", resp.status()));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct Demotion {
+    primary: String,
+}
+
+// Old primary side, called by the standby that replaced it
+pub async fn demote(
+    req: HttpRequest,
+    state: web::Data<Arc<SharedState>>,
+    form: web::Json<Demotion>,
+) -> Result<HttpResponse, ApiError> {
+    replica::check_token(&req, &state)?;
+    let primary = form.primary.trim_end_matches('/').to_string();
+    if !primary.starts_with("http://") && !primary.starts_with("https://") {
+        return Err(ApiError::InvalidPrimaryUrl);
+    }
+    *state.replica_of.lock().unwrap() = Some(primary.clone());
+    log::warn!("stepped down: {} is now the primary", primary);
+    audit::record(
+        &state,
+        "system",
+        "primary_demoted",
+        &state.node_id,
+        format!("replaced by {}", primary),
+    );
+
+    let notice = Failover { primary };
+    let sessions = state.active_sessions.lock().unwrap();
+    for addr in sessions.values().flatten() {
+        addr.do_send(notice.clone());
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
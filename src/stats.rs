@@ -0,0 +1,175 @@
+// Per-room activity statistics: messages per UTC day, per sender, and
+// reactions per emoji. The aggregator keeps one summary per room and only
+// rebuilds it from the log once it is older than STATS_MAX_AGE, so repeated
+// exports don't each walk the whole history.
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::export::utc_datetime;
+use crate::invites;
+use crate::{now_millis, ChatRoom, MessageKind, SharedState};
+
+const STATS_MAX_AGE: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Default, Clone, Copy)]
+pub struct Tally {
+    messages: u64,
+    // Reactions on those messages
+    reactions: u64,
+}
+
+// Only user messages count; tombstones still count as sent
+#[derive(Serialize)]
+pub struct RoomStats {
+    room_id: Uuid,
+    generated_at: u64,
+    days: BTreeMap<String, Tally>, // YYYY-MM-DD (UTC) -> messages sent that day
+    users: BTreeMap<String, Tally>, // sender -> their messages
+    emoji: BTreeMap<String, Tally>, // emoji -> messages carrying it
+}
+
+impl RoomStats {
+    fn collect(room: &ChatRoom, now: u64) -> Self {
+        let mut stats = RoomStats {
+            room_id: room.id,
+            generated_at: now,
+            days: BTreeMap::new(),
+            users: BTreeMap::new(),
+            emoji: BTreeMap::new(),
+        };
+        for msg in room
+            .message_log
+            .iter()
+            .filter(|msg| msg.kind == MessageKind::User)
+        {
+            let reactions: u64 = msg.reactions.values().map(|users| users.len() as u64).sum();
+            let (year, month, day, ..) = utc_datetime(msg.sent_at);
+            for tally in [
+                stats
+                    .days
+                    .entry(format!("{:04}-{:02}-{:02}", year, month, day))
+                    .or_default(),
+                stats.users.entry(msg.sender.clone()).or_default(),
+            ] {
+                tally.messages += 1;
+                tally.reactions += reactions;
+            }
+            for (emoji, users) in msg.reactions.iter().filter(|(_, users)| !users.is_empty()) {
+                let tally = stats.emoji.entry(emoji.clone()).or_default();
+                tally.messages += 1;
+                tally.reactions += users.len() as u64;
+            }
+        }
+        stats
+    }
+
+    // One table so it opens as a single sheet: `section` is day, user or emoji
+    fn to_csv(&self) -> String {
+        let mut csv = String::from("section,key,messages,reactions\r\n");
+        let sections = [
+            ("day", &self.days),
+            ("user", &self.users),
+            ("emoji", &self.emoji),
+        ];
+        for (section, tallies) in sections {
+            for (key, tally) in tallies {
+                csv.push_str(&format!(
+                    "{},{},{},{}\r\n",
+                    section,
+                    csv_field(key),
+                    tally.messages,
+                    tally.reactions
+                ));
+            }
+        }
+        csv
+    }
+}
+
+// RFC 4180 quoting. A leading =, +, - or @ is prefixed with ' so
+// spreadsheets don't evaluate usernames as formulas.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+#[derive(Default)]
+pub struct StatsAggregator {
+    rooms: Mutex<HashMap<Uuid, Arc<RoomStats>>>,
+}
+
+impl StatsAggregator {
+    fn get(&self, state: &SharedState, room_id: Uuid) -> Result<Arc<RoomStats>, ApiError> {
+        let now = now_millis();
+        let max_age = STATS_MAX_AGE.as_millis() as u64;
+        if let Some(stats) = self.rooms.lock().unwrap().get(&room_id) {
+            if now.saturating_sub(stats.generated_at) < max_age {
+                return Ok(stats.clone());
+            }
+        }
+        let rooms = state.chat_rooms.lock().unwrap();
+        let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+        let stats = Arc::new(RoomStats::collect(room, now));
+        drop(rooms);
+        let mut cached = self.rooms.lock().unwrap();
+        // Drop summaries of rooms nobody has asked about for a while
+        cached.retain(|_, stats| now.saturating_sub(stats.generated_at) < max_age);
+        cached.insert(room_id, stats.clone());
+        Ok(stats)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    // csv (default) or json
+    #[serde(default)]
+    format: Option<String>,
+}
+
+pub async fn export_room_stats(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    query: web::Query<StatsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    let actor = &user.username;
+    let csv = match query.format.as_deref() {
+        None | Some("csv") => true,
+        Some("json") => false,
+        Some(_) => return Err(ApiError::InvalidQuery),
+    };
+    {
+        let rooms = state.chat_rooms.lock().unwrap();
+        let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+        invites::check_reader(&state, room, Some(&user))?;
+        if !room.members().contains(actor) && !state.is_admin(actor) {
+            return Err(ApiError::ParticipantNotFound);
+        }
+    }
+    let stats = state.stats.get(&state, room_id)?;
+    if !csv {
+        return Ok(HttpResponse::Ok().json(&*stats));
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            actix_web::http::header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"room-{}-stats.csv\"", room_id),
+        ))
+        .body(stats.to_csv()))
+}
@@ -0,0 +1,486 @@
+// Persistence behind the `Storage` trait. DATABASE_URL picks the backend by
+// scheme: sqlite://chat.db or postgres://user@host/db; unset keeps everything
+// in memory only. The in-memory maps stay the working copy either way: every
+// change is queued to a single writer that applies it in order, and startup
+// reads accounts, rooms and participants back. Room histories aren't read at
+// startup; each loads on its first lookup through
+// `SharedState::resolve_room_id`, or from the background warm-up.
+//
+// sqlx is async but most mutations happen under std mutexes, so the store
+// runs on its own thread and runtime. A lookup that needs a history blocks on
+// it the way cold storage blocks on reading an archive.
+//
+// Lock order: the unloaded-rooms lock is taken before `chat_rooms`, so callers
+// must not hold `chat_rooms` when resolving a room id.
+use actix_web::rt;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::protocol::EventCategory;
+use crate::{cluster, external, postgres, search, sqlite, ChatMessage, ChatRoom, SharedState};
+
+// Changes queued together are applied in one transaction, up to this many
+const MAX_BATCH: usize = 256;
+const WARMUP_PAUSE: Duration = Duration::from_millis(20);
+
+// Which kinds of event outlive the process. Messages always do and typing,
+// presence and receipts never do; reactions are stored unless PERSIST_EVENTS
+// leaves them out. Whatever isn't stored is still relayed live.
+#[derive(Clone, Copy, Debug)]
+pub struct PersistencePolicy {
+    pub reactions: bool,
+}
+
+impl Default for PersistencePolicy {
+    fn default() -> Self {
+        PersistencePolicy { reactions: true }
+    }
+}
+
+impl PersistencePolicy {
+    pub fn keeps(&self, category: EventCategory) -> bool {
+        match category {
+            EventCategory::Messages => true,
+            EventCategory::Reactions => self.reactions,
+            EventCategory::Presence | EventCategory::Typing | EventCategory::Receipts => false,
+        }
+    }
+}
+
+pub type StorageFuture<'a, T> = Pin<Box<dyn Future<Output = sqlx::Result<T>> + Send + 'a>>;
+
+// A database backend. Rooms are stored without their message log, which is
+// kept one row per message so appends stay cheap.
+pub trait Storage: Send + Sync {
+    // Everything but message logs
+    fn load_all(&self) -> StorageFuture<'_, Loaded>;
+    fn load_history(&self, room_id: Uuid) -> StorageFuture<'_, Vec<ChatMessage>>;
+    // All or nothing, in order
+    fn apply(&self, batch: Vec<Change>) -> StorageFuture<'_, ()>;
+}
+
+pub enum Change {
+    User {
+        username: String,
+        password_hash: String,
+        email: Option<String>,
+    },
+    // Settings and participants
+    Room(Box<ChatRoom>),
+    // Messages are moved rather than dropped when rooms merge, so they stay
+    RoomRemoved(Uuid),
+    Messages(Vec<ChatMessage>),
+    MessagesRemoved(Vec<Uuid>),
+    LastSeen {
+        username: String,
+        at: u64,
+    },
+}
+
+pub struct Loaded {
+    pub accounts: HashMap<String, String>,
+    pub emails: HashMap<String, String>,
+    pub rooms: Vec<ChatRoom>,
+    pub last_seen: HashMap<String, u64>,
+}
+
+// What `load_all` reads, as plain columns every backend can produce
+pub struct StoredRows {
+    pub users: Vec<(String, String, Option<String>)>, // username, password hash, email
+    pub participants: Vec<(String, String)>,          // room id, username
+    pub last_seq: Vec<(String, i64)>,                 // room id, highest stored seq
+    pub rooms: Vec<(String, String)>,                 // room id, JSON without participants
+    pub last_seen: Vec<(String, i64)>,                // username, millis
+}
+
+impl Loaded {
+    // `next_seq` comes from the stored messages, since rooms aren't rewritten
+    // on every append
+    pub fn from_rows(rows: StoredRows) -> Loaded {
+        let mut accounts = HashMap::new();
+        let mut emails = HashMap::new();
+        for (username, password_hash, email) in rows.users {
+            if let Some(email) = email {
+                emails.insert(username.clone(), email);
+            }
+            accounts.insert(username, password_hash);
+        }
+        let mut participants: HashMap<String, HashSet<String>> = HashMap::new();
+        for (room_id, username) in rows.participants {
+            participants.entry(room_id).or_default().insert(username);
+        }
+        let last_seq: HashMap<String, i64> = rows.last_seq.into_iter().collect();
+        let mut rooms = Vec::new();
+        for (id, data) in rows.rooms {
+            let mut room: ChatRoom = match serde_json::from_str(&data) {
+                Ok(room) => room,
+                Err(err) => {
+                    log::error!("skipping unreadable room {}: {}", id, err);
+                    continue;
+                }
+            };
+            room.participants = participants.remove(&id).unwrap_or_default();
+            room.message_log = Vec::new();
+            let stored = last_seq.get(&id).copied().unwrap_or(0).max(0) as u64;
+            room.next_seq = room.next_seq.max(stored);
+            rooms.push(room);
+        }
+        Loaded {
+            accounts,
+            emails,
+            rooms,
+            last_seen: rows
+                .last_seen
+                .into_iter()
+                .map(|(username, at)| (username, at.max(0) as u64))
+                .collect(),
+        }
+    }
+}
+
+// Splits a room into its row: id, JSON without participants, participants
+pub fn room_row(mut room: ChatRoom) -> (String, String, Vec<String>) {
+    let participants = std::mem::take(&mut room.participants).into_iter().collect();
+    let data = serde_json::to_string(&room).expect("rooms serialize");
+    (room.id.to_string(), data, participants)
+}
+
+pub fn message_body(msg: &ChatMessage) -> String {
+    serde_json::to_string(msg).expect("messages serialize")
+}
+
+pub fn decode_history(room_id: Uuid, bodies: Vec<String>) -> Vec<ChatMessage> {
+    bodies
+        .into_iter()
+        .filter_map(|body| match serde_json::from_str(&body) {
+            Ok(msg) => Some(msg),
+            Err(err) => {
+                log::error!("skipping unreadable message in {}: {}", room_id, err);
+                None
+            }
+        })
+        .collect()
+}
+
+async fn connect(url: &str) -> sqlx::Result<Arc<dyn Storage>> {
+    if url.starts_with("sqlite:") {
+        Ok(Arc::new(sqlite::SqliteStorage::connect(url).await?))
+    } else if url.starts_with("postgres:") || url.starts_with("postgresql:") {
+        postgres::connect(url).await
+    } else {
+        Err(sqlx::Error::Configuration(
+            format!("unsupported DATABASE_URL scheme in {:?}", url).into(),
+        ))
+    }
+}
+
+enum Write {
+    Change(Change),
+    // Answered once everything queued before it is on disk
+    Flush(std::sync::mpsc::Sender<()>),
+}
+
+pub struct Store {
+    runtime: tokio::runtime::Handle,
+    storage: Arc<dyn Storage>,
+    writes: mpsc::UnboundedSender<Write>,
+    unloaded: Mutex<HashSet<Uuid>>, // rooms whose history is still only in the database
+    read_timeout: Option<Duration>, // for reads a request waits on
+}
+
+impl Store {
+    // Connects, creates the schema and starts the writer; only returns once
+    // the database is usable
+    pub fn open(url: &str, read_timeout: Option<Duration>) -> sqlx::Result<Store> {
+        let url = url.to_string();
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("store".to_string())
+            .spawn(move || {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("store runtime");
+                runtime.block_on(async move {
+                    let storage = match connect(&url).await {
+                        Ok(storage) => storage,
+                        Err(err) => {
+                            let _ = ready_tx.send(Err(err));
+                            return;
+                        }
+                    };
+                    let (writes, queue) = mpsc::unbounded_channel();
+                    let _ = ready_tx.send(Ok((
+                        tokio::runtime::Handle::current(),
+                        storage.clone(),
+                        writes,
+                    )));
+                    write_loop(storage, queue).await;
+                });
+            })
+            .expect("spawn store thread");
+        let (runtime, storage, writes) = ready_rx.recv().map_err(|_| sqlx::Error::PoolClosed)??;
+        Ok(Store {
+            runtime,
+            storage,
+            writes,
+            unloaded: Mutex::new(HashSet::new()),
+            read_timeout,
+        })
+    }
+
+    // Runs `query` on the store runtime and waits for it, at most `limit`;
+    // fine from async handlers too, since the work happens on the store's
+    // own thread. A query that runs out of time still finishes there.
+    fn block_on<T, F>(
+        &self,
+        limit: Option<Duration>,
+        query: impl FnOnce(Arc<dyn Storage>) -> F,
+    ) -> sqlx::Result<T>
+    where
+        T: Send + 'static,
+        F: Future<Output = sqlx::Result<T>> + Send + 'static,
+    {
+        let (done, result) = std::sync::mpsc::channel();
+        let query = query(self.storage.clone());
+        self.runtime.spawn(async move {
+            let _ = done.send(query.await);
+        });
+        match limit {
+            Some(limit) => result.recv_timeout(limit).map_err(|err| match err {
+                std::sync::mpsc::RecvTimeoutError::Timeout => sqlx::Error::PoolTimedOut,
+                std::sync::mpsc::RecvTimeoutError::Disconnected => sqlx::Error::PoolClosed,
+            })?,
+            None => result.recv().expect("store runtime stopped"),
+        }
+    }
+
+    fn queue(&self, change: Change) {
+        if self.writes.send(Write::Change(change)).is_err() {
+            log::error!("store writer stopped; change not persisted");
+        }
+    }
+
+    // Waits until every change queued so far is written
+    pub fn flush(&self) {
+        let (done, flushed) = std::sync::mpsc::channel();
+        if self.writes.send(Write::Flush(done)).is_ok() {
+            let _ = flushed.recv();
+        }
+    }
+}
+
+async fn write_loop(storage: Arc<dyn Storage>, mut queue: mpsc::UnboundedReceiver<Write>) {
+    while let Some(first) = queue.recv().await {
+        let mut pending = vec![first];
+        while pending.len() < MAX_BATCH {
+            match queue.try_recv() {
+                Ok(write) => pending.push(write),
+                Err(_) => break,
+            }
+        }
+        let mut batch = Vec::new();
+        let mut flushes = Vec::new();
+        for write in pending {
+            match write {
+                Write::Change(change) => batch.push(change),
+                Write::Flush(done) => flushes.push(done),
+            }
+        }
+        if !batch.is_empty() {
+            let size = batch.len();
+            if let Err(err) = storage.apply(batch).await {
+                log::error!("lost {} queued changes: {}", size, err);
+            }
+        }
+        for done in flushes {
+            let _ = done.send(());
+        }
+    }
+}
+
+// Fills memory from the database at startup; histories are left unloaded
+pub fn load(state: &SharedState) -> Result<(), sqlx::Error> {
+    let Some(store) = &state.store else {
+        return Ok(());
+    };
+    // Startup waits however long the database takes
+    let loaded = store.block_on(None, |storage| async move { storage.load_all().await })?;
+    log::info!(
+        "loaded {} accounts and {} rooms from the database",
+        loaded.accounts.len(),
+        loaded.rooms.len()
+    );
+    state.user_accounts.lock().unwrap().extend(loaded.accounts);
+    state.user_emails.lock().unwrap().extend(loaded.emails);
+    state.presence.lock().unwrap().restore(loaded.last_seen);
+    let mut unloaded = store.unloaded.lock().unwrap();
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    for room in loaded.rooms {
+        unloaded.insert(room.id);
+        rooms.insert(room.id, room);
+    }
+    external::rebuild(state, &rooms);
+    Ok(())
+}
+
+// Brings a room's stored history into memory on first lookup. Messages sent
+// since startup are already in memory and win over their stored copy.
+pub fn touch(state: &SharedState, room_id: Uuid) {
+    let Some(store) = &state.store else {
+        return;
+    };
+    let mut unloaded = store.unloaded.lock().unwrap();
+    if !unloaded.contains(&room_id) {
+        return;
+    }
+    let history = store.block_on(store.read_timeout, |storage| async move {
+        storage.load_history(room_id).await
+    });
+    let history = match history {
+        Ok(history) => history,
+        Err(err) => {
+            // Retried on the next lookup; until then the room shows no history
+            log::error!("cannot load history of room {}: {}", room_id, err);
+            return;
+        }
+    };
+    let mut rooms = state.chat_rooms.lock().unwrap();
+    // Offloaded to cold storage meanwhile; the next lookup will try again
+    let Some(room) = rooms.get_mut(&room_id) else {
+        return;
+    };
+    let recent = std::mem::take(&mut room.message_log);
+    let known: HashSet<Uuid> = recent.iter().map(|msg| msg.id).collect();
+    room.message_log = history
+        .into_iter()
+        .filter(|msg| !known.contains(&msg.id))
+        .collect();
+    room.message_log.extend(recent);
+    room.message_log.sort_by_key(|msg| msg.seq);
+    let last_seq = room.message_log.last().map_or(0, |msg| msg.seq);
+    room.next_seq = room.next_seq.max(last_seq);
+    search::room_reset(state, room_id);
+    unloaded.remove(&room_id);
+}
+
+// Loads the remaining histories one room at a time, so scans over every room
+// (search, exports, retention) eventually see complete logs
+pub fn spawn_warmup(state: std::sync::Arc<SharedState>) {
+    rt::spawn(async move {
+        let Some(store) = &state.store else {
+            return;
+        };
+        let pending: Vec<Uuid> = store.unloaded.lock().unwrap().iter().copied().collect();
+        for room_id in pending {
+            touch(&state, room_id);
+            rt::time::sleep(WARMUP_PAUSE).await;
+        }
+    });
+}
+
+// For rooms learnt about after startup, whose history the database may hold
+pub fn mark_unloaded(state: &SharedState, room_id: Uuid) {
+    if let Some(store) = &state.store {
+        store.unloaded.lock().unwrap().insert(room_id);
+    }
+}
+
+pub fn flush(state: &SharedState) {
+    if let Some(store) = &state.store {
+        store.flush();
+    }
+}
+
+// Call sites below only queue; none of them block on the database. Each
+// change is also relayed to peer instances when clustering is on.
+
+// Reads the account back, so the caller must not hold `user_accounts` or `user_emails`
+pub fn user_changed(state: &SharedState, username: &str) {
+    let Some(password_hash) = state.user_accounts.lock().unwrap().get(username).cloned() else {
+        return;
+    };
+    let email = state.user_emails.lock().unwrap().get(username).cloned();
+    cluster::user_changed(state, username, &password_hash, email.as_deref());
+    if let Some(store) = &state.store {
+        store.queue(Change::User {
+            username: username.to_string(),
+            password_hash,
+            email,
+        });
+    }
+}
+
+// Room settings and participants; the log is written message by message
+pub fn room_changed(state: &SharedState, room: &ChatRoom) {
+    external::room_changed(state, room);
+    let metadata = room.metadata();
+    cluster::room_changed(state, &metadata);
+    if let Some(store) = &state.store {
+        store.queue(Change::Room(Box::new(metadata)));
+    }
+}
+
+pub fn room_removed(state: &SharedState, room_id: Uuid) {
+    search::room_reset(state, room_id);
+    external::room_removed(state, room_id);
+    cluster::room_removed(state, room_id);
+    if let Some(store) = &state.store {
+        store.queue(Change::RoomRemoved(room_id));
+    }
+}
+
+pub fn last_seen_changed(state: &SharedState, username: &str, at: u64) {
+    if let Some(store) = &state.store {
+        store.queue(Change::LastSeen {
+            username: username.to_string(),
+            at,
+        });
+    }
+}
+
+pub fn messages_changed<'a>(
+    state: &SharedState,
+    messages: impl IntoIterator<Item = &'a ChatMessage>,
+) {
+    let mut messages: Vec<ChatMessage> = messages.into_iter().cloned().collect();
+    if messages.is_empty() {
+        return;
+    }
+    search::messages_changed(state, &messages);
+    cluster::messages_changed(state, &messages);
+    if let Some(store) = &state.store {
+        if !state.persistence.keeps(EventCategory::Reactions) {
+            for msg in &mut messages {
+                msg.reactions.clear();
+            }
+        }
+        store.queue(Change::Messages(messages));
+    }
+}
+
+// For changes to a message's reactions alone, which peers always hear about
+// but the database only when the persistence policy keeps reactions
+pub fn reactions_changed(state: &SharedState, msg: &ChatMessage) {
+    if state.persistence.keeps(EventCategory::Reactions) {
+        messages_changed(state, [msg]);
+    } else {
+        cluster::messages_changed(state, std::slice::from_ref(msg));
+    }
+}
+
+pub fn messages_removed(state: &SharedState, ids: Vec<Uuid>) {
+    if ids.is_empty() {
+        return;
+    }
+    search::messages_removed(state, &ids);
+    cluster::messages_removed(state, &ids);
+    if let Some(store) = &state.store {
+        store.queue(Change::MessagesRemoved(ids));
+    }
+}
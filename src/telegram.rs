@@ -0,0 +1,493 @@
+// Telegram side of the bridges, via the Bot API. The bot long-polls
+// getUpdates, so no public webhook is needed; it must be a member of each
+// linked group with privacy mode off to see ordinary messages. Photos and
+// documents are downloaded once and kept as uploads of the linked room, so
+// the bot token never appears in an attachment URL and the files follow the
+// room's attachment retention. Only built with the `telegram` feature.
+use actix_web::web;
+use std::sync::Arc;
+
+use crate::config::TelegramConfig;
+use crate::{ChatMessage, SharedState};
+
+#[cfg_attr(not(feature = "telegram"), allow(unused_variables))]
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    #[cfg(feature = "telegram")]
+    cfg.route("/admin/bridges/telegram", web::put().to(bot::set_link));
+}
+
+#[cfg_attr(not(feature = "telegram"), allow(unused_variables))]
+pub fn spawn_poller(state: Arc<SharedState>, config: TelegramConfig) {
+    #[cfg(feature = "telegram")]
+    bot::spawn_poller(state, config);
+    #[cfg(not(feature = "telegram"))]
+    log::warn!("TELEGRAM_BOT_TOKEN is set but this build lacks the telegram feature");
+}
+
+#[derive(Default)]
+pub struct TelegramBridge {
+    #[cfg(feature = "telegram")]
+    inner: bot::State,
+}
+
+impl TelegramBridge {
+    pub fn describe(&self) -> serde_json::Value {
+        #[cfg(feature = "telegram")]
+        return self.inner.describe();
+        #[cfg(not(feature = "telegram"))]
+        serde_json::json!({ "enabled": false, "links": [] })
+    }
+}
+
+#[cfg_attr(not(feature = "telegram"), allow(unused_variables))]
+pub fn relay(state: &Arc<SharedState>, msg: &ChatMessage) {
+    #[cfg(feature = "telegram")]
+    bot::relay(state, msg);
+}
+
+#[cfg(feature = "telegram")]
+mod bot {
+    use actix_web::{rt, web, HttpResponse};
+    use serde::Deserialize;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    use crate::attachments::MAX_ATTACHMENTS;
+    use crate::audit;
+    use crate::auth::UserContext;
+    use crate::bridges::{self, BridgeOrigin};
+    use crate::config::TelegramConfig;
+    use crate::error::ApiError;
+    use crate::outbound;
+    use crate::uploads;
+    use crate::{ChatMessage, SharedState};
+
+    const NETWORK: &str = "telegram";
+    const API: &str = "https://api.telegram.org";
+    const POLL_TIMEOUT_SECS: u64 = 30;
+    const RETRY_DELAY: Duration = Duration::from_secs(5);
+    // Bot API downloads are capped at 20 MB anyway
+    const MAX_FILE_BYTES: usize = 20 * 1024 * 1024;
+
+    #[derive(Default)]
+    pub struct State {
+        config: Option<TelegramConfig>,
+        links: HashMap<i64, Uuid>, // chat id -> room
+    }
+
+    impl State {
+        pub fn describe(&self) -> serde_json::Value {
+            let mut links: Vec<_> = self.links.iter().collect();
+            links.sort();
+            serde_json::json!({
+                "enabled": self.config.is_some(),
+                "links": links
+                    .into_iter()
+                    .map(|(chat_id, room_id)| serde_json::json!({
+                        "chat_id": chat_id,
+                        "room_id": room_id,
+                    }))
+                    .collect::<Vec<_>>(),
+            })
+        }
+    }
+
+    fn bridge_config(state: &SharedState) -> Option<TelegramConfig> {
+        state.bridges.lock().unwrap().telegram.inner.config.clone()
+    }
+
+    #[derive(Deserialize)]
+    struct Reply<T> {
+        ok: bool,
+        result: Option<T>,
+        description: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct Update {
+        update_id: i64,
+        #[serde(default)]
+        message: Option<Message>,
+    }
+
+    #[derive(Deserialize)]
+    struct Message {
+        chat: Chat,
+        #[serde(default)]
+        from: Option<User>,
+        #[serde(default)]
+        text: Option<String>,
+        #[serde(default)]
+        caption: Option<String>,
+        // The same photo at increasing resolutions
+        #[serde(default)]
+        photo: Vec<PhotoSize>,
+        #[serde(default)]
+        document: Option<Document>,
+    }
+
+    #[derive(Deserialize)]
+    struct Chat {
+        id: i64,
+    }
+
+    #[derive(Deserialize)]
+    struct User {
+        id: i64,
+        is_bot: bool,
+        first_name: String,
+        #[serde(default)]
+        last_name: Option<String>,
+        #[serde(default)]
+        username: Option<String>,
+    }
+
+    impl User {
+        fn display_name(&self) -> String {
+            match (&self.username, &self.last_name) {
+                (Some(username), _) => username.clone(),
+                (None, Some(last)) => format!("{} {}", self.first_name, last),
+                (None, None) => self.first_name.clone(),
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct PhotoSize {
+        file_id: String,
+        #[serde(default)]
+        file_size: u64,
+    }
+
+    #[derive(Deserialize)]
+    struct Document {
+        file_id: String,
+        #[serde(default)]
+        file_name: Option<String>,
+        #[serde(default)]
+        mime_type: Option<String>,
+        #[serde(default)]
+        file_size: u64,
+    }
+
+    #[derive(Deserialize)]
+    struct File {
+        #[serde(default)]
+        file_path: Option<String>,
+    }
+
+    async fn call<T: serde::de::DeserializeOwned>(
+        client: &awc::Client,
+        config: &TelegramConfig,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<T, String> {
+        let mut resp = client
+            .post(format!("{}/bot{}/{}", API, config.token, method))
+            .send_json(&params)
+            .await
+            .map_err(|err| err.to_string())?;
+        let reply: Reply<T> = resp.json().await.map_err(|err| err.to_string())?;
+        match reply {
+            Reply {
+                ok: true,
+                result: Some(result),
+                ..
+            } => Ok(result),
+            Reply { description, .. } => Err(description.unwrap_or_else(|| "not ok".to_string())),
+        }
+    }
+
+    pub fn spawn_poller(state: Arc<SharedState>, config: TelegramConfig) {
+        state.bridges.lock().unwrap().telegram.inner.config = Some(config.clone());
+        rt::spawn(async move {
+            let client = outbound::client_with_timeout(Duration::from_secs(POLL_TIMEOUT_SECS + 10));
+            let mut offset = 0;
+            loop {
+                let params = serde_json::json!({
+                    "offset": offset,
+                    "timeout": POLL_TIMEOUT_SECS,
+                    "allowed_updates": ["message"],
+                });
+                match call::<Vec<Update>>(&client, &config, "getUpdates", params).await {
+                    Ok(updates) => {
+                        for update in updates {
+                            offset = offset.max(update.update_id + 1);
+                            if let Some(message) = update.message {
+                                deliver(&state, &client, &config, message).await;
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        log::warn!("Telegram getUpdates failed: {}", err);
+                        rt::time::sleep(RETRY_DELAY).await;
+                    }
+                }
+            }
+        });
+    }
+
+    // Fetches a file's bytes; the Bot API hands out a path to download it from
+    async fn download(
+        client: &awc::Client,
+        config: &TelegramConfig,
+        file_id: &str,
+    ) -> Result<Vec<u8>, String> {
+        let located: File = call(
+            client,
+            config,
+            "getFile",
+            serde_json::json!({ "file_id": file_id }),
+        )
+        .await?;
+        let file_path = located.file_path.ok_or("no file_path")?;
+        let mut resp = client
+            .get(format!("{}/file/bot{}/{}", API, config.token, file_path))
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        if !resp.status().is_success() {
+            return Err(format!("download returned {}", resp.status()));
+        }
+        let body = resp
+            .body()
+            .limit(MAX_FILE_BYTES)
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(body.to_vec())
+    }
+
+    async fn deliver(
+        state: &Arc<SharedState>,
+        client: &awc::Client,
+        config: &TelegramConfig,
+        message: Message,
+    ) {
+        let Some(from) = message.from.filter(|from| !from.is_bot) else {
+            return;
+        };
+        let room_id = state
+            .bridges
+            .lock()
+            .unwrap()
+            .telegram
+            .inner
+            .links
+            .get(&message.chat.id)
+            .copied();
+        let Some(room_id) = room_id else {
+            return;
+        };
+        let room_id = state.resolve_room_id(room_id);
+
+        let mut files = Vec::new();
+        if let Some(photo) = message.photo.last() {
+            files.push((
+                photo.file_id.clone(),
+                photo.file_size,
+                "photo.jpg".to_string(),
+                "image/jpeg".to_string(),
+            ));
+        }
+        if let Some(document) = message.document {
+            files.push((
+                document.file_id,
+                document.file_size,
+                document.file_name.unwrap_or_else(|| "file".to_string()),
+                document
+                    .mime_type
+                    .unwrap_or_else(|| "application/octet-stream".to_string()),
+            ));
+        }
+        let mut attachments = Vec::new();
+        for (file_id, size, name, content_type) in files.into_iter().take(MAX_ATTACHMENTS) {
+            // The Bot API won't hand these out
+            if size as usize > MAX_FILE_BYTES {
+                log::warn!("skipped Telegram file {}: {} bytes", file_id, size);
+                continue;
+            }
+            let stored = match download(client, config, &file_id).await {
+                Ok(data) => uploads::store(state, room_id, name, content_type, data)
+                    .await
+                    .map_err(|err| err.code().to_string()),
+                Err(err) => Err(err),
+            };
+            match stored {
+                Ok(attachment) => attachments.push(attachment),
+                Err(err) => log::warn!("skipped Telegram file {}: {}", file_id, err),
+            }
+        }
+
+        let origin = BridgeOrigin {
+            network: NETWORK.to_string(),
+            conversation: message.chat.id.to_string(),
+            author_id: from.id.to_string(),
+        };
+        let content = message.text.or(message.caption).unwrap_or_default();
+        if let Err(err) = bridges::post_inbound(
+            state,
+            room_id,
+            &from.display_name(),
+            origin,
+            &content,
+            attachments.clone(),
+        ) {
+            log::warn!(
+                "dropped Telegram message from {}: {}",
+                message.chat.id,
+                err.code()
+            );
+            uploads::discard(state, &attachments);
+        }
+    }
+
+    fn escape_html(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    }
+
+    // Sends `msg` to every group linked to its room except the one it came
+    // from; attachments go as documents Telegram fetches by URL
+    pub fn relay(state: &Arc<SharedState>, msg: &ChatMessage) {
+        let Some(config) = bridge_config(state) else {
+            return;
+        };
+        let candidates: Vec<(i64, Uuid)> = state
+            .bridges
+            .lock()
+            .unwrap()
+            .telegram
+            .inner
+            .links
+            .iter()
+            .filter(|(chat_id, _)| bridges::should_relay(msg, NETWORK, &chat_id.to_string()))
+            .map(|(chat_id, room_id)| (*chat_id, *room_id))
+            .collect();
+        let chats: Vec<i64> = candidates
+            .into_iter()
+            .filter(|(_, room_id)| state.follow_redirects(*room_id) == msg.room_id)
+            .map(|(chat_id, _)| chat_id)
+            .collect();
+        if chats.is_empty() {
+            return;
+        }
+
+        let mut requests = Vec::new();
+        for chat_id in chats {
+            if !msg.content.trim().is_empty() {
+                requests.push((
+                    chat_id,
+                    "sendMessage",
+                    serde_json::json!({
+                        "chat_id": chat_id,
+                        "parse_mode": "HTML",
+                        "text": format!(
+                            "<b>{}</b>: {}",
+                            escape_html(&msg.sender),
+                            escape_html(&msg.content)
+                        ),
+                    }),
+                ));
+            }
+            for attachment in &msg.attachments {
+                if let Some(url) = &attachment.url {
+                    requests.push((
+                        chat_id,
+                        "sendDocument",
+                        serde_json::json!({
+                            "chat_id": chat_id,
+                            "document": url,
+                            "caption": format!("{}: {}", msg.sender, attachment.name),
+                        }),
+                    ));
+                }
+            }
+        }
+        let state = state.clone();
+        let msg = msg.clone();
+        rt::spawn(async move {
+            let client = outbound::client();
+            let mut failed = Vec::new();
+            // One task per message keeps the text ahead of its attachments
+            for (chat_id, method, params) in requests {
+                if let Err(err) = call::<serde_json::Value>(&client, &config, method, params).await
+                {
+                    log::warn!("Telegram {} failed: {}", method, err);
+                    if !failed.contains(&chat_id) {
+                        failed.push(chat_id);
+                    }
+                }
+            }
+            for chat_id in failed {
+                bridges::relay_failed(&state, NETWORK, &chat_id.to_string(), msg.clone());
+            }
+        });
+    }
+
+    #[derive(Deserialize)]
+    pub struct LinkUpdate {
+        chat_id: i64,
+        // None unlinks the group
+        #[serde(default)]
+        room_id: Option<Uuid>,
+    }
+
+    pub async fn set_link(
+        state: web::Data<Arc<SharedState>>,
+        user: UserContext,
+        form: web::Json<LinkUpdate>,
+    ) -> Result<HttpResponse, ApiError> {
+        if !state.is_admin(&user.username) {
+            return Err(ApiError::AdminRequired);
+        }
+        let chat_id = form.chat_id;
+        let Some(room_id) = form.room_id else {
+            let removed = state
+                .bridges
+                .lock()
+                .unwrap()
+                .telegram
+                .inner
+                .links
+                .remove(&chat_id)
+                .is_some();
+            if removed {
+                audit::record(
+                    &state,
+                    &user.username,
+                    "bridge_unlinked",
+                    &chat_id.to_string(),
+                    NETWORK.into(),
+                );
+            }
+            return Ok(HttpResponse::NoContent().finish());
+        };
+        let room_id = state.resolve_room_id(room_id);
+        if !state.chat_rooms.lock().unwrap().contains_key(&room_id) {
+            return Err(ApiError::RoomNotFound);
+        }
+        state
+            .bridges
+            .lock()
+            .unwrap()
+            .telegram
+            .inner
+            .links
+            .insert(chat_id, room_id);
+        audit::record(
+            &state,
+            &user.username,
+            "bridge_linked",
+            &chat_id.to_string(),
+            format!("telegram -> room {}", room_id),
+        );
+        Ok(HttpResponse::Ok().json(serde_json::json!({
+            "network": NETWORK,
+            "chat_id": chat_id,
+            "room_id": room_id,
+        })))
+    }
+}
@@ -0,0 +1,275 @@
+// Minimal OpenTelemetry tracing. Spans are buffered in-process and exported
+// as OTLP/HTTP JSON when the `otel` feature is enabled; without the feature
+// every call here compiles down to a no-op.
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+}
+
+// W3C trace context: "00-<32 hex trace id>-<16 hex parent id>-<2 hex flags>"
+pub fn parse_traceparent(value: &str) -> Option<SpanContext> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let span_id = parts.next()?;
+    parts.next()?;
+    if version.len() != 2 || version == "ff" || trace_id.len() != 32 || span_id.len() != 16 {
+        return None;
+    }
+    let ctx = SpanContext {
+        trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+        span_id: u64::from_str_radix(span_id, 16).ok()?,
+    };
+    (ctx.trace_id != 0 && ctx.span_id != 0).then_some(ctx)
+}
+
+pub struct SpanGuard {
+    #[cfg(feature = "otel")]
+    inner: otel::ActiveSpan,
+}
+
+impl SpanGuard {
+    #[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+    pub fn attr(&mut self, key: &'static str, value: impl ToString) {
+        #[cfg(feature = "otel")]
+        self.inner.attributes.push((key, value.to_string()));
+    }
+}
+
+// Child of whatever span is currently active on this thread or request
+pub fn span(name: &'static str) -> SpanGuard {
+    span_with_parent(name, None)
+}
+
+#[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+pub fn span_with_parent(name: &'static str, parent: Option<SpanContext>) -> SpanGuard {
+    SpanGuard {
+        #[cfg(feature = "otel")]
+        inner: otel::ActiveSpan::start(name, parent, otel::SPAN_KIND_INTERNAL),
+    }
+}
+
+// Opens a server span per REST request, continuing any incoming `traceparent`
+pub async fn trace_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    #[cfg(feature = "otel")]
+    return otel::trace_request(req, next).await;
+    #[cfg(not(feature = "otel"))]
+    next.call(req).await
+}
+
+pub fn spawn_exporter() {
+    #[cfg(feature = "otel")]
+    otel::spawn_exporter();
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use actix_web::body::MessageBody;
+    use actix_web::dev::{ServiceRequest, ServiceResponse};
+    use actix_web::middleware::Next;
+    use actix_web::rt;
+    use std::cell::RefCell;
+    use std::sync::Mutex;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use uuid::Uuid;
+
+    use super::{parse_traceparent, SpanContext};
+    use crate::outbound;
+
+    pub const SPAN_KIND_INTERNAL: u8 = 1;
+    const SPAN_KIND_SERVER: u8 = 2;
+    const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+    // Spans beyond this are dropped if the collector is unreachable
+    const MAX_BUFFERED_SPANS: usize = 10_000;
+
+    thread_local! {
+        static ACTIVE: RefCell<Vec<SpanContext>> = const { RefCell::new(Vec::new()) };
+    }
+
+    tokio::task_local! {
+        static REQUEST_SPAN: SpanContext;
+    }
+
+    static FINISHED: Mutex<Vec<FinishedSpan>> = Mutex::new(Vec::new());
+
+    struct FinishedSpan {
+        ctx: SpanContext,
+        parent: Option<SpanContext>,
+        name: String,
+        kind: u8,
+        start: u128,
+        end: u128,
+        attributes: Vec<(&'static str, String)>,
+    }
+
+    pub struct ActiveSpan {
+        pub ctx: SpanContext,
+        pub attributes: Vec<(&'static str, String)>,
+        parent: Option<SpanContext>,
+        name: String,
+        kind: u8,
+        start: u128,
+        on_stack: bool,
+    }
+
+    fn now_nanos() -> u128 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0)
+    }
+
+    fn current() -> Option<SpanContext> {
+        ACTIVE
+            .with(|stack| stack.borrow().last().copied())
+            .or_else(|| REQUEST_SPAN.try_with(|ctx| *ctx).ok())
+    }
+
+    impl ActiveSpan {
+        pub fn start(name: &str, parent: Option<SpanContext>, kind: u8) -> Self {
+            let parent = parent.or_else(current);
+            let ctx = SpanContext {
+                trace_id: parent.map_or_else(|| Uuid::new_v4().as_u128(), |p| p.trace_id),
+                span_id: Uuid::new_v4().as_u128() as u64,
+            };
+            // Request spans live across awaits, so they use the task-local instead
+            let on_stack = kind != SPAN_KIND_SERVER;
+            if on_stack {
+                ACTIVE.with(|stack| stack.borrow_mut().push(ctx));
+            }
+            ActiveSpan {
+                ctx,
+                attributes: Vec::new(),
+                parent,
+                name: name.to_string(),
+                kind,
+                start: now_nanos(),
+                on_stack,
+            }
+        }
+    }
+
+    impl Drop for ActiveSpan {
+        fn drop(&mut self) {
+            if self.on_stack {
+                ACTIVE.with(|stack| {
+                    let mut stack = stack.borrow_mut();
+                    if let Some(pos) = stack.iter().rposition(|c| *c == self.ctx) {
+                        stack.remove(pos);
+                    }
+                });
+            }
+            let mut finished = FINISHED.lock().unwrap();
+            if finished.len() < MAX_BUFFERED_SPANS {
+                finished.push(FinishedSpan {
+                    ctx: self.ctx,
+                    parent: self.parent,
+                    name: std::mem::take(&mut self.name),
+                    kind: self.kind,
+                    start: self.start,
+                    end: now_nanos(),
+                    attributes: std::mem::take(&mut self.attributes),
+                });
+            }
+        }
+    }
+
+    pub async fn trace_request(
+        req: ServiceRequest,
+        next: Next<impl MessageBody>,
+    ) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+        let parent = req
+            .headers()
+            .get("traceparent")
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_traceparent);
+        let route = req
+            .match_pattern()
+            .unwrap_or_else(|| req.path().to_string());
+        let mut span = ActiveSpan::start(
+            &format!("{} {}", req.method(), route),
+            parent,
+            SPAN_KIND_SERVER,
+        );
+        span.attributes
+            .push(("http.method", req.method().to_string()));
+        span.attributes.push(("http.route", route));
+
+        let res = REQUEST_SPAN.scope(span.ctx, next.call(req)).await;
+        if let Ok(res) = &res {
+            span.attributes
+                .push(("http.status_code", res.status().as_u16().to_string()));
+        }
+        res
+    }
+
+    fn to_otlp(spans: Vec<FinishedSpan>) -> serde_json::Value {
+        let spans: Vec<_> = spans
+            .into_iter()
+            .map(|span| {
+                let attributes: Vec<_> = span
+                    .attributes
+                    .into_iter()
+                    .map(|(key, value)| {
+                        serde_json::json!({ "key": key, "value": { "stringValue": value } })
+                    })
+                    .collect();
+                serde_json::json!({
+                    "traceId": format!("{:032x}", span.ctx.trace_id),
+                    "spanId": format!("{:016x}", span.ctx.span_id),
+                    "parentSpanId": span
+                        .parent
+                        .map(|p| format!("{:016x}", p.span_id))
+                        .unwrap_or_default(),
+                    "name": span.name,
+                    "kind": span.kind,
+                    "startTimeUnixNano": span.start.to_string(),
+                    "endTimeUnixNano": span.end.to_string(),
+                    "attributes": attributes,
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": env!("CARGO_PKG_NAME") },
+                    }],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": env!("CARGO_PKG_NAME") },
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+
+    pub fn spawn_exporter() {
+        let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+            .unwrap_or_else(|_| "http://localhost:4318".to_string());
+        let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+        rt::spawn(async move {
+            let client = outbound::client();
+            let mut interval = rt::time::interval(EXPORT_INTERVAL);
+            loop {
+                interval.tick().await;
+                let batch = std::mem::take(&mut *FINISHED.lock().unwrap());
+                if batch.is_empty() {
+                    continue;
+                }
+                if let Err(err) = client.post(&url).send_json(&to_otlp(batch)).await {
+                    log::warn!("OTLP export to {} failed: {}", url, err);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,67 @@
+// Threaded replies. A message sent with `parent_message_id` replies in the
+// thread under that message; replying to a reply joins the same thread, so
+// threads stay one level deep. History shows each root's reply count, and
+// the thread endpoint lists the replies themselves.
+use actix_web::{web, HttpResponse};
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::history::{self, HistoryEntry};
+use crate::invites;
+use crate::policy::Mutation;
+use crate::visibility;
+use crate::{ChatMessage, SharedState};
+
+// The root a new reply to `parent` belongs under
+pub fn thread_root(state: &SharedState, room_id: Uuid, parent: Uuid) -> Result<Uuid, ApiError> {
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    let find = |id: Uuid| room.message_log.iter().find(|msg| msg.id == id);
+    let parent = find(parent).ok_or(ApiError::MessageNotFound)?;
+    let root = match parent.parent_message_id {
+        Some(root_id) => find(root_id).ok_or(ApiError::MessageNotFound)?,
+        None => parent,
+    };
+    room.policy.check(root, Mutation::Reply)?;
+    Ok(root.id)
+}
+
+// Thread root id -> replies, over the whole log
+pub fn reply_counts(log: &[ChatMessage]) -> HashMap<Uuid, usize> {
+    let mut counts = HashMap::new();
+    for root in log.iter().filter_map(|msg| msg.parent_message_id) {
+        *counts.entry(root).or_default() += 1;
+    }
+    counts
+}
+
+// Oldest reply first
+pub async fn thread_replies(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, Uuid)>,
+    user: Option<UserContext>,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, message_id) = path.into_inner();
+    let room_id = state.resolve_room_id(room_id);
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    invites::check_reader(&state, room, user.as_ref())?;
+    let log = visibility::visible_log(&state, room, history::username(user.as_ref()));
+    let root = log
+        .iter()
+        .find(|msg| msg.id == message_id)
+        .ok_or(ApiError::MessageNotFound)?;
+    let replies: Vec<_> = log
+        .iter()
+        .filter(|msg| msg.parent_message_id == Some(message_id))
+        .map(HistoryEntry::project)
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": room_id,
+        "root": HistoryEntry::project(root).with_reply_count(replies.len()),
+        "replies": replies,
+    })))
+}
@@ -0,0 +1,159 @@
+use actix_web::rt;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::deadletter::{self, Undelivered};
+use crate::messages::Priority;
+use crate::ratelimit::Limit;
+use crate::{ChatMessage, RoomEvent, SharedState};
+
+// Aggregate fan-out budget for a single room, shared by all of its senders
+pub const ROOM_BROADCAST_LIMIT: Limit = Limit {
+    capacity: 50.0,
+    refill_per_sec: 20.0,
+};
+
+const DRAIN_INTERVAL: Duration = Duration::from_millis(100);
+
+struct RoomThrottle {
+    tokens: f64,
+    last_refill: Instant,
+    queue: VecDeque<ChatMessage>,
+}
+
+impl RoomThrottle {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * ROOM_BROADCAST_LIMIT.refill_per_sec)
+            .min(ROOM_BROADCAST_LIMIT.capacity);
+        self.last_refill = now;
+    }
+}
+
+pub enum Admission {
+    Deliver,
+    // `newly_throttled` is set on the message that tipped the room over its cap
+    Queued { newly_throttled: bool },
+}
+
+#[derive(Default)]
+pub struct BroadcastThrottle {
+    rooms: Mutex<HashMap<Uuid, RoomThrottle>>,
+}
+
+impl BroadcastThrottle {
+    pub fn admit(&self, msg: &ChatMessage) -> Admission {
+        let now = Instant::now();
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.entry(msg.room_id).or_insert(RoomThrottle {
+            tokens: ROOM_BROADCAST_LIMIT.capacity,
+            last_refill: now,
+            queue: VecDeque::new(),
+        });
+        room.refill(now);
+
+        // High priority goes out at once and ahead of any backlog, without
+        // spending the room's budget
+        if msg.priority == Priority::High {
+            return Admission::Deliver;
+        }
+        // Once anything is queued, later messages queue behind it to keep ordering
+        if room.queue.is_empty() && room.tokens >= 1.0 {
+            room.tokens -= 1.0;
+            return Admission::Deliver;
+        }
+        let newly_throttled = room.queue.is_empty();
+        room.queue.push_back(msg.clone());
+        Admission::Queued { newly_throttled }
+    }
+
+    // Pops what each throttled room may deliver right now. The bool is true
+    // when the room's backlog has fully drained.
+    fn drain(&self) -> Vec<(Uuid, Vec<ChatMessage>, bool)> {
+        let now = Instant::now();
+        let mut rooms = self.rooms.lock().unwrap();
+        let mut ready = Vec::new();
+        for (room_id, room) in rooms.iter_mut().filter(|(_, r)| !r.queue.is_empty()) {
+            room.refill(now);
+            let n = (room.tokens as usize).min(room.queue.len());
+            room.tokens -= n as f64;
+            let batch: Vec<_> = room.queue.drain(..n).collect();
+            ready.push((*room_id, batch, room.queue.is_empty()));
+        }
+        ready
+    }
+
+    pub fn queued(&self, room_id: Uuid) -> usize {
+        self.rooms
+            .lock()
+            .unwrap()
+            .get(&room_id)
+            .map_or(0, |room| room.queue.len())
+    }
+}
+
+pub fn notify_moderators(state: &SharedState, room_id: Uuid, event: RoomEvent) {
+    let room_staff: Vec<String> = state
+        .chat_rooms
+        .lock()
+        .unwrap()
+        .get(&room_id)
+        .map(|room| {
+            let mut staff: Vec<String> = room.moderators.iter().cloned().collect();
+            staff.push(room.created_by.clone());
+            staff
+        })
+        .unwrap_or_default();
+    let mut moderators: Vec<String> = state.admins.lock().unwrap().iter().cloned().collect();
+    moderators.extend(room_staff);
+    moderators.sort();
+    moderators.dedup();
+    for username in &moderators {
+        state.notify_user(username, event.clone());
+    }
+}
+
+pub fn spawn_drainer(state: Arc<SharedState>) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(DRAIN_INTERVAL);
+        loop {
+            interval.tick().await;
+            let sessions = state.active_sessions.lock().unwrap();
+            let drained = state.broadcast_throttle.drain();
+            let mut undelivered = Vec::new();
+            for (room_id, batch, _) in &drained {
+                let recipients = sessions.get(room_id).map_or(&[][..], Vec::as_slice);
+                for msg in batch {
+                    let dead = deadletter::fan_out(recipients, msg);
+                    if !dead.is_empty() {
+                        undelivered.push((*room_id, dead, msg.clone()));
+                    }
+                }
+            }
+            drop(sessions);
+            for (room_id, dead, msg) in undelivered {
+                deadletter::record_undelivered(
+                    &state,
+                    room_id,
+                    dead,
+                    Undelivered::Chat(Box::new(msg)),
+                );
+            }
+
+            for (room_id, _, recovered) in drained {
+                if recovered {
+                    notify_moderators(
+                        &state,
+                        room_id,
+                        RoomEvent(serde_json::json!({
+                            "type": "room_throttle_lifted",
+                            "room_id": room_id,
+                        })),
+                    );
+                }
+            }
+        }
+    });
+}
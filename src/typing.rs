@@ -0,0 +1,183 @@
+// Typing indicators. A client sends `typing_start` while its user types and
+// `typing_stop` when they stop; the room's other sessions get the matching
+// events. Nothing is stored in the room log. Repeated starts within the
+// debounce window only extend the indicator, and an indicator that isn't
+// refreshed lapses on its own, as do those of users who send or disconnect.
+// Unless coalescing is off, sessions get per-room summaries instead of the
+// individual events, see `coalesce`.
+use actix_web::rt;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::deadletter;
+use crate::shadowban;
+use crate::{now_millis, RoomEvent, SharedState};
+
+// Starts closer together than this aren't relayed again
+const DEBOUNCE_MS: u64 = 3_000;
+// Clients keep typing alive by repeating typing_start within this
+const TIMEOUT_MS: u64 = 8_000;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Typist {
+    relayed_at: u64,
+    expires_at: u64,
+}
+
+enum Start {
+    New,
+    // Already typing, and due to be relayed again
+    Repeat,
+    Debounced,
+}
+
+#[derive(Default)]
+pub struct TypingTracker {
+    rooms: HashMap<Uuid, HashMap<String, Typist>>, // room_id -> username -> indicator
+}
+
+impl TypingTracker {
+    fn start(&mut self, room_id: Uuid, username: &str, now: u64) -> Start {
+        let typists = self.rooms.entry(room_id).or_default();
+        match typists.get_mut(username) {
+            Some(typist) => {
+                typist.expires_at = now + TIMEOUT_MS;
+                if now.saturating_sub(typist.relayed_at) < DEBOUNCE_MS {
+                    return Start::Debounced;
+                }
+                typist.relayed_at = now;
+                Start::Repeat
+            }
+            None => {
+                typists.insert(
+                    username.to_string(),
+                    Typist {
+                        relayed_at: now,
+                        expires_at: now + TIMEOUT_MS,
+                    },
+                );
+                Start::New
+            }
+        }
+    }
+
+    // Who is typing in the room, by name
+    pub fn typists(&self, room_id: Uuid) -> Vec<String> {
+        let mut typists: Vec<String> = self
+            .rooms
+            .get(&room_id)
+            .map(|typists| typists.keys().cloned().collect())
+            .unwrap_or_default();
+        typists.sort();
+        typists
+    }
+
+    // Whether there was an indicator to take down
+    fn stop(&mut self, room_id: Uuid, username: &str) -> bool {
+        let Some(typists) = self.rooms.get_mut(&room_id) else {
+            return false;
+        };
+        let removed = typists.remove(username).is_some();
+        if typists.is_empty() {
+            self.rooms.remove(&room_id);
+        }
+        removed
+    }
+
+    fn expire(&mut self, now: u64) -> Vec<(Uuid, String)> {
+        let mut lapsed = Vec::new();
+        for (room_id, typists) in self.rooms.iter_mut() {
+            typists.retain(|username, typist| {
+                let live = typist.expires_at > now;
+                if !live {
+                    lapsed.push((*room_id, username.clone()));
+                }
+                live
+            });
+        }
+        self.rooms.retain(|_, typists| !typists.is_empty());
+        lapsed
+    }
+}
+
+// To every session in the room except the typist's own
+fn relay(state: &SharedState, room_id: Uuid, username: &str, event_type: &str) {
+    let event = RoomEvent(serde_json::json!({
+        "type": event_type,
+        "room_id": room_id,
+        "username": username,
+    }));
+    let sessions = state.active_sessions.lock().unwrap();
+    let user_sessions = state.user_sessions.lock().unwrap();
+    let own = user_sessions.get(username).map_or(&[][..], Vec::as_slice);
+    let others: Vec<_> = sessions
+        .get(&room_id)
+        .into_iter()
+        .flatten()
+        .filter(|addr| !own.contains(addr))
+        .cloned()
+        .collect();
+    drop(user_sessions);
+    drop(sessions);
+    // Indicators are momentary, so undelivered ones aren't kept as dead letters
+    deadletter::fan_out(&others, &event);
+}
+
+// A summary only lists who is typing, so repeated starts don't change it
+fn changed(state: &SharedState, room_id: Uuid, username: &str, event_type: &str) {
+    if state.coalescer.enabled() {
+        state.coalescer.typing_changed(room_id);
+    } else {
+        relay(state, room_id, username, event_type);
+    }
+}
+
+pub fn typing_started(state: &SharedState, room_id: Uuid, username: &str) {
+    // Nobody else sees a shadow-banned user's messages, so nor their typing
+    if shadowban::is_banned(state, username) {
+        return;
+    }
+    let start = state
+        .typing
+        .lock()
+        .unwrap()
+        .start(room_id, username, now_millis());
+    match start {
+        Start::New => changed(state, room_id, username, "typing_start"),
+        Start::Repeat if !state.coalescer.enabled() => {
+            relay(state, room_id, username, "typing_start")
+        }
+        _ => {}
+    }
+}
+
+pub fn typing_stopped(state: &SharedState, room_id: Uuid, username: &str) {
+    let stopped = state.typing.lock().unwrap().stop(room_id, username);
+    if stopped {
+        changed(state, room_id, username, "typing_stop");
+    }
+}
+
+// The message itself tells the room the user has stopped, so no typing_stop
+// is relayed; the next summary leaves them out
+pub fn message_sent(state: &SharedState, room_id: Uuid, username: &str) {
+    let stopped = state.typing.lock().unwrap().stop(room_id, username);
+    if stopped && state.coalescer.enabled() {
+        state.coalescer.typing_changed(room_id);
+    }
+}
+
+pub fn spawn_sweeper(state: Arc<SharedState>) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let lapsed = state.typing.lock().unwrap().expire(now_millis());
+            for (room_id, username) in lapsed {
+                changed(&state, room_id, &username, "typing_stop");
+            }
+        }
+    });
+}
@@ -0,0 +1,257 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::receipts;
+use crate::users::owned_username;
+use crate::{ChatMessage, ChatRoom, SharedState};
+
+#[derive(Serialize, Clone, Copy, Default)]
+pub struct RoomUnread {
+    last_read_seq: u64,
+    unread: u64,
+    mentions: u64,
+    // Activity since the last digest run, see `take_digests`
+    #[serde(skip)]
+    digest_messages: u64,
+    #[serde(skip)]
+    digest_mentions: u64,
+}
+
+// Missed activity in one room since the previous digest
+pub struct DigestEntry {
+    pub username: String,
+    pub room_id: Uuid,
+    pub messages: u64,
+    pub mentions: u64,
+}
+
+// Per-user, per-room counters bumped on every append, so the badge summary
+// never scans history. Moving a read marker recounts only that room's tail.
+#[derive(Default)]
+pub struct UnreadTracker {
+    counters: HashMap<String, HashMap<Uuid, RoomUnread>>, // username -> room_id -> counters
+}
+
+fn mentions(msg: &ChatMessage, username: &str) -> bool {
+    msg.mentions.iter().any(|name| name == username)
+}
+
+impl UnreadTracker {
+    pub fn room_removed(&mut self, room_id: Uuid) {
+        for rooms in self.counters.values_mut() {
+            rooms.remove(&room_id);
+        }
+    }
+
+    // Called from the append path while the room is still locked
+    pub fn message_appended(&mut self, room: &ChatRoom, msg: &ChatMessage) {
+        for member in room.members() {
+            if member == msg.sender {
+                continue;
+            }
+            let mentioned = mentions(msg, &member);
+            let counter = self
+                .counters
+                .entry(member)
+                .or_default()
+                .entry(room.id)
+                .or_default();
+            counter.unread += 1;
+            counter.digest_messages += 1;
+            if mentioned {
+                counter.mentions += 1;
+                counter.digest_mentions += 1;
+            }
+        }
+    }
+
+    // Collects what each user missed in the rooms `wanted` selects, capped by
+    // what is still unread, and starts a new digest period everywhere
+    pub fn take_digests(&mut self, wanted: impl Fn(&str, Uuid) -> bool) -> Vec<DigestEntry> {
+        let mut entries = Vec::new();
+        for (username, rooms) in self.counters.iter_mut() {
+            for (room_id, counter) in rooms.iter_mut() {
+                let messages = counter.digest_messages.min(counter.unread);
+                if messages > 0 && wanted(username, *room_id) {
+                    entries.push(DigestEntry {
+                        username: username.clone(),
+                        room_id: *room_id,
+                        messages,
+                        mentions: counter.digest_mentions.min(counter.mentions),
+                    });
+                }
+                counter.digest_messages = 0;
+                counter.digest_mentions = 0;
+            }
+        }
+        entries
+    }
+
+    // Also says whether the marker moved, which is when receipts go out
+    fn mark_read(&mut self, username: &str, room: &ChatRoom, seq: u64) -> (RoomUnread, bool) {
+        let counter = self
+            .counters
+            .entry(username.to_string())
+            .or_default()
+            .entry(room.id)
+            .or_default();
+        // Markers only move forward, so a stale client can't resurrect a badge
+        let previous = counter.last_read_seq;
+        counter.last_read_seq = counter.last_read_seq.max(seq.min(room.next_seq));
+        recount(counter, username, room);
+        (*counter, counter.last_read_seq > previous)
+    }
+
+    // After `source_id` was merged into `room` and its log renumbered.
+    // `remap` turns a marker seq in either old room into one in the merged
+    // log; someone who read both rooms has read up to the nearer marker.
+    pub fn room_merged(
+        &mut self,
+        source_id: Uuid,
+        room: &ChatRoom,
+        remap: impl Fn(Uuid, u64) -> u64,
+    ) {
+        for (username, rooms) in self.counters.iter_mut() {
+            let old = [room.id, source_id].map(|room_id| (room_id, rooms.remove(&room_id)));
+            let mut merged: Option<RoomUnread> = None;
+            for (room_id, counter) in old {
+                let Some(counter) = counter else {
+                    continue;
+                };
+                let marker = remap(room_id, counter.last_read_seq);
+                let merged = merged.get_or_insert(RoomUnread {
+                    last_read_seq: marker,
+                    ..RoomUnread::default()
+                });
+                merged.last_read_seq = merged.last_read_seq.min(marker);
+                merged.digest_messages += counter.digest_messages;
+                merged.digest_mentions += counter.digest_mentions;
+            }
+            if let Some(mut counter) = merged {
+                recount(&mut counter, username, room);
+                rooms.insert(room.id, counter);
+            }
+        }
+    }
+
+    pub fn last_read_seq(&self, username: &str, room_id: Uuid) -> u64 {
+        self.counters
+            .get(username)
+            .and_then(|rooms| rooms.get(&room_id))
+            .map_or(0, |counter| counter.last_read_seq)
+    }
+
+    // How far each user has read in a room; members who never marked are absent
+    pub fn read_markers(&self, room_id: Uuid) -> BTreeMap<String, u64> {
+        self.counters
+            .iter()
+            .filter_map(|(username, rooms)| {
+                let counter = rooms.get(&room_id)?;
+                (counter.last_read_seq > 0).then(|| (username.clone(), counter.last_read_seq))
+            })
+            .collect()
+    }
+}
+
+// Unread and mention counts for what follows the marker
+fn recount(counter: &mut RoomUnread, username: &str, room: &ChatRoom) {
+    let tail = room.message_log.iter().filter(|msg| {
+        msg.seq > counter.last_read_seq && msg.sender != username && msg.deleted_at.is_none()
+    });
+    let (mut unread, mut mentioned) = (0, 0);
+    for msg in tail {
+        unread += 1;
+        if mentions(msg, username) {
+            mentioned += 1;
+        }
+    }
+    counter.unread = unread;
+    counter.mentions = mentioned;
+}
+
+// Moves `username`'s marker in a room, defaulting to its newest message, and
+// tells the room when it advanced. Shared by the REST and WebSocket paths.
+pub fn mark_read(
+    state: &SharedState,
+    username: &str,
+    room_id: Uuid,
+    seq: Option<u64>,
+) -> Result<RoomUnread, ApiError> {
+    let rooms = state.chat_rooms.lock().unwrap();
+    let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+    if !room.members().contains(username) {
+        return Err(ApiError::ParticipantNotFound);
+    }
+    let seq = seq.unwrap_or(room.next_seq);
+    let (counter, advanced) = state.unread.lock().unwrap().mark_read(username, room, seq);
+    drop(rooms);
+    if advanced {
+        receipts::marker_advanced(state, room_id, username, counter.last_read_seq);
+    }
+    Ok(counter)
+}
+
+#[derive(Deserialize)]
+pub struct ReadMarker {
+    // Defaults to the newest message in the room
+    #[serde(default)]
+    seq: Option<u64>,
+}
+
+pub async fn unread_summary(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<String>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let username = owned_username(&state, &path, &user)?;
+    let tracker = state.unread.lock().unwrap();
+    let rooms: Vec<_> = tracker
+        .counters
+        .get(&username)
+        .into_iter()
+        .flatten()
+        .filter(|(_, counter)| counter.unread > 0)
+        .map(|(room_id, counter)| {
+            serde_json::json!({
+                "room_id": room_id,
+                "unread": counter.unread,
+                "mentions": counter.mentions,
+            })
+        })
+        .collect();
+    let total_unread: u64 = rooms
+        .iter()
+        .map(|room| room["unread"].as_u64().unwrap())
+        .sum();
+    let total_mentions: u64 = rooms
+        .iter()
+        .map(|room| room["mentions"].as_u64().unwrap())
+        .sum();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "username": username,
+        "total_unread": total_unread,
+        "total_mentions": total_mentions,
+        "rooms": rooms,
+    })))
+}
+
+pub async fn set_read_marker(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(String, Uuid)>,
+    user: UserContext,
+    form: web::Json<ReadMarker>,
+) -> Result<HttpResponse, ApiError> {
+    let (username, room_id) = path.into_inner();
+    let username = owned_username(&state, &username, &user)?;
+    let room_id = state.resolve_room_id(room_id);
+    let counter = mark_read(&state, &username, room_id, form.seq)?;
+
+    let mut body = serde_json::to_value(counter).unwrap();
+    body["room_id"] = room_id.to_string().into();
+    Ok(HttpResponse::Ok().json(body))
+}
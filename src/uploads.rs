@@ -0,0 +1,669 @@
+// Files uploaded to a room. A member posts a multipart form to
+// /rooms/{id}/attachments and gets back an attachment carrying an upload id;
+// sending a message with `{"id": ...}` in its attachments fills in the rest.
+// The bytes live behind `BlobStore` (a directory, or an S3 bucket), keyed by
+// upload id, while the name, type and size stay on the message that
+// references them. Uploads nobody attaches within an hour are deleted, as
+// are the files of messages that get deleted, expire or age out.
+//
+// Stored files never change, so downloads carry an ETag derived from the
+// upload id and may be cached for a day; revalidation answers 304 without
+// reading the blob. Text-like files are also stored gzipped, and served
+// that way to clients that accept it.
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::{rt, web, HttpRequest, HttpResponse};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::Write;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+use crate::attachments::Attachment;
+use crate::auth::UserContext;
+use crate::config::{S3Bucket, UploadBackend, UploadConfig};
+use crate::error::ApiError;
+use crate::export::S3Client;
+use crate::invites;
+use crate::ratelimit::Limit;
+use crate::{now_millis, SharedState};
+
+const UPLOAD_LIMIT: Limit = Limit {
+    capacity: 5.0,
+    refill_per_sec: 0.2,
+};
+const UNCLAIMED_MILLIS: u64 = 60 * 60 * 1000;
+// Room for the multipart boundaries and part headers around the file
+const FORM_OVERHEAD_BYTES: usize = 64 * 1024;
+const MAX_NAME_CHARS: usize = 255;
+// Smaller text files gain too little from compression to store twice
+const MIN_GZIP_BYTES: usize = 1024;
+// How long clients may reuse a download before revalidating it
+const CACHE_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+pub type BlobFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, String>> + 'a>>;
+
+// Which stored copy of an upload a blob holds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Variant {
+    Original,
+    Gzip,
+}
+
+impl Variant {
+    const ALL: [Variant; 2] = [Variant::Original, Variant::Gzip];
+
+    fn blob_name(self, id: Uuid) -> String {
+        match self {
+            Variant::Original => id.to_string(),
+            Variant::Gzip => format!("{}.gz", id),
+        }
+    }
+}
+
+pub trait BlobStore: Send + Sync {
+    fn put(
+        &self,
+        id: Uuid,
+        variant: Variant,
+        content_type: String,
+        body: Vec<u8>,
+    ) -> BlobFuture<'_, ()>;
+    // None when there is no such blob
+    fn get(&self, id: Uuid, variant: Variant, limit: usize) -> BlobFuture<'_, Option<Vec<u8>>>;
+    // Deleting a missing blob succeeds
+    fn delete(&self, id: Uuid, variant: Variant) -> BlobFuture<'_, ()>;
+}
+
+struct DiskBlobs {
+    dir: PathBuf,
+}
+
+impl BlobStore for DiskBlobs {
+    fn put(
+        &self,
+        id: Uuid,
+        variant: Variant,
+        _content_type: String,
+        body: Vec<u8>,
+    ) -> BlobFuture<'_, ()> {
+        let dir = self.dir.clone();
+        let name = variant.blob_name(id);
+        Box::pin(async move {
+            rt::task::spawn_blocking(move || {
+                std::fs::create_dir_all(&dir)?;
+                // Written aside and renamed, so a crash never leaves half a file
+                let partial = dir.join(format!("{}.partial", name));
+                std::fs::write(&partial, body)?;
+                std::fs::rename(&partial, dir.join(name))
+            })
+            .await
+            .map_err(|err| err.to_string())?
+            .map_err(|err| err.to_string())
+        })
+    }
+
+    fn get(&self, id: Uuid, variant: Variant, _limit: usize) -> BlobFuture<'_, Option<Vec<u8>>> {
+        let path = self.dir.join(variant.blob_name(id));
+        Box::pin(async move {
+            rt::task::spawn_blocking(move || match std::fs::read(path) {
+                Ok(body) => Ok(Some(body)),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err.to_string()),
+            })
+            .await
+            .map_err(|err| err.to_string())?
+        })
+    }
+
+    fn delete(&self, id: Uuid, variant: Variant) -> BlobFuture<'_, ()> {
+        let path = self.dir.join(variant.blob_name(id));
+        Box::pin(async move {
+            rt::task::spawn_blocking(move || match std::fs::remove_file(path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.to_string()),
+                _ => Ok(()),
+            })
+            .await
+            .map_err(|err| err.to_string())?
+        })
+    }
+}
+
+// awc clients are tied to their thread, so each call opens its own
+struct S3Blobs {
+    bucket: S3Bucket,
+    prefix: String,
+}
+
+impl S3Blobs {
+    fn key(&self, id: Uuid, variant: Variant) -> String {
+        format!("{}/{}", self.prefix, variant.blob_name(id))
+    }
+}
+
+impl BlobStore for S3Blobs {
+    fn put(
+        &self,
+        id: Uuid,
+        variant: Variant,
+        content_type: String,
+        body: Vec<u8>,
+    ) -> BlobFuture<'_, ()> {
+        Box::pin(async move {
+            let client = S3Client::new(self.bucket.clone());
+            client
+                .put(&self.key(id, variant), &content_type, body)
+                .await
+        })
+    }
+
+    fn get(&self, id: Uuid, variant: Variant, limit: usize) -> BlobFuture<'_, Option<Vec<u8>>> {
+        Box::pin(async move {
+            let client = S3Client::new(self.bucket.clone());
+            client.get(&self.key(id, variant), limit).await
+        })
+    }
+
+    fn delete(&self, id: Uuid, variant: Variant) -> BlobFuture<'_, ()> {
+        Box::pin(async move {
+            let client = S3Client::new(self.bucket.clone());
+            client.delete(&self.key(id, variant)).await
+        })
+    }
+}
+
+struct Upload {
+    room_id: Uuid,
+    uploader: String,
+    attachment: Attachment,
+    uploaded_at: u64,
+    attached: bool,
+}
+
+pub struct Uploads {
+    blobs: Arc<dyn BlobStore>,
+    max_bytes: usize,
+    allowed_types: Vec<String>,
+    public_url: String,
+    recent: Mutex<HashMap<Uuid, Upload>>, // upload id -> upload, until claimed or swept
+}
+
+impl Default for Uploads {
+    fn default() -> Self {
+        Uploads::new(UploadConfig::default())
+    }
+}
+
+impl Uploads {
+    pub fn new(config: UploadConfig) -> Self {
+        let blobs: Arc<dyn BlobStore> = match config.backend {
+            UploadBackend::Disk(dir) => Arc::new(DiskBlobs { dir }),
+            UploadBackend::S3 { bucket, prefix } => Arc::new(S3Blobs { bucket, prefix }),
+        };
+        Uploads {
+            blobs,
+            max_bytes: config.max_bytes,
+            allowed_types: config.allowed_types,
+            public_url: config.public_url,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Absolute, so the link also works once bridged or mailed out
+    fn attachment_url(&self, room_id: Uuid, id: Uuid) -> String {
+        format!("{}/rooms/{}/attachments/{}", self.public_url, room_id, id)
+    }
+
+    pub fn max_form_bytes(&self) -> usize {
+        self.max_bytes + FORM_OVERHEAD_BYTES
+    }
+
+    fn allows(&self, content_type: &str) -> bool {
+        self.allowed_types
+            .iter()
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(family) => content_type
+                    .split_once('/')
+                    .is_some_and(|(kind, _)| kind.eq_ignore_ascii_case(family)),
+                None => allowed.eq_ignore_ascii_case(content_type),
+            })
+    }
+}
+
+// Swaps attachments that name an upload for the upload itself. Only the
+// uploader can attach it, in the room it went to, and only once.
+pub fn resolve(
+    state: &SharedState,
+    room_id: Uuid,
+    sender: &str,
+    attachments: Vec<Attachment>,
+) -> Result<Vec<Attachment>, ApiError> {
+    if attachments.iter().all(|attachment| attachment.id.is_none()) {
+        return Ok(attachments);
+    }
+    let recent = state.uploads.recent.lock().unwrap();
+    attachments
+        .into_iter()
+        .map(|attachment| {
+            let Some(id) = attachment.id else {
+                return Ok(attachment);
+            };
+            match recent.get(&id) {
+                Some(upload)
+                    if upload.room_id == room_id
+                        && upload.uploader == sender
+                        && !upload.attached =>
+                {
+                    Ok(upload.attachment.clone())
+                }
+                _ => Err(ApiError::InvalidAttachment),
+            }
+        })
+        .collect()
+}
+
+// Once the message carrying them is accepted
+pub fn attached(state: &SharedState, attachments: &[Attachment]) {
+    let mut recent = state.uploads.recent.lock().unwrap();
+    for id in attachments.iter().filter_map(|attachment| attachment.id) {
+        if let Some(upload) = recent.get_mut(&id) {
+            upload.attached = true;
+        }
+    }
+}
+
+// Deletes the stored files behind uploaded attachments, in the background
+pub fn discard<'a>(state: &SharedState, attachments: impl IntoIterator<Item = &'a Attachment>) {
+    let ids: Vec<Uuid> = attachments
+        .into_iter()
+        .filter_map(|attachment| attachment.id)
+        .collect();
+    if ids.is_empty() {
+        return;
+    }
+    let blobs = state.uploads.blobs.clone();
+    rt::spawn(async move {
+        for id in ids {
+            for variant in Variant::ALL {
+                if let Err(err) = blobs.delete(id, variant).await {
+                    log::error!("cannot delete upload {} ({:?}): {}", id, variant, err);
+                }
+            }
+        }
+    });
+}
+
+// Run from the retention janitor
+pub fn sweep_unclaimed(state: &SharedState) {
+    let cutoff = now_millis().saturating_sub(UNCLAIMED_MILLIS);
+    let mut unclaimed = Vec::new();
+    state.uploads.recent.lock().unwrap().retain(|_, upload| {
+        let keep = upload.uploaded_at >= cutoff;
+        if !keep && !upload.attached {
+            unclaimed.push(upload.attachment.clone());
+        }
+        keep
+    });
+    discard(state, &unclaimed);
+}
+
+struct FilePart {
+    name: String,
+    content_type: String,
+    data: Vec<u8>,
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|at| at + from)
+}
+
+// `name="value"` from a header such as Content-Disposition
+fn header_param<'a>(header: &'a str, param: &str) -> Option<&'a str> {
+    header.split(';').skip(1).find_map(|part| {
+        let (key, value) = part.trim().split_once('=')?;
+        key.eq_ignore_ascii_case(param)
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+// The first part of a multipart/form-data body that carries a filename
+fn file_part(content_type: &str, body: &[u8]) -> Result<FilePart, ApiError> {
+    let is_form = content_type
+        .split(';')
+        .next()
+        .is_some_and(|kind| kind.trim().eq_ignore_ascii_case("multipart/form-data"));
+    let boundary = header_param(content_type, "boundary")
+        .filter(|boundary| is_form && !boundary.is_empty())
+        .ok_or(ApiError::InvalidUpload)?;
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut at = find(body, &delimiter, 0).ok_or(ApiError::InvalidUpload)? + delimiter.len();
+    loop {
+        // `--` after a delimiter closes the form
+        if body.get(at..at + 2) != Some(b"\r\n") {
+            return Err(ApiError::InvalidUpload);
+        }
+        let headers_end = find(body, b"\r\n\r\n", at + 2).ok_or(ApiError::InvalidUpload)?;
+        let data_start = headers_end + 4;
+        let closing = [b"\r\n".as_slice(), &delimiter].concat();
+        let data_end = find(body, &closing, data_start).ok_or(ApiError::InvalidUpload)?;
+        let headers =
+            std::str::from_utf8(&body[at + 2..headers_end]).map_err(|_| ApiError::InvalidUpload)?;
+        let mut filename = None;
+        let mut part_type = None;
+        for line in headers.split("\r\n") {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            if name.trim().eq_ignore_ascii_case("content-disposition") {
+                filename = header_param(value, "filename");
+            } else if name.trim().eq_ignore_ascii_case("content-type") {
+                part_type = Some(value.trim());
+            }
+        }
+        if let Some(filename) = filename {
+            return Ok(FilePart {
+                name: clean_name(filename),
+                content_type: part_type
+                    .unwrap_or("application/octet-stream")
+                    .to_ascii_lowercase(),
+                data: body[data_start..data_end].to_vec(),
+            });
+        }
+        at = data_end + closing.len();
+    }
+}
+
+// Browsers send a bare name, but older ones sent the whole client path
+fn clean_name(raw: &str) -> String {
+    let base = raw.rsplit(['/', '\\']).next().unwrap_or(raw);
+    let name: String = base
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(MAX_NAME_CHARS)
+        .collect();
+    if name.trim().is_empty() {
+        "file".to_string()
+    } else {
+        name
+    }
+}
+
+pub async fn upload_attachment(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<Uuid>,
+    user: UserContext,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let room_id = state.resolve_room_id(path.into_inner());
+    {
+        let rooms = state.chat_rooms.lock().unwrap();
+        let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+        if !room.members().contains(&user.username) {
+            return Err(ApiError::ParticipantNotFound);
+        }
+    }
+    state
+        .rate_limiter
+        .check("upload", &user.username, UPLOAD_LIMIT)?;
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let file = file_part(content_type, &body)?;
+    let attachment = store(&state, room_id, file.name, file.content_type, file.data).await?;
+    let id = attachment.id.expect("stored attachments have an id");
+    state.uploads.recent.lock().unwrap().insert(
+        id,
+        Upload {
+            room_id,
+            uploader: user.username,
+            attachment: attachment.clone(),
+            uploaded_at: now_millis(),
+            attached: false,
+        },
+    );
+    Ok(HttpResponse::Created().json(attachment))
+}
+
+// Keeps a file for `room_id` under the same limits as member uploads. The
+// attachment it returns is for a message to reference; bridges attach it
+// directly, members get it back from `upload_attachment` to claim.
+pub async fn store(
+    state: &SharedState,
+    room_id: Uuid,
+    name: String,
+    content_type: String,
+    data: Vec<u8>,
+) -> Result<Attachment, ApiError> {
+    if data.len() > state.uploads.max_bytes {
+        return Err(ApiError::UploadTooLarge);
+    }
+    if !state.uploads.allows(&content_type) {
+        return Err(ApiError::UploadTypeNotAllowed);
+    }
+
+    let id = Uuid::new_v4();
+    let attachment = Attachment {
+        id: Some(id),
+        name: clean_name(&name),
+        content_type: content_type.clone(),
+        size: data.len() as u64,
+        url: Some(state.uploads.attachment_url(room_id, id)),
+        expired_at: None,
+    };
+    let gzipped = if compressible(&content_type, data.len()) {
+        let data = data.clone();
+        web::block(move || gzip(&data)).await.ok().flatten()
+    } else {
+        None
+    };
+    if let Err(err) = state
+        .uploads
+        .blobs
+        .put(id, Variant::Original, content_type.clone(), data)
+        .await
+    {
+        log::error!("cannot store upload {}: {}", id, err);
+        return Err(ApiError::UploadFailed);
+    }
+    // Downloads fall back to the original when this copy is missing
+    if let Some(gzipped) = gzipped {
+        let stored = state
+            .uploads
+            .blobs
+            .put(id, Variant::Gzip, content_type, gzipped)
+            .await;
+        if let Err(err) = stored {
+            log::warn!("cannot store gzipped copy of upload {}: {}", id, err);
+        }
+    }
+    Ok(attachment)
+}
+
+// Text, JSON, XML and SVG compress well; images, audio, video and archives
+// are compressed already
+fn text_like(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/json" | "application/xml" | "application/javascript"
+        )
+}
+
+fn compressible(content_type: &str, len: usize) -> bool {
+    len >= MIN_GZIP_BYTES && text_like(content_type)
+}
+
+// None unless compression actually saves space
+fn gzip(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).ok()?;
+    let compressed = encoder.finish().ok()?;
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+// q=0 means the coding is refused
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    let Some(accepted) = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    accepted.split(',').any(|coding| {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let refused = params.any(|param| {
+            param
+                .trim()
+                .strip_prefix("q=")
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+    })
+}
+
+// Weak comparison, as If-None-Match calls for
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    let bare = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|tags| {
+            tags.split(',')
+                .any(|tag| tag.trim() == "*" || bare(tag) == bare(etag))
+        })
+}
+
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    // Forces a download even for types browsers would show inline
+    #[serde(default)]
+    download: bool,
+}
+
+// Upload ids are random, so in open rooms the URL itself is what grants
+// access, the same way a message's external attachment links do. Private
+// and direct rooms also check the caller, and keep shared caches out.
+pub async fn download_attachment(
+    req: HttpRequest,
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<(Uuid, Uuid)>,
+    query: web::Query<DownloadQuery>,
+    user: Option<UserContext>,
+) -> Result<HttpResponse, ApiError> {
+    let (room_id, id) = path.into_inner();
+    let room_id = state.resolve_room_id(room_id);
+    let shared = {
+        let rooms = state.chat_rooms.lock().unwrap();
+        let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+        invites::check_reader(&state, room, user.as_ref())?;
+        !room.private && !room.direct
+    };
+    let pending = state
+        .uploads
+        .recent
+        .lock()
+        .unwrap()
+        .get(&id)
+        .filter(|upload| upload.room_id == room_id)
+        .map(|upload| upload.attachment.clone());
+    let attachment = match pending {
+        Some(attachment) => attachment,
+        None => {
+            let rooms = state.chat_rooms.lock().unwrap();
+            let room = rooms.get(&room_id).ok_or(ApiError::RoomNotFound)?;
+            room.message_log
+                .iter()
+                .flat_map(|msg| &msg.attachments)
+                .find(|attachment| attachment.id == Some(id) && !attachment.is_expired())
+                .cloned()
+                .ok_or(ApiError::AttachmentNotFound)?
+        }
+    };
+
+    // Weak, since the gzipped copy is the same file in another encoding
+    let etag = format!("W/\"{}\"", id);
+    let cache_control = format!(
+        "{}, max-age={}",
+        if shared { "public" } else { "private" },
+        CACHE_MAX_AGE_SECS
+    );
+    let compressible = compressible(&attachment.content_type, attachment.size as usize);
+    let not_modified = etag_matches(req.headers(), &etag);
+    let mut res = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    res.insert_header((header::ETAG, etag.as_str()))
+        .insert_header((header::CACHE_CONTROL, cache_control));
+    if compressible {
+        res.insert_header((header::VARY, "Accept-Encoding"));
+    }
+    if not_modified {
+        return Ok(res.finish());
+    }
+
+    let limit = state.uploads.max_bytes;
+    let mut gzipped = None;
+    if compressible && accepts_gzip(req.headers()) {
+        match state.uploads.blobs.get(id, Variant::Gzip, limit).await {
+            Ok(body) => gzipped = body,
+            Err(err) => log::warn!("cannot read gzipped upload {}: {}", id, err),
+        }
+    }
+    let body = match gzipped {
+        Some(body) => {
+            res.insert_header((header::CONTENT_ENCODING, "gzip"));
+            body
+        }
+        None => match state.uploads.blobs.get(id, Variant::Original, limit).await {
+            Ok(Some(body)) => body,
+            Ok(None) => return Err(ApiError::AttachmentNotFound),
+            Err(err) => {
+                log::error!("cannot read upload {}: {}", id, err);
+                return Err(ApiError::UploadFailed);
+            }
+        },
+    };
+    // Only raster images are previewed in place; anything else, SVG
+    // included, could be a page running scripts in this server's origin. The
+    // sandbox keeps scripts off even if a browser renders it anyway.
+    let content_type = attachment.content_type.to_ascii_lowercase();
+    let inline = !query.download
+        && content_type.starts_with("image/")
+        && !content_type.starts_with("image/svg");
+    Ok(res
+        .content_type(attachment.content_type.as_str())
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .insert_header((header::CONTENT_SECURITY_POLICY, "sandbox"))
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!(
+                "{}; filename=\"{}\"",
+                if inline { "inline" } else { "attachment" },
+                attachment.name
+            ),
+        ))
+        .body(body))
+}
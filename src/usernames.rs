@@ -0,0 +1,48 @@
+use unicode_normalization::UnicodeNormalization;
+
+// How usernames are compared. Every place that accepts a username from a
+// client maps it through `canonical` before storing or looking it up.
+#[derive(Clone, Copy)]
+pub struct UsernamePolicy {
+    pub case_insensitive: bool,
+    // Refuse registrations that look like an existing account (UTS #39 skeletons)
+    pub reject_confusables: bool,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        UsernamePolicy {
+            case_insensitive: true,
+            reject_confusables: true,
+        }
+    }
+}
+
+impl UsernamePolicy {
+    pub fn canonical(&self, username: &str) -> String {
+        let normalized: String = username.trim().nfc().collect();
+        if self.case_insensitive {
+            normalized.to_lowercase()
+        } else {
+            normalized
+        }
+    }
+
+    pub fn confusable_with<'a>(
+        &self,
+        username: &str,
+        existing: impl IntoIterator<Item = &'a String>,
+    ) -> bool {
+        if !self.reject_confusables {
+            return false;
+        }
+        let target = skeleton(username);
+        existing
+            .into_iter()
+            .any(|other| other != username && skeleton(other) == target)
+    }
+}
+
+fn skeleton(username: &str) -> String {
+    unicode_security::skeleton(username).collect()
+}
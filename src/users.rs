@@ -0,0 +1,81 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::UserContext;
+use crate::error::ApiError;
+use crate::SharedState;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationLevel {
+    #[default]
+    All,
+    // Only mentions notify; everything else is rolled into periodic digests
+    Mentions,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserSettings {
+    pub share_presence: bool,
+    // Rooms not listed use NotificationLevel::All
+    #[serde(default)]
+    pub room_notifications: BTreeMap<Uuid, NotificationLevel>,
+}
+
+impl Default for UserSettings {
+    fn default() -> Self {
+        UserSettings {
+            share_presence: true,
+            room_notifications: BTreeMap::new(),
+        }
+    }
+}
+
+// Resolves a per-user path segment, allowing only the account's own user
+pub fn owned_username(
+    state: &SharedState,
+    path: &str,
+    user: &UserContext,
+) -> Result<String, ApiError> {
+    let username = state.canonical_username(path);
+    if user.username != username {
+        return Err(ApiError::NotAccountOwner);
+    }
+    if !state.user_accounts.lock().unwrap().contains_key(&username) {
+        return Err(ApiError::UserNotFound);
+    }
+    Ok(username)
+}
+
+#[derive(Deserialize)]
+pub struct SettingsUpdate {
+    settings: UserSettings,
+}
+
+pub async fn get_user_settings(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<String>,
+    user: UserContext,
+) -> Result<HttpResponse, ApiError> {
+    let username = owned_username(&state, &path, &user)?;
+    Ok(HttpResponse::Ok().json(state.user_settings(&username)))
+}
+
+pub async fn update_user_settings(
+    state: web::Data<Arc<SharedState>>,
+    path: web::Path<String>,
+    user: UserContext,
+    form: web::Json<SettingsUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    let username = owned_username(&state, &path, &user)?;
+    let form = form.into_inner();
+    state
+        .user_settings
+        .lock()
+        .unwrap()
+        .insert(username, form.settings.clone());
+    Ok(HttpResponse::Ok().json(form.settings))
+}
@@ -0,0 +1,101 @@
+// Per-message version vectors, so copies of a message changed on different
+// server instances converge to the same result wherever they are merged.
+// Every in-place change to a stored message bumps the local node's counter.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use crate::ChatMessage;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct VersionVector(BTreeMap<String, u64>); // node id -> changes made there
+
+#[derive(Debug, PartialEq, Eq)]
+enum Causality {
+    Equal,
+    Before,
+    After,
+    Concurrent,
+}
+
+impl VersionVector {
+    pub fn bump(&mut self, node_id: &str) {
+        *self.0.entry(node_id.to_string()).or_default() += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn compare(&self, other: &VersionVector) -> Causality {
+        let nodes = self.0.keys().chain(other.0.keys());
+        let (mut behind, mut ahead) = (false, false);
+        for node in nodes {
+            let mine = self.0.get(node).copied().unwrap_or(0);
+            let theirs = other.0.get(node).copied().unwrap_or(0);
+            behind |= mine < theirs;
+            ahead |= mine > theirs;
+        }
+        match (behind, ahead) {
+            (false, false) => Causality::Equal,
+            (true, false) => Causality::Before,
+            (false, true) => Causality::After,
+            (true, true) => Causality::Concurrent,
+        }
+    }
+
+    fn merge(&mut self, other: &VersionVector) {
+        for (node, count) in &other.0 {
+            let entry = self.0.entry(node.clone()).or_default();
+            *entry = (*entry).max(*count);
+        }
+    }
+}
+
+// Two changes made without seeing each other; the loser's version is kept
+// for the audit trail
+pub struct Conflict {
+    pub message_id: Uuid,
+    pub kept: VersionVector,
+    pub discarded: VersionVector,
+}
+
+// Last-writer-wins order for concurrent changes: latest change time, then
+// the serialized vector, which gives every node the same answer
+fn writer_rank(msg: &ChatMessage) -> (u64, String) {
+    let changed_at = msg
+        .sent_at
+        .max(msg.edited_at.unwrap_or(0))
+        .max(msg.deleted_at.unwrap_or(0));
+    (changed_at, serde_json::to_string(&msg.version).unwrap())
+}
+
+// Folds `remote` into `local`. Causally newer copies replace older ones;
+// concurrent ones are settled by `writer_rank` and reported.
+pub fn reconcile(local: &mut ChatMessage, remote: ChatMessage) -> Option<Conflict> {
+    match local.version.compare(&remote.version) {
+        Causality::Equal | Causality::After => None,
+        Causality::Before => {
+            *local = remote;
+            None
+        }
+        Causality::Concurrent => {
+            let mut merged = local.version.clone();
+            merged.merge(&remote.version);
+            let (winner, loser) = if writer_rank(&remote) > writer_rank(local) {
+                (remote, local.clone())
+            } else {
+                (local.clone(), remote)
+            };
+            let conflict = Conflict {
+                message_id: winner.id,
+                kept: winner.version.clone(),
+                discarded: loser.version,
+            };
+            *local = winner;
+            local.version = merged;
+            Some(conflict)
+        }
+    }
+}
@@ -0,0 +1,274 @@
+// History visibility: how much of a room's past a member who joined later
+// may read. `all` (the default) shows everything, `since_join` only what
+// was posted after they joined, and `{"last_n": n}` that plus the n messages
+// before. Staff always see the whole log, as do members who joined before
+// join times were recorded; readers who haven't joined are treated as if
+// they joined just now. The owner sets it with PATCH /rooms/{id}, and every
+// REST read of a room's messages goes through `visible_log`.
+use serde::{Deserialize, Serialize};
+
+use crate::roles;
+use crate::{now_millis, ChatMessage, ChatRoom, SharedState};
+
+const MAX_LAST_N: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryVisibility {
+    #[default]
+    All,
+    SinceJoin,
+    LastN(usize),
+}
+
+impl HistoryVisibility {
+    pub fn is_valid(&self) -> bool {
+        !matches!(self, HistoryVisibility::LastN(count) if *count > MAX_LAST_N)
+    }
+}
+
+// Records when a user entered the room; call wherever a participant is added
+pub fn joined(room: &mut ChatRoom, username: &str) {
+    room.joined_at.insert(username.to_string(), now_millis());
+}
+
+// Index of the first message in the log `username` may read
+fn first_visible(state: &SharedState, room: &ChatRoom, username: Option<&str>) -> usize {
+    let before = match room.history_visibility {
+        HistoryVisibility::All => return 0,
+        HistoryVisibility::SinceJoin => 0,
+        HistoryVisibility::LastN(count) => count,
+    };
+    let log = &room.message_log;
+    let joined_at = match username {
+        Some(username) if roles::is_staff(state, room, username) => return 0,
+        Some(username) if room.members().contains(username) => match room.joined_at.get(username) {
+            Some(&at) => at,
+            None => return 0,
+        },
+        _ => u64::MAX,
+    };
+    // The log is in seq order, not strictly in time order: imports arrive
+    // with their original timestamps. So the cut is at the first message
+    // sent after the join, and whatever came in after that stays readable.
+    log.iter()
+        .position(|msg| msg.sent_at >= joined_at)
+        .unwrap_or(log.len())
+        .saturating_sub(before)
+}
+
+// The part of the room's log `username` (or an anonymous reader) may see
+pub fn visible_log<'a>(
+    state: &SharedState,
+    room: &'a ChatRoom,
+    username: Option<&str>,
+) -> &'a [ChatMessage] {
+    &room.message_log[first_visible(state, room, username)..]
+}
+
+// Lowest seq `username` may see, for filtering results that aren't slices
+// of the log
+pub fn first_visible_seq(state: &SharedState, room: &ChatRoom, username: Option<&str>) -> u64 {
+    visible_log(state, room, username)
+        .first()
+        .map_or(room.next_seq + 1, |msg| msg.seq)
+}
+
+// Joining, accepting an invite and looking a room up all answer with the
+// room, and that answer must be cut to what the caller may now read
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App};
+    use std::sync::Arc;
+
+    use crate::messages;
+    use crate::policy::MessageKind;
+    use crate::{external, invites};
+
+    struct Fixture {
+        state: Arc<SharedState>,
+        room_id: uuid::Uuid,
+    }
+
+    impl Fixture {
+        // A since_join room owned by alice, with one message from before
+        // anyone else joined
+        fn new(private: bool) -> Fixture {
+            let state = Arc::new(SharedState::default());
+            let mut room = ChatRoom::new("history".to_string(), "alice".to_string());
+            room.private = private;
+            room.history_visibility = HistoryVisibility::SinceJoin;
+            room.external_id = Some("crm-42".to_string());
+            let mut msg = messages::compose(
+                room.id,
+                "alice",
+                "before you joined".to_string(),
+                None,
+                MessageKind::User,
+                Vec::new(),
+            );
+            msg.sent_at = now_millis() - 60_000;
+            msg.seq = 1;
+            room.next_seq = 1;
+            room.message_log.push(msg);
+            let room_id = room.id;
+            external::room_changed(&state, &room);
+            state.chat_rooms.lock().unwrap().insert(room_id, room);
+            for username in ["alice", "bob"] {
+                state
+                    .user_accounts
+                    .lock()
+                    .unwrap()
+                    .insert(username.to_string(), String::new());
+            }
+            Fixture { state, room_id }
+        }
+
+        fn token(&self, username: &str) -> String {
+            let (token, _) = self.state.auth_tokens.lock().unwrap().issue(username);
+            format!("Bearer {}", token)
+        }
+
+        fn app(
+            &self,
+        ) -> App<
+            impl actix_web::dev::ServiceFactory<
+                actix_web::dev::ServiceRequest,
+                Config = (),
+                Response = actix_web::dev::ServiceResponse,
+                Error = actix_web::Error,
+                InitError = (),
+            >,
+        > {
+            App::new()
+                .app_data(web::Data::new(self.state.clone()))
+                .route("/add_user", web::post().to(crate::add_participant))
+                .route(
+                    "/invites/{token}/accept",
+                    web::post().to(invites::accept_link),
+                )
+                .route("/rooms/lookup", web::get().to(external::lookup_room))
+                .route("/rooms/{id}/invites", web::post().to(invites::invite_user))
+                .route(
+                    "/rooms/{id}/accept_invite",
+                    web::post().to(invites::accept_invite),
+                )
+        }
+    }
+
+    fn contents(view: &serde_json::Value) -> Vec<&str> {
+        view["message_log"]
+            .as_array()
+            .expect("room views carry a message log")
+            .iter()
+            .map(|msg| msg["content"].as_str().unwrap_or_default())
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn joining_shows_no_earlier_messages() {
+        let fixture = Fixture::new(false);
+        let app = test::init_service(fixture.app()).await;
+        let req = test::TestRequest::post()
+            .uri("/add_user")
+            .insert_header(("authorization", fixture.token("bob")))
+            .set_json(serde_json::json!({ "room_id": fixture.room_id, "username": "bob" }))
+            .to_request();
+        let view: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(contents(&view).is_empty(), "{:?}", contents(&view));
+    }
+
+    #[actix_web::test]
+    async fn accepting_an_invite_shows_no_earlier_messages() {
+        let fixture = Fixture::new(true);
+        let app = test::init_service(fixture.app()).await;
+        let invite = test::TestRequest::post()
+            .uri(&format!("/rooms/{}/invites", fixture.room_id))
+            .insert_header(("authorization", fixture.token("alice")))
+            .set_json(serde_json::json!({ "username": "bob" }))
+            .to_request();
+        assert!(test::call_service(&app, invite).await.status().is_success());
+        let req = test::TestRequest::post()
+            .uri(&format!("/rooms/{}/accept_invite", fixture.room_id))
+            .insert_header(("authorization", fixture.token("bob")))
+            .to_request();
+        let view: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(contents(&view).is_empty(), "{:?}", contents(&view));
+    }
+
+    #[actix_web::test]
+    async fn accepting_a_link_shows_no_earlier_messages() {
+        let fixture = Fixture::new(true);
+        let app = test::init_service(fixture.app()).await;
+        let mint = test::TestRequest::post()
+            .uri(&format!("/rooms/{}/invites", fixture.room_id))
+            .insert_header(("authorization", fixture.token("alice")))
+            .set_json(serde_json::json!({}))
+            .to_request();
+        let link: serde_json::Value = test::call_and_read_body_json(&app, mint).await;
+        let token = link["token"].as_str().expect("a link token");
+        for _ in 0..2 {
+            // The second time bob is a member already and the link isn't used up
+            let req = test::TestRequest::post()
+                .uri(&format!("/invites/{}/accept", token))
+                .insert_header(("authorization", fixture.token("bob")))
+                .to_request();
+            let view: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            assert!(contents(&view).is_empty(), "{:?}", contents(&view));
+        }
+    }
+
+    #[actix_web::test]
+    async fn history_imported_after_a_join_does_not_move_the_cut() {
+        let fixture = Fixture::new(false);
+        let mut rooms = fixture.state.chat_rooms.lock().unwrap();
+        let room = rooms.get_mut(&fixture.room_id).unwrap();
+        room.participants.insert("bob".to_string());
+        room.joined_at
+            .insert("bob".to_string(), now_millis() - 30_000);
+        let arrivals = [
+            ("after you joined", 10_000),
+            ("imported", 120_000),
+            ("imported too", 115_000),
+        ];
+        for (content, age) in arrivals {
+            let mut msg = messages::compose(
+                room.id,
+                "alice",
+                content.to_string(),
+                None,
+                MessageKind::User,
+                Vec::new(),
+            );
+            msg.sent_at = now_millis() - age;
+            room.next_seq += 1;
+            msg.seq = room.next_seq;
+            room.message_log.push(msg);
+        }
+        let visible: Vec<_> = visible_log(&fixture.state, room, Some("bob"))
+            .iter()
+            .map(|msg| msg.content.as_str())
+            .collect();
+        assert_eq!(visible, ["after you joined", "imported", "imported too"]);
+    }
+
+    #[actix_web::test]
+    async fn lookup_shows_anonymous_readers_no_earlier_messages() {
+        let fixture = Fixture::new(false);
+        let app = test::init_service(fixture.app()).await;
+        let req = test::TestRequest::get()
+            .uri("/rooms/lookup?external_id=crm-42")
+            .to_request();
+        let view: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(contents(&view).is_empty(), "{:?}", contents(&view));
+
+        // The owner still reads the whole log
+        let req = test::TestRequest::get()
+            .uri("/rooms/lookup?external_id=crm-42")
+            .insert_header(("authorization", fixture.token("alice")))
+            .to_request();
+        let view: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(contents(&view), ["before you joined"]);
+    }
+}